@@ -0,0 +1,52 @@
+// Serves the TPM selected by the TCTI environment variables (see
+// device::tcti::from_env) on localhost port 2321, the default port of the
+// mssim TCTI, keeping clients from clearing the TPM or changing the owner
+// authorization:
+//
+//   TPM2TOOLS_TCTI=device:/dev/tpmrm0 cargo run --example proxy
+//   TPM2TOOLS_TCTI=mssim tpm2_getrandom 8
+//
+// Clients share the TPM through a resource manager, so that their transient
+// objects and sessions are kept apart.
+use std::process;
+use tpm2::device::errors::TpmDeviceError;
+use tpm2::device::policy::{CommandPolicy, TpmPolicyFilter};
+use tpm2::device::proxy::TpmProxyServer;
+use tpm2::device::socket::SocketAddress;
+use tpm2::device::tcp::{DEFAULT_COMMAND_PORT, DEFAULT_HOST, DEFAULT_PLATFORM_PORT};
+use tpm2::device::{raw, rm, tcti};
+use tpm2::tpm2::types::tcg;
+
+fn main() {
+    env_logger::init();
+
+    let transport = match tcti::from_env() {
+        Ok(transport) => transport,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    };
+    let manager = rm::TpmResourceManager::new(raw::TpmOwnedDevice::new(transport));
+    let policy = CommandPolicy::allow_all()
+        .deny(tcg::TPM_CC_CLEAR)
+        .deny_on_handle(tcg::TPM_CC_HIERARCHY_CHANGE_AUTH, tcg::TPM_RH_OWNER);
+
+    let command = SocketAddress::tcp(DEFAULT_HOST, DEFAULT_COMMAND_PORT);
+    let platform = SocketAddress::tcp(DEFAULT_HOST, DEFAULT_PLATFORM_PORT);
+    let server = TpmProxyServer::bind(&command, Some(&platform), move || {
+        Ok::<_, TpmDeviceError>(TpmPolicyFilter::new(manager.client(), policy.clone()))
+    });
+    let server = match server {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("cannot listen on {}: {}", command, err);
+            process::exit(1);
+        }
+    };
+    println!("serving on {} (platform {})", command, platform);
+    if let Err(err) = server.serve() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
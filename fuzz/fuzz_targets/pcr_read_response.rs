@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tpm2::tpm2::commands::pcrread::PcrReadResponse;
+use tpm2::tpm2::serialization::inout::DynamicByteBuffer;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buff = DynamicByteBuffer::from_vec(data.to_vec());
+    if let Ok(response) = PcrReadResponse::new(&mut buff) {
+        let _ = response.to_pcr_values();
+    }
+});
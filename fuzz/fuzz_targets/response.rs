@@ -0,0 +1,54 @@
+#![no_main]
+
+// response feeds the fuzz input, past its first byte, as the TPM response
+// to the command selected by the first byte, going through run_command and
+// the parsing of the command response
+use libfuzzer_sys::fuzz_target;
+use tpm2::tpm2::commands::pcrs::{PCRSelection, MAX_PCR};
+use tpm2::tpm2::commands::run::{self, RetryPolicy};
+use tpm2::tpm2::commands::{context, load, pcrread, session, startup, unseal};
+use tpm2::tpm2::serialization::inout::DynamicByteBuffer;
+use tpm2::tpm2::types::tcg;
+use tpm2_fuzz::FuzzDevice;
+
+const HANDLE: tcg::Handle = 0x80000000;
+
+fuzz_target!(|data: &[u8]| {
+    let (command, response) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut tpm = FuzzDevice::new(response);
+    let _ = match command % 8 {
+        0 => {
+            let selection = PCRSelection::new((0..=MAX_PCR as u8).collect());
+            pcrread::tpm2_pcr_read(&mut tpm, &[selection]).map(|_| ())
+        }
+        1 => unseal::tpm2_unseal(&mut tpm, HANDLE).map(|_| ()),
+        2 => context::tpm2_context_save(&mut tpm, HANDLE).map(|_| ()),
+        3 => context::tpm2_context_load(&mut tpm, &tcg::TpmsContext::new()).map(|_| ()),
+        4 => {
+            let auth = tcg::TpmsAuthCommand {
+                session_handle: tcg::TPM_RS_PW,
+                nonce: tcg::Tpm2bNonce::new(),
+                session_attributes: 0,
+                hmac: tcg::Tpm2bAuth::new(),
+            };
+            let public = tcg::Tpm2bPublic {
+                public: tcg::TpmtPublic::default(),
+            };
+            load::tpm2_load(&mut tpm, HANDLE, auth, tcg::Tpm2bPrivate::new(), public).map(|_| ())
+        }
+        5 => session::tpm2_startauth_session(&mut tpm).map(|_| ()),
+        6 => startup::tpm2_startup(&mut tpm, tcg::TPM_SU_CLEAR),
+        _ => run::run_command_with_policy(
+            &mut tpm,
+            &RetryPolicy::new_no_retry(),
+            tcg::TPM_CC_PCR_READ,
+            &[],
+            &[],
+            &[],
+            &mut DynamicByteBuffer::new(),
+        ),
+    };
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tpm2::tpm2::types::constants::TpmRc;
+
+fuzz_target!(|data: &[u8]| {
+    tpm2_fuzz::unpack(TpmRc::default(), data);
+});
@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tpm2::tpm2::types::tcg::TpmsSymcipherParms;
+
+fuzz_target!(|data: &[u8]| tpm2_fuzz::roundtrip(TpmsSymcipherParms::default, data));
@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tpm2::tpm2::types::tcg::TpmtRsaScheme;
+
+fuzz_target!(|data: &[u8]| tpm2_fuzz::roundtrip(TpmtRsaScheme::default, data));
@@ -0,0 +1,69 @@
+// Helpers shared by the fuzz targets. Run a target with e.g.
+//
+//   cargo fuzz run tpmt_public
+//
+// Unpacking hostile input must fail cleanly: it must neither panic nor
+// produce values that cannot be marshalled back.
+
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind};
+use std::result;
+use tpm2::device::errors::TpmDeviceError;
+use tpm2::device::raw::TpmDeviceOps;
+use tpm2::tpm2::serialization::inout::{DynamicByteBuffer, RwBytes, Tpm2StructIn, Tpm2StructOut};
+
+// unpack unpacks data into value
+pub fn unpack<T: Tpm2StructIn>(mut value: T, data: &[u8]) -> Option<T> {
+    let mut buff = DynamicByteBuffer::from_vec(data.to_vec());
+    value.unpack(&mut buff).ok().map(|_| value)
+}
+
+// roundtrip unpacks data into a value built by empty and, if that succeeds,
+// checks that the value packs and that its packed form unpacks to it. TPM2B
+// structures of size 0 unpack to an empty value that need not unpack back
+// once packed, so the final comparison only happens if that succeeds.
+pub fn roundtrip<T, F>(empty: F, data: &[u8])
+where
+    T: Tpm2StructIn + Tpm2StructOut + PartialEq + Debug,
+    F: Fn() -> T,
+{
+    let value = match unpack(empty(), data) {
+        Some(value) => value,
+        None => return,
+    };
+    let mut packed = DynamicByteBuffer::new();
+    if let Err(err) = value.pack(&mut packed) {
+        panic!("cannot pack unpacked {:?}: {}", value, err.msg);
+    }
+    if let Some(unpacked) = unpack(empty(), packed.to_bytes()) {
+        assert_eq!(unpacked, value);
+    }
+}
+
+// FuzzDevice is a transport answering the first command it is sent with
+// the fuzz input, and failing every following one. Retried commands thus
+// fail on their second attempt instead of replaying the same response.
+pub struct FuzzDevice<'a> {
+    response: Option<&'a [u8]>,
+}
+
+impl<'a> FuzzDevice<'a> {
+    pub fn new(response: &'a [u8]) -> Self {
+        FuzzDevice {
+            response: Some(response),
+        }
+    }
+}
+
+impl<'a> TpmDeviceOps for FuzzDevice<'a> {
+    fn send_recv(
+        &mut self,
+        _buff_command: &mut dyn RwBytes,
+        buff_answer: &mut dyn RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        match self.response.take() {
+            Some(response) => Ok(buff_answer.write_bytes(response)?),
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "no more fuzz input").into()),
+        }
+    }
+}
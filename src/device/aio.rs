@@ -0,0 +1,539 @@
+use crate::device::errors::{ResponseSizeError, TpmDeviceError};
+use crate::device::raw::{header_size, is_valid_locality, MAX_RESPONSE_SIZE, RESPONSE_HEADER_SIZE};
+use crate::device::socket::SocketAddress;
+use crate::device::tcp::{TPM_SEND_COMMAND, TPM_SESSION_END};
+use crate::device::timeout::Timeouts;
+
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::result;
+#[cfg(unix)]
+use std::task::{ready, Context, Poll};
+
+#[cfg(unix)]
+use tokio::io::unix::AsyncFd;
+#[cfg(unix)]
+use tokio::io::ReadBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// DeviceFuture is the future returned by AsyncTpmDeviceOps::send_recv, it
+// resolves to the complete response, header included
+pub type DeviceFuture<'a> =
+    Pin<Box<dyn Future<Output = result::Result<Vec<u8>, TpmDeviceError>> + Send + 'a>>;
+
+// AsyncTpmDeviceOps is the asynchronous counterpart of TpmDeviceOps, see
+// run::run_command_async for running commands on it
+pub trait AsyncTpmDeviceOps: Send {
+    fn send_recv<'a>(&'a mut self, command: &'a [u8]) -> DeviceFuture<'a>;
+}
+
+impl<T: AsyncTpmDeviceOps + ?Sized> AsyncTpmDeviceOps for &mut T {
+    fn send_recv<'a>(&'a mut self, command: &'a [u8]) -> DeviceFuture<'a> {
+        (**self).send_recv(command)
+    }
+}
+
+impl<T: AsyncTpmDeviceOps + ?Sized> AsyncTpmDeviceOps for Box<T> {
+    fn send_recv<'a>(&'a mut self, command: &'a [u8]) -> DeviceFuture<'a> {
+        (**self).send_recv(command)
+    }
+}
+
+// AsyncReadWrite combines the tokio I/O traits a transport must implement
+pub trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadWrite for T {}
+
+// AsyncTpmDevice talks to a TPM over an asynchronous byte stream carrying
+// raw commands and responses, such as a TPM character device opened with
+// open_device.
+//
+// When a response is not received within the timeout of the command class,
+// or the command future is dropped before the response is complete, the
+// rest of the response is still due. That stale response is read and
+// discarded before the next command is sent, within the timeout of the
+// next command.
+pub struct AsyncTpmDevice<RW: AsyncReadWrite> {
+    rw: RW,
+    timeouts: Option<Timeouts>,
+    // response holds the bytes received so far of the response to the last
+    // command written, it is None when no response is due. It is kept here
+    // rather than in the command future so that a cancelled read can be
+    // resumed.
+    response: Option<Vec<u8>>,
+}
+
+impl<RW: AsyncReadWrite> AsyncTpmDevice<RW> {
+    pub fn new(rw: RW) -> Self {
+        AsyncTpmDevice {
+            rw,
+            timeouts: Some(Timeouts::new()),
+            response: None,
+        }
+    }
+
+    // set_timeouts replaces the response timeouts, None waits forever
+    pub fn set_timeouts(&mut self, timeouts: Option<Timeouts>) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn into_inner(self) -> RW {
+        self.rw
+    }
+
+    // receive reads the response due, resuming the read of a cancelled
+    // command if any
+    async fn receive(&mut self) -> result::Result<Vec<u8>, TpmDeviceError> {
+        let response = self.response.get_or_insert_with(Vec::new);
+        let result = read_response(&mut self.rw, response).await;
+        // Nothing is left to read once the response is complete, and there
+        // is no telling where the next response starts after a failed read
+        let response = self.response.take().unwrap_or_default();
+        result.map(|()| response)
+    }
+}
+
+// open_device opens a TPM character device, e.g. /dev/tpmrm0. It must be
+// called from within a tokio runtime.
+#[cfg(unix)]
+pub async fn open_device<P: AsRef<Path>>(
+    path: P,
+) -> result::Result<AsyncTpmDevice<TpmCharDevice>, std::io::Error> {
+    Ok(AsyncTpmDevice::new(TpmCharDevice::open(path)?))
+}
+
+// TpmCharDevice is a TPM character device opened non-blocking and driven
+// by the tokio reactor. The kernel processes a command in the background
+// once written, and the device polls readable when the response is ready.
+// No read is in flight outside of a poll, so dropping a command future,
+// e.g. on timeout, leaves the response to be read later.
+#[cfg(unix)]
+pub struct TpmCharDevice {
+    fd: AsyncFd<std::fs::File>,
+}
+
+#[cfg(unix)]
+impl TpmCharDevice {
+    // open opens a TPM character device, it must be called from within a
+    // tokio runtime
+    pub fn open<P: AsRef<Path>>(path: P) -> result::Result<Self, std::io::Error> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+        // Safety: the file is owned by the AsyncFd, so its descriptor stays
+        // open and unchanged for as long as it is registered
+        let fd = unsafe { AsyncFd::register(file)? };
+        Ok(TpmCharDevice { fd })
+    }
+}
+
+#[cfg(unix)]
+impl AsyncRead for TpmCharDevice {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<result::Result<(), std::io::Error>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            match guard.try_io(|fd| fd.get_ref().read(buf.initialize_unfilled())) {
+                Ok(result) => {
+                    buf.advance(result?);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsyncWrite for TpmCharDevice {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<result::Result<usize, std::io::Error>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            match guard.try_io(|fd| fd.get_ref().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), std::io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<RW: AsyncReadWrite> AsyncTpmDeviceOps for AsyncTpmDevice<RW> {
+    fn send_recv<'a>(&'a mut self, command: &'a [u8]) -> DeviceFuture<'a> {
+        Box::pin(async move {
+            let timeouts = self.timeouts;
+            if self.response.is_some() {
+                with_timeout(timeouts, command, self.receive()).await?;
+                log::debug!("discarded the response to a cancelled command");
+            }
+            let exchange = async {
+                self.rw.write_all(command).await?;
+                self.response = Some(Vec::new());
+                self.rw.flush().await?;
+                self.receive().await
+            };
+            with_timeout(timeouts, command, exchange).await
+        })
+    }
+}
+
+// AsyncTpmSocket talks to a software TPM over the simulator command
+// protocol (see tcp::TpmSwtpmIO). Only the command channel is driven,
+// platform signals such as power on are sent with TpmSwtpmIO.
+//
+// The connection is opened on the first command and dropped after any
+// error, including timeouts, a new one is opened on the next command.
+pub struct AsyncTpmSocket {
+    address: SocketAddress,
+    stream: Option<Box<dyn AsyncReadWrite>>,
+    locality: u8,
+    timeouts: Option<Timeouts>,
+}
+
+impl AsyncTpmSocket {
+    pub fn new(address: SocketAddress) -> Self {
+        AsyncTpmSocket {
+            address,
+            stream: None,
+            locality: 0,
+            timeouts: Some(Timeouts::new()),
+        }
+    }
+
+    // set_timeouts replaces the response timeouts, None waits forever
+    pub fn set_timeouts(&mut self, timeouts: Option<Timeouts>) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    // set_locality selects the locality the following commands are sent at
+    pub fn set_locality(&mut self, locality: u8) -> result::Result<(), std::io::Error> {
+        if !is_valid_locality(locality) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid locality {}", locality),
+            ));
+        }
+        self.locality = locality;
+        Ok(())
+    }
+
+    // session_end tells the simulator that we are done with the command
+    // connection and closes it
+    pub async fn session_end(&mut self) -> result::Result<(), std::io::Error> {
+        match self.stream.take() {
+            None => Ok(()),
+            Some(mut s) => s.write_all(&TPM_SESSION_END.to_be_bytes()).await,
+        }
+    }
+}
+
+impl AsyncTpmDeviceOps for AsyncTpmSocket {
+    fn send_recv<'a>(&'a mut self, command: &'a [u8]) -> DeviceFuture<'a> {
+        Box::pin(async move {
+            // The stream is only put back after a complete exchange, a
+            // failed or cancelled one may leave part of the response unread
+            let mut stream = match self.stream.take() {
+                Some(s) => s,
+                None => connect(&self.address).await?,
+            };
+            let exchange = exchange_frame(&mut stream, self.locality, command);
+            let response = with_timeout(self.timeouts, command, exchange).await?;
+            self.stream = Some(stream);
+            Ok(response)
+        })
+    }
+}
+
+async fn connect(
+    address: &SocketAddress,
+) -> result::Result<Box<dyn AsyncReadWrite>, std::io::Error> {
+    match address {
+        SocketAddress::Tcp { host, port } => Ok(Box::new(
+            tokio::net::TcpStream::connect((host.as_str(), *port)).await?,
+        )),
+        #[cfg(unix)]
+        SocketAddress::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+    }
+}
+
+// with_timeout bounds exchange by the timeout of command
+async fn with_timeout<F>(
+    timeouts: Option<Timeouts>,
+    command: &[u8],
+    exchange: F,
+) -> result::Result<Vec<u8>, TpmDeviceError>
+where
+    F: Future<Output = result::Result<Vec<u8>, TpmDeviceError>>,
+{
+    let timeout = match timeouts {
+        None => return exchange.await,
+        Some(timeouts) => timeouts.for_command(command),
+    };
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("no response within {:?}", timeout),
+        )
+        .into()),
+    }
+}
+
+// read_response appends to response the rest of the response header, then
+// as many bytes as announced by its responseSize field. Bytes already in
+// response count as received, so that a read whose future was dropped can
+// be resumed.
+async fn read_response<RW: AsyncReadWrite + ?Sized>(
+    rw: &mut RW,
+    response: &mut Vec<u8>,
+) -> result::Result<(), TpmDeviceError> {
+    while response.len() < RESPONSE_HEADER_SIZE {
+        read_some(rw, response, RESPONSE_HEADER_SIZE).await?;
+    }
+    let size = header_size(response).unwrap_or(0);
+    check_size(size)?;
+    while response.len() < size {
+        read_some(rw, response, size).await?;
+    }
+    Ok(())
+}
+
+// read_some reads once from the stream, appending to response no more than
+// the bytes missing for it to reach expected bytes. Data is only appended
+// once the read completed, so dropping the future loses no byte.
+async fn read_some<RW: AsyncReadWrite + ?Sized>(
+    rw: &mut RW,
+    response: &mut Vec<u8>,
+    expected: usize,
+) -> result::Result<(), TpmDeviceError> {
+    let mut buf = [0; 1024];
+    let len = buf.len().min(expected - response.len());
+    match rw.read(&mut buf[..len]).await? {
+        0 => Err(ResponseSizeError::ShortRead {
+            expected,
+            received: response.len(),
+        }
+        .into()),
+        n => {
+            response.extend_from_slice(&buf[..n]);
+            Ok(())
+        }
+    }
+}
+
+// exchange_frame sends a command framed with TPM_SEND_COMMAND and reads the
+// framed response
+async fn exchange_frame<RW: AsyncReadWrite + ?Sized>(
+    rw: &mut RW,
+    locality: u8,
+    command: &[u8],
+) -> result::Result<Vec<u8>, TpmDeviceError> {
+    let mut frame = Vec::with_capacity(command.len() + 9);
+    frame.extend_from_slice(&TPM_SEND_COMMAND.to_be_bytes());
+    frame.push(locality);
+    frame.extend_from_slice(&(command.len() as u32).to_be_bytes());
+    frame.extend_from_slice(command);
+    rw.write_all(&frame).await?;
+
+    let size = rw.read_u32().await? as usize;
+    check_size(size)?;
+    let mut response = vec![0; size];
+    read_full(rw, &mut response, 0).await?;
+    let ack = rw.read_u32().await?;
+    if ack != 0 {
+        return Err(Error::other(format!(
+            "simulator did not acknowledge the command: {:#x}",
+            ack
+        ))
+        .into());
+    }
+
+    let declared = header_size(&response).unwrap_or(0);
+    if declared != size {
+        return Err(ResponseSizeError::Mismatch {
+            declared,
+            received: size,
+        }
+        .into());
+    }
+    Ok(response)
+}
+
+fn check_size(size: usize) -> result::Result<(), TpmDeviceError> {
+    if size < RESPONSE_HEADER_SIZE {
+        return Err(ResponseSizeError::Mismatch {
+            declared: size,
+            received: RESPONSE_HEADER_SIZE,
+        }
+        .into());
+    }
+    if size > MAX_RESPONSE_SIZE {
+        return Err(ResponseSizeError::Oversize {
+            size,
+            max: MAX_RESPONSE_SIZE,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+// read_full fills buf, failing with ResponseSizeError::ShortRead if the
+// stream ends first. offset is the number of response bytes already
+// received, and is only used for reporting.
+async fn read_full<RW: AsyncReadWrite + ?Sized>(
+    rw: &mut RW,
+    buf: &mut [u8],
+    offset: usize,
+) -> result::Result<(), TpmDeviceError> {
+    let mut received = 0;
+    while received < buf.len() {
+        match rw.read(&mut buf[received..]).await? {
+            0 => {
+                return Err(ResponseSizeError::ShortRead {
+                    expected: offset + buf.len(),
+                    received: offset + received,
+                }
+                .into())
+            }
+            n => received += n,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn command(tag: u8) -> Vec<u8> {
+        vec![
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x01, 0x7b, 0x00, tag,
+        ]
+    }
+
+    fn response(tag: u8) -> Vec<u8> {
+        vec![
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x00, 0x00, 0x00, tag,
+        ]
+    }
+
+    #[tokio::test]
+    async fn stale_response_discarded() {
+        let (client, mut tpm) = tokio::io::duplex(64);
+        let mut device = AsyncTpmDevice::new(client);
+        device.set_timeouts(Some(Timeouts::new_uniform(Duration::from_millis(50))));
+
+        // The first command times out, its response arrives afterwards
+        let first = device.send_recv(&command(1)).await;
+        assert!(matches!(first, Err(TpmDeviceError::TimeoutError(_))));
+        tpm.write_all(&response(1)).await.unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0; 24];
+            tpm.read_exact(&mut buf).await.unwrap();
+            tpm.write_all(&response(2)).await.unwrap();
+            tpm
+        });
+        assert_eq!(device.send_recv(&command(2)).await.unwrap(), response(2));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_read_drained() {
+        // Cut the response inside the header and inside the body
+        for split in [4, RESPONSE_HEADER_SIZE].iter() {
+            let (client, mut tpm) = tokio::io::duplex(64);
+            let mut device = AsyncTpmDevice::new(client);
+            device.set_timeouts(None);
+
+            // The caller gives up on the command while its response is
+            // being read
+            let server = tokio::spawn(async move {
+                let mut buf = vec![0; 12];
+                tpm.read_exact(&mut buf).await.unwrap();
+                tpm.write_all(&response(1)[..*split]).await.unwrap();
+                tpm
+            });
+            let first =
+                tokio::time::timeout(Duration::from_millis(50), device.send_recv(&command(1)))
+                    .await;
+            assert!(first.is_err(), "split {}", split);
+            let mut tpm = server.await.unwrap();
+            tpm.write_all(&response(1)[*split..]).await.unwrap();
+
+            let server = tokio::spawn(async move {
+                let mut buf = vec![0; 12];
+                tpm.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, command(2));
+                tpm.write_all(&response(2)).await.unwrap();
+                tpm
+            });
+            assert_eq!(device.send_recv(&command(2)).await.unwrap(), response(2));
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_socket_reset() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut device = AsyncTpmSocket::new(SocketAddress::tcp("127.0.0.1", port));
+        device.set_timeouts(None);
+
+        let server = tokio::spawn(async move {
+            // The first connection answers with part of a frame only
+            let (mut first, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 21];
+            first.read_exact(&mut buf).await.unwrap();
+            first.write_all(&11u32.to_be_bytes()).await.unwrap();
+            first.write_all(&response(1)[..4]).await.unwrap();
+
+            let (mut second, _) = listener.accept().await.unwrap();
+            second.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf[9..], command(2)[..]);
+            second.write_all(&11u32.to_be_bytes()).await.unwrap();
+            second.write_all(&response(2)).await.unwrap();
+            second.write_all(&0u32.to_be_bytes()).await.unwrap();
+            (first, second)
+        });
+
+        let first =
+            tokio::time::timeout(Duration::from_millis(50), device.send_recv(&command(1))).await;
+        assert!(first.is_err());
+        assert_eq!(device.send_recv(&command(2)).await.unwrap(), response(2));
+        server.await.unwrap();
+    }
+}
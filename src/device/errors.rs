@@ -1,3 +1,5 @@
+use crate::tpm2::errors::SerializationError;
+
 use std::error::Error;
 use std::fmt;
 
@@ -19,3 +21,188 @@ impl From<std::io::Error> for DeviceIoError {
         Self { msg: e.to_string() }
     }
 }
+
+// TctiError indicates a TCTI configuration string which cannot be parsed
+// or which selects an unsupported backend
+#[derive(Debug)]
+pub struct TctiError {
+    pub msg: String,
+}
+
+impl Error for TctiError {}
+
+impl fmt::Display for TctiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TctiError: {}", self.msg)
+    }
+}
+
+// TimeoutError indicates that the TPM did not answer a command in time
+#[derive(Debug)]
+pub struct TimeoutError {
+    pub msg: String,
+}
+
+impl Error for TimeoutError {}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TimeoutError: {}", self.msg)
+    }
+}
+
+// PolicyError indicates a command rejected by a policy before reaching the
+// TPM (see device::policy)
+#[derive(Debug)]
+pub struct PolicyError {
+    pub command_code: u32,
+    pub msg: String,
+}
+
+impl Error for PolicyError {}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PolicyError: {}", self.msg)
+    }
+}
+
+// ResponseSizeError indicates a TPM response whose length does not match
+// the responseSize field of its header
+#[derive(Debug)]
+pub enum ResponseSizeError {
+    // The transport returned fewer bytes than announced
+    ShortRead { expected: usize, received: usize },
+    // The announced size exceeds the size of the receive buffer
+    Oversize { size: usize, max: usize },
+    // The announced size cannot describe the bytes actually received
+    Mismatch { declared: usize, received: usize },
+}
+
+impl Error for ResponseSizeError {}
+
+impl fmt::Display for ResponseSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResponseSizeError::ShortRead { expected, received } => write!(
+                f,
+                "ResponseSizeError: short read, expected {} bytes, received {}",
+                expected, received
+            ),
+            ResponseSizeError::Oversize { size, max } => write!(
+                f,
+                "ResponseSizeError: response of {} bytes exceeds maximum of {}",
+                size, max
+            ),
+            ResponseSizeError::Mismatch { declared, received } => write!(
+                f,
+                "ResponseSizeError: declared size {} does not match {} bytes received",
+                declared, received
+            ),
+        }
+    }
+}
+
+// ReplayError indicates that a replayed session diverged from the recording
+#[derive(Debug)]
+pub enum ReplayError {
+    // More commands were sent than were recorded
+    Exhausted {
+        index: usize,
+    },
+    // The command sent differs from the recorded one
+    Mismatch {
+        index: usize,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+
+impl Error for ReplayError {}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Exhausted { index } => write!(
+                f,
+                "ReplayError: command {} was not recorded, recording exhausted",
+                index
+            ),
+            ReplayError::Mismatch {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "ReplayError: command {} does not match recording, expected {}, got {}",
+                index,
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+        }
+    }
+}
+
+// TpmDeviceError is an error raised while exchanging a command and its
+// response with a TPM device
+#[derive(Debug)]
+pub enum TpmDeviceError {
+    IoError(DeviceIoError),
+    ResponseSizeError(ResponseSizeError),
+    ReplayError(ReplayError),
+    TimeoutError(TimeoutError),
+    PolicyError(PolicyError),
+    // SerializationError is a response which cannot be written to the buffer
+    // provided by the caller
+    SerializationError(SerializationError),
+}
+
+impl Error for TpmDeviceError {}
+
+impl fmt::Display for TpmDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TpmDeviceError::IoError(err) => write!(f, "TpmDeviceError: {}", err),
+            TpmDeviceError::ResponseSizeError(err) => write!(f, "TpmDeviceError: {}", err),
+            TpmDeviceError::ReplayError(err) => write!(f, "TpmDeviceError: {}", err),
+            TpmDeviceError::TimeoutError(err) => write!(f, "TpmDeviceError: {}", err),
+            TpmDeviceError::PolicyError(err) => write!(f, "TpmDeviceError: {}", err),
+            TpmDeviceError::SerializationError(err) => write!(f, "TpmDeviceError: {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for TpmDeviceError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => {
+                TpmDeviceError::TimeoutError(TimeoutError { msg: e.to_string() })
+            }
+            _ => TpmDeviceError::IoError(DeviceIoError::from(e)),
+        }
+    }
+}
+
+impl From<ResponseSizeError> for TpmDeviceError {
+    fn from(e: ResponseSizeError) -> Self {
+        TpmDeviceError::ResponseSizeError(e)
+    }
+}
+
+impl From<ReplayError> for TpmDeviceError {
+    fn from(e: ReplayError) -> Self {
+        TpmDeviceError::ReplayError(e)
+    }
+}
+
+impl From<PolicyError> for TpmDeviceError {
+    fn from(e: PolicyError) -> Self {
+        TpmDeviceError::PolicyError(e)
+    }
+}
+
+impl From<SerializationError> for TpmDeviceError {
+    fn from(e: SerializationError) -> Self {
+        TpmDeviceError::SerializationError(e)
+    }
+}
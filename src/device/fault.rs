@@ -0,0 +1,440 @@
+use crate::device::errors::TpmDeviceError;
+use crate::device::raw::{command_code, error_response, TpmDeviceOps};
+use crate::tpm2::serialization::inout;
+use crate::tpm2::types::constants::cc;
+use crate::tpm2::types::constants::TpmRc;
+use crate::tpm2::types::tcg;
+
+use std::io::{Error, ErrorKind};
+use std::result;
+use std::thread;
+use std::time::Duration;
+
+// Fault is a misbehaviour of the TPM or of the transport
+#[derive(Debug, Clone)]
+pub enum Fault {
+    // Truncate cuts the response after the given number of bytes, leaving
+    // the responseSize field untouched
+    Truncate(usize),
+    // FlipByte xors the response byte at offset with mask. Offsets past the
+    // end of the response are ignored.
+    FlipByte { offset: usize, mask: u8 },
+    // ResponseCode answers with the given response code instead of passing
+    // the command on, as a TPM rejecting the command would
+    ResponseCode(TpmRc),
+    // Disconnect passes the command on, then drops the response and fails
+    // with a connection reset, as a transport failing mid-exchange would
+    Disconnect,
+    // Delay holds the response back for the given time
+    Delay(Duration),
+}
+
+// FaultRule selects the commands a fault is applied to
+struct FaultRule {
+    // command_code restricts the rule to one command, None matches all
+    command_code: Option<tcg::TpmCc>,
+    fault: Fault,
+    // remaining is the number of times the rule still applies, None for
+    // rules which never expire
+    remaining: Option<usize>,
+}
+
+// TpmFaultInjector wraps a TpmDeviceOps and corrupts the exchanges matching
+// its rules, to check that callers fail with errors rather than panicking
+// when the TPM or the transport misbehaves.
+//
+// All rules matching a command are applied in registration order, so that
+// e.g. a delay and a byte flip can be combined. ResponseCode and Disconnect
+// end the exchange, rules registered after them are not applied.
+pub struct TpmFaultInjector<T: TpmDeviceOps> {
+    inner: T,
+    rules: Vec<FaultRule>,
+    injected: usize,
+}
+
+impl<T: TpmDeviceOps> TpmFaultInjector<T> {
+    pub fn new(inner: T) -> Self {
+        TpmFaultInjector {
+            inner,
+            rules: Vec::new(),
+            injected: 0,
+        }
+    }
+
+    // inject applies fault to every command
+    pub fn inject(&mut self, fault: Fault) -> &mut Self {
+        self.push(None, fault, None)
+    }
+
+    // inject_for applies fault to every command with code command_code
+    pub fn inject_for(&mut self, command_code: tcg::TpmCc, fault: Fault) -> &mut Self {
+        self.push(Some(command_code), fault, None)
+    }
+
+    // inject_once applies fault to the next command with code command_code
+    pub fn inject_once(&mut self, command_code: tcg::TpmCc, fault: Fault) -> &mut Self {
+        self.push(Some(command_code), fault, Some(1))
+    }
+
+    // clear removes all rules, exchanges are passed on untouched afterwards
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    // injected returns the number of faults applied so far
+    pub fn injected(&self) -> usize {
+        self.injected
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn push(
+        &mut self,
+        command_code: Option<tcg::TpmCc>,
+        fault: Fault,
+        remaining: Option<usize>,
+    ) -> &mut Self {
+        self.rules.push(FaultRule {
+            command_code,
+            fault,
+            remaining,
+        });
+        self
+    }
+
+    // take_faults returns the faults to apply to a command, consuming one
+    // use of every matching rule
+    fn take_faults(&mut self, command_code: Option<tcg::TpmCc>) -> Vec<Fault> {
+        let mut faults = Vec::new();
+        for rule in self.rules.iter_mut() {
+            if rule.command_code.is_some() && rule.command_code != command_code {
+                continue;
+            }
+            match &mut rule.remaining {
+                Some(0) => continue,
+                Some(remaining) => *remaining -= 1,
+                None => (),
+            }
+            faults.push(rule.fault.clone());
+        }
+        self.rules.retain(|rule| rule.remaining != Some(0));
+        faults
+    }
+}
+
+impl<T: TpmDeviceOps> TpmDeviceOps for TpmFaultInjector<T> {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        let command_code = command_code(buff_command.to_bytes());
+        let faults = self.take_faults(command_code);
+        if faults.is_empty() {
+            return self.inner.send_recv(buff_command, buff_answer);
+        }
+        let name = command_code.map_or("Unknown", cc::command_name);
+
+        // Response codes are injected in place of the TPM, every other fault
+        // needs the actual response
+        if let Some(Fault::ResponseCode(rc)) = faults.first() {
+            log::debug!("injecting {:?} into TPM2_{}", rc, name);
+            self.injected += 1;
+            buff_answer.write_bytes(&error_response(*rc as u32))?;
+            return Ok(());
+        }
+        let mut answer = inout::DynamicByteBuffer::new();
+        self.inner.send_recv(buff_command, &mut answer)?;
+        let mut response = answer.into_vec();
+
+        for fault in faults {
+            log::debug!("injecting {:?} into TPM2_{}", fault, name);
+            self.injected += 1;
+            match fault {
+                Fault::Truncate(len) => response.truncate(len),
+                Fault::FlipByte { offset, mask } => {
+                    if let Some(byte) = response.get_mut(offset) {
+                        *byte ^= mask;
+                    }
+                }
+                Fault::ResponseCode(rc) => {
+                    response = error_response(rc as u32);
+                    break;
+                }
+                Fault::Disconnect => {
+                    return Err(Error::new(
+                        ErrorKind::ConnectionReset,
+                        format!("injected disconnect during TPM2_{}", name),
+                    )
+                    .into())
+                }
+                Fault::Delay(delay) => thread::sleep(delay),
+            }
+        }
+        buff_answer.write_bytes(&response)?;
+        Ok(())
+    }
+
+    fn locality(&self) -> u8 {
+        self.inner.locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        self.inner.set_locality(locality)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::device::raw::RESPONSE_HEADER_SIZE;
+    use crate::tpm2::commands::pcrread::PcrReadResponse;
+    use crate::tpm2::commands::run;
+    use crate::tpm2::errors::{CommandError, DeserializationError, SerializationError};
+    use crate::tpm2::serialization::inout::{RwBytes, Tpm2StructIn, Tpm2StructOut};
+    use crate::tpm2::types::constants::TpmAlgId;
+
+    const MASKS: [u8; 3] = [0x01, 0x80, 0xff];
+
+    // tpm answers TPM2_PCR_Read with three SHA256 digests, TPM2_Import with
+    // a TPM2B_PRIVATE and TPM2_Unseal with a TPM2B_SENSITIVE_DATA, every
+    // time. Import and Unseal are sent with a password session, so their
+    // responses carry parameterSize and an authorization area.
+    fn tpm() -> TpmFaultInjector<TpmMockDevice> {
+        let mut mock = TpmMockDevice::new();
+        mock.expect_repeated(tcg::TPM_CC_PCR_READ, |_, resp| {
+            pcr_read_response(resp).map_err(|_| TpmRc::Failure)
+        });
+        mock.expect_repeated(tcg::TPM_CC_IMPORT, |_, resp| {
+            tcg::Tpm2bPrivate::from_vec(vec![0xa5; 64])
+                .pack(resp)
+                .map_err(|_| TpmRc::Failure)
+        });
+        mock.expect_repeated(tcg::TPM_CC_UNSEAL, |_, resp| {
+            tcg::Tpm2bSensitiveData::from_vec(vec![0x5a; 32])
+                .pack(resp)
+                .map_err(|_| TpmRc::Failure)
+        });
+        TpmFaultInjector::new(mock)
+    }
+
+    fn pcr_read_response(resp: &mut dyn RwBytes) -> result::Result<(), SerializationError> {
+        let mut selection = tcg::TpmlPcrSelection::new();
+        selection.count = 1;
+        selection.pcr_selections[0] = tcg::TpmsPcrSelection {
+            hash: TpmAlgId::SHA256,
+            sizeof_select: 3,
+            pcr_select: [0x07, 0x00, 0x00],
+        };
+        1u32.pack(resp)?;
+        selection.pack(resp)?;
+        3u32.pack(resp)?;
+        for pcr in 0..3 {
+            tcg::Tpm2bDigest::from_vec(vec![pcr; 32]).pack(resp)?;
+        }
+        Ok(())
+    }
+
+    // sessions returns the handles and authorizations a command is sent
+    // with: TPM2_Import and TPM2_Unseal act on an object authorized by
+    // password, TPM2_PCR_Read takes neither
+    fn sessions(command_code: tcg::TpmCc) -> (Vec<tcg::Handle>, Vec<tcg::TpmsAuthCommand>) {
+        if command_code == tcg::TPM_CC_PCR_READ {
+            return (Vec::new(), Vec::new());
+        }
+        let auth = tcg::TpmsAuthCommand {
+            session_handle: tcg::TPM_RS_PW,
+            nonce: tcg::Tpm2bNonce::new(),
+            session_attributes: tcg::TPMA_SESSION_CONTINUE_SESSION,
+            hmac: tcg::Tpm2bAuth::new(),
+        };
+        (vec![0x80000001], vec![auth])
+    }
+
+    // exchange sends a command without parameters and returns the raw
+    // response
+    fn exchange(tpm: &mut TpmFaultInjector<TpmMockDevice>, command_code: tcg::TpmCc) -> Vec<u8> {
+        let (handles, auths) = sessions(command_code);
+        let mut body = inout::DynamicByteBuffer::new();
+        for handle in handles.iter() {
+            handle.pack(&mut body).unwrap();
+        }
+        let tag = if auths.is_empty() {
+            tcg::TPM_ST_NO_SESSION
+        } else {
+            let mut area = inout::DynamicByteBuffer::new();
+            for auth in auths.iter() {
+                auth.pack(&mut area).unwrap();
+            }
+            (area.to_bytes().len() as u32).pack(&mut body).unwrap();
+            body.write_bytes(area.to_bytes()).unwrap();
+            tcg::TPM_ST_SESSIONS
+        };
+
+        let mut command = inout::DynamicByteBuffer::new();
+        tag.pack(&mut command).unwrap();
+        ((RESPONSE_HEADER_SIZE + body.to_bytes().len()) as u32)
+            .pack(&mut command)
+            .unwrap();
+        command_code.pack(&mut command).unwrap();
+        command.write_bytes(body.to_bytes()).unwrap();
+
+        let mut answer = inout::DynamicByteBuffer::new();
+        tpm.send_recv(&mut command, &mut answer).unwrap();
+        answer.into_vec()
+    }
+
+    // parse_parameters unpacks the parameters of a response to
+    // TPM2_Import or TPM2_Unseal, bounded by parameterSize, into their
+    // actual type
+    fn parse_parameters(
+        command_code: tcg::TpmCc,
+        buff: &mut dyn RwBytes,
+    ) -> result::Result<(), DeserializationError> {
+        let mut param_size: u32 = 0;
+        param_size.unpack(buff)?;
+        match command_code {
+            tcg::TPM_CC_IMPORT => {
+                inout::unpack_exact(&mut tcg::Tpm2bPrivate::new(), param_size as usize, buff)
+            }
+            _ => inout::unpack_exact(
+                &mut tcg::Tpm2bSensitiveData::new(),
+                param_size as usize,
+                buff,
+            ),
+        }
+    }
+
+    // parse unpacks the response to command_code, header included, with the
+    // parser for that command
+    fn parse(
+        command_code: tcg::TpmCc,
+        response: Vec<u8>,
+    ) -> result::Result<(), DeserializationError> {
+        let mut buff = inout::DynamicByteBuffer::from_vec(response);
+        match command_code {
+            tcg::TPM_CC_PCR_READ => {
+                let resp = PcrReadResponse::new(&mut buff)?;
+                // Corrupted selections must not panic when turned into values
+                let _ = resp.to_pcr_values();
+            }
+            _ => {
+                buff.read_bytes(RESPONSE_HEADER_SIZE)?;
+                parse_parameters(command_code, &mut buff)?;
+                tcg::TpmsAuthResponse::default().unpack(&mut buff)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run(
+        tpm: &mut TpmFaultInjector<TpmMockDevice>,
+        command_code: tcg::TpmCc,
+    ) -> result::Result<Vec<u8>, CommandError> {
+        let (handles, auths) = sessions(command_code);
+        let mut body = inout::DynamicByteBuffer::new();
+        run::run_command(tpm, command_code, &handles, &auths, &[], &mut body)?;
+        if !auths.is_empty() {
+            parse_parameters(command_code, &mut body)?;
+        }
+        Ok(body.into_vec())
+    }
+
+    const COMMANDS: [tcg::TpmCc; 3] =
+        [tcg::TPM_CC_PCR_READ, tcg::TPM_CC_IMPORT, tcg::TPM_CC_UNSEAL];
+
+    #[test]
+    fn untouched() {
+        let mut tpm = tpm();
+        for &command_code in COMMANDS.iter() {
+            let response = exchange(&mut tpm, command_code);
+            parse(command_code, response).unwrap();
+            run(&mut tpm, command_code).unwrap();
+        }
+        assert_eq!(tpm.injected(), 0);
+    }
+
+    #[test]
+    fn truncate() {
+        let mut tpm = tpm();
+        for &command_code in COMMANDS.iter() {
+            let size = exchange(&mut tpm, command_code).len();
+            for len in 0..size {
+                tpm.inject_once(command_code, Fault::Truncate(len));
+                let response = exchange(&mut tpm, command_code);
+                assert!(parse(command_code, response).is_err(), "len {}", len);
+
+                tpm.inject_once(command_code, Fault::Truncate(len));
+                assert!(run(&mut tpm, command_code).is_err(), "len {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn flip_byte() {
+        let mut tpm = tpm();
+        for &command_code in COMMANDS.iter() {
+            let size = exchange(&mut tpm, command_code).len();
+            for offset in 0..size {
+                for &mask in MASKS.iter() {
+                    let fault = Fault::FlipByte { offset, mask };
+                    // A flipped payload byte may still parse, it just must
+                    // not panic
+                    tpm.inject_once(command_code, fault.clone());
+                    let response = exchange(&mut tpm, command_code);
+                    let _ = parse(command_code, response);
+
+                    tpm.inject_once(command_code, fault);
+                    let result = run(&mut tpm, command_code);
+                    // responseSize and responseCode are checked by run_command
+                    if (2..RESPONSE_HEADER_SIZE).contains(&offset) {
+                        assert!(result.is_err(), "offset {} mask {:#x}", offset, mask);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flip_size() {
+        // Setting the top bit of parameterSize makes it overrun the
+        // response, setting it in the size of the sized buffer which
+        // follows makes the buffer larger than its maximum
+        let mut tpm = tpm();
+        for &command_code in [tcg::TPM_CC_IMPORT, tcg::TPM_CC_UNSEAL].iter() {
+            for &offset in [RESPONSE_HEADER_SIZE, RESPONSE_HEADER_SIZE + 4].iter() {
+                let fault = Fault::FlipByte { offset, mask: 0x80 };
+                tpm.inject_once(command_code, fault.clone());
+                let response = exchange(&mut tpm, command_code);
+                assert!(parse(command_code, response).is_err(), "offset {}", offset);
+
+                tpm.inject_once(command_code, fault);
+                assert!(run(&mut tpm, command_code).is_err(), "offset {}", offset);
+            }
+        }
+    }
+
+    #[test]
+    fn session_responses() {
+        // The responses to Import and Unseal end with the response to the
+        // password session, after the parameters
+        let mut tpm = tpm();
+        let response = exchange(&mut tpm, tcg::TPM_CC_UNSEAL);
+        assert_eq!(response.len(), RESPONSE_HEADER_SIZE + 4 + 2 + 32 + 5);
+        assert_eq!(
+            response[RESPONSE_HEADER_SIZE..RESPONSE_HEADER_SIZE + 4],
+            34u32.to_be_bytes()
+        );
+        assert_eq!(
+            response[response.len() - 5..],
+            [0x00, 0x00, tcg::TPMA_SESSION_CONTINUE_SESSION, 0x00, 0x00]
+        );
+        assert_eq!(
+            run(&mut tpm, tcg::TPM_CC_IMPORT).unwrap().len(),
+            4 + 2 + 64 + 5
+        );
+    }
+}
@@ -0,0 +1,436 @@
+use crate::device::errors::TpmDeviceError;
+use crate::device::raw::{command_code, response_code, TpmDeviceOps};
+use crate::tpm2::serialization::inout;
+use crate::tpm2::types::constants::cc;
+use crate::tpm2::types::tcg;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::result;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Upper bounds of the latency histogram buckets. They span from fast
+// commands on discrete TPMs to key generation on firmware TPMs, which takes
+// seconds.
+pub const LATENCY_BUCKETS: [Duration; 14] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+];
+
+// Histogram counts durations per LATENCY_BUCKETS bucket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    // counts holds the number of durations falling in each bucket, that is
+    // above the bound of the previous bucket and up to the bound of this
+    // one. The last entry counts durations above every bound.
+    pub counts: [u64; LATENCY_BUCKETS.len() + 1],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            counts: [0; LATENCY_BUCKETS.len() + 1],
+            count: 0,
+            sum: Duration::ZERO,
+        }
+    }
+
+    pub fn observe(&mut self, duration: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| duration <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += duration;
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+// CommandMetrics holds the metrics of one command code
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandMetrics {
+    pub calls: u64,
+    // response_codes counts the responses per non-zero response code
+    pub response_codes: BTreeMap<u32, u64>,
+    // transport_errors counts exchanges failing without a response
+    pub transport_errors: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub latency: Histogram,
+}
+
+// MetricsSnapshot is a copy of the metrics collected up to some point, per
+// command code. Commands too short to carry a command code are accounted
+// under command code 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub commands: BTreeMap<tcg::TpmCc, CommandMetrics>,
+}
+
+impl MetricsSnapshot {
+    // to_prometheus renders the snapshot in the Prometheus text exposition
+    // format, with command names and codes as labels
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        self.write_counter(&mut out, "tpm_commands_total", "TPM commands sent.", |m| {
+            m.calls
+        });
+        self.write_counter(
+            &mut out,
+            "tpm_command_transport_errors_total",
+            "TPM commands which got no response.",
+            |m| m.transport_errors,
+        );
+        self.write_counter(
+            &mut out,
+            "tpm_command_sent_bytes_total",
+            "Bytes of TPM commands sent.",
+            |m| m.bytes_sent,
+        );
+        self.write_counter(
+            &mut out,
+            "tpm_command_received_bytes_total",
+            "Bytes of TPM responses received.",
+            |m| m.bytes_received,
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP tpm_command_errors_total TPM responses with a non-zero response code."
+        );
+        let _ = writeln!(out, "# TYPE tpm_command_errors_total counter");
+        for (command_code, metrics) in self.commands.iter() {
+            for (rc, count) in metrics.response_codes.iter() {
+                let _ = writeln!(
+                    out,
+                    "tpm_command_errors_total{{{},rc=\"{:#x}\"}} {}",
+                    labels(*command_code),
+                    rc,
+                    count
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP tpm_command_duration_seconds Time from sending a TPM command to receiving its response."
+        );
+        let _ = writeln!(out, "# TYPE tpm_command_duration_seconds histogram");
+        for (command_code, metrics) in self.commands.iter() {
+            let labels = labels(*command_code);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(metrics.latency.counts.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "tpm_command_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels,
+                    bound.as_secs_f64(),
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "tpm_command_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, metrics.latency.count
+            );
+            let _ = writeln!(
+                out,
+                "tpm_command_duration_seconds_sum{{{}}} {}",
+                labels,
+                metrics.latency.sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "tpm_command_duration_seconds_count{{{}}} {}",
+                labels, metrics.latency.count
+            );
+        }
+        out
+    }
+
+    fn write_counter<F: Fn(&CommandMetrics) -> u64>(
+        &self,
+        out: &mut String,
+        name: &str,
+        help: &str,
+        value: F,
+    ) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (command_code, metrics) in self.commands.iter() {
+            let _ = writeln!(
+                out,
+                "{}{{{}}} {}",
+                name,
+                labels(*command_code),
+                value(metrics)
+            );
+        }
+    }
+}
+
+fn labels(command_code: tcg::TpmCc) -> String {
+    format!(
+        "command=\"{}\",code=\"{:#x}\"",
+        cc::command_name(command_code),
+        command_code
+    )
+}
+
+// MetricsRegistry is a cloneable handle to the metrics collected by one or
+// more TpmMetrics, so that they can be read while the device is in use,
+// e.g. from a metrics endpoint
+#[derive(Clone, Default)]
+pub struct MetricsRegistry {
+    inner: Arc<Mutex<MetricsSnapshot>>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        MetricsRegistry::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.lock().clone()
+    }
+
+    // reset discards everything collected so far
+    pub fn reset(&self) {
+        self.lock().commands.clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MetricsSnapshot> {
+        // Metrics are plain counters, they remain usable after a panic
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+// TpmMetrics wraps a TpmDeviceOps and records, per command code, the number
+// of calls, response codes, transport errors, bytes exchanged and latency
+pub struct TpmMetrics<T: TpmDeviceOps> {
+    inner: T,
+    registry: MetricsRegistry,
+}
+
+impl<T: TpmDeviceOps> TpmMetrics<T> {
+    pub fn new(inner: T) -> Self {
+        TpmMetrics::with_registry(inner, MetricsRegistry::new())
+    }
+
+    // with_registry records into an existing registry, e.g. to aggregate the
+    // traffic of several devices
+    pub fn with_registry(inner: T, registry: MetricsRegistry) -> Self {
+        TpmMetrics { inner, registry }
+    }
+
+    pub fn registry(&self) -> MetricsRegistry {
+        self.registry.clone()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.registry.snapshot()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: TpmDeviceOps> TpmDeviceOps for TpmMetrics<T> {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        let command = buff_command.to_bytes();
+        let command_code = command_code(command).unwrap_or(0);
+        let bytes_sent = command.len() as u64;
+        let received_before = buff_answer.to_bytes().len();
+
+        let start = Instant::now();
+        let result = self.inner.send_recv(buff_command, buff_answer);
+        let elapsed = start.elapsed();
+
+        let mut registry = self.registry.lock();
+        let metrics = registry.commands.entry(command_code).or_default();
+        metrics.calls += 1;
+        metrics.bytes_sent += bytes_sent;
+        metrics.latency.observe(elapsed);
+        match &result {
+            Err(_) => metrics.transport_errors += 1,
+            Ok(()) => {
+                let response = &buff_answer.to_bytes()[received_before..];
+                metrics.bytes_received += response.len() as u64;
+                match response_code(response) {
+                    Some(0) | None => (),
+                    Some(rc) => *metrics.response_codes.entry(rc).or_default() += 1,
+                }
+            }
+        }
+        result
+    }
+
+    fn locality(&self) -> u8 {
+        self.inner.locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        self.inner.set_locality(locality)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::tpm2::commands::run;
+    use crate::tpm2::types::constants::TpmRc;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::new();
+        // Bounds are inclusive, the next nanosecond falls in the next bucket
+        histogram.observe(Duration::ZERO);
+        histogram.observe(Duration::from_millis(1));
+        histogram.observe(Duration::from_millis(1) + Duration::from_nanos(1));
+        histogram.observe(Duration::from_secs(60));
+        histogram.observe(Duration::from_secs(60) + Duration::from_nanos(1));
+
+        let mut counts = [0; LATENCY_BUCKETS.len() + 1];
+        counts[0] = 2;
+        counts[1] = 1;
+        counts[LATENCY_BUCKETS.len() - 1] = 1;
+        counts[LATENCY_BUCKETS.len()] = 1;
+        assert_eq!(histogram.counts, counts);
+        assert_eq!(histogram.count, 5);
+        assert_eq!(
+            histogram.sum,
+            Duration::from_secs(120) + Duration::from_nanos(2) + Duration::from_millis(2)
+        );
+    }
+
+    #[test]
+    fn prometheus() {
+        let mut metrics = CommandMetrics {
+            calls: 4,
+            transport_errors: 1,
+            bytes_sent: 80,
+            bytes_received: 120,
+            ..CommandMetrics::default()
+        };
+        metrics.response_codes.insert(TpmRc::Value as u32, 1);
+        metrics.latency.observe(Duration::from_millis(500));
+        metrics.latency.observe(Duration::from_secs(2));
+        metrics.latency.observe(Duration::from_secs(90));
+        let mut snapshot = MetricsSnapshot::default();
+        snapshot.commands.insert(tcg::TPM_CC_PCR_READ, metrics);
+
+        let labels = "command=\"PCR_Read\",code=\"0x17e\"";
+        let mut expected = String::new();
+        for (name, help, value) in [
+            ("tpm_commands_total", "TPM commands sent.", 4),
+            (
+                "tpm_command_transport_errors_total",
+                "TPM commands which got no response.",
+                1,
+            ),
+            (
+                "tpm_command_sent_bytes_total",
+                "Bytes of TPM commands sent.",
+                80,
+            ),
+            (
+                "tpm_command_received_bytes_total",
+                "Bytes of TPM responses received.",
+                120,
+            ),
+        ]
+        .iter()
+        {
+            expected += &format!(
+                "# HELP {} {}\n# TYPE {} counter\n{}{{{}}} {}\n",
+                name, help, name, name, labels, value
+            );
+        }
+        expected +=
+            "# HELP tpm_command_errors_total TPM responses with a non-zero response code.\n";
+        expected += "# TYPE tpm_command_errors_total counter\n";
+        expected += &format!("tpm_command_errors_total{{{},rc=\"0x84\"}} 1\n", labels);
+        expected += "# HELP tpm_command_duration_seconds Time from sending a TPM command to receiving its response.\n";
+        expected += "# TYPE tpm_command_duration_seconds histogram\n";
+        for (le, count) in [
+            ("0.001", 0),
+            ("0.005", 0),
+            ("0.01", 0),
+            ("0.025", 0),
+            ("0.05", 0),
+            ("0.1", 0),
+            ("0.25", 0),
+            ("0.5", 1),
+            ("1", 1),
+            ("2.5", 2),
+            ("5", 2),
+            ("10", 2),
+            ("30", 2),
+            ("60", 2),
+            ("+Inf", 3),
+        ]
+        .iter()
+        {
+            expected += &format!(
+                "tpm_command_duration_seconds_bucket{{{},le=\"{}\"}} {}\n",
+                labels, le, count
+            );
+        }
+        expected += &format!("tpm_command_duration_seconds_sum{{{}}} 92.5\n", labels);
+        expected += &format!("tpm_command_duration_seconds_count{{{}}} 3\n", labels);
+
+        assert_eq!(snapshot.to_prometheus(), expected);
+    }
+
+    #[test]
+    fn records() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect(tcg::TPM_CC_PCR_READ, |_, _| Ok(()));
+        tpm.expect_error(tcg::TPM_CC_PCR_READ, TpmRc::Value);
+        let mut tpm = TpmMetrics::new(tpm);
+
+        for _ in 0..2 {
+            let mut resp = inout::DynamicByteBuffer::new();
+            let _ = run::run_command(&mut tpm, tcg::TPM_CC_PCR_READ, &[], &[], &[], &mut resp);
+        }
+        let snapshot = tpm.snapshot();
+        let metrics = &snapshot.commands[&tcg::TPM_CC_PCR_READ];
+        assert_eq!(metrics.calls, 2);
+        assert_eq!(metrics.bytes_sent, 20);
+        assert_eq!(metrics.bytes_received, 20);
+        assert_eq!(metrics.transport_errors, 0);
+        assert_eq!(metrics.response_codes.get(&(TpmRc::Value as u32)), Some(&1));
+        assert_eq!(metrics.latency.count, 2);
+    }
+}
@@ -0,0 +1,350 @@
+use crate::device::errors::TpmDeviceError;
+use crate::device::raw::{read_u16, read_u32, TpmDeviceOps, RESPONSE_HEADER_SIZE};
+use crate::tpm2::serialization::inout;
+use crate::tpm2::serialization::inout::{RwBytes, Tpm2StructIn, Tpm2StructOut};
+use crate::tpm2::types::constants::{cc, TpmRc};
+use crate::tpm2::types::tcg;
+
+use std::collections::VecDeque;
+use std::result;
+
+// MockHandler produces the response to a command. It receives the command
+// body (handle, authorization and parameter areas) to decode with
+// Tpm2StructIn, and a buffer where the response handles, if any, followed by
+// the response parameters are to be packed with Tpm2StructOut. Returning an
+// error makes the mock answer with that response code and an empty body.
+//
+// Responses to commands with sessions are completed as a TPM does: the
+// parameterSize field is inserted after the response handles, and one
+// TPMS_AUTH_RESPONSE is appended for every session of the command.
+pub type MockHandler =
+    Box<dyn FnMut(&mut dyn RwBytes, &mut dyn RwBytes) -> result::Result<(), TpmRc> + Send>;
+
+// MockCommand is a command received by TpmMockDevice
+#[derive(Debug, Clone)]
+pub struct MockCommand {
+    pub tag: tcg::TpmiStCommandTag,
+    pub command_code: tcg::TpmCc,
+    // locality is the locality the command was sent at
+    pub locality: u8,
+    // bytes holds the whole command, header included
+    pub bytes: Vec<u8>,
+}
+
+struct Expectation {
+    command_code: tcg::TpmCc,
+    handler: MockHandler,
+    // repeat expectations are never consumed
+    repeat: bool,
+}
+
+// TpmMockDevice is a scriptable TpmDeviceOps for unit tests. Expectations
+// are registered per command code and matched in registration order, each
+// one answering a single command unless registered with expect_repeated.
+// Commands without a matching expectation are answered with
+// TPM_RC_COMMAND_CODE, as a TPM would for an unimplemented command.
+pub struct TpmMockDevice {
+    expectations: VecDeque<Expectation>,
+    calls: Vec<MockCommand>,
+    locality: u8,
+}
+
+impl TpmMockDevice {
+    pub fn new() -> Self {
+        TpmMockDevice {
+            expectations: VecDeque::new(),
+            calls: Vec::new(),
+            locality: 0,
+        }
+    }
+
+    // expect registers a handler answering the next command with code
+    // command_code
+    pub fn expect<F>(&mut self, command_code: tcg::TpmCc, handler: F) -> &mut Self
+    where
+        F: FnMut(&mut dyn RwBytes, &mut dyn RwBytes) -> result::Result<(), TpmRc> + Send + 'static,
+    {
+        self.push(command_code, Box::new(handler), false)
+    }
+
+    // expect_repeated registers a handler answering every command with code
+    // command_code
+    pub fn expect_repeated<F>(&mut self, command_code: tcg::TpmCc, handler: F) -> &mut Self
+    where
+        F: FnMut(&mut dyn RwBytes, &mut dyn RwBytes) -> result::Result<(), TpmRc> + Send + 'static,
+    {
+        self.push(command_code, Box::new(handler), true)
+    }
+
+    // expect_error answers the next command with code command_code with the
+    // response code rc
+    pub fn expect_error(&mut self, command_code: tcg::TpmCc, rc: TpmRc) -> &mut Self {
+        self.expect(command_code, move |_, _| Err(rc))
+    }
+
+    // expect_response answers the next command with code command_code with
+    // a successful response carrying the given fields, or with
+    // TPM_RC_FAILURE if they cannot be packed
+    pub fn expect_response(
+        &mut self,
+        command_code: tcg::TpmCc,
+        fields: Vec<Box<dyn Tpm2StructOut + Send>>,
+    ) -> &mut Self {
+        self.expect(command_code, move |_, resp| {
+            for field in fields.iter() {
+                field.pack(resp).map_err(|_| TpmRc::Failure)?;
+            }
+            Ok(())
+        })
+    }
+
+    // calls returns all commands received so far
+    pub fn calls(&self) -> &[MockCommand] {
+        &self.calls
+    }
+
+    // unmet_expectations returns the command codes of expectations which
+    // have not been consumed
+    pub fn unmet_expectations(&self) -> Vec<tcg::TpmCc> {
+        self.expectations
+            .iter()
+            .filter(|e| !e.repeat)
+            .map(|e| e.command_code)
+            .collect()
+    }
+
+    fn push(&mut self, command_code: tcg::TpmCc, handler: MockHandler, repeat: bool) -> &mut Self {
+        self.expectations.push_back(Expectation {
+            command_code,
+            handler,
+            repeat,
+        });
+        self
+    }
+}
+
+impl Default for TpmMockDevice {
+    fn default() -> Self {
+        TpmMockDevice::new()
+    }
+}
+
+impl TpmDeviceOps for TpmMockDevice {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        let mut command = inout::DynamicByteBuffer::new();
+        command.write_bytes(buff_command.to_bytes())?;
+
+        let mut body = inout::DynamicByteBuffer::new();
+        let mut sessions = Vec::new();
+        let rc = match decode_header(&mut command) {
+            // A command too short to carry a header
+            None => TpmRc::CommandSize,
+            Some((tag, command_code)) => {
+                self.calls.push(MockCommand {
+                    tag,
+                    command_code,
+                    locality: self.locality,
+                    bytes: command.to_bytes().to_vec(),
+                });
+                match self
+                    .expectations
+                    .iter()
+                    .position(|e| e.command_code == command_code)
+                {
+                    None => TpmRc::CommandCode,
+                    Some(index) => match decode_sessions(tag, command_code, command.to_bytes()) {
+                        // The authorization area is checked before the
+                        // command reaches its handler, as a TPM does
+                        Err(rc) => rc,
+                        Ok(attributes) => {
+                            sessions = attributes;
+                            let result =
+                                (self.expectations[index].handler)(&mut command, &mut body);
+                            if !self.expectations[index].repeat {
+                                self.expectations.remove(index);
+                            }
+                            match result {
+                                Ok(()) => TpmRc::Success,
+                                Err(rc) => rc,
+                            }
+                        }
+                    },
+                }
+            }
+        };
+
+        // Error responses never carry a body and always use TPM_ST_NO_SESSIONS
+        let (tag, body) = match (rc, self.calls.last()) {
+            (TpmRc::Success, Some(call)) if call.tag == tcg::TPM_ST_SESSIONS => (
+                call.tag,
+                session_body(call.command_code, body.to_bytes(), &sessions)?,
+            ),
+            (TpmRc::Success, Some(call)) => (call.tag, body.to_bytes().to_vec()),
+            _ => (tcg::TPM_ST_NO_SESSION, Vec::new()),
+        };
+        let response_size = (RESPONSE_HEADER_SIZE + body.len()) as u32;
+        tag.pack(buff_answer)?;
+        response_size.pack(buff_answer)?;
+        (rc as u32).pack(buff_answer)?;
+        buff_answer.write_bytes(&body)?;
+        Ok(())
+    }
+
+    fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        self.locality = locality;
+        Ok(())
+    }
+}
+
+// decode_header returns tag and command code of a command, or None if the
+// command is too short to carry a header
+fn decode_header(command: &mut dyn RwBytes) -> Option<(tcg::TpmiStCommandTag, tcg::TpmCc)> {
+    if command.to_bytes().len() < RESPONSE_HEADER_SIZE {
+        return None;
+    }
+    let mut tag: tcg::TpmiStCommandTag = 0;
+    let mut command_size: u32 = 0;
+    let mut command_code: tcg::TpmCc = 0;
+    tag.unpack(command).ok()?;
+    command_size.unpack(command).ok()?;
+    command_code.unpack(command).ok()?;
+    Some((tag, command_code))
+}
+
+// decode_sessions returns the attributes of the sessions in the authorization
+// area of a command, none if the command is tagged TPM_ST_NO_SESSIONS
+fn decode_sessions(
+    tag: tcg::TpmiStCommandTag,
+    command_code: tcg::TpmCc,
+    command: &[u8],
+) -> result::Result<Vec<tcg::TpmaSession>, TpmRc> {
+    if tag != tcg::TPM_ST_SESSIONS {
+        return Ok(Vec::new());
+    }
+    let handles = cc::command_info(command_code)
+        .ok_or(TpmRc::CommandCode)?
+        .handles;
+    let mut offset = RESPONSE_HEADER_SIZE + 4 * handles;
+    let auth_size = read_u32(command, offset).ok_or(TpmRc::AuthMissing)? as usize;
+    offset += 4;
+    let end = offset
+        .checked_add(auth_size)
+        .filter(|end| *end <= command.len())
+        .ok_or(TpmRc::AuthSize)?;
+
+    let mut sessions = Vec::new();
+    while offset < end {
+        // sessionHandle, nonce, sessionAttributes and hmac
+        let nonce_size = read_u16(command, offset + 4).ok_or(TpmRc::AuthSize)? as usize;
+        offset += 6 + nonce_size;
+        let attributes = *command.get(offset).ok_or(TpmRc::AuthSize)?;
+        let hmac_size = read_u16(command, offset + 1).ok_or(TpmRc::AuthSize)? as usize;
+        offset += 3 + hmac_size;
+        sessions.push(attributes);
+    }
+    match (offset == end, sessions.is_empty()) {
+        (false, _) => Err(TpmRc::AuthSize),
+        (true, true) => Err(TpmRc::AuthMissing),
+        (true, false) => Ok(sessions),
+    }
+}
+
+// session_body lays out the body of a response to a command with sessions:
+// the response handles, parameterSize, the parameters, and an empty
+// TPMS_AUTH_RESPONSE per session, keeping its continueSession attribute
+fn session_body(
+    command_code: tcg::TpmCc,
+    body: &[u8],
+    sessions: &[tcg::TpmaSession],
+) -> result::Result<Vec<u8>, TpmDeviceError> {
+    let handles = body.len().min(4 * cc::response_handles(command_code));
+    let parameters = &body[handles..];
+
+    let mut response = inout::DynamicByteBuffer::new();
+    response.write_bytes(&body[..handles])?;
+    (parameters.len() as u32).pack(&mut response)?;
+    response.write_bytes(parameters)?;
+    for attributes in sessions.iter() {
+        tcg::TpmsAuthResponse {
+            session_attributes: attributes & tcg::TPMA_SESSION_CONTINUE_SESSION,
+            ..Default::default()
+        }
+        .pack(&mut response)?;
+    }
+    Ok(response.into_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::raw::error_response;
+
+    fn exchange(tpm: &mut TpmMockDevice, command: Vec<u8>) -> Vec<u8> {
+        let mut buff_command = inout::DynamicByteBuffer::from_vec(command);
+        let mut buff_answer = inout::DynamicByteBuffer::new();
+        tpm.send_recv(&mut buff_command, &mut buff_answer).unwrap();
+        buff_answer.into_vec()
+    }
+
+    #[test]
+    fn session_response() {
+        // TPM2_Load under a password session whose continueSession is set
+        let command = vec![
+            0x80, 0x02, 0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x01, 0x57, // header
+            0x40, 0x00, 0x00, 0x01, // parentHandle
+            0x00, 0x00, 0x00, 0x09, // authorizationSize
+            0x40, 0x00, 0x00, 0x09, 0x00, 0x00, 0x01, 0x00, 0x00, // password session
+            0x00, 0x00, // inPrivate
+            0x00, 0x00, // inPublic
+        ];
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(
+            tcg::TPM_CC_LOAD,
+            vec![
+                Box::new(0x80000001u32),
+                Box::new(tcg::Tpm2bName::from_slice(&[0x0b; 2])),
+            ],
+        );
+
+        assert_eq!(
+            exchange(&mut tpm, command),
+            vec![
+                0x80, 0x02, 0x00, 0x00, 0x00, 0x1b, 0x00, 0x00, 0x00, 0x00, // header
+                0x80, 0x00, 0x00, 0x01, // objectHandle
+                0x00, 0x00, 0x00, 0x04, // parameterSize
+                0x00, 0x02, 0x0b, 0x0b, // name
+                0x00, 0x00, 0x01, 0x00, 0x00, // password session
+            ]
+        );
+    }
+
+    #[test]
+    fn session_errors() {
+        let header = |size: u8| vec![0x80, 0x02, 0x00, 0x00, 0x00, size, 0x00, 0x00, 0x01, 0x5e];
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_repeated(tcg::TPM_CC_UNSEAL, |_, _| Ok(()));
+
+        // Without authorization area
+        let mut command = header(14);
+        command.extend_from_slice(&[0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(exchange(&mut tpm, command), error_response(0x125));
+
+        // With an empty authorization area
+        let mut command = header(18);
+        command.extend_from_slice(&[0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(exchange(&mut tpm, command), error_response(0x125));
+
+        // With an authorization area larger than the command
+        let mut command = header(18);
+        command.extend_from_slice(&[0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09]);
+        assert_eq!(exchange(&mut tpm, command), error_response(0x144));
+    }
+}
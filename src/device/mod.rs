@@ -1,3 +1,17 @@
+#[cfg(feature = "tokio")]
+pub mod aio;
 pub mod errors;
+pub mod fault;
+pub mod metrics;
+pub mod mock;
+pub mod policy;
+pub mod proxy;
 pub mod raw;
+pub mod replay;
+pub mod rm;
+pub mod shared;
+pub mod socket;
 pub mod tcp;
+pub mod tcti;
+pub mod timeout;
+pub mod trace;
@@ -0,0 +1,354 @@
+use crate::device::errors::{PolicyError, TpmDeviceError};
+use crate::device::raw::{command_code, read_u32, TpmDeviceOps, RESPONSE_HEADER_SIZE};
+use crate::tpm2::serialization::inout;
+use crate::tpm2::types::constants::cc;
+use crate::tpm2::types::tcg;
+
+use std::result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyAction {
+    Allow,
+    Deny,
+}
+
+// PolicyRule matches commands by command code and by the handles in their
+// handle area. Fields set to None match any command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyRule {
+    pub command_code: Option<tcg::TpmCc>,
+    // handle matches commands carrying this handle in their handle area
+    pub handle: Option<tcg::Handle>,
+    pub action: PolicyAction,
+}
+
+impl PolicyRule {
+    fn matches(&self, command_code: Option<tcg::TpmCc>, handles: &[tcg::Handle]) -> bool {
+        if self.command_code.is_some() && self.command_code != command_code {
+            return false;
+        }
+        match self.handle {
+            None => true,
+            Some(handle) => handles.contains(&handle),
+        }
+    }
+}
+
+// CommandPolicy decides which commands may reach the TPM. Rules are checked
+// in the order they were added and the first matching rule decides, commands
+// matching no rule get the default action.
+//
+// Commands missing from cc::COMMANDS, such as vendor commands, are denied
+// whatever the rules and the default action: where their handles are is not
+// known, so handle rules cannot be enforced on them. Those which should
+// reach the TPM have to be listed with allow_unknown. E.g.
+//
+//   // Keep a plugin from clearing the TPM or taking over the owner hierarchy
+//   CommandPolicy::allow_all()
+//       .deny(tcg::TPM_CC_CLEAR)
+//       .deny_on_handle(tcg::TPM_CC_HIERARCHY_CHANGE_AUTH, tcg::TPM_RH_OWNER)
+//       .deny_on_handle(tcg::TPM_CC_EVICT_CONTROL, tcg::TPM_RH_OWNER)
+//
+//   // Let an untrusted tenant read PCRs and quote them, nothing else
+//   CommandPolicy::deny_all()
+//       .allow(tcg::TPM_CC_PCR_READ)
+//       .allow(tcg::TPM_CC_QUOTE)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandPolicy {
+    pub rules: Vec<PolicyRule>,
+    pub default: PolicyAction,
+    // allowed_unknown lists the commands missing from cc::COMMANDS which are
+    // allowed
+    pub allowed_unknown: Vec<tcg::TpmCc>,
+}
+
+impl CommandPolicy {
+    pub fn allow_all() -> Self {
+        CommandPolicy {
+            rules: Vec::new(),
+            default: PolicyAction::Allow,
+            allowed_unknown: Vec::new(),
+        }
+    }
+
+    pub fn deny_all() -> Self {
+        CommandPolicy {
+            rules: Vec::new(),
+            default: PolicyAction::Deny,
+            allowed_unknown: Vec::new(),
+        }
+    }
+
+    pub fn rule(mut self, rule: PolicyRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn allow(self, command_code: tcg::TpmCc) -> Self {
+        self.rule(PolicyRule {
+            command_code: Some(command_code),
+            handle: None,
+            action: PolicyAction::Allow,
+        })
+    }
+
+    pub fn deny(self, command_code: tcg::TpmCc) -> Self {
+        self.rule(PolicyRule {
+            command_code: Some(command_code),
+            handle: None,
+            action: PolicyAction::Deny,
+        })
+    }
+
+    // deny_on_handle denies a command when it refers to handle in its handle
+    // area, e.g. an owner hierarchy operation
+    pub fn deny_on_handle(self, command_code: tcg::TpmCc, handle: tcg::Handle) -> Self {
+        self.rule(PolicyRule {
+            command_code: Some(command_code),
+            handle: Some(handle),
+            action: PolicyAction::Deny,
+        })
+    }
+
+    // deny_handle denies every command referring to handle in its handle area
+    pub fn deny_handle(self, handle: tcg::Handle) -> Self {
+        self.rule(PolicyRule {
+            command_code: None,
+            handle: Some(handle),
+            action: PolicyAction::Deny,
+        })
+    }
+
+    // allow_unknown allows a command missing from cc::COMMANDS, e.g. a
+    // vendor command. Its handles are not checked against handle rules.
+    pub fn allow_unknown(mut self, command_code: tcg::TpmCc) -> Self {
+        self.allowed_unknown.push(command_code);
+        self
+    }
+
+    // check returns the action for a command, given its code and the handles
+    // of its handle area. command_code is None for commands too short to
+    // carry one, those are denied.
+    pub fn check(&self, command_code: Option<tcg::TpmCc>, handles: &[tcg::Handle]) -> PolicyAction {
+        let code = match command_code {
+            None => return PolicyAction::Deny,
+            Some(code) => code,
+        };
+        if cc::command_info(code).is_none() {
+            if self.allowed_unknown.contains(&code) {
+                return PolicyAction::Allow;
+            }
+            return PolicyAction::Deny;
+        }
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(command_code, handles))
+            .map_or(self.default, |rule| rule.action)
+    }
+}
+
+// TpmPolicyFilter wraps a TpmDeviceOps and rejects the commands denied by a
+// CommandPolicy with a PolicyError, without sending them to the TPM.
+//
+// Only the handle area is inspected, as described by cc::COMMANDS. Handles
+// passed as parameters, such as the handle of TPM2_FlushContext, are not
+// matched by handle rules. Commands too short to carry their command code
+// or their whole handle area are denied.
+pub struct TpmPolicyFilter<T: TpmDeviceOps> {
+    inner: T,
+    policy: CommandPolicy,
+}
+
+impl<T: TpmDeviceOps> TpmPolicyFilter<T> {
+    pub fn new(inner: T, policy: CommandPolicy) -> Self {
+        TpmPolicyFilter { inner, policy }
+    }
+
+    pub fn policy(&self) -> &CommandPolicy {
+        &self.policy
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: TpmDeviceOps> TpmDeviceOps for TpmPolicyFilter<T> {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        let (command_code, handles) = decode_handles(buff_command.to_bytes());
+        let action = match &handles {
+            Some(handles) => self.policy.check(command_code, handles),
+            None => PolicyAction::Deny,
+        };
+        if action == PolicyAction::Deny {
+            let name = command_code.map_or("Unknown", cc::command_name);
+            log::warn!("TPM2_{} denied by policy, handles {:x?}", name, handles);
+            return Err(PolicyError {
+                command_code: command_code.unwrap_or(0),
+                msg: format!("TPM2_{} is not allowed by policy", name),
+            }
+            .into());
+        }
+        self.inner.send_recv(buff_command, buff_answer)
+    }
+
+    fn locality(&self) -> u8 {
+        self.inner.locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        self.inner.set_locality(locality)
+    }
+}
+
+// decode_handles returns the command code and the handle area of a command.
+// The handle area is None for commands cut short of it, and empty for
+// commands missing from cc::COMMANDS.
+fn decode_handles(bytes: &[u8]) -> (Option<tcg::TpmCc>, Option<Vec<tcg::Handle>>) {
+    let command_code = match command_code(bytes) {
+        Some(command_code) => command_code,
+        None => return (None, None),
+    };
+    let count = cc::command_info(command_code).map_or(0, |info| info.handles);
+    let handles = (0..count)
+        .map(|index| read_u32(bytes, RESPONSE_HEADER_SIZE + 4 * index))
+        .collect();
+    (Some(command_code), handles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+
+    const TPM_CC_VENDOR: tcg::TpmCc = 0x20000001;
+
+    // command marshals a command with the given handle area, cut to len
+    // bytes if given
+    fn command(command_code: tcg::TpmCc, handles: &[tcg::Handle], len: Option<usize>) -> Vec<u8> {
+        let mut bytes = tcg::TPM_ST_NO_SESSION.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&((RESPONSE_HEADER_SIZE + 4 * handles.len()) as u32).to_be_bytes());
+        bytes.extend_from_slice(&command_code.to_be_bytes());
+        for handle in handles.iter() {
+            bytes.extend_from_slice(&handle.to_be_bytes());
+        }
+        bytes.truncate(len.unwrap_or(bytes.len()));
+        bytes
+    }
+
+    // filter wraps a TPM accepting every command the tests send
+    fn filter(policy: CommandPolicy) -> TpmPolicyFilter<TpmMockDevice> {
+        let mut tpm = TpmMockDevice::new();
+        for command_code in [
+            tcg::TPM_CC_PCR_READ,
+            tcg::TPM_CC_CLEAR,
+            tcg::TPM_CC_EVICT_CONTROL,
+            TPM_CC_VENDOR,
+        ]
+        .iter()
+        {
+            tpm.expect_repeated(*command_code, |_, _| Ok(()));
+        }
+        TpmPolicyFilter::new(tpm, policy)
+    }
+
+    // send returns whether the command went through the filter
+    fn send(tpm: &mut TpmPolicyFilter<TpmMockDevice>, command: Vec<u8>) -> bool {
+        let mut buff_command = inout::DynamicByteBuffer::from_vec(command);
+        let mut buff_answer = inout::DynamicByteBuffer::new();
+        match tpm.send_recv(&mut buff_command, &mut buff_answer) {
+            Ok(()) => true,
+            Err(TpmDeviceError::PolicyError(_)) => false,
+            Err(err) => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn command_rules() {
+        let mut tpm = filter(CommandPolicy::allow_all().deny(tcg::TPM_CC_CLEAR));
+        assert!(send(&mut tpm, command(tcg::TPM_CC_PCR_READ, &[], None)));
+        assert!(!send(
+            &mut tpm,
+            command(tcg::TPM_CC_CLEAR, &[tcg::TPM_RH_PLATFORM], None)
+        ));
+
+        let mut tpm = filter(CommandPolicy::deny_all().allow(tcg::TPM_CC_PCR_READ));
+        assert!(send(&mut tpm, command(tcg::TPM_CC_PCR_READ, &[], None)));
+        assert!(!send(
+            &mut tpm,
+            command(tcg::TPM_CC_CLEAR, &[tcg::TPM_RH_PLATFORM], None)
+        ));
+    }
+
+    #[test]
+    fn handle_rules() {
+        let policy = CommandPolicy::allow_all()
+            .deny_on_handle(tcg::TPM_CC_EVICT_CONTROL, tcg::TPM_RH_OWNER)
+            .deny_handle(tcg::TPM_RH_PLATFORM);
+        let mut tpm = filter(policy);
+
+        let evict = |auth| command(tcg::TPM_CC_EVICT_CONTROL, &[auth, 0x80000001], None);
+        assert!(!send(&mut tpm, evict(tcg::TPM_RH_OWNER)));
+        assert!(!send(&mut tpm, evict(tcg::TPM_RH_PLATFORM)));
+        assert!(send(&mut tpm, evict(tcg::TPM_RH_ENDORSEMENT)));
+        assert!(!send(
+            &mut tpm,
+            command(tcg::TPM_CC_CLEAR, &[tcg::TPM_RH_PLATFORM], None)
+        ));
+        assert!(send(
+            &mut tpm,
+            command(tcg::TPM_CC_CLEAR, &[tcg::TPM_RH_LOCKOUT], None)
+        ));
+    }
+
+    #[test]
+    fn truncated_commands() {
+        // A handle rule cannot be dodged by cutting the handle area short
+        let mut tpm = filter(CommandPolicy::allow_all().deny_handle(tcg::TPM_RH_OWNER));
+        let evict = [tcg::TPM_RH_ENDORSEMENT, tcg::TPM_RH_OWNER];
+        assert!(send(
+            &mut tpm,
+            command(
+                tcg::TPM_CC_EVICT_CONTROL,
+                &[tcg::TPM_RH_ENDORSEMENT, 0x80000001],
+                None
+            )
+        ));
+        for len in 0..RESPONSE_HEADER_SIZE + 8 {
+            assert!(
+                !send(
+                    &mut tpm,
+                    command(tcg::TPM_CC_EVICT_CONTROL, &evict, Some(len))
+                ),
+                "len {}",
+                len
+            );
+        }
+        assert_eq!(
+            CommandPolicy::allow_all().check(None, &[]),
+            PolicyAction::Deny
+        );
+    }
+
+    #[test]
+    fn unknown_commands() {
+        // The handles of unknown commands cannot be checked, an allow_all
+        // default does not let them through
+        let mut tpm = filter(CommandPolicy::allow_all().deny_handle(tcg::TPM_RH_OWNER));
+        assert!(!send(
+            &mut tpm,
+            command(TPM_CC_VENDOR, &[tcg::TPM_RH_OWNER], None)
+        ));
+        let mut tpm = filter(CommandPolicy::allow_all().allow(TPM_CC_VENDOR));
+        assert!(!send(&mut tpm, command(TPM_CC_VENDOR, &[], None)));
+
+        let mut tpm = filter(CommandPolicy::deny_all().allow_unknown(TPM_CC_VENDOR));
+        assert!(send(&mut tpm, command(TPM_CC_VENDOR, &[], None)));
+        assert!(!send(&mut tpm, command(TPM_CC_VENDOR + 1, &[], None)));
+        assert!(!send(&mut tpm, command(tcg::TPM_CC_PCR_READ, &[], None)));
+    }
+}
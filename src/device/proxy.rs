@@ -0,0 +1,286 @@
+use crate::device::errors::TpmDeviceError;
+use crate::device::raw::{error_response, TpmDeviceOps, MAX_COMMAND_SIZE};
+use crate::device::socket::{SocketAddress, SocketListener, SocketStream};
+use crate::device::tcp::{TPM_SEND_COMMAND, TPM_SESSION_END};
+use crate::tpm2::serialization::inout;
+use crate::tpm2::types::constants::TpmRc;
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::result;
+use std::sync::Arc;
+use std::thread;
+
+// The only platform signal carrying a payload, a u32 size followed by data
+const TPM_SIGNAL_HASH_DATA: u32 = 6;
+
+// DeviceFactory creates the backend serving one client connection
+pub type DeviceFactory =
+    dyn Fn() -> result::Result<Box<dyn TpmDeviceOps + Send>, TpmDeviceError> + Send + Sync;
+
+// TpmProxyServer exposes a TpmDeviceOps backend over the MS simulator
+// protocol (see tcp::TpmSwtpmIO), so that tools using the mssim TCTI, such
+// as tpm2-tools, can reach e.g. /dev/tpmrm0 of the host from a container.
+//
+// Every client connection is served by its own thread and its own backend,
+// created by the factory passed to bind. Backends sharing one TPM should
+// keep clients apart, e.g. by being clients of the same
+// rm::TpmResourceManager.
+//
+// Platform signals (power, NV, cancel, ...) are acknowledged and otherwise
+// ignored, a proxied TPM cannot be power cycled by its clients.
+//
+// Commands the backend fails to run, e.g. denied by a policy::CommandPolicy
+// or lost to a transport error, are answered with an error response and
+// the connection is kept open. See the proxy subcommand of the tpm2 binary.
+pub struct TpmProxyServer {
+    command: SocketListener,
+    platform: Option<SocketListener>,
+    factory: Arc<DeviceFactory>,
+}
+
+impl TpmProxyServer {
+    // bind listens on the command endpoint and, if given, on the platform
+    // endpoint. Port 0 picks a free port, see command_address.
+    pub fn bind<F, D>(
+        command: &SocketAddress,
+        platform: Option<&SocketAddress>,
+        factory: F,
+    ) -> result::Result<Self, std::io::Error>
+    where
+        F: Fn() -> result::Result<D, TpmDeviceError> + Send + Sync + 'static,
+        D: TpmDeviceOps + Send + 'static,
+    {
+        let platform = match platform {
+            Some(platform) => Some(platform.bind()?),
+            None => None,
+        };
+        Ok(TpmProxyServer {
+            command: command.bind()?,
+            platform,
+            factory: Arc::new(move || {
+                factory().map(|device| Box::new(device) as Box<dyn TpmDeviceOps + Send>)
+            }),
+        })
+    }
+
+    pub fn command_address(&self) -> result::Result<SocketAddress, std::io::Error> {
+        self.command.local_addr()
+    }
+
+    pub fn platform_address(&self) -> result::Result<Option<SocketAddress>, std::io::Error> {
+        match &self.platform {
+            Some(platform) => Ok(Some(platform.local_addr()?)),
+            None => Ok(None),
+        }
+    }
+
+    // serve accepts clients until accepting fails
+    pub fn serve(self) -> result::Result<(), std::io::Error> {
+        if let Some(platform) = self.platform {
+            thread::spawn(move || accept_loop(&platform, serve_platform));
+        }
+        let factory = self.factory;
+        accept_loop(&self.command, move |stream| {
+            let device = factory().map_err(|err| Error::other(err.to_string()))?;
+            serve_commands(stream, device)
+        })
+    }
+
+    // spawn serves clients from a background thread
+    pub fn spawn(self) -> thread::JoinHandle<result::Result<(), std::io::Error>> {
+        thread::spawn(move || self.serve())
+    }
+}
+
+// accept_loop serves every connection accepted on listener with handler,
+// each one on its own thread
+fn accept_loop<H>(listener: &SocketListener, handler: H) -> result::Result<(), std::io::Error>
+where
+    H: Fn(SocketStream) -> result::Result<(), std::io::Error> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    loop {
+        let stream = listener.accept()?;
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || {
+            if let Err(err) = handler(stream) {
+                log::warn!("proxy client dropped: {}", err);
+            }
+        });
+    }
+}
+
+// serve_commands answers the requests of a client on the command port
+fn serve_commands(
+    mut stream: SocketStream,
+    mut device: Box<dyn TpmDeviceOps + Send>,
+) -> result::Result<(), std::io::Error> {
+    loop {
+        let request = match read_u32(&mut stream) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        };
+        match request {
+            TPM_SEND_COMMAND => {
+                let mut locality = [0; 1];
+                stream.read_exact(&mut locality)?;
+                let size = read_u32(&mut stream)? as usize;
+                if size > MAX_COMMAND_SIZE {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("command of {} bytes is too large", size),
+                    ));
+                }
+                let mut command = vec![0; size];
+                stream.read_exact(&mut command)?;
+
+                let response = execute(device.as_mut(), locality[0], command);
+                let mut frame = Vec::with_capacity(response.len() + 8);
+                frame.extend_from_slice(&(response.len() as u32).to_be_bytes());
+                frame.extend_from_slice(&response);
+                frame.extend_from_slice(&0u32.to_be_bytes());
+                stream.write_all(&frame)?;
+            }
+            TPM_SESSION_END => return Ok(()),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported command port request {}", request),
+                ))
+            }
+        }
+    }
+}
+
+// execute runs a command on the backend at the requested locality and
+// returns the response for the client. Errors of the backend are turned
+// into error responses, so that the client sees a failed command rather
+// than a dropped connection.
+fn execute(device: &mut dyn TpmDeviceOps, locality: u8, command: Vec<u8>) -> Vec<u8> {
+    if device.locality() != locality && device.set_locality(locality).is_err() {
+        return error_response(TpmRc::Locality as u32);
+    }
+    let mut buff_command = inout::DynamicByteBuffer::from_vec(command);
+    let mut buff_answer = inout::DynamicByteBuffer::new();
+    match device.send_recv(&mut buff_command, &mut buff_answer) {
+        Ok(()) => buff_answer.into_vec(),
+        Err(err) => {
+            log::warn!("proxied command failed: {}", err);
+            error_response(error_code(&err))
+        }
+    }
+}
+
+// error_code returns the response code a client gets for a command the
+// backend failed to run
+fn error_code(err: &TpmDeviceError) -> u32 {
+    match err {
+        TpmDeviceError::PolicyError(_) => TpmRc::Disabled as u32,
+        TpmDeviceError::TimeoutError(_) => TpmRc::Canceled as u32,
+        _ => TpmRc::Failure as u32,
+    }
+}
+
+// serve_platform acknowledges the signals of a client on the platform port
+fn serve_platform(mut stream: SocketStream) -> result::Result<(), std::io::Error> {
+    loop {
+        let signal = match read_u32(&mut stream) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        };
+        match signal {
+            TPM_SESSION_END => return Ok(()),
+            TPM_SIGNAL_HASH_DATA => {
+                let size = read_u32(&mut stream)?;
+                std::io::copy(&mut (&mut stream).take(size as u64), &mut std::io::sink())?;
+            }
+            _ => log::debug!("ignoring platform signal {}", signal),
+        }
+        stream.write_all(&0u32.to_be_bytes())?;
+    }
+}
+
+fn read_u32(stream: &mut SocketStream) -> result::Result<u32, std::io::Error> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::fault::{Fault, TpmFaultInjector};
+    use crate::device::mock::TpmMockDevice;
+    use crate::device::policy::{CommandPolicy, TpmPolicyFilter};
+    use crate::device::raw::TpmDevice;
+    use crate::device::tcp::{PlatformProtocol, TpmSwtpmIO};
+    use crate::tpm2::commands::run;
+    use crate::tpm2::errors::CommandError;
+    use crate::tpm2::serialization::inout::Tpm2StructOut;
+    use crate::tpm2::types::tcg;
+
+    // proxy serves, on loopback ports, a mock TPM answering TPM2_GetRandom
+    // and TPM2_Clear behind a policy denying TPM2_Clear. The first command
+    // of every connection fails with a transport error.
+    fn proxy() -> TpmSwtpmIO {
+        let loopback = SocketAddress::tcp("127.0.0.1", 0);
+        let server = TpmProxyServer::bind(&loopback, Some(&loopback), || {
+            let mut mock = TpmMockDevice::new();
+            mock.expect_repeated(tcg::TPM_CC_GET_RANDOM, |_, resp| {
+                tcg::Tpm2bDigest::from_vec(vec![0x2a; 8])
+                    .pack(resp)
+                    .map_err(|_| TpmRc::Failure)
+            });
+            mock.expect_repeated(tcg::TPM_CC_CLEAR, |_, _| Ok(()));
+            let mut faults = TpmFaultInjector::new(mock);
+            faults.inject_once(tcg::TPM_CC_GET_RANDOM, Fault::Disconnect);
+            let policy = CommandPolicy::allow_all().deny(tcg::TPM_CC_CLEAR);
+            Ok(TpmPolicyFilter::new(faults, policy))
+        })
+        .unwrap();
+        let command = server.command_address().unwrap();
+        let platform = server.platform_address().unwrap().unwrap();
+        server.spawn();
+
+        let mut io = TpmSwtpmIO::with_endpoints(command, platform, PlatformProtocol::Mssim);
+        io.power_on().unwrap();
+        io
+    }
+
+    fn get_random(tpm: &mut TpmDevice) -> result::Result<Vec<u8>, CommandError> {
+        let mut resp = inout::DynamicByteBuffer::new();
+        run::run_command(tpm, tcg::TPM_CC_GET_RANDOM, &[], &[], &[&8u16], &mut resp)?;
+        Ok(resp.into_vec())
+    }
+
+    fn response_code<T: std::fmt::Debug>(result: result::Result<T, CommandError>) -> u32 {
+        match result {
+            Err(CommandError::ResponseError(err)) => err.error_code,
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        let mut io = proxy();
+        let mut tpm = TpmDevice { rw: &mut io };
+
+        // Backend and policy errors come back as error responses on the
+        // same connection
+        assert_eq!(response_code(get_random(&mut tpm)), TpmRc::Failure as u32);
+        let mut resp = inout::DynamicByteBuffer::new();
+        let clear = run::run_command(
+            &mut tpm,
+            tcg::TPM_CC_CLEAR,
+            &[tcg::TPM_RH_PLATFORM],
+            &[],
+            &[],
+            &mut resp,
+        );
+        assert_eq!(response_code(clear), TpmRc::Disabled as u32);
+
+        let random = get_random(&mut tpm).unwrap();
+        assert_eq!(random, [&[0x00, 0x08][..], &[0x2a; 8][..]].concat());
+        io.session_end().unwrap();
+    }
+}
@@ -1,44 +1,294 @@
+use crate::device::errors::{ResponseSizeError, TpmDeviceError};
+use crate::device::timeout::Timeouts;
 use crate::tpm2::serialization::inout;
+use crate::tpm2::types::tcg;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{Error, ErrorKind};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::result;
+use std::time::Instant;
+
+// Size of the response header: tag (u16), responseSize (u32) and
+// responseCode (u32)
+pub const RESPONSE_HEADER_SIZE: usize = 10;
+
+// read_u16 reads the big-endian u16 at offset of a marshalled command or
+// response, None if it runs past the end
+pub fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let b = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_be_bytes([b[0], b[1]]))
+}
+
+// read_u32 reads the big-endian u32 at offset of a marshalled command or
+// response, None if it runs past the end
+pub fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+// header_size returns the commandSize or responseSize field of a marshalled
+// command or response
+pub fn header_size(bytes: &[u8]) -> Option<usize> {
+    read_u32(bytes, 2).map(|size| size as usize)
+}
+
+// command_code returns the command code of a marshalled command, None if it
+// is too short to carry one
+pub fn command_code(bytes: &[u8]) -> Option<tcg::TpmCc> {
+    read_u32(bytes, 6)
+}
+
+// response_code returns the response code of a marshalled response, None if
+// it is too short to carry one
+pub fn response_code(bytes: &[u8]) -> Option<u32> {
+    read_u32(bytes, 6)
+}
+
+// MAX_RESPONSE_SIZE is the largest response accepted from the TPM. Responses
+// are read into growable buffers, the bound only protects against corrupted
+// responseSize fields.
+pub const MAX_RESPONSE_SIZE: usize = 1 << 20;
+
+// MAX_COMMAND_SIZE is the largest command accepted from clients by layers
+// answering in place of the TPM
+pub const MAX_COMMAND_SIZE: usize = 1 << 20;
+
+// error_response builds the response a TPM gives to a command failing with
+// rc, for layers answering commands in place of the TPM
+pub fn error_response(rc: u32) -> Vec<u8> {
+    let mut response = Vec::with_capacity(RESPONSE_HEADER_SIZE);
+    response.extend_from_slice(&tcg::TPM_ST_NO_SESSION.to_be_bytes());
+    response.extend_from_slice(&(RESPONSE_HEADER_SIZE as u32).to_be_bytes());
+    response.extend_from_slice(&rc.to_be_bytes());
+    response
+}
 
 // Define a combined ReadWrite trait.
-pub trait ReadWrite: io::Read + io::Write {}
-impl<T: io::Read + io::Write> ReadWrite for T {}
+//
+// Transports able to send commands at a locality other than 0 override
+// locality and set_locality, the others only accept locality 0.
+pub trait ReadWrite: io::Read + io::Write {
+    fn locality(&self) -> u8 {
+        0
+    }
+
+    // set_locality selects the locality the following commands are sent at
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), std::io::Error> {
+        match locality {
+            0 => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("locality {} is not supported by this transport", locality),
+            )),
+        }
+    }
+}
+
+impl<T: ReadWrite + ?Sized> ReadWrite for &mut T {
+    fn locality(&self) -> u8 {
+        (**self).locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), std::io::Error> {
+        (**self).set_locality(locality)
+    }
+}
+
+impl<T: ReadWrite + ?Sized> ReadWrite for Box<T> {
+    fn locality(&self) -> u8 {
+        (**self).locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), std::io::Error> {
+        (**self).set_locality(locality)
+    }
+}
+
+// NoLocality turns any byte stream carrying raw commands and responses, such
+// as a socket or a pipe to a TPM proxy, into a ReadWrite transport sending
+// every command at locality 0. ReadWrite has no blanket implementation, as
+// transports supporting localities implement it themselves.
+#[derive(Debug)]
+pub struct NoLocality<T: io::Read + io::Write>(pub T);
+
+impl<T: io::Read + io::Write> NoLocality<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: io::Read + io::Write> io::Read for NoLocality<T> {
+    fn read(&mut self, buf: &mut [u8]) -> result::Result<usize, std::io::Error> {
+        self.0.read(buf)
+    }
+}
+
+impl<T: io::Read + io::Write> io::Write for NoLocality<T> {
+    fn write(&mut self, buf: &[u8]) -> result::Result<usize, std::io::Error> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> result::Result<(), std::io::Error> {
+        self.0.flush()
+    }
+}
+
+impl<T: io::Read + io::Write> ReadWrite for NoLocality<T> {}
+
+// is_valid_locality tells whether locality is one of the localities defined
+// by the TCG: 0 to 4, and the extended localities 32 to 255
+pub fn is_valid_locality(locality: u8) -> bool {
+    locality <= 4 || locality >= 32
+}
+
+// Character devices exposed by the kernel TPM driver. /dev/tpmrm0 goes
+// through the in-kernel resource manager and can be shared between processes.
+pub const DEFAULT_DEVICE_PATH: &str = "/dev/tpm0";
+pub const RESOURCE_MANAGER_DEVICE_PATH: &str = "/dev/tpmrm0";
 
-// TpmRawIO implements communication with the TPM via /dev/tpm[0-9] device file
+// TpmRawIO implements communication with the TPM via /dev/tpm[0-9] or
+// /dev/tpmrm[0-9] device file.
+//
+// Reads block until the TPM answers unless timeouts are set with
+// set_timeouts. The device is then opened non-blocking so that the kernel
+// executes commands asynchronously, and reads wait for the response no
+// longer than the timeout of the command class (see device::timeout). On
+// timeout the device file is closed, which makes the kernel discard the
+// response once the command completes.
 pub struct TpmRawIO {
+    path: PathBuf,
     device_file: Option<File>,
+    timeouts: Option<Timeouts>,
+    // deadline for the response to the last command
+    deadline: Option<Instant>,
+}
+
+impl TpmRawIO {
+    pub fn new() -> Self {
+        TpmRawIO::with_path(DEFAULT_DEVICE_PATH)
+    }
+
+    // with_path creates a TpmRawIO for an arbitrary device file, such as
+    // /dev/tpmrm0. The device is opened on first write.
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Self {
+        TpmRawIO {
+            path: path.as_ref().to_path_buf(),
+            device_file: None,
+            timeouts: None,
+            deadline: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // set_timeouts replaces the response timeouts, None waits forever. It
+    // takes effect the next time the device is opened.
+    pub fn set_timeouts(&mut self, timeouts: Option<Timeouts>) {
+        self.timeouts = timeouts;
+    }
+
+    fn open(&self) -> result::Result<File, std::io::Error> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        #[cfg(unix)]
+        {
+            if self.timeouts.is_some() {
+                options.custom_flags(libc::O_NONBLOCK);
+            }
+        }
+        options.open(&self.path)
+    }
+}
+
+// The kernel TPM driver sends every command at locality 0
+impl ReadWrite for TpmRawIO {}
+
+impl Default for TpmRawIO {
+    fn default() -> Self {
+        TpmRawIO::new()
+    }
 }
 
 // Implementation of ReadWrite trait for TpmRawIO
 impl io::Read for TpmRawIO {
     fn read(&mut self, buf: &mut [u8]) -> result::Result<usize, std::io::Error> {
         match &mut self.device_file {
-            None => {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "device file not open for reading".to_string(),
-                ))
-            }
-            Some(f) => {
-                let n = f.read(buf)?;
-                Ok(n)
+            None => Err(Error::other("device file not open for reading")),
+            Some(f) => match self.deadline {
+                None => f.read(buf),
+                Some(deadline) => match read_until(f, buf, deadline) {
+                    Err(err) if err.kind() == ErrorKind::TimedOut => {
+                        self.device_file = None;
+                        self.deadline = None;
+                        Err(Error::new(
+                            ErrorKind::TimedOut,
+                            format!("no response from {} in time", self.path.display()),
+                        ))
+                    }
+                    result => result,
+                },
+            },
+        }
+    }
+}
+
+// read_until reads from a device file opened non-blocking, waiting for data
+// until deadline
+#[cfg(unix)]
+fn read_until(
+    f: &mut File,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> result::Result<usize, std::io::Error> {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut pollfd = libc::pollfd {
+            fd: f.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = remaining.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        // Safety: pollfd is a valid array of one element for the whole call
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            -1 => {
+                let err = Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err);
+                }
             }
+            0 => return Err(Error::from(ErrorKind::TimedOut)),
+            _ => match io::Read::read(f, buf) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+                result => return result,
+            },
         }
     }
 }
 
+#[cfg(not(unix))]
+fn read_until(
+    f: &mut File,
+    buf: &mut [u8],
+    _deadline: Instant,
+) -> result::Result<usize, std::io::Error> {
+    io::Read::read(f, buf)
+}
+
 impl io::Write for TpmRawIO {
     fn write(&mut self, buf: &[u8]) -> result::Result<usize, std::io::Error> {
         match self.device_file {
-            None => match OpenOptions::new().read(true).write(true).open("/dev/tpm0") {
+            None => match self.open() {
                 Err(err) => {
                     return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("could not open /dev/tpm0: {}", err),
+                        err.kind(),
+                        format!("could not open {}: {}", self.path.display(), err),
                     ));
                 }
                 Ok(f) => {
@@ -49,22 +299,19 @@ impl io::Write for TpmRawIO {
         }
 
         match &mut self.device_file {
-            None => Err(Error::new(
-                ErrorKind::Other,
-                "device file is not set, cannot write input buffer".to_string(),
+            None => Err(Error::other(
+                "device file is not set, cannot write input buffer",
             )),
             Some(f) => {
                 f.write_all(buf)?;
+                self.deadline = self.timeouts.map(|t| Instant::now() + t.for_command(buf));
                 Ok(buf.len() as usize)
             }
         }
     }
 
     fn flush(&mut self) -> result::Result<(), std::io::Error> {
-        Err(Error::new(
-            ErrorKind::Other,
-            "flush is not supported on TpmRawIO".to_string(),
-        ))
+        Err(Error::other("flush is not supported on TpmRawIO"))
     }
 }
 
@@ -80,7 +327,80 @@ pub trait TpmDeviceOps {
         &mut self,
         buff_command: &mut dyn inout::RwBytes,
         buff_answer: &mut dyn inout::RwBytes,
-    ) -> result::Result<(), std::io::Error>;
+    ) -> result::Result<(), TpmDeviceError>;
+
+    // locality returns the locality commands are sent at
+    fn locality(&self) -> u8 {
+        0
+    }
+
+    // set_locality selects the locality the following commands are sent at,
+    // see run::run_command_at_locality for a single command. Devices without
+    // locality support only accept locality 0.
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        match locality {
+            0 => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("locality {} is not supported by this device", locality),
+            )
+            .into()),
+        }
+    }
+}
+
+// Forwarding implementations, so that wrappers around a TpmDeviceOps can
+// either own it or borrow it
+impl<T: TpmDeviceOps + ?Sized> TpmDeviceOps for &mut T {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        (**self).send_recv(buff_command, buff_answer)
+    }
+
+    fn locality(&self) -> u8 {
+        (**self).locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        (**self).set_locality(locality)
+    }
+}
+
+impl<T: TpmDeviceOps + ?Sized> TpmDeviceOps for Box<T> {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        (**self).send_recv(buff_command, buff_answer)
+    }
+
+    fn locality(&self) -> u8 {
+        (**self).locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        (**self).set_locality(locality)
+    }
+}
+
+// TpmOwnedDevice is a TpmDevice owning its transport, so that it can be
+// moved across threads or shared (see device::shared)
+pub struct TpmOwnedDevice<RW: ReadWrite> {
+    pub rw: RW,
+}
+
+impl<RW: ReadWrite> TpmOwnedDevice<RW> {
+    pub fn new(rw: RW) -> Self {
+        TpmOwnedDevice { rw }
+    }
+
+    pub fn into_inner(self) -> RW {
+        self.rw
+    }
 }
 
 impl TpmDeviceOps for TpmDevice<'_> {
@@ -88,11 +408,236 @@ impl TpmDeviceOps for TpmDevice<'_> {
         &mut self,
         buff_command: &mut dyn inout::RwBytes,
         buff_answer: &mut dyn inout::RwBytes,
-    ) -> result::Result<(), std::io::Error> {
-        self.rw.write(&buff_command.to_bytes())?;
-        let mut buff_in = [0; 4096];
-        self.rw.read(&mut buff_in)?;
-        buff_answer.write_bytes(&buff_in);
-        Ok(())
+    ) -> result::Result<(), TpmDeviceError> {
+        transceive(self.rw, buff_command, buff_answer)
+    }
+
+    fn locality(&self) -> u8 {
+        self.rw.locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        Ok(self.rw.set_locality(locality)?)
+    }
+}
+
+impl<RW: ReadWrite> TpmDeviceOps for TpmOwnedDevice<RW> {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        transceive(&mut self.rw, buff_command, buff_answer)
+    }
+
+    fn locality(&self) -> u8 {
+        self.rw.locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        Ok(self.rw.set_locality(locality)?)
+    }
+}
+
+// transceive writes the command, then reads the response header followed by
+// exactly as many bytes as announced by its responseSize field
+fn transceive(
+    rw: &mut dyn ReadWrite,
+    buff_command: &mut dyn inout::RwBytes,
+    buff_answer: &mut dyn inout::RwBytes,
+) -> result::Result<(), TpmDeviceError> {
+    rw.write_all(buff_command.to_bytes())?;
+
+    let mut header = [0; RESPONSE_HEADER_SIZE];
+    read_full(rw, &mut header, 0)?;
+
+    let size = header_size(&header).unwrap_or(0);
+    if size < RESPONSE_HEADER_SIZE {
+        return Err(ResponseSizeError::Mismatch {
+            declared: size,
+            received: RESPONSE_HEADER_SIZE,
+        }
+        .into());
+    }
+    if size > MAX_RESPONSE_SIZE {
+        return Err(ResponseSizeError::Oversize {
+            size,
+            max: MAX_RESPONSE_SIZE,
+        }
+        .into());
+    }
+
+    let mut body = vec![0; size - RESPONSE_HEADER_SIZE];
+    read_full(rw, &mut body, RESPONSE_HEADER_SIZE)?;
+
+    buff_answer.write_bytes(&header)?;
+    buff_answer.write_bytes(&body)?;
+    Ok(())
+}
+
+// read_full fills buf from rw, failing with ResponseSizeError::ShortRead if
+// the stream ends first. offset is the number of response bytes already
+// received, and is only used for reporting.
+fn read_full(
+    rw: &mut dyn ReadWrite,
+    buf: &mut [u8],
+    offset: usize,
+) -> result::Result<(), TpmDeviceError> {
+    let mut received = 0;
+    while received < buf.len() {
+        match rw.read(&mut buf[received..]) {
+            Ok(0) => {
+                return Err(ResponseSizeError::ShortRead {
+                    expected: offset + buf.len(),
+                    received: offset + received,
+                }
+                .into())
+            }
+            Ok(n) => received += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm2::serialization::inout::RwBytes;
+
+    // Stream answers with canned bytes and keeps what is written to it
+    struct Stream {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl io::Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> result::Result<usize, std::io::Error> {
+            self.input.read(buf)
+        }
+    }
+
+    impl io::Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> result::Result<usize, std::io::Error> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn blocking_by_default() {
+        // timeouts open the device non-blocking, existing users keep
+        // blocking reads unless they opt in
+        let mut tpm = TpmRawIO::with_path(RESOURCE_MANAGER_DEVICE_PATH);
+        assert_eq!(tpm.timeouts, None);
+        assert_eq!(TpmRawIO::new().timeouts, None);
+
+        tpm.set_timeouts(Some(Timeouts::new()));
+        assert_eq!(tpm.timeouts, Some(Timeouts::new()));
+    }
+
+    #[test]
+    fn header_fields() {
+        let response = error_response(0x101);
+        assert_eq!(read_u16(&response, 0), Some(tcg::TPM_ST_NO_SESSION));
+        assert_eq!(header_size(&response), Some(RESPONSE_HEADER_SIZE));
+        assert_eq!(response_code(&response), Some(0x101));
+        assert_eq!(command_code(&response[..9]), None);
+        assert_eq!(read_u32(&response, usize::MAX), None);
+    }
+
+    // exchange sends a TPM2_Startup through a device answering with response
+    fn exchange(response: Vec<u8>) -> result::Result<Vec<u8>, TpmDeviceError> {
+        let command = vec![0x80, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x01, 0x44];
+        let mut device = TpmOwnedDevice::new(NoLocality(Stream {
+            input: io::Cursor::new(response),
+            output: Vec::new(),
+        }));
+        let mut buff_command = inout::DynamicByteBuffer::from_vec(command);
+        let mut buff_answer = inout::DynamicByteBuffer::new();
+        device.send_recv(&mut buff_command, &mut buff_answer)?;
+        Ok(buff_answer.to_bytes().to_vec())
+    }
+
+    #[test]
+    fn short_read() {
+        let mut response = error_response(0);
+        response[5] = 14;
+        response.extend_from_slice(&[0, 0]);
+        match exchange(response) {
+            Err(TpmDeviceError::ResponseSizeError(ResponseSizeError::ShortRead {
+                expected: 14,
+                received: 12,
+            })) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        match exchange(error_response(0)[..6].to_vec()) {
+            Err(TpmDeviceError::ResponseSizeError(ResponseSizeError::ShortRead {
+                expected: RESPONSE_HEADER_SIZE,
+                received: 6,
+            })) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversize() {
+        let mut response = error_response(0);
+        response[2..6].copy_from_slice(&u32::MAX.to_be_bytes());
+        match exchange(response) {
+            Err(TpmDeviceError::ResponseSizeError(ResponseSizeError::Oversize {
+                size,
+                max: MAX_RESPONSE_SIZE,
+            })) => assert_eq!(size, u32::MAX as usize),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mismatch() {
+        let mut response = error_response(0);
+        response[5] = 6;
+        match exchange(response) {
+            Err(TpmDeviceError::ResponseSizeError(ResponseSizeError::Mismatch {
+                declared: 6,
+                received: RESPONSE_HEADER_SIZE,
+            })) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        // Only the bytes announced by responseSize are read
+        let mut response = error_response(0);
+        response.extend_from_slice(&[0xff; 4]);
+        assert_eq!(exchange(response).unwrap(), error_response(0));
+    }
+
+    #[test]
+    fn no_locality() {
+        let command = vec![0x80, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x01, 0x44];
+        let response = error_response(0);
+        let mut device = TpmOwnedDevice::new(NoLocality(Stream {
+            input: io::Cursor::new(response.clone()),
+            output: Vec::new(),
+        }));
+
+        let mut buff_command = inout::DynamicByteBuffer::from_vec(command.clone());
+        let mut buff_answer = inout::DynamicByteBuffer::new();
+        device
+            .send_recv(&mut buff_command, &mut buff_answer)
+            .unwrap();
+        assert_eq!(buff_answer.to_bytes(), &response[..]);
+
+        device.set_locality(0).unwrap();
+        assert!(device.set_locality(3).is_err());
+        assert_eq!(device.locality(), 0);
+        assert_eq!(device.into_inner().into_inner().output, command);
     }
 }
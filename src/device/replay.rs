@@ -0,0 +1,369 @@
+use crate::device::errors::{ReplayError, TpmDeviceError};
+use crate::device::raw::TpmDeviceOps;
+use crate::tpm2::serialization::inout;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+use std::result;
+
+// Recordings are text files with one exchange per pair of lines, command
+// first, each hex encoded:
+//
+// > 80010000000c000001440000
+// < 80010000000a00000000
+//
+// Empty lines and lines starting with '#' are ignored, so recordings can be
+// annotated by hand.
+const COMMAND_PREFIX: &str = "> ";
+const RESPONSE_PREFIX: &str = "< ";
+
+// TpmRecorder wraps a TpmDeviceOps and appends every successful
+// command/response exchange to a recording file
+pub struct TpmRecorder<T: TpmDeviceOps> {
+    inner: T,
+    out: BufWriter<File>,
+}
+
+impl<T: TpmDeviceOps> TpmRecorder<T> {
+    // new creates the recording file at path, truncating any previous
+    // recording
+    pub fn new<P: AsRef<Path>>(inner: T, path: P) -> result::Result<Self, std::io::Error> {
+        Ok(TpmRecorder {
+            inner,
+            out: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: TpmDeviceOps> TpmDeviceOps for TpmRecorder<T> {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        self.inner.send_recv(buff_command, buff_answer)?;
+        writeln!(
+            self.out,
+            "{}{}",
+            COMMAND_PREFIX,
+            hex::encode(buff_command.to_bytes())
+        )?;
+        writeln!(
+            self.out,
+            "{}{}",
+            RESPONSE_PREFIX,
+            hex::encode(buff_answer.to_bytes())
+        )?;
+        // Flush every exchange, so that the recording survives a crash of
+        // the session being recorded
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn locality(&self) -> u8 {
+        self.inner.locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        self.inner.set_locality(locality)
+    }
+}
+
+// Exchange is a recorded command and the response the TPM gave to it
+#[derive(Debug, Clone)]
+struct Exchange {
+    command: Vec<u8>,
+    response: Vec<u8>,
+}
+
+// TpmReplayer serves the responses of a recording, checking that commands
+// match the recorded ones byte for byte. No TPM is needed.
+//
+// Commands must be reproducible for the replay to match: commands carrying
+// fresh randomness, such as the encrypted seed of TPM2_Import, need to be
+// built from a seeded RNG (see import::tpm2_import_with_rng).
+//
+// Localities are not part of recordings, any locality is accepted.
+pub struct TpmReplayer {
+    exchanges: Vec<Exchange>,
+    next: usize,
+    locality: u8,
+}
+
+impl TpmReplayer {
+    // open loads a recording created by TpmRecorder
+    pub fn open<P: AsRef<Path>>(path: P) -> result::Result<Self, std::io::Error> {
+        TpmReplayer::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> result::Result<Self, std::io::Error> {
+        let mut exchanges = Vec::new();
+        let mut command: Option<Vec<u8>> = None;
+
+        for (num, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match (command.take(), line.get(..2)) {
+                (None, Some(COMMAND_PREFIX)) => {
+                    command = Some(decode_line(&line[2..], num)?);
+                }
+                (Some(cmd), Some(RESPONSE_PREFIX)) => exchanges.push(Exchange {
+                    command: cmd,
+                    response: decode_line(&line[2..], num)?,
+                }),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("line {}: unexpected record '{}'", num + 1, line),
+                    ))
+                }
+            }
+        }
+
+        if command.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "recording ends with a command without response".to_string(),
+            ));
+        }
+
+        Ok(TpmReplayer {
+            exchanges,
+            next: 0,
+            locality: 0,
+        })
+    }
+
+    // remaining returns the number of recorded exchanges not replayed yet.
+    // A test replaying a whole session expects this to be 0 at the end.
+    pub fn remaining(&self) -> usize {
+        self.exchanges.len() - self.next
+    }
+}
+
+fn decode_line(data: &str, num: usize) -> result::Result<Vec<u8>, std::io::Error> {
+    hex::decode(data.trim()).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("line {}: invalid hex data: {}", num + 1, err),
+        )
+    })
+}
+
+impl TpmDeviceOps for TpmReplayer {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        let index = self.next;
+        let exchange = match self.exchanges.get(index) {
+            None => return Err(ReplayError::Exhausted { index }.into()),
+            Some(exchange) => exchange,
+        };
+        if exchange.command.as_slice() != buff_command.to_bytes() {
+            return Err(ReplayError::Mismatch {
+                index,
+                expected: exchange.command.clone(),
+                actual: buff_command.to_bytes().to_vec(),
+            }
+            .into());
+        }
+        buff_answer.write_bytes(&exchange.response)?;
+        self.next += 1;
+        Ok(())
+    }
+
+    fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        self.locality = locality;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::tpm2::commands::pcrread::tpm2_pcr_read;
+    use crate::tpm2::commands::pcrs::PCRSelection;
+    use crate::tpm2::commands::{import, run, session, unseal};
+    use crate::tpm2::errors::CommandError;
+    use crate::tpm2::serialization::inout::RwBytes;
+    use crate::tpm2::types::constants::TpmAlgId;
+    use crate::tpm2::types::tcg;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    // startup sends TPM2_Startup(startup_type) through tpm
+    fn startup(
+        tpm: &mut dyn TpmDeviceOps,
+        startup_type: tcg::TpmSu,
+    ) -> result::Result<(), CommandError> {
+        let params: [&dyn inout::Tpm2StructOut; 1] = [&startup_type];
+        let mut resp_buff = inout::DynamicByteBuffer::new();
+        run::run_command(tpm, tcg::TPM_CC_STARTUP, &[], &[], &params, &mut resp_buff)
+    }
+
+    // send sends raw command bytes, so that replay errors are not turned into
+    // CommandError
+    fn send(tpm: &mut dyn TpmDeviceOps, command: &str) -> result::Result<Vec<u8>, TpmDeviceError> {
+        let mut buff_command = inout::DynamicByteBuffer::from_vec(hex::decode(command).unwrap());
+        let mut buff_answer = inout::DynamicByteBuffer::new();
+        tpm.send_recv(&mut buff_command, &mut buff_answer)?;
+        Ok(buff_answer.to_bytes().to_vec())
+    }
+
+    // fixture opens a recording of data/recordings
+    fn fixture(name: &str) -> TpmReplayer {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("data/recordings")
+            .join(name);
+        TpmReplayer::open(path).unwrap()
+    }
+
+    fn recording_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("tpm2-replay-{}-{}.rec", std::process::id(), name))
+    }
+
+    #[test]
+    fn record_replay() {
+        let path = recording_path("record_replay");
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(tcg::TPM_CC_STARTUP, vec![])
+            .expect_response(tcg::TPM_CC_STARTUP, vec![]);
+
+        let mut recorder = TpmRecorder::new(tpm, &path).unwrap();
+        startup(&mut recorder, tcg::TPM_SU_CLEAR).unwrap();
+        startup(&mut recorder, tcg::TPM_SU_STATE).unwrap();
+        assert_eq!(recorder.into_inner().calls().len(), 2);
+
+        let mut replayer = TpmReplayer::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(replayer.remaining(), 2);
+        startup(&mut replayer, tcg::TPM_SU_CLEAR).unwrap();
+        startup(&mut replayer, tcg::TPM_SU_STATE).unwrap();
+        assert_eq!(replayer.remaining(), 0);
+
+        match send(&mut replayer, "80010000000c000001440000") {
+            Err(TpmDeviceError::ReplayError(ReplayError::Exhausted { index: 2 })) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replay_fixture() {
+        let mut replayer = fixture("pcr_read.rec");
+
+        let pcrs = tpm2_pcr_read(&mut replayer, &[PCRSelection::new(vec![0])]).unwrap();
+        assert_eq!(replayer.remaining(), 0);
+        assert_eq!(
+            hex::encode(&pcrs.get_map()[&TpmAlgId::SHA256].get_map()[&0]),
+            "1c9ecec90e28d2461650418635878a5c91e49f47586ecf75f2b0cbb94e897112"
+        );
+    }
+
+    #[test]
+    fn replay_import() {
+        let mut replayer = fixture("import.rec");
+
+        let auth = session::tpm2_startauth_session(&mut replayer).unwrap();
+        session::tpm2_policy_secret(&mut replayer, tcg::TPM_RH_ENDORSEMENT, auth.clone()).unwrap();
+        let data = import::tpm2_import_with_rng(
+            &mut replayer,
+            0x80000000,
+            auth,
+            &mut StdRng::seed_from_u64(0),
+        )
+        .unwrap();
+        assert_eq!(replayer.remaining(), 0);
+        assert_eq!(data.get_buffer(), b"secret data");
+    }
+
+    #[test]
+    fn replay_import_other_seed() {
+        // The encrypted seed differs, so TPM2_Import does not match
+        let mut replayer = fixture("import.rec");
+
+        let auth = session::tpm2_startauth_session(&mut replayer).unwrap();
+        session::tpm2_policy_secret(&mut replayer, tcg::TPM_RH_ENDORSEMENT, auth.clone()).unwrap();
+        match import::tpm2_import_with_rng(
+            &mut replayer,
+            0x80000000,
+            auth,
+            &mut StdRng::seed_from_u64(1),
+        ) {
+            Err(CommandError::IoError(err)) => {
+                assert!(err.msg.contains("command 2 does not match"), "{}", err.msg)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replay_unseal() {
+        let mut replayer = fixture("unseal.rec");
+
+        let data = unseal::tpm2_unseal(&mut replayer, 0x80000001).unwrap();
+        assert_eq!(replayer.remaining(), 0);
+        assert_eq!(data.get_buffer(), b"secret data");
+    }
+
+    #[test]
+    fn replay_mismatch() {
+        let recording = "> 80010000000c000001440000\n< 80010000000a00000000\n";
+        let mut replayer = TpmReplayer::from_reader(Cursor::new(recording)).unwrap();
+
+        match send(&mut replayer, "80010000000c000001440001") {
+            Err(TpmDeviceError::ReplayError(ReplayError::Mismatch {
+                index: 0,
+                expected,
+                actual,
+            })) => {
+                assert_eq!(hex::encode(expected), "80010000000c000001440000");
+                assert_eq!(hex::encode(actual), "80010000000c000001440001");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        // A mismatch does not consume the exchange
+        assert_eq!(replayer.remaining(), 1);
+        startup(&mut replayer, tcg::TPM_SU_CLEAR).unwrap();
+    }
+
+    #[test]
+    fn malformed_recordings() {
+        let recordings = [
+            // Not hex
+            "> 8001zz\n< 80010000000a00000000\n",
+            // Odd number of digits
+            "> 80010000000c000001440000\n< 80010000000a0000000\n",
+            // Missing prefix
+            "80010000000c000001440000\n< 80010000000a00000000\n",
+            // Response without command
+            "< 80010000000a00000000\n",
+            // Command without response
+            "# comment\n\n> 80010000000c000001440000\n",
+        ];
+        for recording in recordings.iter() {
+            match TpmReplayer::from_reader(Cursor::new(recording)) {
+                Err(err) => assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", recording),
+                Ok(_) => panic!("malformed recording accepted: {}", recording),
+            }
+        }
+    }
+}
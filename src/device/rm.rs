@@ -0,0 +1,1133 @@
+use crate::device::errors::{DeviceIoError, TpmDeviceError};
+use crate::device::raw::{
+    command_code, error_response, read_u16, read_u32, response_code, TpmDeviceOps,
+    RESPONSE_HEADER_SIZE,
+};
+use crate::tpm2::commands::context;
+use crate::tpm2::errors::CommandError;
+use crate::tpm2::serialization::inout;
+use crate::tpm2::types::constants::cc;
+use crate::tpm2::types::constants::TpmRc;
+use crate::tpm2::types::tcg;
+
+use std::collections::HashMap;
+use std::result;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// DEFAULT_CLIENT_QUOTA is the number of transient objects, and separately of
+// sessions, a client may keep loaded in the TPM. Three is the minimum the PC
+// Client profile requires a TPM to hold.
+pub const DEFAULT_CLIENT_QUOTA: usize = 3;
+
+// Virtual transient handles are allocated from the top of the transient
+// range, which TPMs do not use for their own handles
+const VIRTUAL_HANDLE_FIRST: tcg::Handle = 0x80FF0000;
+const VIRTUAL_HANDLE_LAST: tcg::Handle = 0x80FFFFFF;
+
+const HT_TRANSIENT: u32 = 0x80;
+const HT_HMAC_SESSION: u32 = 0x02;
+const HT_POLICY_SESSION: u32 = 0x03;
+
+// Response code modifiers locating the handle, parameter or session a
+// format-one response code refers to
+const RC_P: u32 = 0x040;
+const RC_S: u32 = 0x800;
+const RC_1: u32 = 0x100;
+
+// continueSession bit of TPMA_SESSION
+const ATTR_CONTINUE_SESSION: u8 = 0x01;
+
+// TpmResourceManager shares a TPM between clients the way the kernel
+// resource manager behind /dev/tpmrm0 does, for TPMs reached without it,
+// e.g. a simulator or a TPM passed into a container.
+//
+// Every client sees its own virtual handles for the transient objects and
+// sequences it loads. Objects are swapped out of the TPM with
+// TPM2_ContextSave and TPM2_FlushContext, and back in with TPM2_ContextLoad,
+// when the TPM runs out of object or session memory or when a client exceeds
+// its quota. Sessions keep their handle, but are likewise saved and loaded
+// on demand. Objects and sessions left behind by a client are flushed when
+// the client is dropped.
+//
+// Clients cannot see or use objects and sessions of other clients.
+// Persistent objects, NV indices and permanent handles are passed through.
+pub struct TpmResourceManager<T: TpmDeviceOps> {
+    state: Arc<Mutex<RmState<T>>>,
+}
+
+impl<T: TpmDeviceOps> TpmResourceManager<T> {
+    pub fn new(device: T) -> Self {
+        TpmResourceManager::with_quota(device, DEFAULT_CLIENT_QUOTA)
+    }
+
+    // with_quota returns a resource manager allowing each client at most
+    // quota loaded transient objects and quota loaded sessions
+    pub fn with_quota(device: T, quota: usize) -> Self {
+        TpmResourceManager {
+            state: Arc::new(Mutex::new(RmState {
+                device,
+                quota: quota.max(1),
+                next_client: 0,
+                next_virtual: VIRTUAL_HANDLE_FIRST,
+                clock: 0,
+                objects: HashMap::new(),
+                sessions: HashMap::new(),
+            })),
+        }
+    }
+
+    // client opens a new connection to the TPM
+    pub fn client(&self) -> RmClient<T> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.next_client += 1;
+        RmClient {
+            id: state.next_client,
+            locality: 0,
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+// RmClient is a connection to a TpmResourceManager. Commands are processed
+// one at a time across all clients of the same resource manager, each at
+// the locality of the client which sent it.
+pub struct RmClient<T: TpmDeviceOps> {
+    id: u32,
+    locality: u8,
+    state: Arc<Mutex<RmState<T>>>,
+}
+
+impl<T: TpmDeviceOps> RmClient<T> {
+    fn lock(&self) -> result::Result<MutexGuard<'_, RmState<T>>, TpmDeviceError> {
+        self.state.lock().map_err(|_| {
+            TpmDeviceError::IoError(DeviceIoError {
+                msg: "resource manager lock poisoned by a panicking thread".to_string(),
+            })
+        })
+    }
+}
+
+impl<T: TpmDeviceOps> TpmDeviceOps for RmClient<T> {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        let mut state = self.lock()?;
+        state.device.set_locality(self.locality)?;
+        let response = state.process(self.id, buff_command.to_bytes())?;
+        buff_answer.write_bytes(&response)?;
+        Ok(())
+    }
+
+    fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        // Let the device reject localities it does not support right away
+        self.lock()?.device.set_locality(locality)?;
+        self.locality = locality;
+        Ok(())
+    }
+}
+
+impl<T: TpmDeviceOps> Drop for RmClient<T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.release(self.id);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Object,
+    Session,
+}
+
+// Entry tracks a transient object or a session. Objects are keyed by their
+// virtual handle and get a new physical handle on every load, sessions keep
+// their handle.
+struct Entry {
+    owner: u32,
+    // physical is the handle of the entry in the TPM, None if swapped out
+    physical: Option<tcg::Handle>,
+    // context holds the entry while swapped out. Sessions saved by the
+    // client itself have neither physical handle nor context.
+    context: Option<Box<tcg::TpmsContext>>,
+    last_used: u64,
+}
+
+// Failure is an error of a resource manager operation. TPM errors are
+// returned to the client as a response, transport errors as an error.
+enum Failure {
+    Rc(u32),
+    Device(TpmDeviceError),
+}
+
+impl From<CommandError> for Failure {
+    fn from(err: CommandError) -> Self {
+        match err {
+            CommandError::ResponseError(err) => Failure::Rc(err.error_code),
+            CommandError::TimeoutError(err) => Failure::Device(TpmDeviceError::TimeoutError(err)),
+            CommandError::ResponseSizeError(err) => {
+                Failure::Device(TpmDeviceError::ResponseSizeError(err))
+            }
+            CommandError::PolicyError(err) => Failure::Device(TpmDeviceError::PolicyError(err)),
+            err => Failure::Device(TpmDeviceError::IoError(DeviceIoError {
+                msg: err.to_string(),
+            })),
+        }
+    }
+}
+
+impl From<TpmDeviceError> for Failure {
+    fn from(err: TpmDeviceError) -> Self {
+        Failure::Device(err)
+    }
+}
+
+// Command holds the fields of a client command the resource manager acts on
+struct Command {
+    command_code: tcg::TpmCc,
+    handles: Vec<tcg::Handle>,
+    // sessions holds handle and attributes of the authorization sessions
+    sessions: Vec<(tcg::Handle, u8)>,
+}
+
+struct RmState<T: TpmDeviceOps> {
+    device: T,
+    quota: usize,
+    next_client: u32,
+    next_virtual: tcg::Handle,
+    clock: u64,
+    objects: HashMap<tcg::Handle, Entry>,
+    sessions: HashMap<tcg::Handle, Entry>,
+}
+
+impl<T: TpmDeviceOps> RmState<T> {
+    // process runs a command of a client and returns the complete response
+    fn process(&mut self, owner: u32, bytes: &[u8]) -> result::Result<Vec<u8>, TpmDeviceError> {
+        self.clock += 1;
+        // Commands whose handles cannot be told apart cannot be
+        // virtualized, they never reach the TPM
+        let command = match decode_command(bytes) {
+            Ok(command) => command,
+            Err(rc) => return Ok(error_response(rc)),
+        };
+        let result = if command.command_code == tcg::TPM_CC_FLUSH_CONTEXT {
+            self.flush_context(owner, bytes)
+        } else {
+            self.execute(owner, &command, bytes)
+        };
+        match result {
+            Ok(response) => Ok(response),
+            Err(Failure::Rc(rc)) => Ok(error_response(rc)),
+            Err(Failure::Device(err)) => Err(err),
+        }
+    }
+
+    fn execute(
+        &mut self,
+        owner: u32,
+        command: &Command,
+        bytes: &[u8],
+    ) -> result::Result<Vec<u8>, Failure> {
+        // Check that the client owns every object and session it refers
+        // to, and collect what has to be loaded
+        let mut pinned = Vec::new();
+        for (i, handle) in command.handles.iter().enumerate() {
+            match self.lookup(owner, *handle) {
+                Err(()) => return Err(Failure::Rc(TpmRc::Handle as u32 + RC_1 * (i as u32 + 1))),
+                Ok(Some(kind)) => pinned.push((kind, *handle)),
+                Ok(None) => (),
+            }
+        }
+        for (i, (handle, _)) in command.sessions.iter().enumerate() {
+            match self.lookup(owner, *handle) {
+                Err(()) => {
+                    return Err(Failure::Rc(
+                        TpmRc::Handle as u32 + RC_S + RC_1 * (i as u32 + 1),
+                    ))
+                }
+                Ok(Some(kind)) => pinned.push((kind, *handle)),
+                Ok(None) => (),
+            }
+        }
+        for (kind, handle) in pinned.iter() {
+            self.load(*kind, *handle, &pinned)?;
+        }
+
+        // Replace virtual handles with the physical ones
+        let mut physical = bytes.to_vec();
+        for (i, handle) in command.handles.iter().enumerate() {
+            if let Some(entry) = self.objects.get(handle) {
+                let offset = RESPONSE_HEADER_SIZE + 4 * i;
+                let loaded = entry.physical.unwrap_or(*handle);
+                physical[offset..offset + 4].copy_from_slice(&loaded.to_be_bytes());
+            }
+        }
+
+        let mut response = self.transmit_with_room(&physical, &pinned)?;
+        if response_code(&response) != Some(TpmRc::Success as u32) {
+            return Ok(response);
+        }
+
+        // Track what the command created, saved or flushed
+        if cc::response_handles(command.command_code) == 1 {
+            if let Some(handle) = read_u32(&response, RESPONSE_HEADER_SIZE) {
+                match handle >> 24 {
+                    HT_TRANSIENT => {
+                        let virtual_handle = self.allocate(owner, handle)?;
+                        response[RESPONSE_HEADER_SIZE..RESPONSE_HEADER_SIZE + 4]
+                            .copy_from_slice(&virtual_handle.to_be_bytes());
+                    }
+                    HT_HMAC_SESSION | HT_POLICY_SESSION => {
+                        let clock = self.clock;
+                        self.sessions.insert(
+                            handle,
+                            Entry {
+                                owner,
+                                physical: Some(handle),
+                                context: None,
+                                last_used: clock,
+                            },
+                        );
+                    }
+                    _ => (),
+                }
+            }
+        }
+        for (handle, attributes) in command.sessions.iter() {
+            if attributes & ATTR_CONTINUE_SESSION == 0 {
+                self.sessions.remove(handle);
+            }
+        }
+        match command.command_code {
+            // TPM2_SequenceComplete flushes its sequenceHandle
+            tcg::TPM_CC_SEQUENCE_COMPLETE => {
+                if let Some(handle) = command.handles.first() {
+                    self.objects.remove(handle);
+                }
+            }
+            // TPM2_EventSequenceComplete flushes its sequenceHandle
+            tcg::TPM_CC_EVENT_SEQUENCE_COMPLETE => {
+                if let Some(handle) = command.handles.get(1) {
+                    self.objects.remove(handle);
+                }
+            }
+            // A session saved by the client is no longer loaded
+            tcg::TPM_CC_CONTEXT_SAVE => {
+                let handle = command.handles.first().copied().unwrap_or(0);
+                if let Some(entry) = self.sessions.get_mut(&handle) {
+                    entry.physical = None;
+                    entry.context = None;
+                }
+            }
+            _ => (),
+        }
+        self.enforce_quota(owner, Kind::Object, &[])?;
+        self.enforce_quota(owner, Kind::Session, &[])?;
+        Ok(response)
+    }
+
+    // flush_context runs TPM2_FlushContext, whose handle is a parameter
+    fn flush_context(&mut self, owner: u32, bytes: &[u8]) -> result::Result<Vec<u8>, Failure> {
+        let handle = match read_u32(bytes, RESPONSE_HEADER_SIZE) {
+            Some(handle) => handle,
+            None => return Ok(self.transmit(bytes)?),
+        };
+        let handle_error = Failure::Rc(TpmRc::Handle as u32 + RC_P + RC_1);
+        match self.lookup(owner, handle) {
+            Err(()) => Err(handle_error),
+            Ok(None) => Ok(self.transmit(bytes)?),
+            Ok(Some(Kind::Object)) => {
+                let entry = self.objects.remove(&handle).ok_or(handle_error)?;
+                match entry.physical {
+                    // A swapped out object only exists in our table
+                    None => Ok(success_response()),
+                    Some(physical) => {
+                        let mut command = bytes.to_vec();
+                        command[RESPONSE_HEADER_SIZE..RESPONSE_HEADER_SIZE + 4]
+                            .copy_from_slice(&physical.to_be_bytes());
+                        Ok(self.transmit(&command)?)
+                    }
+                }
+            }
+            Ok(Some(Kind::Session)) => {
+                // Saved sessions are flushed by handle as well
+                let response = self.transmit(bytes)?;
+                if response_code(&response) == Some(TpmRc::Success as u32) {
+                    self.sessions.remove(&handle);
+                }
+                Ok(response)
+            }
+        }
+    }
+
+    // lookup returns the kind of a handle tracked for owner, None for
+    // handles passed through, and an error for transient handles not
+    // belonging to owner
+    fn lookup(&self, owner: u32, handle: tcg::Handle) -> result::Result<Option<Kind>, ()> {
+        let (kind, entry) = match handle >> 24 {
+            HT_TRANSIENT => (Kind::Object, self.objects.get(&handle).ok_or(())?),
+            HT_HMAC_SESSION | HT_POLICY_SESSION => match self.sessions.get(&handle) {
+                Some(entry) => (Kind::Session, entry),
+                // Sessions started before the resource manager are not ours
+                // to manage
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        if entry.owner != owner {
+            return Err(());
+        }
+        Ok(Some(kind))
+    }
+
+    fn table(&mut self, kind: Kind) -> &mut HashMap<tcg::Handle, Entry> {
+        match kind {
+            Kind::Object => &mut self.objects,
+            Kind::Session => &mut self.sessions,
+        }
+    }
+
+    // load makes sure an object or session is loaded in the TPM, making room
+    // if the TPM or the quota of its owner asks for it
+    fn load(
+        &mut self,
+        kind: Kind,
+        handle: tcg::Handle,
+        pinned: &[(Kind, tcg::Handle)],
+    ) -> result::Result<(), Failure> {
+        let clock = self.clock;
+        let (owner, context) = match self.table(kind).get_mut(&handle) {
+            None => return Ok(()),
+            Some(entry) => {
+                entry.last_used = clock;
+                if entry.physical.is_some() {
+                    return Ok(());
+                }
+                match &entry.context {
+                    Some(context) => (entry.owner, context.clone()),
+                    None => return Ok(()),
+                }
+            }
+        };
+        while self.loaded(owner, kind) >= self.quota {
+            if !self.evict_lru(Some(owner), kind, pinned)? {
+                break;
+            }
+        }
+
+        let physical = loop {
+            match context::tpm2_context_load(&mut self.device, &context) {
+                Ok(physical) => break physical,
+                Err(CommandError::ResponseError(err)) => {
+                    if !self.make_room(err.error_code, pinned)? {
+                        return Err(Failure::Rc(err.error_code));
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+        if let Some(entry) = self.table(kind).get_mut(&handle) {
+            entry.physical = Some(physical);
+            entry.context = None;
+        }
+        Ok(())
+    }
+
+    // evict swaps an object or session out of the TPM
+    fn evict(&mut self, kind: Kind, handle: tcg::Handle) -> result::Result<(), Failure> {
+        let physical = match self.table(kind).get(&handle).and_then(|e| e.physical) {
+            Some(physical) => physical,
+            None => return Ok(()),
+        };
+        log::debug!("swapping out {:?} {:#010x}", kind, handle);
+        let saved = context::tpm2_context_save(&mut self.device, physical)?;
+        // Saving a session removes it from TPM memory, objects stay loaded
+        // until flushed
+        if kind == Kind::Object {
+            context::tpm2_flush_context(&mut self.device, physical)?;
+        }
+        if let Some(entry) = self.table(kind).get_mut(&handle) {
+            entry.physical = None;
+            entry.context = Some(Box::new(saved));
+        }
+        Ok(())
+    }
+
+    // evict_lru swaps out the least recently used loaded entry of a kind,
+    // optionally restricted to an owner. It returns false if every loaded
+    // entry is pinned.
+    fn evict_lru(
+        &mut self,
+        owner: Option<u32>,
+        kind: Kind,
+        pinned: &[(Kind, tcg::Handle)],
+    ) -> result::Result<bool, Failure> {
+        let victim = self
+            .table(kind)
+            .iter()
+            .filter(|(h, e)| e.physical.is_some() && !pinned.contains(&(kind, **h)))
+            .filter(|(_, e)| match owner {
+                Some(owner) => e.owner == owner,
+                None => true,
+            })
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(h, _)| *h);
+        match victim {
+            Some(handle) => {
+                self.evict(kind, handle)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // make_room evicts an entry if rc reports a lack of TPM memory. It
+    // returns false if rc is another error or nothing could be evicted.
+    fn make_room(
+        &mut self,
+        rc: u32,
+        pinned: &[(Kind, tcg::Handle)],
+    ) -> result::Result<bool, Failure> {
+        if rc == TpmRc::ObjectMemory as u32 {
+            self.evict_lru(None, Kind::Object, pinned)
+        } else if rc == TpmRc::SessionMemory as u32 {
+            self.evict_lru(None, Kind::Session, pinned)
+        } else if rc == TpmRc::Memory as u32 {
+            Ok(self.evict_lru(None, Kind::Object, pinned)?
+                || self.evict_lru(None, Kind::Session, pinned)?)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn loaded(&self, owner: u32, kind: Kind) -> usize {
+        let table = match kind {
+            Kind::Object => &self.objects,
+            Kind::Session => &self.sessions,
+        };
+        table
+            .values()
+            .filter(|e| e.owner == owner && e.physical.is_some())
+            .count()
+    }
+
+    // enforce_quota swaps out entries of owner until it is within quota
+    fn enforce_quota(
+        &mut self,
+        owner: u32,
+        kind: Kind,
+        pinned: &[(Kind, tcg::Handle)],
+    ) -> result::Result<(), Failure> {
+        while self.loaded(owner, kind) > self.quota {
+            if !self.evict_lru(Some(owner), kind, pinned)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // allocate assigns a virtual handle to a newly loaded object
+    fn allocate(
+        &mut self,
+        owner: u32,
+        physical: tcg::Handle,
+    ) -> result::Result<tcg::Handle, Failure> {
+        let mut handle = self.next_virtual;
+        while self.objects.contains_key(&handle) {
+            handle = if handle == VIRTUAL_HANDLE_LAST {
+                VIRTUAL_HANDLE_FIRST
+            } else {
+                handle + 1
+            };
+            if handle == self.next_virtual {
+                let _ = context::tpm2_flush_context(&mut self.device, physical);
+                return Err(Failure::Rc(TpmRc::ObjectHandles as u32));
+            }
+        }
+        self.next_virtual = if handle == VIRTUAL_HANDLE_LAST {
+            VIRTUAL_HANDLE_FIRST
+        } else {
+            handle + 1
+        };
+        let clock = self.clock;
+        self.objects.insert(
+            handle,
+            Entry {
+                owner,
+                physical: Some(physical),
+                context: None,
+                last_used: clock,
+            },
+        );
+        Ok(handle)
+    }
+
+    // transmit_with_room sends a command, making room and resending it while
+    // the TPM runs out of memory
+    fn transmit_with_room(
+        &mut self,
+        command: &[u8],
+        pinned: &[(Kind, tcg::Handle)],
+    ) -> result::Result<Vec<u8>, Failure> {
+        loop {
+            let response = self.transmit(command)?;
+            match response_code(&response) {
+                Some(rc) if self.make_room(rc, pinned)? => continue,
+                _ => return Ok(response),
+            }
+        }
+    }
+
+    fn transmit(&mut self, command: &[u8]) -> result::Result<Vec<u8>, TpmDeviceError> {
+        let mut buff_command = inout::DynamicByteBuffer::from_vec(command.to_vec());
+        let mut buff_answer = inout::DynamicByteBuffer::new();
+        self.device.send_recv(&mut buff_command, &mut buff_answer)?;
+        Ok(buff_answer.into_vec())
+    }
+
+    // release flushes everything owned by a client which went away
+    fn release(&mut self, owner: u32) {
+        let objects: Vec<tcg::Handle> = self
+            .objects
+            .iter()
+            .filter(|(_, e)| e.owner == owner)
+            .map(|(h, _)| *h)
+            .collect();
+        for handle in objects {
+            if let Some(Entry {
+                physical: Some(physical),
+                ..
+            }) = self.objects.remove(&handle)
+            {
+                if let Err(err) = context::tpm2_flush_context(&mut self.device, physical) {
+                    log::warn!("cannot flush object {:#010x}: {}", physical, err);
+                }
+            }
+        }
+        let sessions: Vec<tcg::Handle> = self
+            .sessions
+            .iter()
+            .filter(|(_, e)| e.owner == owner)
+            .map(|(h, _)| *h)
+            .collect();
+        for handle in sessions {
+            self.sessions.remove(&handle);
+            if let Err(err) = context::tpm2_flush_context(&mut self.device, handle) {
+                log::warn!("cannot flush session {:#010x}: {}", handle, err);
+            }
+        }
+    }
+}
+
+// decode_command returns the fields of a command the resource manager acts
+// on. Commands which are truncated, or whose command code is unknown and
+// thus whose handles cannot be located, fail with the response code the
+// client gets.
+fn decode_command(bytes: &[u8]) -> result::Result<Command, u32> {
+    let (tag, command_code) = match (read_u16(bytes, 0), command_code(bytes)) {
+        (Some(tag), Some(command_code)) => (tag, command_code),
+        _ => return Err(TpmRc::CommandSize as u32),
+    };
+    let info = cc::command_info(command_code).ok_or(TpmRc::CommandCode as u32)?;
+    let mut command = Command {
+        command_code,
+        handles: Vec::new(),
+        sessions: Vec::new(),
+    };
+
+    let mut offset = RESPONSE_HEADER_SIZE;
+    for i in 0..info.handles {
+        let handle = read_u32(bytes, offset).ok_or(TpmRc::Handle as u32 + RC_1 * (i as u32 + 1))?;
+        command.handles.push(handle);
+        offset += 4;
+    }
+    if tag != tcg::TPM_ST_SESSIONS {
+        return Ok(command);
+    }
+
+    // Walk the TPMS_AUTH_COMMAND entries of the authorization area
+    let auth_size = TpmRc::AuthSize as u32;
+    let end = offset + 4 + read_u32(bytes, offset).ok_or(auth_size)? as usize;
+    offset += 4;
+    while offset < end {
+        let handle = read_u32(bytes, offset).ok_or(auth_size)?;
+        offset += 4;
+        offset += 2 + read_u16(bytes, offset).ok_or(auth_size)? as usize;
+        let attributes = *bytes.get(offset).ok_or(auth_size)?;
+        offset += 1;
+        offset += 2 + read_u16(bytes, offset).ok_or(auth_size)? as usize;
+        command.sessions.push((handle, attributes));
+    }
+    Ok(command)
+}
+
+fn success_response() -> Vec<u8> {
+    error_response(TpmRc::Success as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::tpm2::commands::{run, session};
+    use crate::tpm2::serialization::inout::{RwBytes, Tpm2StructIn, Tpm2StructOut};
+
+    const TPM_CC_READ_PUBLIC: tcg::TpmCc = 0x00000173;
+
+    // Sim is the state of a TPM with a fixed number of object and session
+    // slots, driven through TpmMockDevice. Every object gets an identity,
+    // kept in its saved context, to tell which object a command ran on.
+    #[derive(Default)]
+    struct Sim {
+        object_slots: usize,
+        session_slots: usize,
+        next_handle: u32,
+        next_id: u32,
+        // objects maps the handles of loaded objects to their identity
+        objects: HashMap<tcg::Handle, u32>,
+        sessions: Vec<tcg::Handle>,
+        saved_sessions: Vec<tcg::Handle>,
+        // used holds the identities of the objects TPM2_ReadPublic ran on
+        used: Vec<u32>,
+    }
+
+    impl Sim {
+        fn load_object(&mut self, id: u32) -> result::Result<tcg::Handle, TpmRc> {
+            if self.objects.len() >= self.object_slots {
+                return Err(TpmRc::ObjectMemory);
+            }
+            self.next_handle += 1;
+            let handle = 0x80000000 + self.next_handle;
+            self.objects.insert(handle, id);
+            Ok(handle)
+        }
+
+        // check_object fails like a TPM would for a transient handle which
+        // is not loaded
+        fn check_object(&self, handle: tcg::Handle) -> result::Result<(), TpmRc> {
+            if handle >> 24 == HT_TRANSIENT && !self.objects.contains_key(&handle) {
+                return Err(TpmRc::Handle);
+            }
+            Ok(())
+        }
+    }
+
+    // lock ignores poisoning, so that a failed assertion does not turn into
+    // a panic while the resource manager flushes in drop
+    fn lock(sim: &Mutex<Sim>) -> MutexGuard<'_, Sim> {
+        sim.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn unpack_u32(buff: &mut dyn RwBytes) -> result::Result<u32, TpmRc> {
+        let mut value: u32 = 0;
+        value.unpack(buff).map_err(|_| TpmRc::CommandSize)?;
+        Ok(value)
+    }
+
+    fn simulated_tpm(
+        object_slots: usize,
+        session_slots: usize,
+    ) -> (TpmMockDevice, Arc<Mutex<Sim>>) {
+        let sim = Arc::new(Mutex::new(Sim {
+            object_slots,
+            session_slots,
+            ..Sim::default()
+        }));
+        let mut tpm = TpmMockDevice::new();
+
+        let s = Arc::clone(&sim);
+        tpm.expect_repeated(tcg::TPM_CC_CREATE_PRIMARY, move |_, resp| {
+            let mut sim = lock(&s);
+            let id = sim.next_id + 1;
+            let handle = sim.load_object(id)?;
+            sim.next_id = id;
+            handle.pack(resp).map_err(|_| TpmRc::Failure)
+        });
+
+        let s = Arc::clone(&sim);
+        tpm.expect_repeated(tcg::TPM_CC_LOAD, move |cmd, resp| {
+            let mut sim = lock(&s);
+            sim.check_object(unpack_u32(cmd)?)?;
+            let id = sim.next_id + 1;
+            let handle = sim.load_object(id)?;
+            sim.next_id = id;
+            handle.pack(resp).map_err(|_| TpmRc::Failure)
+        });
+
+        // TPM2_ReadPublic stands for any command acting on an object. The
+        // session of its authorization area, if any, must be loaded.
+        let s = Arc::clone(&sim);
+        tpm.expect_repeated(TPM_CC_READ_PUBLIC, move |cmd, _| {
+            let mut sim = lock(&s);
+            let handle = unpack_u32(cmd)?;
+            sim.check_object(handle)?;
+            if unpack_u32(cmd).is_ok() {
+                let session = unpack_u32(cmd)?;
+                if !sim.sessions.contains(&session) {
+                    return Err(TpmRc::Value);
+                }
+            }
+            let id = sim.objects[&handle];
+            sim.used.push(id);
+            Ok(())
+        });
+
+        let s = Arc::clone(&sim);
+        tpm.expect_repeated(tcg::TPM_START_AUTH_SESSION, move |_, resp| {
+            let mut sim = lock(&s);
+            if sim.sessions.len() >= sim.session_slots {
+                return Err(TpmRc::SessionMemory);
+            }
+            sim.next_handle += 1;
+            let handle = 0x02000000 + sim.next_handle;
+            sim.sessions.push(handle);
+            handle.pack(resp).map_err(|_| TpmRc::Failure)?;
+            tcg::Tpm2bNonce::new()
+                .pack(resp)
+                .map_err(|_| TpmRc::Failure)
+        });
+
+        let s = Arc::clone(&sim);
+        tpm.expect_repeated(tcg::TPM_CC_CONTEXT_SAVE, move |cmd, resp| {
+            let mut sim = lock(&s);
+            let handle = unpack_u32(cmd)?;
+            let id = match sim.objects.get(&handle) {
+                Some(id) => *id,
+                None => {
+                    let index = sim
+                        .sessions
+                        .iter()
+                        .position(|s| *s == handle)
+                        .ok_or(TpmRc::Handle)?;
+                    sim.sessions.remove(index);
+                    sim.saved_sessions.push(handle);
+                    handle
+                }
+            };
+            tcg::TpmsContext {
+                sequence: 1,
+                saved_handle: handle,
+                hierarchy: tcg::TPM_RH_OWNER,
+                context_blob: tcg::Tpm2bContextData::from_slice(&id.to_be_bytes()),
+            }
+            .pack(resp)
+            .map_err(|_| TpmRc::Failure)
+        });
+
+        let s = Arc::clone(&sim);
+        tpm.expect_repeated(tcg::TPM_CC_CONTEXT_LOAD, move |cmd, resp| {
+            let mut sim = lock(&s);
+            let mut context = tcg::TpmsContext::new();
+            context.unpack(cmd).map_err(|_| TpmRc::CommandSize)?;
+            let handle = if context.saved_handle >> 24 == HT_TRANSIENT {
+                let blob = context.context_blob.get_buffer();
+                sim.load_object(read_u32(blob, 0).ok_or(TpmRc::Size)?)?
+            } else {
+                if sim.sessions.len() >= sim.session_slots {
+                    return Err(TpmRc::SessionMemory);
+                }
+                sim.saved_sessions.retain(|s| *s != context.saved_handle);
+                sim.sessions.push(context.saved_handle);
+                context.saved_handle
+            };
+            handle.pack(resp).map_err(|_| TpmRc::Failure)
+        });
+
+        let s = Arc::clone(&sim);
+        tpm.expect_repeated(tcg::TPM_CC_FLUSH_CONTEXT, move |cmd, _| {
+            let mut sim = lock(&s);
+            let handle = unpack_u32(cmd)?;
+            let sessions = sim.sessions.len() + sim.saved_sessions.len();
+            sim.sessions.retain(|s| *s != handle);
+            sim.saved_sessions.retain(|s| *s != handle);
+            if sim.objects.remove(&handle).is_none()
+                && sessions == sim.sessions.len() + sim.saved_sessions.len()
+            {
+                return Err(TpmRc::Handle);
+            }
+            Ok(())
+        });
+
+        (tpm, sim)
+    }
+
+    fn password_auth() -> tcg::TpmsAuthCommand {
+        tcg::TpmsAuthCommand {
+            session_handle: tcg::TPM_RS_PW,
+            nonce: tcg::Tpm2bNonce::new(),
+            session_attributes: tcg::TPMA_SESSION_CONTINUE_SESSION,
+            hmac: tcg::Tpm2bAuth::new(),
+        }
+    }
+
+    fn create_primary(tpm: &mut dyn TpmDeviceOps) -> result::Result<tcg::Handle, CommandError> {
+        let mut resp = inout::DynamicByteBuffer::new();
+        run::run_command(
+            tpm,
+            tcg::TPM_CC_CREATE_PRIMARY,
+            &[tcg::TPM_RH_OWNER],
+            &[password_auth()],
+            &[],
+            &mut resp,
+        )?;
+        let mut handle: tcg::Handle = 0;
+        handle.unpack(&mut resp)?;
+        Ok(handle)
+    }
+
+    fn read_public(
+        tpm: &mut dyn TpmDeviceOps,
+        handle: tcg::Handle,
+        auths: &[tcg::TpmsAuthCommand],
+    ) -> result::Result<(), CommandError> {
+        let mut resp = inout::DynamicByteBuffer::new();
+        run::run_command(tpm, TPM_CC_READ_PUBLIC, &[handle], auths, &[], &mut resp)
+    }
+
+    // send sends raw command bytes and returns the raw response
+    fn send(tpm: &mut dyn TpmDeviceOps, command: &[u8]) -> Vec<u8> {
+        let mut buff_command = inout::DynamicByteBuffer::from_vec(command.to_vec());
+        let mut buff_answer = inout::DynamicByteBuffer::new();
+        tpm.send_recv(&mut buff_command, &mut buff_answer).unwrap();
+        buff_answer.into_vec()
+    }
+
+    fn response_code(result: result::Result<(), CommandError>) -> u32 {
+        match result {
+            Err(CommandError::ResponseError(err)) => err.error_code,
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn virtual_handles() {
+        let (tpm, sim) = simulated_tpm(3, 3);
+        let rm = TpmResourceManager::new(tpm);
+        let mut client = rm.client();
+
+        // Objects are handed out virtual handles, which are replaced with
+        // the physical ones in commands
+        let first = create_primary(&mut client).unwrap();
+        let second = create_primary(&mut client).unwrap();
+        assert_eq!(first, VIRTUAL_HANDLE_FIRST);
+        assert_eq!(second, VIRTUAL_HANDLE_FIRST + 1);
+        assert!(!lock(&sim).objects.contains_key(&first));
+
+        read_public(&mut client, second, &[]).unwrap();
+        read_public(&mut client, first, &[]).unwrap();
+        assert_eq!(lock(&sim).used, vec![2, 1]);
+
+        // Responses carry virtual handles as well
+        let mut resp = inout::DynamicByteBuffer::new();
+        let params: [&dyn inout::Tpm2StructOut; 0] = [];
+        run::run_command(
+            &mut client,
+            tcg::TPM_CC_LOAD,
+            &[first],
+            &[password_auth()],
+            &params,
+            &mut resp,
+        )
+        .unwrap();
+        let mut loaded: tcg::Handle = 0;
+        loaded.unpack(&mut resp).unwrap();
+        assert_eq!(loaded, VIRTUAL_HANDLE_FIRST + 2);
+    }
+
+    #[test]
+    fn object_memory() {
+        // The TPM holds two objects, the client may keep more loaded
+        let (tpm, sim) = simulated_tpm(2, 3);
+        let rm = TpmResourceManager::with_quota(tpm, 8);
+        let mut client = rm.client();
+
+        let handles: Vec<tcg::Handle> = (0..4)
+            .map(|_| create_primary(&mut client).unwrap())
+            .collect();
+        assert_eq!(lock(&sim).objects.len(), 2);
+
+        // Swapped out objects are loaded back on use, in place of the least
+        // recently used ones
+        for handle in handles.iter() {
+            read_public(&mut client, *handle, &[]).unwrap();
+        }
+        read_public(&mut client, handles[0], &[]).unwrap();
+        assert_eq!(lock(&sim).used, vec![1, 2, 3, 4, 1]);
+        assert_eq!(lock(&sim).objects.len(), 2);
+    }
+
+    #[test]
+    fn object_memory_pinned() {
+        // Objects a command refers to are never swapped out to make room
+        // for the command, the TPM error is returned instead
+        let (tpm, _) = simulated_tpm(1, 3);
+        let rm = TpmResourceManager::with_quota(tpm, 8);
+        let mut client = rm.client();
+
+        let parent = create_primary(&mut client).unwrap();
+        let mut resp = inout::DynamicByteBuffer::new();
+        let params: [&dyn inout::Tpm2StructOut; 0] = [];
+        let result = run::run_command(
+            &mut client,
+            tcg::TPM_CC_LOAD,
+            &[parent],
+            &[password_auth()],
+            &params,
+            &mut resp,
+        );
+        assert_eq!(response_code(result), TpmRc::ObjectMemory as u32);
+    }
+
+    #[test]
+    fn client_quota() {
+        let (tpm, sim) = simulated_tpm(8, 8);
+        let rm = TpmResourceManager::with_quota(tpm, 2);
+        let mut first = rm.client();
+        let mut second = rm.client();
+
+        let handles: Vec<tcg::Handle> = (0..3)
+            .map(|_| create_primary(&mut first).unwrap())
+            .collect();
+        assert_eq!(lock(&sim).objects.len(), 2);
+
+        // The quota of a client does not affect the others
+        let other = create_primary(&mut second).unwrap();
+        assert_eq!(lock(&sim).objects.len(), 3);
+
+        read_public(&mut first, handles[0], &[]).unwrap();
+        read_public(&mut second, other, &[]).unwrap();
+        assert_eq!(lock(&sim).used, vec![1, 4]);
+        assert_eq!(lock(&sim).objects.len(), 3);
+    }
+
+    #[test]
+    fn client_isolation() {
+        let (tpm, _) = simulated_tpm(3, 3);
+        let rm = TpmResourceManager::new(tpm);
+        let mut first = rm.client();
+        let mut second = rm.client();
+
+        let handle = create_primary(&mut first).unwrap();
+        assert_eq!(
+            response_code(read_public(&mut second, handle, &[])),
+            TpmRc::Handle as u32 + RC_1
+        );
+        assert_eq!(
+            response_code(context::tpm2_flush_context(&mut second, handle)),
+            TpmRc::Handle as u32 + RC_P + RC_1
+        );
+        read_public(&mut first, handle, &[]).unwrap();
+    }
+
+    #[test]
+    fn session_memory() {
+        let (tpm, sim) = simulated_tpm(3, 1);
+        let rm = TpmResourceManager::new(tpm);
+        let mut client = rm.client();
+        let object = create_primary(&mut client).unwrap();
+
+        // Starting a second session swaps the first one out, using it loads
+        // it back with the same handle
+        let first = session::tpm2_startauth_session(&mut client).unwrap();
+        let second = session::tpm2_startauth_session(&mut client).unwrap();
+        assert_eq!(lock(&sim).sessions, vec![second.session_handle]);
+
+        read_public(&mut client, object, std::slice::from_ref(&first)).unwrap();
+        assert_eq!(lock(&sim).sessions, vec![first.session_handle]);
+        assert_eq!(lock(&sim).saved_sessions, vec![second.session_handle]);
+    }
+
+    #[test]
+    fn flush_context() {
+        let (tpm, sim) = simulated_tpm(1, 3);
+        let rm = TpmResourceManager::with_quota(tpm, 8);
+        let mut client = rm.client();
+
+        let first = create_primary(&mut client).unwrap();
+        let second = create_primary(&mut client).unwrap();
+
+        // first is swapped out and only needs to be forgotten, second is
+        // flushed from the TPM by its physical handle
+        context::tpm2_flush_context(&mut client, first).unwrap();
+        context::tpm2_flush_context(&mut client, second).unwrap();
+        assert!(lock(&sim).objects.is_empty());
+        assert_eq!(
+            response_code(read_public(&mut client, first, &[])),
+            TpmRc::Handle as u32 + RC_1
+        );
+    }
+
+    #[test]
+    fn release() {
+        let (tpm, sim) = simulated_tpm(1, 1);
+        let rm = TpmResourceManager::with_quota(tpm, 8);
+        let mut client = rm.client();
+
+        create_primary(&mut client).unwrap();
+        create_primary(&mut client).unwrap();
+        session::tpm2_startauth_session(&mut client).unwrap();
+        session::tpm2_startauth_session(&mut client).unwrap();
+
+        // Loaded objects and all sessions, loaded or saved, are flushed
+        // when the client goes away
+        drop(client);
+        let sim = lock(&sim);
+        assert!(sim.objects.is_empty());
+        assert!(sim.sessions.is_empty());
+        assert!(sim.saved_sessions.is_empty());
+    }
+
+    #[test]
+    fn undecodable_commands() {
+        // Expectations are never consumed, so any command reaching the TPM
+        // succeeds
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_repeated(TPM_CC_READ_PUBLIC, |_, _| Ok(()));
+        tpm.expect_repeated(0x20000001, |_, _| Ok(()));
+        let rm = TpmResourceManager::new(tpm);
+        let mut client = rm.client();
+
+        let commands: [(&[u8], u32); 4] = [
+            // Header cut before the command code
+            (
+                &[0x80, 0x01, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00],
+                TpmRc::CommandSize as u32,
+            ),
+            // Vendor command, its handles cannot be located
+            (
+                &[
+                    0x80, 0x01, 0x00, 0x00, 0x00, 0x0e, 0x20, 0x00, 0x00, 0x01, 0x80, 0xff, 0x00,
+                    0x00,
+                ],
+                TpmRc::CommandCode as u32,
+            ),
+            // TPM2_ReadPublic cut inside its objectHandle
+            (
+                &[
+                    0x80, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x01, 0x73, 0x80, 0xff,
+                ],
+                TpmRc::Handle as u32 + RC_1,
+            ),
+            // TPM2_ReadPublic cut inside its authorization area
+            (
+                &[
+                    0x80, 0x02, 0x00, 0x00, 0x00, 0x15, 0x00, 0x00, 0x01, 0x73, 0x80, 0xff, 0x00,
+                    0x00, 0x00, 0x00, 0x00, 0x09, 0x40, 0x00, 0x00,
+                ],
+                TpmRc::AuthSize as u32,
+            ),
+        ];
+        for (command, rc) in commands.iter() {
+            assert_eq!(send(&mut client, command), error_response(*rc));
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::device::errors::{DeviceIoError, TpmDeviceError};
+use crate::device::raw::{ReadWrite, TpmDeviceOps, TpmOwnedDevice};
+use crate::tpm2::serialization::inout;
+
+use std::result;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub type SharedTpmDeviceOps = Box<dyn TpmDeviceOps + Send>;
+
+// TpmSharedDevice is a cloneable handle to a TPM which can be used from
+// several threads. Each exchange holds an internal lock, so that commands and
+// responses of concurrent callers are never interleaved on the transport.
+//
+// Sequences of commands which must not be interleaved with other callers,
+// e.g. starting a session and using it, can run under lock.
+//
+// Each handle has its own locality, a clone starts at the locality of the
+// handle it was cloned from. Commands run under lock are sent at whatever
+// locality the device was last set to.
+#[derive(Clone)]
+pub struct TpmSharedDevice {
+    inner: Arc<Mutex<SharedTpmDeviceOps>>,
+    locality: u8,
+}
+
+impl TpmSharedDevice {
+    // new shares an arbitrary TpmDeviceOps, such as a decorated device
+    pub fn new<T: TpmDeviceOps + Send + 'static>(device: T) -> Self {
+        TpmSharedDevice {
+            locality: device.locality(),
+            inner: Arc::new(Mutex::new(Box::new(device))),
+        }
+    }
+
+    // from_transport shares a TPM reached via a ReadWrite transport, e.g. one
+    // created by tcti::open
+    pub fn from_transport<RW: ReadWrite + Send + 'static>(rw: RW) -> Self {
+        TpmSharedDevice::new(TpmOwnedDevice::new(rw))
+    }
+
+    // lock gives exclusive access to the device until the guard is dropped
+    pub fn lock(&self) -> result::Result<MutexGuard<'_, SharedTpmDeviceOps>, TpmDeviceError> {
+        // A poisoned lock means a caller panicked in the middle of an
+        // exchange, the transport can no longer be trusted to be in sync
+        self.inner.lock().map_err(|_| {
+            TpmDeviceError::IoError(DeviceIoError {
+                msg: "TPM device lock poisoned by a panicking thread".to_string(),
+            })
+        })
+    }
+}
+
+impl TpmDeviceOps for TpmSharedDevice {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        let mut device = self.lock()?;
+        device.set_locality(self.locality)?;
+        device.send_recv(buff_command, buff_answer)
+    }
+
+    fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        // Let the device reject localities it does not support right away
+        self.lock()?.set_locality(locality)?;
+        self.locality = locality;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::tpm2::commands::run;
+    use crate::tpm2::types::tcg;
+
+    // Localities records the locality of every command sent to a mock
+    struct Localities {
+        tpm: TpmMockDevice,
+        sent: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl TpmDeviceOps for Localities {
+        fn send_recv(
+            &mut self,
+            buff_command: &mut dyn inout::RwBytes,
+            buff_answer: &mut dyn inout::RwBytes,
+        ) -> result::Result<(), TpmDeviceError> {
+            self.sent.lock().unwrap().push(self.tpm.locality());
+            self.tpm.send_recv(buff_command, buff_answer)
+        }
+
+        fn locality(&self) -> u8 {
+            self.tpm.locality()
+        }
+
+        fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+            self.tpm.set_locality(locality)
+        }
+    }
+
+    fn startup(tpm: &mut TpmSharedDevice) {
+        let mut resp = inout::DynamicByteBuffer::new();
+        let params: [&dyn inout::Tpm2StructOut; 1] = [&tcg::TPM_SU_CLEAR];
+        run::run_command(tpm, tcg::TPM_CC_STARTUP, &[], &[], &params, &mut resp).unwrap();
+    }
+
+    #[test]
+    fn locality_per_handle() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_repeated(tcg::TPM_CC_STARTUP, |_, _| Ok(()));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut first = TpmSharedDevice::new(Localities {
+            tpm,
+            sent: Arc::clone(&sent),
+        });
+        let mut second = first.clone();
+
+        first.set_locality(3).unwrap();
+        assert_eq!(second.locality(), 0);
+        startup(&mut second);
+        startup(&mut first);
+        startup(&mut second);
+        assert_eq!(*sent.lock().unwrap(), vec![0, 3, 0]);
+
+        // Clones start at the locality of their origin
+        let mut third = first.clone();
+        startup(&mut third);
+        assert_eq!(*sent.lock().unwrap(), vec![0, 3, 0, 3]);
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::result;
+use std::time::Duration;
+
+// SocketAddress identifies a software TPM endpoint, reachable either via
+// TCP or via a Unix domain socket (swtpm `type=unixio`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketAddress {
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl SocketAddress {
+    pub fn tcp(host: &str, port: u16) -> Self {
+        SocketAddress::Tcp {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Self {
+        SocketAddress::Unix(path.into())
+    }
+
+    // connect opens a new connection to the endpoint
+    pub fn connect(&self) -> result::Result<SocketStream, std::io::Error> {
+        match self {
+            SocketAddress::Tcp { host, port } => Ok(SocketStream::Tcp(TcpStream::connect((
+                host.as_str(),
+                *port,
+            ))?)),
+            #[cfg(unix)]
+            SocketAddress::Unix(path) => Ok(SocketStream::Unix(UnixStream::connect(path)?)),
+        }
+    }
+
+    // bind listens on the endpoint. Unix socket files are not removed
+    // beforehand, binding fails if the path exists.
+    pub fn bind(&self) -> result::Result<SocketListener, std::io::Error> {
+        match self {
+            SocketAddress::Tcp { host, port } => Ok(SocketListener::Tcp(TcpListener::bind((
+                host.as_str(),
+                *port,
+            ))?)),
+            #[cfg(unix)]
+            SocketAddress::Unix(path) => Ok(SocketListener::Unix(UnixListener::bind(path)?)),
+        }
+    }
+}
+
+impl fmt::Display for SocketAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocketAddress::Tcp { host, port } => write!(f, "{}:{}", host, port),
+            #[cfg(unix)]
+            SocketAddress::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+// SocketStream is a connected stream towards a SocketAddress
+pub enum SocketStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl SocketStream {
+    // set_read_timeout bounds how long a read blocks, None blocks forever.
+    // Reads timing out fail with ErrorKind::WouldBlock or ErrorKind::TimedOut
+    // depending on the platform.
+    pub fn set_read_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> result::Result<(), std::io::Error> {
+        match self {
+            SocketStream::Tcp(s) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            SocketStream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+// SocketListener accepts connections on a SocketAddress
+pub enum SocketListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl SocketListener {
+    pub fn accept(&self) -> result::Result<SocketStream, std::io::Error> {
+        match self {
+            SocketListener::Tcp(l) => Ok(SocketStream::Tcp(l.accept()?.0)),
+            #[cfg(unix)]
+            SocketListener::Unix(l) => Ok(SocketStream::Unix(l.accept()?.0)),
+        }
+    }
+
+    // local_addr returns the address the listener is bound to, e.g. to
+    // find out the port picked when binding to port 0
+    pub fn local_addr(&self) -> result::Result<SocketAddress, std::io::Error> {
+        match self {
+            SocketListener::Tcp(l) => {
+                let addr = l.local_addr()?;
+                Ok(SocketAddress::tcp(&addr.ip().to_string(), addr.port()))
+            }
+            #[cfg(unix)]
+            SocketListener::Unix(l) => match l.local_addr()?.as_pathname() {
+                Some(path) => Ok(SocketAddress::unix(path)),
+                None => Err(io::Error::other("unnamed unix socket")),
+            },
+        }
+    }
+}
+
+impl io::Read for SocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> result::Result<usize, std::io::Error> {
+        match self {
+            SocketStream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            SocketStream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl io::Write for SocketStream {
+    fn write(&mut self, buf: &[u8]) -> result::Result<usize, std::io::Error> {
+        match self {
+            SocketStream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            SocketStream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> result::Result<(), std::io::Error> {
+        match self {
+            SocketStream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            SocketStream::Unix(s) => s.flush(),
+        }
+    }
+}
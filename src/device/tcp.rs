@@ -1,62 +1,616 @@
+use crate::device::errors::ResponseSizeError;
+use crate::device::raw::{
+    header_size, is_valid_locality, ReadWrite, MAX_RESPONSE_SIZE, RESPONSE_HEADER_SIZE,
+};
+use crate::device::socket::{SocketAddress, SocketStream};
+use crate::device::timeout::Timeouts;
+
 use std::io;
-use std::io::{Error, ErrorKind};
-use std::net::TcpStream;
+use std::io::{Error, ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::result;
+use std::time::{Duration, Instant};
+
+// Default ports of the TPM simulator. The command port carries TPM commands,
+// the platform port carries power, NV and reset signals.
+pub const DEFAULT_HOST: &str = "localhost";
+pub const DEFAULT_COMMAND_PORT: u16 = 2321;
+pub const DEFAULT_PLATFORM_PORT: u16 = 2322;
+
+// Requests understood by the simulator on the command port
+pub const TPM_SEND_COMMAND: u32 = 8;
+pub const TPM_SESSION_END: u32 = 20;
+
+// Signals understood by the MS simulator on the platform port
+const TPM_SIGNAL_POWER_ON: u32 = 1;
+const TPM_SIGNAL_POWER_OFF: u32 = 2;
+const TPM_SIGNAL_CANCEL_ON: u32 = 9;
+const TPM_SIGNAL_CANCEL_OFF: u32 = 10;
+const TPM_SIGNAL_NV_ON: u32 = 11;
+const TPM_SIGNAL_NV_OFF: u32 = 12;
+const TPM_SIGNAL_RESET: u32 = 17;
 
-// TpmSwtpmIO implements communication with the TPM via socket
+// Requests understood by swtpm on its control channel
+const SWTPM_CMD_INIT: u32 = 0x02;
+const SWTPM_CMD_SHUTDOWN: u32 = 0x03;
+const SWTPM_CMD_CANCEL_TPM_CMD: u32 = 0x09;
+
+// PlatformProtocol selects how power, NV and reset signals are delivered.
+// tpm_server speaks the MS simulator platform protocol, while swtpm exposes
+// its own control channel protocol.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PlatformProtocol {
+    Mssim,
+    Swtpm,
+}
+
+// TpmSwtpmIO implements communication with a software TPM via socket.
+//
+// Commands written to TpmSwtpmIO are framed according to the simulator
+// command protocol:
+// * TPM_SEND_COMMAND (u32)
+// * locality (u8)
+// * command size (u32)
+// * command bytes
+//
+// and the simulator answers with:
+// * response size (u32)
+// * response bytes
+// * acknowledgement (u32, always 0)
+//
+// The framing is removed on read, so that callers only see the TPM response.
+//
+// Reads are bounded by the timeout of the command class (see
+// device::timeout). When a response does not arrive in time, the command is
+// cancelled and the command connection is dropped, since the stream is no
+// longer in sync with the simulator.
 pub struct TpmSwtpmIO {
-    pub stream: Option<TcpStream>,
+    pub stream: Option<SocketStream>,
+    platform_stream: Option<SocketStream>,
+    command_address: SocketAddress,
+    platform_address: SocketAddress,
+    protocol: PlatformProtocol,
+    locality: u8,
+    // response holds the unframed response of the last command, rdptr
+    // tracks how much of it has already been returned by read
+    response: Vec<u8>,
+    rdptr: usize,
+    awaiting_response: bool,
+    timeouts: Option<Timeouts>,
+    // deadline for the response to the last command
+    deadline: Option<Instant>,
+    // cancel_pending is set while the MS simulator cancel line is asserted
+    cancel_pending: bool,
 }
 
 impl TpmSwtpmIO {
     pub fn new() -> Self {
-        TpmSwtpmIO { stream: None }
+        TpmSwtpmIO::with_endpoints(
+            SocketAddress::tcp(DEFAULT_HOST, DEFAULT_COMMAND_PORT),
+            SocketAddress::tcp(DEFAULT_HOST, DEFAULT_PLATFORM_PORT),
+            PlatformProtocol::Mssim,
+        )
+    }
+
+    // new_swtpm creates a TpmSwtpmIO which drives the swtpm control channel
+    // on the default ports instead of the MS simulator platform port
+    pub fn new_swtpm() -> Self {
+        TpmSwtpmIO::with_endpoints(
+            SocketAddress::tcp(DEFAULT_HOST, DEFAULT_COMMAND_PORT),
+            SocketAddress::tcp(DEFAULT_HOST, DEFAULT_PLATFORM_PORT),
+            PlatformProtocol::Swtpm,
+        )
+    }
+
+    // new_tcp creates a TpmSwtpmIO towards a MS simulator listening on
+    // host:port, with the platform port at port + 1. The last port cannot be
+    // used, as it leaves no room for the platform port.
+    pub fn new_tcp(host: &str, port: u16) -> result::Result<Self, std::io::Error> {
+        let platform_port = port.checked_add(1).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("no platform port after command port {}", port),
+            )
+        })?;
+        Ok(TpmSwtpmIO::with_endpoints(
+            SocketAddress::tcp(host, port),
+            SocketAddress::tcp(host, platform_port),
+            PlatformProtocol::Mssim,
+        ))
+    }
+
+    // new_unix creates a TpmSwtpmIO towards swtpm started with
+    // `--server type=unixio,path=<server>` and `--ctrl type=unixio,path=<ctrl>`
+    #[cfg(unix)]
+    pub fn new_unix<P: Into<PathBuf>, Q: Into<PathBuf>>(server: P, ctrl: Q) -> Self {
+        TpmSwtpmIO::with_endpoints(
+            SocketAddress::unix(server),
+            SocketAddress::unix(ctrl),
+            PlatformProtocol::Swtpm,
+        )
+    }
+
+    // with_endpoints creates a TpmSwtpmIO with arbitrary command and platform
+    // endpoints. Connections are opened lazily, on first use.
+    pub fn with_endpoints(
+        command: SocketAddress,
+        platform: SocketAddress,
+        protocol: PlatformProtocol,
+    ) -> Self {
+        TpmSwtpmIO {
+            stream: None,
+            platform_stream: None,
+            command_address: command,
+            platform_address: platform,
+            protocol,
+            locality: 0,
+            response: Vec::new(),
+            rdptr: 0,
+            awaiting_response: false,
+            timeouts: Some(Timeouts::new()),
+            deadline: None,
+            cancel_pending: false,
+        }
+    }
+
+    // set_timeouts replaces the response timeouts, None waits forever
+    pub fn set_timeouts(&mut self, timeouts: Option<Timeouts>) {
+        self.timeouts = timeouts;
+    }
+
+    // cancel asks the simulator to abort the command being executed, which
+    // then completes with TPM_RC_CANCELED. The MS simulator cancel line stays
+    // asserted until the next command is sent.
+    pub fn cancel(&mut self) -> result::Result<(), std::io::Error> {
+        match self.protocol {
+            PlatformProtocol::Mssim => {
+                self.platform_signal(TPM_SIGNAL_CANCEL_ON)?;
+                self.cancel_pending = true;
+                Ok(())
+            }
+            PlatformProtocol::Swtpm => self.control_command(SWTPM_CMD_CANCEL_TPM_CMD, &[]),
+        }
+    }
+
+    // power_on powers on the simulated TPM. The TPM still needs TPM2_Startup
+    // before accepting other commands.
+    pub fn power_on(&mut self) -> result::Result<(), std::io::Error> {
+        match self.protocol {
+            PlatformProtocol::Mssim => self.platform_signal(TPM_SIGNAL_POWER_ON),
+            // Init flags are left to 0, volatile state is preserved
+            PlatformProtocol::Swtpm => self.control_command(SWTPM_CMD_INIT, &0u32.to_be_bytes()),
+        }
+    }
+
+    // power_off powers off the simulated TPM
+    pub fn power_off(&mut self) -> result::Result<(), std::io::Error> {
+        match self.protocol {
+            PlatformProtocol::Mssim => self.platform_signal(TPM_SIGNAL_POWER_OFF),
+            PlatformProtocol::Swtpm => self.control_command(SWTPM_CMD_SHUTDOWN, &[]),
+        }
+    }
+
+    // nv_on makes NV memory available to the simulated TPM. swtpm NV is
+    // always available, so this is a no-op there.
+    pub fn nv_on(&mut self) -> result::Result<(), std::io::Error> {
+        match self.protocol {
+            PlatformProtocol::Mssim => self.platform_signal(TPM_SIGNAL_NV_ON),
+            PlatformProtocol::Swtpm => Ok(()),
+        }
+    }
+
+    // nv_off makes NV memory unavailable to the simulated TPM
+    pub fn nv_off(&mut self) -> result::Result<(), std::io::Error> {
+        match self.protocol {
+            PlatformProtocol::Mssim => self.platform_signal(TPM_SIGNAL_NV_OFF),
+            PlatformProtocol::Swtpm => Ok(()),
+        }
+    }
+
+    // reset signals a TPM reset (_TPM_Init) without removing power. swtpm
+    // performs a reset when initialized while already running.
+    pub fn reset(&mut self) -> result::Result<(), std::io::Error> {
+        self.discard_response();
+        match self.protocol {
+            PlatformProtocol::Mssim => self.platform_signal(TPM_SIGNAL_RESET),
+            PlatformProtocol::Swtpm => self.control_command(SWTPM_CMD_INIT, &0u32.to_be_bytes()),
+        }
+    }
+
+    // power_cycle removes and restores power and NV, leaving the simulated
+    // TPM in the state of a freshly booted platform
+    pub fn power_cycle(&mut self) -> result::Result<(), std::io::Error> {
+        self.discard_response();
+        self.power_off()?;
+        self.power_on()?;
+        self.nv_on()
+    }
+
+    // session_end tells the simulator that we are done with the command
+    // connection and closes both connections. A new connection is opened on
+    // the next command.
+    pub fn session_end(&mut self) -> result::Result<(), std::io::Error> {
+        self.discard_response();
+        self.platform_stream = None;
+        match self.stream.take() {
+            None => Ok(()),
+            Some(mut s) => s.write_all(&TPM_SESSION_END.to_be_bytes()),
+        }
+    }
+
+    // platform_signal sends a signal on the MS simulator platform port, which
+    // acknowledges every signal with a 0 u32
+    fn platform_signal(&mut self, signal: u32) -> result::Result<(), std::io::Error> {
+        let stream = self.platform()?;
+        stream.write_all(&signal.to_be_bytes())?;
+        let ack = read_u32(stream)?;
+        if ack != 0 {
+            return Err(Error::other(format!(
+                "platform signal {} failed with {:#x}",
+                signal, ack
+            )));
+        }
+        Ok(())
+    }
+
+    // control_command sends a command on the swtpm control channel, which
+    // answers with a TPM result code
+    fn control_command(
+        &mut self,
+        command: u32,
+        payload: &[u8],
+    ) -> result::Result<(), std::io::Error> {
+        let stream = self.platform()?;
+        let mut request = command.to_be_bytes().to_vec();
+        request.extend_from_slice(payload);
+        stream.write_all(&request)?;
+        let result = read_u32(stream)?;
+        if result != 0 {
+            return Err(Error::other(format!(
+                "control command {:#x} failed with {:#x}",
+                command, result
+            )));
+        }
+        Ok(())
+    }
+
+    fn platform(&mut self) -> result::Result<&mut SocketStream, std::io::Error> {
+        if self.platform_stream.is_none() {
+            let stream = self.platform_address.connect()?;
+            // Platform requests are served while a command executes, so they
+            // are bounded even when the command channel is stuck
+            stream.set_read_timeout(self.timeouts.map(|t| t.long))?;
+            self.platform_stream = Some(stream);
+        }
+        match &mut self.platform_stream {
+            None => Err(Error::other(
+                "platform stream is not configured".to_string(),
+            )),
+            Some(s) => Ok(s),
+        }
+    }
+
+    // receive_response reads a framed response from the command port. On
+    // failure no response is awaited anymore, so that the next command can
+    // be sent.
+    fn receive_response(&mut self) -> result::Result<(), std::io::Error> {
+        let result = self.read_response();
+        if result.is_err() {
+            self.discard_response();
+        }
+        result
+    }
+
+    fn read_response(&mut self) -> result::Result<(), std::io::Error> {
+        let timeout = match self.deadline {
+            None => None,
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if remaining > Duration::ZERO => Some(remaining),
+                _ => return Err(self.timed_out()),
+            },
+        };
+        let stream = match &mut self.stream {
+            None => return Err(Error::other("stream not open for reading".to_string())),
+            Some(s) => s,
+        };
+        stream.set_read_timeout(timeout)?;
+        let (size, response, ack) = match read_frame(stream) {
+            Err(err)
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+            {
+                return Err(self.timed_out())
+            }
+            // The rest of the frame is still pending, the stream is out of
+            // sync with the simulator
+            Err(err) => {
+                self.stream = None;
+                return Err(err);
+            }
+            Ok(frame) => frame,
+        };
+        if ack != 0 {
+            return Err(Error::other(format!(
+                "simulator did not acknowledge the command: {:#x}",
+                ack
+            )));
+        }
+        // The frame must carry exactly the response announced in its header,
+        // otherwise the stream is out of sync with the simulator
+        if header_size(&response) != Some(size) {
+            self.stream = None;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "simulator frame of {} bytes does not match response size",
+                    size
+                ),
+            ));
+        }
+        self.response = response;
+        self.rdptr = 0;
+        self.awaiting_response = false;
+        Ok(())
+    }
+
+    // timed_out cancels the command whose response did not arrive in time
+    // and drops the command connection, a new one is opened on the next
+    // command
+    fn timed_out(&mut self) -> std::io::Error {
+        if let Err(err) = self.cancel() {
+            log::warn!("could not cancel command: {}", err);
+        }
+        self.stream = None;
+        self.deadline = None;
+        self.discard_response();
+        Error::new(
+            ErrorKind::TimedOut,
+            format!("no response from {} in time", self.command_address),
+        )
+    }
+
+    fn discard_response(&mut self) {
+        self.response.clear();
+        self.rdptr = 0;
+        self.awaiting_response = false;
+    }
+}
+
+impl Default for TpmSwtpmIO {
+    fn default() -> Self {
+        TpmSwtpmIO::new()
+    }
+}
+
+impl Drop for TpmSwtpmIO {
+    fn drop(&mut self) {
+        let _ = self.session_end();
+    }
+}
+
+fn read_u32<S: Read>(stream: &mut S) -> result::Result<u32, std::io::Error> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+// read_frame reads response size, response and acknowledgement of a framed
+// response. The size is checked before allocating, so that a peer cannot make
+// us allocate more than MAX_RESPONSE_SIZE.
+fn read_frame<S: Read>(stream: &mut S) -> result::Result<(usize, Vec<u8>, u32), std::io::Error> {
+    let size = read_u32(stream)? as usize;
+    if size < RESPONSE_HEADER_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            ResponseSizeError::ShortRead {
+                expected: RESPONSE_HEADER_SIZE,
+                received: size,
+            },
+        ));
+    }
+    if size > MAX_RESPONSE_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            ResponseSizeError::Oversize {
+                size,
+                max: MAX_RESPONSE_SIZE,
+            },
+        ));
+    }
+    let mut response = vec![0; size];
+    stream.read_exact(&mut response)?;
+    let ack = read_u32(stream)?;
+    Ok((size, response, ack))
+}
+
+// The simulator command protocol carries the locality of every command
+impl ReadWrite for TpmSwtpmIO {
+    fn locality(&self) -> u8 {
+        self.locality
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), std::io::Error> {
+        if !is_valid_locality(locality) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid locality {}", locality),
+            ));
+        }
+        self.locality = locality;
+        Ok(())
     }
 }
 
 impl io::Read for TpmSwtpmIO {
     fn read(&mut self, buf: &mut [u8]) -> result::Result<usize, std::io::Error> {
-        match &mut self.stream {
-            None => {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "stream not open for reading".to_string(),
-                ))
-            }
-            Some(s) => {
-                let n = s.read(buf)?;
-                Ok(n)
-            }
+        if self.awaiting_response {
+            self.receive_response()?;
         }
+        let n = buf.len().min(self.response.len() - self.rdptr);
+        buf[..n].clone_from_slice(&self.response[self.rdptr..self.rdptr + n]);
+        self.rdptr += n;
+        Ok(n)
     }
 }
 
 impl io::Write for TpmSwtpmIO {
+    // write sends a whole TPM command, buf is expected to hold exactly one
+    // command
     fn write(&mut self, buf: &[u8]) -> result::Result<usize, std::io::Error> {
-        match self.stream {
-            None => {
-                let stream = TcpStream::connect("localhost:2322")?;
-                self.stream = Some(stream);
-            }
-            Some(_) => (),
+        if self.awaiting_response {
+            return Err(Error::other(
+                "response to the previous command has not been read".to_string(),
+            ));
+        }
+
+        if self.cancel_pending {
+            self.platform_signal(TPM_SIGNAL_CANCEL_OFF)?;
+            self.cancel_pending = false;
+        }
+
+        if self.stream.is_none() {
+            self.stream = Some(self.command_address.connect()?);
         }
 
         match &mut self.stream {
-            None => Err(Error::new(
-                ErrorKind::Other,
+            None => Err(Error::other(
                 "stream is not configured for writing ".to_string(),
             )),
             Some(s) => {
-                let n = s.write(buf)?;
-                Ok(n)
+                let mut frame = Vec::with_capacity(buf.len() + 9);
+                frame.extend_from_slice(&TPM_SEND_COMMAND.to_be_bytes());
+                frame.push(self.locality);
+                frame.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+                frame.extend_from_slice(buf);
+                s.write_all(&frame)?;
+                self.discard_response();
+                self.awaiting_response = true;
+                self.deadline = self.timeouts.map(|t| Instant::now() + t.for_command(buf));
+                Ok(buf.len())
             }
         }
     }
 
     fn flush(&mut self) -> result::Result<(), std::io::Error> {
-        Err(Error::new(
-            ErrorKind::Other,
+        Err(Error::other(
             "flush is not supported on TpmSwtpmIO".to_string(),
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::raw::error_response;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // simulator answers each framed command with the raw bytes of the next
+    // entry of frames. A closed connection is replaced by the next one.
+    fn simulator(frames: Vec<Vec<u8>>) -> (TpmSwtpmIO, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            for frame in frames {
+                while read_command(&mut stream).is_none() {
+                    stream = listener.accept().unwrap().0;
+                }
+                stream.write_all(&frame).unwrap();
+            }
+        });
+        let mut io = TpmSwtpmIO::with_endpoints(
+            SocketAddress::tcp("127.0.0.1", port),
+            SocketAddress::tcp("127.0.0.1", 0),
+            PlatformProtocol::Mssim,
+        );
+        io.set_timeouts(None);
+        (io, handle)
+    }
+
+    // read_command reads a framed command, None if the connection is closed
+    fn read_command(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut head = [0; 5];
+        stream.read_exact(&mut head).ok()?;
+        assert_eq!(&head[..4], &TPM_SEND_COMMAND.to_be_bytes());
+        let mut command = vec![0; read_u32(stream).unwrap() as usize];
+        stream.read_exact(&mut command).unwrap();
+        Some(command)
+    }
+
+    fn frame(size: u32, response: &[u8], ack: u32) -> Vec<u8> {
+        let mut frame = size.to_be_bytes().to_vec();
+        frame.extend_from_slice(response);
+        frame.extend_from_slice(&ack.to_be_bytes());
+        frame
+    }
+
+    fn startup() -> Vec<u8> {
+        vec![0x80, 0x01, 0, 0, 0, 0x0c, 0, 0, 0x01, 0x44, 0, 0]
+    }
+
+    fn exchange(io: &mut TpmSwtpmIO) -> result::Result<Vec<u8>, std::io::Error> {
+        io.write_all(&startup())?;
+        let mut response = vec![0; RESPONSE_HEADER_SIZE];
+        io.read_exact(&mut response)?;
+        Ok(response)
+    }
+
+    fn size_error(err: &std::io::Error) -> &ResponseSizeError {
+        err.get_ref().unwrap().downcast_ref().unwrap()
+    }
+
+    #[test]
+    fn frame_size_bounds() {
+        let mut oversize = &frame(u32::MAX, &[], 0)[..];
+        let err = read_frame(&mut oversize).unwrap_err();
+        assert!(matches!(
+            size_error(&err),
+            ResponseSizeError::Oversize { size, max: MAX_RESPONSE_SIZE } if *size == u32::MAX as usize
+        ));
+
+        let mut short = &frame(4, &[0; 4], 0)[..];
+        let err = read_frame(&mut short).unwrap_err();
+        assert!(matches!(
+            size_error(&err),
+            ResponseSizeError::ShortRead {
+                expected: RESPONSE_HEADER_SIZE,
+                received: 4
+            }
+        ));
+    }
+
+    #[test]
+    fn command_after_failed_response() {
+        let ok = error_response(0);
+        let (mut io, simulator) = simulator(vec![
+            // Not acknowledged, the stream stays in sync
+            frame(ok.len() as u32, &ok, 1),
+            // Oversized, the connection is dropped and reopened
+            frame(u32::MAX, &[], 0),
+            frame(ok.len() as u32, &ok, 0),
+        ]);
+
+        assert!(exchange(&mut io).is_err());
+        assert!(io.stream.is_some());
+
+        let err = exchange(&mut io).unwrap_err();
+        assert!(matches!(
+            size_error(&err),
+            ResponseSizeError::Oversize { .. }
+        ));
+        assert!(io.stream.is_none());
+
+        assert_eq!(exchange(&mut io).unwrap(), ok);
+        simulator.join().unwrap();
+    }
+
+    #[test]
+    fn new_tcp_platform_port() {
+        let io = TpmSwtpmIO::new_tcp("localhost", 2321).unwrap();
+        assert_eq!(io.platform_address, SocketAddress::tcp("localhost", 2322));
+
+        let err = TpmSwtpmIO::new_tcp("localhost", u16::MAX).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
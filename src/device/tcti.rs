@@ -0,0 +1,300 @@
+use crate::device::errors::TctiError;
+use crate::device::raw::{ReadWrite, TpmRawIO, DEFAULT_DEVICE_PATH};
+use crate::device::socket::SocketAddress;
+use crate::device::tcp::{PlatformProtocol, TpmSwtpmIO, DEFAULT_COMMAND_PORT, DEFAULT_HOST};
+
+use std::env;
+use std::path::PathBuf;
+use std::result;
+
+// Environment variables consulted by from_env, in order of precedence. These
+// are the same variables tpm2-tools reads to select its TCTI.
+pub const TCTI_ENV_VARS: [&str; 3] = ["TPM2TOOLS_TCTI", "TCTI", "TSS2_TCTI"];
+
+// DEFAULT_TCTI is used by from_env when none of TCTI_ENV_VARS is set
+pub const DEFAULT_TCTI: &str = "device:/dev/tpmrm0";
+
+// TctiConfig is the parsed form of a TCTI configuration string such as
+// `device:/dev/tpmrm0`, `mssim:host=127.0.0.1,port=2321` or
+// `swtpm:path=/run/swtpm.sock`.
+//
+// For socket backends the platform (mssim) or control (swtpm) channel is
+// derived from the command channel as tpm2-tss does: port + 1 for TCP and
+// `<path>.ctrl` for Unix sockets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TctiConfig {
+    Device(PathBuf),
+    Socket {
+        command: SocketAddress,
+        platform: SocketAddress,
+        protocol: PlatformProtocol,
+    },
+}
+
+impl TctiConfig {
+    // parse parses a TCTI configuration string. The backend name may also be
+    // given as a tpm2-tss library name, e.g. `libtss2-tcti-mssim.so.0`.
+    pub fn parse(tcti: &str) -> result::Result<Self, TctiError> {
+        let (name, conf) = match tcti.find(':') {
+            Some(pos) => (&tcti[..pos], &tcti[pos + 1..]),
+            None => (tcti, ""),
+        };
+        let name = name.trim_start_matches("libtss2-tcti-");
+        let name = match name.find(".so") {
+            Some(pos) => &name[..pos],
+            None => name,
+        };
+
+        match name {
+            "device" => parse_device(conf),
+            "mssim" => parse_socket(conf, PlatformProtocol::Mssim),
+            "swtpm" => parse_socket(conf, PlatformProtocol::Swtpm),
+            "" => Err(TctiError {
+                msg: format!("missing backend name in tcti string '{}'", tcti),
+            }),
+            _ => Err(TctiError {
+                msg: format!("unsupported tcti backend '{}'", name),
+            }),
+        }
+    }
+
+    // open creates the transport described by the configuration. No I/O
+    // happens until the first command is written.
+    pub fn open(&self) -> Box<dyn ReadWrite + Send> {
+        match self {
+            TctiConfig::Device(path) => Box::new(TpmRawIO::with_path(path)),
+            TctiConfig::Socket {
+                command,
+                platform,
+                protocol,
+            } => Box::new(TpmSwtpmIO::with_endpoints(
+                command.clone(),
+                platform.clone(),
+                *protocol,
+            )),
+        }
+    }
+}
+
+// open parses a TCTI configuration string and creates the corresponding
+// transport
+pub fn open(tcti: &str) -> result::Result<Box<dyn ReadWrite + Send>, TctiError> {
+    Ok(TctiConfig::parse(tcti)?.open())
+}
+
+// from_env creates the transport selected by the first of TCTI_ENV_VARS
+// which is set, falling back to DEFAULT_TCTI
+pub fn from_env() -> result::Result<Box<dyn ReadWrite + Send>, TctiError> {
+    open(&select_tcti(|var| env::var(var).ok()))
+}
+
+// select_tcti returns the TCTI string from_env uses, looking variables up
+// through lookup
+fn select_tcti<F: Fn(&str) -> Option<String>>(lookup: F) -> String {
+    TCTI_ENV_VARS
+        .iter()
+        .find_map(|var| lookup(var))
+        .unwrap_or_else(|| String::from(DEFAULT_TCTI))
+}
+
+fn parse_device(conf: &str) -> result::Result<TctiConfig, TctiError> {
+    // tpm2-tss accepts the bare device path, also accept the path= form
+    // used by the socket backends
+    let path = conf.strip_prefix("path=").unwrap_or(conf);
+    match path {
+        "" => Ok(TctiConfig::Device(PathBuf::from(DEFAULT_DEVICE_PATH))),
+        _ => Ok(TctiConfig::Device(PathBuf::from(path))),
+    }
+}
+
+fn parse_socket(conf: &str, protocol: PlatformProtocol) -> result::Result<TctiConfig, TctiError> {
+    let mut host: Option<&str> = None;
+    let mut port: Option<u16> = None;
+    let mut path: Option<&str> = None;
+
+    for option in conf.split(',').filter(|o| !o.is_empty()) {
+        let (key, value) = match option.find('=') {
+            Some(pos) => (&option[..pos], &option[pos + 1..]),
+            None => {
+                return Err(TctiError {
+                    msg: format!("expected key=value, got '{}'", option),
+                })
+            }
+        };
+        match key {
+            "host" => host = Some(value),
+            "port" => match value.parse::<u16>() {
+                Ok(p) if p < u16::MAX => port = Some(p),
+                _ => {
+                    return Err(TctiError {
+                        msg: format!("invalid port '{}'", value),
+                    })
+                }
+            },
+            "path" => path = Some(value),
+            _ => {
+                return Err(TctiError {
+                    msg: format!("unknown tcti option '{}'", key),
+                })
+            }
+        }
+    }
+
+    match path {
+        Some(path) => {
+            if host.is_some() || port.is_some() {
+                return Err(TctiError {
+                    msg: String::from("path cannot be combined with host or port"),
+                });
+            }
+            unix_config(path, protocol)
+        }
+        None => {
+            let host = host.unwrap_or(DEFAULT_HOST);
+            let port = port.unwrap_or(DEFAULT_COMMAND_PORT);
+            Ok(TctiConfig::Socket {
+                command: SocketAddress::tcp(host, port),
+                platform: SocketAddress::tcp(host, port + 1),
+                protocol,
+            })
+        }
+    }
+}
+
+#[cfg(unix)]
+fn unix_config(path: &str, protocol: PlatformProtocol) -> result::Result<TctiConfig, TctiError> {
+    Ok(TctiConfig::Socket {
+        command: SocketAddress::unix(path),
+        platform: SocketAddress::unix(format!("{}.ctrl", path)),
+        protocol,
+    })
+}
+
+#[cfg(not(unix))]
+fn unix_config(path: &str, _protocol: PlatformProtocol) -> result::Result<TctiConfig, TctiError> {
+    Err(TctiError {
+        msg: format!("unix sockets are not supported on this platform: {}", path),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(host: &str, port: u16, protocol: PlatformProtocol) -> TctiConfig {
+        TctiConfig::Socket {
+            command: SocketAddress::tcp(host, port),
+            platform: SocketAddress::tcp(host, port + 1),
+            protocol,
+        }
+    }
+
+    #[test]
+    fn parse_device() {
+        assert_eq!(
+            TctiConfig::parse("device:/dev/tpm0").unwrap(),
+            TctiConfig::Device(PathBuf::from("/dev/tpm0"))
+        );
+        assert_eq!(
+            TctiConfig::parse("device:path=/dev/tpmrm1").unwrap(),
+            TctiConfig::Device(PathBuf::from("/dev/tpmrm1"))
+        );
+        assert_eq!(
+            TctiConfig::parse("libtss2-tcti-device.so.0:/dev/tpmrm0").unwrap(),
+            TctiConfig::Device(PathBuf::from("/dev/tpmrm0"))
+        );
+    }
+
+    #[test]
+    fn parse_mssim() {
+        assert_eq!(
+            TctiConfig::parse("mssim:host=127.0.0.1,port=4321").unwrap(),
+            tcp("127.0.0.1", 4321, PlatformProtocol::Mssim)
+        );
+        assert_eq!(
+            TctiConfig::parse("mssim:port=4321").unwrap(),
+            tcp(DEFAULT_HOST, 4321, PlatformProtocol::Mssim)
+        );
+    }
+
+    #[test]
+    fn parse_swtpm() {
+        assert_eq!(
+            TctiConfig::parse("swtpm:host=tpm.local").unwrap(),
+            tcp("tpm.local", DEFAULT_COMMAND_PORT, PlatformProtocol::Swtpm)
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            TctiConfig::parse("swtpm:path=/run/swtpm.sock").unwrap(),
+            TctiConfig::Socket {
+                command: SocketAddress::unix("/run/swtpm.sock"),
+                platform: SocketAddress::unix("/run/swtpm.sock.ctrl"),
+                protocol: PlatformProtocol::Swtpm,
+            }
+        );
+        assert!(TctiConfig::parse("swtpm:path=/run/swtpm.sock,port=2321").is_err());
+    }
+
+    #[test]
+    fn parse_defaults() {
+        assert_eq!(
+            TctiConfig::parse("device").unwrap(),
+            TctiConfig::Device(PathBuf::from(DEFAULT_DEVICE_PATH))
+        );
+        assert_eq!(
+            TctiConfig::parse("device:").unwrap(),
+            TctiConfig::Device(PathBuf::from(DEFAULT_DEVICE_PATH))
+        );
+        assert_eq!(
+            TctiConfig::parse("mssim:").unwrap(),
+            tcp(DEFAULT_HOST, DEFAULT_COMMAND_PORT, PlatformProtocol::Mssim)
+        );
+        assert_eq!(
+            TctiConfig::parse("swtpm").unwrap(),
+            tcp(DEFAULT_HOST, DEFAULT_COMMAND_PORT, PlatformProtocol::Swtpm)
+        );
+        assert!(TctiConfig::parse("").is_err());
+        assert!(TctiConfig::parse(":/dev/tpm0").is_err());
+    }
+
+    #[test]
+    fn parse_bad_port() {
+        for port in ["", "abc", "-1", "65535", "70000"].iter() {
+            let tcti = format!("mssim:port={}", port);
+            assert!(TctiConfig::parse(&tcti).is_err(), "{}", tcti);
+        }
+    }
+
+    #[test]
+    fn parse_unknown() {
+        assert!(TctiConfig::parse("mssim:host=localhost,timeout=10").is_err());
+        assert!(TctiConfig::parse("mssim:localhost").is_err());
+        assert!(TctiConfig::parse("tabrmd:bus_type=session").is_err());
+    }
+
+    #[test]
+    fn env_precedence() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |var: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+
+        assert_eq!(select_tcti(env(&[])), DEFAULT_TCTI);
+        assert_eq!(select_tcti(env(&[("TSS2_TCTI", "swtpm")])), "swtpm");
+        assert_eq!(
+            select_tcti(env(&[("TSS2_TCTI", "swtpm"), ("TCTI", "mssim")])),
+            "mssim"
+        );
+        assert_eq!(
+            select_tcti(env(&[
+                ("TSS2_TCTI", "swtpm"),
+                ("TCTI", "mssim"),
+                ("TPM2TOOLS_TCTI", "device:/dev/tpm0"),
+            ])),
+            "device:/dev/tpm0"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use crate::device::raw::command_code;
+use crate::tpm2::types::tcg;
+
+use std::time::Duration;
+
+// Command durations of the TCG PC Client Platform TPM Profile, with the
+// values the Linux TPM driver uses for TPM 2.0 devices
+pub const DURATION_SHORT: Duration = Duration::from_millis(20);
+pub const DURATION_MEDIUM: Duration = Duration::from_millis(750);
+pub const DURATION_LONG: Duration = Duration::from_millis(2000);
+pub const DURATION_LONG_LONG: Duration = Duration::from_millis(300000);
+pub const DURATION_DEFAULT: Duration = Duration::from_millis(120000);
+
+// DurationClass is the class of maximum execution time of a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurationClass {
+    Short,
+    Medium,
+    Long,
+    LongLong,
+    Default,
+}
+
+impl DurationClass {
+    // for_command returns the duration class of a command. Commands the PC
+    // Client profile does not single out fall in the Default class.
+    pub fn for_command(command_code: tcg::TpmCc) -> Self {
+        match command_code {
+            tcg::TPM_CC_STARTUP
+            | tcg::TPM_CC_SEQUENCE_UPDATE
+            | tcg::TPM_CC_SEQUENCE_COMPLETE
+            | tcg::TPM_CC_EVENT_SEQUENCE_COMPLETE
+            | tcg::TPM_CC_HASH_SEQUENCE_START
+            | tcg::TPM_CC_PCR_EXTEND
+            | tcg::TPM_CC_GET_CAPABILITY => DurationClass::Medium,
+            tcg::TPM_CC_SELF_TEST
+            | tcg::TPM_CC_GET_RANDOM
+            | tcg::TPM_CC_HIERARCHY_CONTROL
+            | tcg::TPM_CC_HIERARCHY_CHANGE_AUTH
+            | tcg::TPM_CC_NV_READ => DurationClass::Long,
+            tcg::TPM_CC_VERIFY_SIGNATURE
+            | tcg::TPM_CC_CREATE_PRIMARY
+            | tcg::TPM_CC_CREATE
+            | tcg::TPM_CC_CREATE_LOADED => DurationClass::LongLong,
+            _ => DurationClass::Default,
+        }
+    }
+}
+
+// Timeouts holds the time a transport waits for the response of a command,
+// per duration class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub short: Duration,
+    pub medium: Duration,
+    pub long: Duration,
+    pub long_long: Duration,
+    pub default: Duration,
+}
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Timeouts {
+            short: DURATION_SHORT,
+            medium: DURATION_MEDIUM,
+            long: DURATION_LONG,
+            long_long: DURATION_LONG_LONG,
+            default: DURATION_DEFAULT,
+        }
+    }
+
+    // new_uniform returns Timeouts applying the same timeout to every
+    // command, e.g. for a simulator reached over a slow network
+    pub fn new_uniform(timeout: Duration) -> Self {
+        Timeouts {
+            short: timeout,
+            medium: timeout,
+            long: timeout,
+            long_long: timeout,
+            default: timeout,
+        }
+    }
+
+    pub fn for_class(&self, class: DurationClass) -> Duration {
+        match class {
+            DurationClass::Short => self.short,
+            DurationClass::Medium => self.medium,
+            DurationClass::Long => self.long,
+            DurationClass::LongLong => self.long_long,
+            DurationClass::Default => self.default,
+        }
+    }
+
+    // for_command returns the timeout of a marshalled command. Commands too
+    // short to carry a command code get the default timeout.
+    pub fn for_command(&self, command: &[u8]) -> Duration {
+        match command_code(command) {
+            Some(cc) => self.for_class(DurationClass::for_command(cc)),
+            None => self.default,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(command_code: tcg::TpmCc) -> Vec<u8> {
+        let mut command = vec![0x80, 0x01, 0x00, 0x00, 0x00, 0x0a];
+        command.extend_from_slice(&command_code.to_be_bytes());
+        command
+    }
+
+    #[test]
+    fn startup_class() {
+        // Startup follows a TPM reset and may take well over DURATION_SHORT
+        assert_eq!(
+            DurationClass::for_command(tcg::TPM_CC_STARTUP),
+            DurationClass::Medium
+        );
+
+        let startup = [
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x01, 0x44, 0x00, 0x00,
+        ];
+        assert_eq!(Timeouts::new().for_command(&startup), DURATION_MEDIUM);
+    }
+
+    #[test]
+    fn command_classes() {
+        let classes = [
+            (tcg::TPM_CC_PCR_EXTEND, DurationClass::Medium),
+            (tcg::TPM_CC_GET_CAPABILITY, DurationClass::Medium),
+            (tcg::TPM_CC_SELF_TEST, DurationClass::Long),
+            (tcg::TPM_CC_HIERARCHY_CHANGE_AUTH, DurationClass::Long),
+            (tcg::TPM_CC_NV_READ, DurationClass::Long),
+            (tcg::TPM_CC_CREATE_PRIMARY, DurationClass::LongLong),
+            (tcg::TPM_CC_CREATE_LOADED, DurationClass::LongLong),
+            (tcg::TPM_CC_PCR_READ, DurationClass::Default),
+            (tcg::TPM_CC_UNSEAL, DurationClass::Default),
+            // vendor specific command
+            (0x20000000, DurationClass::Default),
+        ];
+        for (command_code, class) in classes.iter() {
+            assert_eq!(
+                DurationClass::for_command(*command_code),
+                *class,
+                "{:#x}",
+                command_code
+            );
+        }
+    }
+
+    #[test]
+    fn command_timeouts() {
+        let timeouts = Timeouts::new();
+        assert_eq!(
+            timeouts.for_command(&command(tcg::TPM_CC_GET_RANDOM)),
+            DURATION_LONG
+        );
+        assert_eq!(
+            timeouts.for_command(&command(tcg::TPM_CC_CREATE)),
+            DURATION_LONG_LONG
+        );
+        assert_eq!(
+            timeouts.for_command(&command(tcg::TPM_CC_QUOTE)),
+            DURATION_DEFAULT
+        );
+        // too short to carry a command code
+        assert_eq!(timeouts.for_command(&[0x80, 0x01]), DURATION_DEFAULT);
+
+        let uniform = Timeouts::new_uniform(Duration::from_secs(5));
+        assert_eq!(
+            uniform.for_command(&command(tcg::TPM_CC_CREATE)),
+            Duration::from_secs(5)
+        );
+        assert_eq!(uniform.for_command(&[]), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::device::errors::TpmDeviceError;
+use crate::device::raw::{
+    command_code, read_u16, read_u32, response_code, TpmDeviceOps, RESPONSE_HEADER_SIZE,
+};
+use crate::tpm2::serialization::inout;
+use crate::tpm2::types::constants::cc;
+use crate::tpm2::types::tcg;
+
+use log::{debug, log_enabled, trace, warn, Level};
+use std::fmt;
+use std::result;
+use std::time::Instant;
+
+// TpmTracer wraps a TpmDeviceOps and logs every exchange through the `log`
+// crate:
+//
+// * debug: one line per exchange with command name, handles, number of
+//   sessions, response code and duration
+// * trace: additionally, hex dumps of command and response
+// * warn: transport errors
+//
+// Hex dumps include authorization values and sensitive parameters exactly
+// as sent to the TPM, so trace level must not be enabled where logs leave
+// the machine.
+pub struct TpmTracer<T: TpmDeviceOps> {
+    inner: T,
+}
+
+impl<T: TpmDeviceOps> TpmTracer<T> {
+    pub fn new(inner: T) -> Self {
+        TpmTracer { inner }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: TpmDeviceOps> TpmDeviceOps for TpmTracer<T> {
+    fn send_recv(
+        &mut self,
+        buff_command: &mut dyn inout::RwBytes,
+        buff_answer: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), TpmDeviceError> {
+        let command = CommandSummary::decode(buff_command.to_bytes());
+        if log_enabled!(Level::Trace) {
+            trace!(
+                "{} > {}",
+                command.name(),
+                hex::encode(buff_command.to_bytes())
+            );
+        }
+
+        let start = Instant::now();
+        let result = self.inner.send_recv(buff_command, buff_answer);
+        let elapsed = start.elapsed();
+
+        match &result {
+            Err(err) => warn!("{} failed after {:?}: {}", command, elapsed, err),
+            Ok(()) => {
+                let response = buff_answer.to_bytes();
+                match response_code(response) {
+                    Some(rc) => debug!("{} -> rc {:#x} in {:?}", command, rc, elapsed),
+                    None => debug!("{} -> truncated response in {:?}", command, elapsed),
+                }
+                if log_enabled!(Level::Trace) {
+                    trace!("{} < {}", command.name(), hex::encode(response));
+                }
+            }
+        }
+        result
+    }
+
+    fn locality(&self) -> u8 {
+        self.inner.locality()
+    }
+
+    fn set_locality(&mut self, locality: u8) -> result::Result<(), TpmDeviceError> {
+        self.inner.set_locality(locality)
+    }
+}
+
+// CommandSummary holds the fields of a command worth logging. Fields the
+// command is too short to carry are left empty.
+struct CommandSummary {
+    command_code: Option<tcg::TpmCc>,
+    handles: Vec<tcg::Handle>,
+    sessions: usize,
+}
+
+impl CommandSummary {
+    fn decode(bytes: &[u8]) -> Self {
+        let mut summary = CommandSummary {
+            command_code: None,
+            handles: Vec::new(),
+            sessions: 0,
+        };
+        let tag = match read_u16(bytes, 0) {
+            Some(tag) => tag,
+            None => return summary,
+        };
+        let command_code = match command_code(bytes) {
+            Some(command_code) => command_code,
+            None => return summary,
+        };
+        summary.command_code = Some(command_code);
+
+        let mut offset = RESPONSE_HEADER_SIZE;
+        let handles = cc::command_info(command_code).map_or(0, |info| info.handles);
+        for _ in 0..handles {
+            match read_u32(bytes, offset) {
+                Some(handle) => summary.handles.push(handle),
+                None => return summary,
+            }
+            offset += 4;
+        }
+
+        if tag == tcg::TPM_ST_SESSIONS {
+            summary.sessions = count_sessions(bytes, offset);
+        }
+        summary
+    }
+
+    fn name(&self) -> &'static str {
+        match self.command_code {
+            Some(command_code) => cc::command_name(command_code),
+            None => "Unknown",
+        }
+    }
+}
+
+impl fmt::Display for CommandSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.command_code {
+            None => return write!(f, "TPM2_<truncated>"),
+            Some(command_code) => write!(f, "TPM2_{} ({:#x})", self.name(), command_code)?,
+        }
+        write!(f, " handles [")?;
+        for (i, handle) in self.handles.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{:#010x}", handle)?;
+        }
+        write!(f, "] sessions {}", self.sessions)
+    }
+}
+
+// count_sessions walks the authorization area starting at offset, counting
+// TPMS_AUTH_COMMAND entries: sessionHandle, nonce (TPM2B), sessionAttributes
+// and hmac (TPM2B)
+fn count_sessions(bytes: &[u8], offset: usize) -> usize {
+    let auth_size = match read_u32(bytes, offset) {
+        Some(size) => size as usize,
+        None => return 0,
+    };
+    let end = offset + 4 + auth_size;
+    let mut offset = offset + 4;
+    let mut sessions = 0;
+    while offset < end {
+        offset += 4;
+        let nonce_size = match read_u16(bytes, offset) {
+            Some(size) => size as usize,
+            None => break,
+        };
+        offset += 2 + nonce_size + 1;
+        let hmac_size = match read_u16(bytes, offset) {
+            Some(size) => size as usize,
+            None => break,
+        };
+        offset += 2 + hmac_size;
+        if offset > end {
+            break;
+        }
+        sessions += 1;
+    }
+    sessions
+}
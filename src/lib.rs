@@ -0,0 +1,14 @@
+// Without the std feature only the TPM structure definitions and their
+// marshalling are built, e.g. for UEFI applications and bootloaders
+#![cfg_attr(not(feature = "std"), no_std)]
+
+//mod crypto;
+#[cfg(feature = "std")]
+pub mod device;
+pub mod tpm2;
+
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
+
+#[macro_use]
+extern crate mem_macros;
@@ -1,17 +1,25 @@
-//mod crypto;
-mod device;
-mod tpm2;
-use device::{raw, tcp};
+use std::env;
+use std::process;
 use tcg::Handle;
-use tpm2::commands::import;
-use tpm2::commands::pcrs::{PCRSelection, MAX_PCR};
-use tpm2::commands::{session, startup};
-use tpm2::types::tcg;
-
-#[macro_use]
-extern crate mem_macros;
+use tpm2::device::errors::TpmDeviceError;
+use tpm2::device::proxy::TpmProxyServer;
+use tpm2::device::tcti::{self, TctiConfig};
+use tpm2::device::{raw, rm, tcp, trace};
+use tpm2::tpm2::commands::import;
+use tpm2::tpm2::commands::pcrs::{PCRSelection, MAX_PCR};
+use tpm2::tpm2::commands::{session, startup};
+use tpm2::tpm2::types::tcg;
 
 fn main() {
+    // Log level is selected via RUST_LOG, e.g. RUST_LOG=debug traces every
+    // TPM command
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("proxy") {
+        process::exit(proxy(&args[2..]));
+    }
+
     let mut pcrs = Vec::new();
     for n in 0..MAX_PCR + 1 {
         pcrs.push(n as u8);
@@ -29,17 +37,96 @@ fn main() {
     //};
 
     let mut stream = tcp::TpmSwtpmIO::new();
-    let mut tpm: raw::TpmDevice = raw::TpmDevice { rw: &mut stream };
+    println!("power on");
+    stream
+        .power_cycle()
+        .expect("could not power on the simulator");
+    let mut tpm = trace::TpmTracer::new(raw::TpmDevice { rw: &mut stream });
 
     println!("startup");
-    startup::tpm2_startup(&mut tpm, tcg::TPM_SU_CLEAR);
+    startup::tpm2_startup(&mut tpm, tcg::TPM_SU_CLEAR).expect("TPM2_Startup failed");
     println!("auth session");
     let auth: tcg::TpmsAuthCommand = session::tpm2_startauth_session(&mut tpm).unwrap();
 
     let handle: Handle = 0x80000000;
     // Create import blob
     println!("policy secret");
-    session::tpm2_policy_secret(&mut tpm, 0x4000000B, auth);
+    session::tpm2_policy_secret(&mut tpm, 0x4000000B, auth.clone())
+        .expect("TPM2_PolicySecret failed");
     println!("import");
-    let data: tcg::Tpm2bData = import::tpm2_import(&mut tpm, handle, auth).unwrap();
+    let data: tcg::Tpm2bSensitiveData = import::tpm2_import(&mut tpm, handle, auth).unwrap();
+    println!("unsealed {}", String::from_utf8_lossy(data.get_buffer()));
+}
+
+const PROXY_USAGE: &str = "usage: tpm2 proxy [--listen <tcti>] [--tcti <tcti>]
+
+Serves the TPM selected by --tcti (default: from TPM2TOOLS_TCTI, TCTI or
+TSS2_TCTI) over the simulator protocol on the endpoint given by --listen
+(default: mssim:host=localhost,port=2321), e.g.
+
+  tpm2 proxy --tcti device:/dev/tpmrm0 --listen mssim:host=0.0.0.0,port=2321
+  tpm2 proxy --listen mssim:path=/run/tpm.sock";
+
+// proxy runs the proxy subcommand and returns the exit code
+fn proxy(args: &[String]) -> i32 {
+    let mut listen = "mssim:host=localhost,port=2321";
+    let mut backend: Option<&str> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--listen", Some(value)) => listen = value,
+            ("--tcti", Some(value)) => backend = Some(value),
+            _ => {
+                eprintln!("{}", PROXY_USAGE);
+                return 2;
+            }
+        }
+    }
+
+    let (command, platform) = match TctiConfig::parse(listen) {
+        Ok(TctiConfig::Socket {
+            command, platform, ..
+        }) => (command, platform),
+        Ok(TctiConfig::Device(_)) => {
+            eprintln!("cannot listen on a device: {}", listen);
+            return 2;
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            return 2;
+        }
+    };
+    let transport = match backend {
+        Some(backend) => tcti::open(backend),
+        None => tcti::from_env(),
+    };
+    let transport = match transport {
+        Ok(transport) => transport,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 2;
+        }
+    };
+
+    // Clients share the TPM through a resource manager, so that their
+    // transient objects and sessions are kept apart
+    let manager = rm::TpmResourceManager::new(raw::TpmOwnedDevice::new(transport));
+    let server = TpmProxyServer::bind(&command, Some(&platform), move || {
+        Ok::<_, TpmDeviceError>(manager.client())
+    });
+    let server = match server {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("cannot listen on {}: {}", command, err);
+            return 1;
+        }
+    };
+    println!("serving on {} (platform {})", command, platform);
+    match server.serve() {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
 }
@@ -40,10 +40,13 @@ impl CommandHeader {
 }
 
 impl inout::Tpm2StructOut for CommandHeader {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.tag.pack(buff);
-        self.command_size.pack(buff);
-        self.command_code.pack(buff);
+    fn pack(
+        &self,
+        buff: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), errors::SerializationError> {
+        self.tag.pack(buff)?;
+        self.command_size.pack(buff)?;
+        self.command_code.pack(buff)
     }
 }
 
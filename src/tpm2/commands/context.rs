@@ -0,0 +1,79 @@
+use crate::device;
+use crate::tpm2::commands::run;
+use crate::tpm2::errors;
+use crate::tpm2::serialization::inout;
+use crate::tpm2::serialization::inout::Tpm2StructIn;
+use crate::tpm2::types::tcg;
+use std::result;
+
+// tpm2_context_save saves the context of a loaded object, sequence or
+// session. Sessions are removed from TPM memory by the save, objects and
+// sequences remain loaded until flushed.
+pub fn tpm2_context_save(
+    tpm: &mut dyn device::raw::TpmDeviceOps,
+    save_handle: tcg::Handle,
+) -> result::Result<tcg::TpmsContext, errors::CommandError> {
+    let handles: [tcg::Handle; 1] = [save_handle];
+    let auths: [tcg::TpmsAuthCommand; 0] = [];
+    let params: [&dyn inout::Tpm2StructOut; 0] = [];
+
+    let mut resp_buff = inout::DynamicByteBuffer::new();
+    run::run_command(
+        tpm,
+        tcg::TPM_CC_CONTEXT_SAVE,
+        &handles,
+        &auths,
+        &params,
+        &mut resp_buff,
+    )?;
+
+    let mut context = tcg::TpmsContext::new();
+    context.unpack(&mut resp_buff)?;
+    Ok(context)
+}
+
+// tpm2_context_load loads a saved context and returns the handle assigned
+// to it. Sessions keep their handle, objects and sequences get a new one.
+pub fn tpm2_context_load(
+    tpm: &mut dyn device::raw::TpmDeviceOps,
+    context: &tcg::TpmsContext,
+) -> result::Result<tcg::Handle, errors::CommandError> {
+    let handles: [tcg::Handle; 0] = [];
+    let auths: [tcg::TpmsAuthCommand; 0] = [];
+    let params: [&dyn inout::Tpm2StructOut; 1] = [context];
+
+    let mut resp_buff = inout::DynamicByteBuffer::new();
+    run::run_command(
+        tpm,
+        tcg::TPM_CC_CONTEXT_LOAD,
+        &handles,
+        &auths,
+        &params,
+        &mut resp_buff,
+    )?;
+
+    let mut loaded_handle: tcg::Handle = 0;
+    loaded_handle.unpack(&mut resp_buff)?;
+    Ok(loaded_handle)
+}
+
+// tpm2_flush_context removes a loaded object, sequence or session, or a
+// saved session, from the TPM
+pub fn tpm2_flush_context(
+    tpm: &mut dyn device::raw::TpmDeviceOps,
+    flush_handle: tcg::Handle,
+) -> result::Result<(), errors::CommandError> {
+    let handles: [tcg::Handle; 0] = [];
+    let auths: [tcg::TpmsAuthCommand; 0] = [];
+    let params: [&dyn inout::Tpm2StructOut; 1] = [&flush_handle];
+
+    let mut resp_buff = inout::DynamicByteBuffer::new();
+    run::run_command(
+        tpm,
+        tcg::TPM_CC_FLUSH_CONTEXT,
+        &handles,
+        &auths,
+        &params,
+        &mut resp_buff,
+    )
+}
@@ -2,7 +2,7 @@ use crate::device;
 use crate::tpm2::commands::{load, run, session, unseal};
 use crate::tpm2::errors;
 use crate::tpm2::serialization::inout;
-use crate::tpm2::serialization::inout::{RwBytes, Tpm2StructIn, Tpm2StructOut};
+use crate::tpm2::serialization::inout::Tpm2StructIn;
 use crate::tpm2::types::tcg;
 
 use std::result;
@@ -26,8 +26,19 @@ pub fn tpm2_import(
     tpm: &mut dyn device::raw::TpmDeviceOps,
     parent_handle: tcg::Handle,
     auth: tcg::TpmsAuthCommand,
-) -> result::Result<tcg::Tpm2bData, errors::CommandError> {
-    println!("importing with parent_handle {:02x?}", parent_handle);
+) -> result::Result<tcg::Tpm2bSensitiveData, errors::CommandError> {
+    tpm2_import_with_rng(tpm, parent_handle, auth, &mut rand::thread_rng())
+}
+
+// tpm2_import_with_rng is tpm2_import with a caller provided RNG. A seeded
+// RNG makes the command stream reproducible, e.g. to replay a recording.
+pub fn tpm2_import_with_rng<R: rand::CryptoRng + rand::RngCore>(
+    tpm: &mut dyn device::raw::TpmDeviceOps,
+    parent_handle: tcg::Handle,
+    auth: tcg::TpmsAuthCommand,
+    rng: &mut R,
+) -> result::Result<tcg::Tpm2bSensitiveData, errors::CommandError> {
+    log::debug!("importing with parent handle {:#010x}", parent_handle);
 
     let pem_result = parse(SAMPLE);
     match pem_result {
@@ -35,7 +46,7 @@ pub fn tpm2_import(
         Err(_) => panic!("pem error"),
     }
     let pem = pem_result.unwrap();
-    println!("{}", pem.tag());
+    log::trace!("parent key is a {}", pem.tag());
 
     let public_key_result = rsa::RsaPublicKey::from_public_key_pem(SAMPLE);
     match public_key_result {
@@ -53,43 +64,21 @@ pub fn tpm2_import(
     // Create the TPMT_PUBLIC from the sensitive object
     let public = tcg::TpmtPublic::new_data_object(&sensitive);
 
-    tcg::kdfa(
-        &[
-            0xda, 0x82, 0xeb, 0x71, 0xb1, 0x8c, 0xb9, 0xae, 0xfc, 0x9c, 0x88, 0xa5, 0xff, 0x03,
-            0x01, 0x6f, 0x12, 0xd1, 0x74, 0x0b, 0x05, 0x78, 0x21, 0xcd, 0xff, 0x9e, 0xac, 0xba,
-            0xb7, 0xbd, 0xd3, 0xc9,
-        ],
-        "STORAGE".as_bytes(),
-        &[
-            0x00, 0x0b, 0x8c, 0xca, 0x34, 0xd8, 0xb9, 0xf4, 0xae, 0xbe, 0xe7, 0x91, 0xf8, 0xd0,
-            0xa4, 0xdf, 0xcf, 0xc2, 0x2f, 0x20, 0x87, 0xc1, 0xc9, 0xfa, 0x4c, 0x79, 0xb5, 0xa0,
-            0x8b, 0x27, 0xcf, 0x8a, 0xd6, 0x59,
-        ],
-        &[],
-        128,
-    );
-
     let mut enc_seed: tcg::Tpm2bEncryptedSecret = tcg::Tpm2bEncryptedSecret::new();
 
     // Create the duplicate (TPM2B_PRIVATE) object based on the sensitive content
-    let duplicate = tcg::Tpm2bPrivate::new_duplicate(&public_key, sensitive, public, &mut enc_seed);
-
-    let mut buff_public = inout::StaticByteBuffer::new();
-    public.pack(&mut buff_public);
+    let duplicate =
+        tcg::Tpm2bPrivate::new_duplicate(&public_key, &sensitive, &public, &mut enc_seed, rng)?;
 
-    let mut resp_buff = inout::StaticByteBuffer::new();
+    let mut resp_buff = inout::DynamicByteBuffer::new();
 
     let handles: [tcg::Handle; 1] = [parent_handle];
-    let auths: [tcg::TpmsAuthCommand; 1] = [auth];
+    let auths: [tcg::TpmsAuthCommand; 1] = [auth.clone()];
 
     let params: [&dyn inout::Tpm2StructOut; 5] = [
-        &tcg::Tpm2bData {
-            size: 0,
-            buffer: [0; 1024],
-        },
+        &tcg::Tpm2bData::new(),
         &tcg::Tpm2bPublic {
-            size: buff_public.to_bytes().len() as u16,
-            public: public,
+            public: public.clone(),
         },
         &duplicate,
         &enc_seed,
@@ -109,19 +98,16 @@ pub fn tpm2_import(
     param_size.unpack(&mut resp_buff)?;
 
     let mut out_private: tcg::Tpm2bPrivate = tcg::Tpm2bPrivate::new();
-    out_private.unpack(&mut resp_buff)?;
+    inout::unpack_exact(&mut out_private, param_size as usize, &mut resp_buff)?;
 
-    session::tpm2_policy_secret(tpm, 0x4000000B, auth)?;
+    session::tpm2_policy_secret(tpm, 0x4000000B, auth.clone())?;
 
     let loaded_handle = load::tpm2_load(
         tpm,
         parent_handle,
         auth,
         out_private,
-        tcg::Tpm2bPublic {
-            size: buff_public.to_bytes().len() as u16,
-            public: public,
-        },
+        tcg::Tpm2bPublic { public: public },
     )?;
 
     // This is unnecessary. Just use emptyAuth
@@ -130,3 +116,80 @@ pub fn tpm2_import(
     let data = unseal::tpm2_unseal(tpm, loaded_handle)?;
     Ok(data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::tpm2::types::constants::TpmRc;
+
+    fn password_auth() -> tcg::TpmsAuthCommand {
+        tcg::TpmsAuthCommand {
+            session_handle: tcg::TPM_RS_PW,
+            nonce: tcg::Tpm2bNonce::new(),
+            session_attributes: tcg::TPMA_SESSION_CONTINUE_SESSION,
+            hmac: tcg::Tpm2bAuth::new(),
+        }
+    }
+
+    #[test]
+    fn import_error() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_error(tcg::TPM_CC_IMPORT, TpmRc::Integrity);
+
+        match tpm2_import(&mut tpm, 0x81000001, password_auth()).err() {
+            Some(errors::CommandError::ResponseError(err)) => {
+                assert_eq!(err.error_code, TpmRc::Integrity as u32)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        // Nothing is loaded after a failed import
+        assert_eq!(tpm.calls().len(), 1);
+    }
+
+    #[test]
+    fn import_truncated() {
+        // parameterSize without outPrivate
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(tcg::TPM_CC_IMPORT, vec![]);
+
+        match tpm2_import(&mut tpm, 0x81000001, password_auth()).err() {
+            Some(errors::CommandError::DeserializationError(_)) => (),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn import_unseal_error() {
+        // Errors of the commands following TPM2_Import are returned as well
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(
+            tcg::TPM_CC_IMPORT,
+            vec![Box::new(tcg::Tpm2bPrivate::from_slice(&[0x5a; 32]))],
+        )
+        .expect_response(tcg::TPM_CC_POLICY_SECRET, vec![])
+        .expect_response(
+            tcg::TPM_CC_LOAD,
+            vec![
+                Box::new(0x80000000u32),
+                Box::new(tcg::Tpm2bName::from_slice(&[0x0b; 34])),
+            ],
+        )
+        .expect_response(
+            tcg::TPM_START_AUTH_SESSION,
+            vec![
+                Box::new(0x03000000u32),
+                Box::new(tcg::Tpm2bNonce::from_slice(&[0xa5; 16])),
+            ],
+        )
+        .expect_error(tcg::TPM_CC_UNSEAL, TpmRc::PolicyFail);
+
+        match tpm2_import(&mut tpm, 0x81000001, password_auth()).err() {
+            Some(errors::CommandError::ResponseError(err)) => {
+                assert_eq!(err.error_code, TpmRc::PolicyFail as u32)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(tpm.unmet_expectations().is_empty());
+    }
+}
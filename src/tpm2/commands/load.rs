@@ -1,9 +1,9 @@
 use crate::device;
-use crate::tcg;
 use crate::tpm2::commands::run;
 use crate::tpm2::errors;
 use crate::tpm2::serialization::inout;
 use crate::tpm2::serialization::inout::Tpm2StructIn;
+use crate::tpm2::types::tcg;
 use std::result;
 
 pub fn tpm2_load(
@@ -24,7 +24,7 @@ pub fn tpm2_load(
     // expiration
     let params: [&dyn inout::Tpm2StructOut; 2] = [&in_private, &in_public];
 
-    let mut resp_buff = inout::StaticByteBuffer::new();
+    let mut resp_buff = inout::DynamicByteBuffer::new();
 
     run::run_command(
         tpm,
@@ -35,10 +35,79 @@ pub fn tpm2_load(
         &mut resp_buff,
     )?;
 
+    // objectHandle precedes parameterSize, which the response to a command
+    // with sessions carries ahead of the parameters
     let mut resp_handle: tcg::Handle = 0;
-    let mut name: tcg::Tpm2bDigest = tcg::Tpm2bDigest::new();
+    let mut param_size: u32 = 0;
+    let mut name: tcg::Tpm2bName = tcg::Tpm2bName::new();
     resp_handle.unpack(&mut resp_buff)?;
-    name.unpack(&mut resp_buff)?;
+    param_size.unpack(&mut resp_buff)?;
+    inout::unpack_exact(&mut name, param_size as usize, &mut resp_buff)?;
 
     Ok(resp_handle)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::tpm2::types::constants::TpmRc;
+
+    fn load(tpm: &mut TpmMockDevice) -> result::Result<tcg::Handle, errors::CommandError> {
+        let sensitive = tcg::TpmtSensitive::new(b"secret");
+        let public = tcg::TpmtPublic::new_data_object(&sensitive);
+        tpm2_load(
+            tpm,
+            tcg::TPM_RH_OWNER,
+            tcg::TpmsAuthCommand {
+                session_handle: tcg::TPM_RS_PW,
+                nonce: tcg::Tpm2bNonce::new(),
+                session_attributes: tcg::TPMA_SESSION_CONTINUE_SESSION,
+                hmac: tcg::Tpm2bAuth::new(),
+            },
+            tcg::Tpm2bPrivate::from_slice(&[0x5a; 32]),
+            tcg::Tpm2bPublic { public },
+        )
+    }
+
+    #[test]
+    fn load_object() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(
+            tcg::TPM_CC_LOAD,
+            vec![
+                Box::new(0x80000001u32),
+                Box::new(tcg::Tpm2bName::from_slice(&[0x0b; 34])),
+            ],
+        );
+
+        assert_eq!(load(&mut tpm).unwrap(), 0x80000001);
+        assert!(tpm.unmet_expectations().is_empty());
+    }
+
+    #[test]
+    fn load_error() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_error(tcg::TPM_CC_LOAD, TpmRc::Integrity);
+
+        match load(&mut tpm).err() {
+            Some(errors::CommandError::ResponseError(err)) => {
+                assert_eq!(err.error_code, TpmRc::Integrity as u32)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(tpm.calls()[0].tag, tcg::TPM_ST_SESSIONS);
+    }
+
+    #[test]
+    fn load_truncated() {
+        // The object handle without its name
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(tcg::TPM_CC_LOAD, vec![Box::new(0x80000000u32)]);
+
+        match load(&mut tpm).err() {
+            Some(errors::CommandError::DeserializationError(_)) => (),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
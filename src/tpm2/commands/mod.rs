@@ -1,4 +1,5 @@
 pub mod commands;
+pub mod context;
 pub mod import;
 pub mod load;
 pub mod pcrread;
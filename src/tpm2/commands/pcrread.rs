@@ -23,8 +23,12 @@ impl PcrReadCommand {
         tag: tcg::TpmiStCommandTag,
         pcr_selection: tcg::TpmlPcrSelection,
     ) -> result::Result<Self, errors::TpmError> {
-        let mut buff = inout::StaticByteBuffer::new();
-        pcr_selection.pack(&mut buff);
+        let mut buff = inout::DynamicByteBuffer::new();
+        pcr_selection
+            .pack(&mut buff)
+            .map_err(|err| errors::TpmError {
+                msg: err.to_string(),
+            })?;
         let pcr_selection_size = buff.to_bytes().len();
 
         if pcr_selection_size > u32::MAX as usize {
@@ -53,9 +57,12 @@ impl PcrReadCommand {
 }
 
 impl inout::Tpm2StructOut for PcrReadCommand {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.header.pack(buff);
-        self.pcr_selection_in.pack(buff);
+    fn pack(
+        &self,
+        buff: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), errors::SerializationError> {
+        self.header.pack(buff)?;
+        self.pcr_selection_in.pack(buff)
     }
 }
 
@@ -95,6 +102,23 @@ impl PcrReadResponse {
         Ok(resp)
     }
 
+    // from_body builds a PcrReadResponse from the response parameters, as
+    // returned by run_command once the header has been checked and stripped
+    pub fn from_body(
+        buff: &mut dyn inout::RwBytes,
+    ) -> result::Result<Self, errors::DeserializationError> {
+        let mut resp = PcrReadResponse {
+            header: ResponseHeader::new(),
+            pcr_update_counter: 0,
+            pcr_selection_in: tcg::TpmlPcrSelection::new(),
+            pcr_values: tcg::TpmlDigest::new(),
+        };
+        resp.pcr_update_counter.unpack(buff)?;
+        resp.pcr_selection_in.unpack(buff)?;
+        resp.pcr_values.unpack(buff)?;
+        Ok(resp)
+    }
+
     // to_pcr_values turns TpmlPcrSelection and TpmlDigest structures into
     // a PCRValues
     pub fn to_pcr_values(
@@ -166,21 +190,21 @@ pub fn tpm2_pcr_read(
                     pcr_selections: pcr_selections,
                 };
 
-                let mut resp_buffer = inout::StaticByteBuffer::new();
+                let mut resp_buffer = inout::DynamicByteBuffer::new();
                 let params: [&dyn inout::Tpm2StructOut; 1] = [&pcr_selection];
                 let auth: [tcg::TpmsAuthCommand; 0] = [];
                 let handle: [tcg::Handle; 0] = [];
 
                 run::run_command(
                     tpm,
-                    tcg::TPM_START_AUTH_SESSION,
+                    tcg::TPM_CC_PCR_READ,
                     &handle,
                     &auth,
                     &params,
                     &mut resp_buffer,
                 )?;
 
-                let resp = PcrReadResponse::new(&mut resp_buffer)?;
+                let resp = PcrReadResponse::from_body(&mut resp_buffer)?;
 
                 let pcrs = resp.to_pcr_values()?;
                 all_pcrs.merge(pcrs.get_map());
@@ -192,3 +216,48 @@ pub fn tpm2_pcr_read(
     }
     Ok(all_pcrs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::tpm2::types::constants::{TpmAlgId, TpmRc};
+
+    #[test]
+    fn pcr_read_error() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_error(tcg::TPM_CC_PCR_READ, TpmRc::Value);
+
+        let selection = PCRSelection::new(vec![0, 1, 2]);
+        match tpm2_pcr_read(&mut tpm, &[selection]).err() {
+            Some(errors::CommandError::ResponseError(err)) => {
+                assert_eq!(err.error_code, TpmRc::Value as u32)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert!(tpm.unmet_expectations().is_empty());
+    }
+
+    #[test]
+    fn pcr_read_truncated() {
+        // pcrUpdateCounter and a selection of one bank, but no digests
+        let mut selection = tcg::TpmlPcrSelection::new();
+        selection.count = 1;
+        selection.pcr_selections[0] = tcg::TpmsPcrSelection {
+            hash: TpmAlgId::SHA256,
+            sizeof_select: 3,
+            pcr_select: [0x07, 0x00, 0x00],
+        };
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(
+            tcg::TPM_CC_PCR_READ,
+            vec![Box::new(1u32), Box::new(selection)],
+        );
+
+        let selection = PCRSelection::new(vec![0, 1, 2]);
+        match tpm2_pcr_read(&mut tpm, &[selection]).err() {
+            Some(errors::CommandError::DeserializationError(_)) => (),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
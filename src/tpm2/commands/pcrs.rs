@@ -1,4 +1,8 @@
+#[cfg(feature = "serde")]
+use crate::tpm2::serialization::tools;
 use crate::tpm2::types::constants::TpmAlgId;
+#[cfg(feature = "serde")]
+use serde::ser::SerializeMap;
 use std::collections::HashMap;
 use std::ops::Index;
 
@@ -22,7 +26,7 @@ impl PCRValues {
     }
 
     pub fn get_map(&self) -> &HashMap<u32, Vec<u8>> {
-        return &self.pcrs;
+        &self.pcrs
     }
 
     pub fn merge(&mut self, map: &HashMap<u32, Vec<u8>>) {
@@ -49,6 +53,40 @@ impl std::fmt::Display for PCRValues {
     }
 }
 
+// PCRValues serializes as tpm2_pcrread prints a bank, i.e. a map from PCR
+// index to digest, e.g. {0: 0x3D45...}
+#[cfg(feature = "serde")]
+impl serde::Serialize for PCRValues {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut indexes: Vec<&u32> = self.pcrs.keys().collect();
+        indexes.sort();
+        let mut map = serializer.serialize_map(Some(indexes.len()))?;
+        for pcr in indexes {
+            map.serialize_entry(pcr, &tools::encode_digest(&self.pcrs[pcr]))?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PCRValues {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let digests: HashMap<u32, String> = serde::Deserialize::deserialize(deserializer)?;
+        let mut pcr_values = PCRValues::new();
+        for (pcr, digest) in digests {
+            if pcr > MAX_PCR {
+                return Err(serde::de::Error::custom(format!(
+                    "invalid PCR index {}",
+                    pcr
+                )));
+            }
+            let digest = tools::decode_hex(&digest).map_err(serde::de::Error::custom)?;
+            pcr_values.add(pcr, digest);
+        }
+        Ok(pcr_values)
+    }
+}
+
 // PlatformConfigurationRegisters represents a set of multi-algorithm PCR values
 #[derive(Debug)]
 pub struct PlatformConfigurationRegisters {
@@ -108,6 +146,30 @@ impl std::fmt::Display for PlatformConfigurationRegisters {
     }
 }
 
+// PlatformConfigurationRegisters serializes as the output of tpm2_pcrread,
+// i.e. a map from bank name to PCRValues, e.g. {sha256: {0: 0x3D45...}}
+#[cfg(feature = "serde")]
+impl serde::Serialize for PlatformConfigurationRegisters {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut algos: Vec<&TpmAlgId> = self.pcrs.keys().collect();
+        algos.sort_by_key(|algo| **algo as u16);
+        let mut map = serializer.serialize_map(Some(algos.len()))?;
+        for algo in algos {
+            map.serialize_entry(algo, &self.pcrs[algo])?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PlatformConfigurationRegisters {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(PlatformConfigurationRegisters {
+            pcrs: serde::Deserialize::deserialize(deserializer)?,
+        })
+    }
+}
+
 // PCRSelection represents a selection of PCR registers to manipulate with TPM commands
 #[derive(Debug)]
 pub struct PCRSelection {
@@ -131,3 +193,40 @@ impl PCRSelection {
         self.algorithm
     }
 }
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serde_pcrs() {
+        let mut pcrs = PlatformConfigurationRegisters::new();
+        pcrs.add(TpmAlgId::SHA256, 10, vec![0xab; 32]);
+        pcrs.add(TpmAlgId::SHA256, 0, vec![0x00; 32]);
+        pcrs.add(TpmAlgId::SHA1, 0, vec![0x3d; 20]);
+
+        let json = serde_json::to_string(&pcrs).unwrap();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"sha1":{{"0":"0x{}"}},"sha256":{{"0":"0x{}","10":"0x{}"}}}}"#,
+                "3D".repeat(20),
+                "00".repeat(32),
+                "AB".repeat(32)
+            )
+        );
+
+        let decoded: PlatformConfigurationRegisters = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.get_map().len(), 2);
+        assert_eq!(
+            decoded.get_map()[&TpmAlgId::SHA256].get_map()[&10],
+            vec![0xab; 32]
+        );
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+
+        assert!(serde_json::from_str::<PlatformConfigurationRegisters>(
+            r#"{"sha256":{"24":"00"}}"#
+        )
+        .is_err());
+    }
+}
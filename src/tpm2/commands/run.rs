@@ -1,12 +1,136 @@
 use crate::device;
-use crate::tcg;
+use crate::device::errors::ResponseSizeError;
 use crate::tpm2::errors;
 use crate::tpm2::serialization::inout;
 use crate::tpm2::serialization::inout::{RwBytes, Tpm2StructIn, Tpm2StructOut};
+use crate::tpm2::types::constants::TpmRc;
+use crate::tpm2::types::tcg;
 
+#[cfg(feature = "tokio")]
+use std::future::Future;
 use std::mem;
 use std::result;
+use std::thread;
+use std::time::Duration;
 
+// Attempts made by the default RetryPolicy, the same bound tpm2-tss applies
+// to resubmissions of a command
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+pub const DEFAULT_INITIAL_DELAY: Duration = Duration::from_millis(20);
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(1);
+
+// RetryEvent describes a command about to be reissued after a warning
+#[derive(Debug, Clone, Copy)]
+pub struct RetryEvent {
+    pub command_code: tcg::TpmCc,
+    pub response_code: TpmRc,
+    // attempt is the number of the attempt which failed, starting at 1
+    pub attempt: u32,
+    // delay is the time waited before the next attempt
+    pub delay: Duration,
+}
+
+pub type RetryHook = Box<dyn Fn(&RetryEvent) + Send + Sync>;
+
+// RetryPolicy decides which response codes cause a command to be reissued
+// and how long to wait in between. Delays double after every attempt,
+// starting from initial_delay and capped at max_delay.
+pub struct RetryPolicy {
+    // max_attempts is the total number of times a command is sent, so 1
+    // disables retries
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub retry_on: Vec<TpmRc>,
+    // on_retry, if set, is called before waiting for every retry
+    pub on_retry: Option<RetryHook>,
+}
+
+impl RetryPolicy {
+    // new returns the default policy: TPM_RC_RETRY, TPM_RC_YIELDED,
+    // TPM_RC_TESTING and TPM_RC_NV_RATE are retried up to
+    // DEFAULT_MAX_ATTEMPTS times
+    pub fn new() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            retry_on: vec![TpmRc::Retry, TpmRc::Yielded, TpmRc::Testing, TpmRc::NvRate],
+            on_retry: None,
+        }
+    }
+
+    // new_no_retry returns a policy sending every command exactly once
+    pub fn new_no_retry() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::new()
+        }
+    }
+
+    // retryable returns the TpmRc matching response_code if the policy
+    // retries it
+    fn retryable(&self, response_code: u32) -> Option<TpmRc> {
+        self.retry_on
+            .iter()
+            .find(|rc| **rc as u32 == response_code)
+            .copied()
+    }
+
+    // retry returns the RetryEvent describing the next attempt if err, the
+    // outcome of the given attempt, is to be retried. The on_retry hook is
+    // notified, waiting is left to the caller.
+    fn retry(
+        &self,
+        command_code: tcg::TpmCc,
+        err: &errors::CommandError,
+        attempt: u32,
+    ) -> Option<RetryEvent> {
+        let error_code = match err {
+            errors::CommandError::ResponseError(err) => err.error_code,
+            _ => return None,
+        };
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let event = RetryEvent {
+            command_code,
+            response_code: self.retryable(error_code)?,
+            attempt,
+            delay: self.delay(attempt),
+        };
+        log::debug!(
+            "command {:#x} returned {:?}, retrying in {:?}",
+            command_code,
+            event.response_code,
+            event.delay
+        );
+        if let Some(hook) = &self.on_retry {
+            hook(&event);
+        }
+        Some(event)
+    }
+
+    // delay returns the time to wait after the given failed attempt
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new()
+    }
+}
+
+// run_command sends a command to the TPM and writes the response body, past
+// the header, into response. Warnings are retried according to the default
+// RetryPolicy.
 pub fn run_command(
     tpm: &mut dyn device::raw::TpmDeviceOps,
     command_code: tcg::TpmCc,
@@ -15,73 +139,356 @@ pub fn run_command(
     params: &[&dyn inout::Tpm2StructOut],
     response: &mut dyn inout::RwBytes,
 ) -> result::Result<(), errors::CommandError> {
+    run_command_with_policy(
+        tpm,
+        &RetryPolicy::new(),
+        command_code,
+        handles,
+        auths,
+        params,
+        response,
+    )
+}
+
+// run_command_with_policy is run_command with a caller provided RetryPolicy
+pub fn run_command_with_policy(
+    tpm: &mut dyn device::raw::TpmDeviceOps,
+    policy: &RetryPolicy,
+    command_code: tcg::TpmCc,
+    handles: &[tcg::Handle],
+    auths: &[tcg::TpmsAuthCommand],
+    params: &[&dyn inout::Tpm2StructOut],
+    response: &mut dyn inout::RwBytes,
+) -> result::Result<(), errors::CommandError> {
+    let mut command_buff = build_command(command_code, handles, auths, params)?;
+
+    let mut attempt = 1;
+    loop {
+        let mut resp_buff = inout::DynamicByteBuffer::new();
+        let result = match tpm.send_recv(&mut command_buff, &mut resp_buff) {
+            Err(err) => Err(err.into()),
+            Ok(()) => check_response(&mut resp_buff, response),
+        };
+        match result {
+            Err(err) => match policy.retry(command_code, &err, attempt) {
+                Some(event) => {
+                    thread::sleep(event.delay);
+                    attempt += 1;
+                }
+                None => return Err(err),
+            },
+            Ok(()) => return Ok(()),
+        }
+    }
+}
+
+// run_command_at_locality is run_command sending the command at the given
+// locality, e.g. to reset PCRs 16 to 23 or to satisfy TPM2_PolicyLocality.
+// The device is put back to its previous locality afterwards.
+pub fn run_command_at_locality(
+    tpm: &mut dyn device::raw::TpmDeviceOps,
+    locality: u8,
+    command_code: tcg::TpmCc,
+    handles: &[tcg::Handle],
+    auths: &[tcg::TpmsAuthCommand],
+    params: &[&dyn inout::Tpm2StructOut],
+    response: &mut dyn inout::RwBytes,
+) -> result::Result<(), errors::CommandError> {
+    let previous = tpm.locality();
+    tpm.set_locality(locality)?;
+    let result = run_command(tpm, command_code, handles, auths, params, response);
+    let restored = tpm.set_locality(previous);
+    result?;
+    Ok(restored?)
+}
+
+// run_command_async is the asynchronous counterpart of run_command. The
+// command is marshalled before the returned future is first polled, so the
+// future only borrows the device and can be spawned on a multi-threaded
+// runtime. It resolves to the response body, past the header, ready to be
+// unpacked with Tpm2StructIn.
+#[cfg(feature = "tokio")]
+pub fn run_command_async<'a>(
+    tpm: &'a mut dyn device::aio::AsyncTpmDeviceOps,
+    command_code: tcg::TpmCc,
+    handles: &[tcg::Handle],
+    auths: &[tcg::TpmsAuthCommand],
+    params: &[&dyn inout::Tpm2StructOut],
+) -> impl Future<Output = result::Result<inout::DynamicByteBuffer, errors::CommandError>> + Send + 'a
+{
+    let command = build_command(command_code, handles, auths, params);
+    async move { run_built_command_async(tpm, &RetryPolicy::new(), command_code, command?).await }
+}
+
+// run_command_async_with_policy is run_command_async with a caller provided
+// RetryPolicy
+#[cfg(feature = "tokio")]
+pub fn run_command_async_with_policy<'a>(
+    tpm: &'a mut dyn device::aio::AsyncTpmDeviceOps,
+    policy: &'a RetryPolicy,
+    command_code: tcg::TpmCc,
+    handles: &[tcg::Handle],
+    auths: &[tcg::TpmsAuthCommand],
+    params: &[&dyn inout::Tpm2StructOut],
+) -> impl Future<Output = result::Result<inout::DynamicByteBuffer, errors::CommandError>> + Send + 'a
+{
+    let command = build_command(command_code, handles, auths, params);
+    async move { run_built_command_async(tpm, policy, command_code, command?).await }
+}
+
+#[cfg(feature = "tokio")]
+async fn run_built_command_async(
+    tpm: &mut dyn device::aio::AsyncTpmDeviceOps,
+    policy: &RetryPolicy,
+    command_code: tcg::TpmCc,
+    command: inout::DynamicByteBuffer,
+) -> result::Result<inout::DynamicByteBuffer, errors::CommandError> {
+    let mut attempt = 1;
+    loop {
+        let result = match tpm.send_recv(command.to_bytes()).await {
+            Err(err) => Err(err.into()),
+            Ok(resp) => {
+                let mut resp_buff = inout::DynamicByteBuffer::from_vec(resp);
+                let mut response = inout::DynamicByteBuffer::new();
+                check_response(&mut resp_buff, &mut response).map(|_| response)
+            }
+        };
+        match result {
+            Err(err) => match policy.retry(command_code, &err, attempt) {
+                Some(event) => {
+                    tokio::time::sleep(event.delay).await;
+                    attempt += 1;
+                }
+                None => return Err(err),
+            },
+            Ok(response) => return Ok(response),
+        }
+    }
+}
+
+// build_command assembles a complete command, header included
+fn build_command(
+    command_code: tcg::TpmCc,
+    handles: &[tcg::Handle],
+    auths: &[tcg::TpmsAuthCommand],
+    params: &[&dyn inout::Tpm2StructOut],
+) -> result::Result<inout::DynamicByteBuffer, errors::SerializationError> {
     //
     // Assemble the body of the command, including handle area,
     // auth area, params area
     //
-    let mut body_buff = inout::StaticByteBuffer::new();
+    let mut body_buff = inout::DynamicByteBuffer::new();
     for handle in handles.iter() {
-        handle.pack(&mut body_buff);
+        handle.pack(&mut body_buff)?;
     }
     for auth in auths.iter() {
-        let mut auth_buff = inout::StaticByteBuffer::new();
-        auth.pack(&mut auth_buff);
+        let mut auth_buff = inout::DynamicByteBuffer::new();
+        auth.pack(&mut auth_buff)?;
         let size_auth: u32 = auth_buff.to_bytes().len() as u32;
-        size_auth.pack(&mut body_buff);
-        body_buff.write_bytes(auth_buff.to_bytes());
+        size_auth.pack(&mut body_buff)?;
+        body_buff.write_bytes(auth_buff.to_bytes())?;
     }
     for param in params.iter() {
-        let mut param_buff = inout::StaticByteBuffer::new();
-        param.pack(&mut body_buff);
-        param.pack(&mut param_buff);
+        param.pack(&mut body_buff)?;
     }
 
     //
     // Assemble the header, including tag, command code and command size
     //
-    let mut header_buff = inout::StaticByteBuffer::new();
+    let mut header_buff = inout::DynamicByteBuffer::new();
     if auths.len() > 0 {
-        tcg::TPM_ST_SESSIONS.pack(&mut header_buff);
+        tcg::TPM_ST_SESSIONS.pack(&mut header_buff)?;
     } else {
-        tcg::TPM_ST_NO_SESSION.pack(&mut header_buff);
+        tcg::TPM_ST_NO_SESSION.pack(&mut header_buff)?;
     }
     let header_size: u32 = mem::size_of::<tcg::TpmiStCommandTag>() as u32
         + mem::size_of::<u32>() as u32
         + mem::size_of::<tcg::TpmCc>() as u32;
     let command_size: u32 = header_size + body_buff.to_bytes().len() as u32;
-    command_size.pack(&mut header_buff);
-    command_code.pack(&mut header_buff);
+    command_size.pack(&mut header_buff)?;
+    command_code.pack(&mut header_buff)?;
 
     //
     // Assemble the final command, packing header and body together
     //
-    let mut command_buff = inout::StaticByteBuffer::new();
-    command_buff.write_bytes(header_buff.to_bytes());
-    command_buff.write_bytes(body_buff.to_bytes());
+    let mut command_buff = inout::DynamicByteBuffer::with_capacity(command_size as usize);
+    command_buff.write_bytes(header_buff.to_bytes())?;
+    command_buff.write_bytes(body_buff.to_bytes())?;
+    Ok(command_buff)
+}
 
-    let mut resp_buff = inout::StaticByteBuffer::new();
+// check_response checks the header of a complete response and writes the
+// response body into response
+fn check_response(
+    resp_buff: &mut dyn inout::RwBytes,
+    response: &mut dyn inout::RwBytes,
+) -> result::Result<(), errors::CommandError> {
+    let header_size: u32 = mem::size_of::<tcg::TpmiStCommandTag>() as u32
+        + mem::size_of::<u32>() as u32
+        + mem::size_of::<tcg::TpmCc>() as u32;
     let mut response_code: u32 = 0;
 
-    match tpm.send_recv(&mut command_buff, &mut resp_buff) {
-        Err(err) => {
-            return Err(errors::CommandError::IoError(errors::IoError {
-                msg: err.to_string(),
-            }))
+    if resp_buff.to_bytes().len() < header_size as usize {
+        return Err(errors::CommandError::ResponseSizeError(
+            ResponseSizeError::ShortRead {
+                expected: header_size as usize,
+                received: resp_buff.to_bytes().len(),
+            },
+        ));
+    }
+
+    let mut tag: tcg::TpmiStCommandTag = 0;
+    let mut response_size: u32 = 0;
+    tag.unpack(resp_buff)?;
+    response_size.unpack(resp_buff)?;
+    response_code.unpack(resp_buff)?;
+    if response_size as usize != resp_buff.to_bytes().len() {
+        return Err(errors::CommandError::ResponseSizeError(
+            ResponseSizeError::Mismatch {
+                declared: response_size as usize,
+                received: resp_buff.to_bytes().len(),
+            },
+        ));
+    }
+    if response_code != 0 {
+        return Err(errors::CommandError::ResponseError(errors::ResponseError {
+            error_code: response_code,
+        }));
+    }
+    response.write_bytes(resp_buff.read_bytes(response_size as usize - header_size as usize)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use std::sync::{Arc, Mutex};
+
+    // policy retries without waiting, so that tests run fast
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            ..RetryPolicy::new()
         }
-        _ => {
-            let mut tag: tcg::TpmiStCommandTag = 0;
-            let mut response_size: u32 = 0;
-            tag.unpack(&mut resp_buff)?;
-            response_size.unpack(&mut resp_buff)?;
-            response_code.unpack(&mut resp_buff)?;
-            if response_code != 0 {
-                return Err(errors::CommandError::ResponseError(errors::ResponseError {
-                    error_code: response_code,
-                }));
-            }
-            response
-                .write_bytes(resp_buff.read_bytes(response_size as usize - header_size as usize));
+    }
+
+    fn startup(
+        tpm: &mut TpmMockDevice,
+        policy: &RetryPolicy,
+    ) -> result::Result<(), errors::CommandError> {
+        let mut response = inout::DynamicByteBuffer::new();
+        run_command_with_policy(
+            tpm,
+            policy,
+            tcg::TPM_CC_STARTUP,
+            &[],
+            &[],
+            &[&tcg::TPM_SU_CLEAR],
+            &mut response,
+        )
+    }
+
+    fn response_code(result: result::Result<(), errors::CommandError>) -> u32 {
+        match result {
+            Err(errors::CommandError::ResponseError(err)) => err.error_code,
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn retry_warnings() {
+        for &rc in [TpmRc::Retry, TpmRc::Yielded, TpmRc::Testing, TpmRc::NvRate].iter() {
+            let mut tpm = TpmMockDevice::new();
+            tpm.expect_error(tcg::TPM_CC_STARTUP, rc)
+                .expect_error(tcg::TPM_CC_STARTUP, rc)
+                .expect_response(tcg::TPM_CC_STARTUP, vec![]);
+
+            startup(&mut tpm, &policy(DEFAULT_MAX_ATTEMPTS)).unwrap();
+            assert_eq!(tpm.calls().len(), 3, "{:?}", rc);
+        }
+    }
+
+    #[test]
+    fn retry_max_attempts() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_repeated(tcg::TPM_CC_STARTUP, |_, _| Err(TpmRc::Retry));
+
+        let result = startup(&mut tpm, &policy(3));
+        assert_eq!(response_code(result), TpmRc::Retry as u32);
+        assert_eq!(tpm.calls().len(), 3);
+
+        // A single attempt disables retries
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_error(tcg::TPM_CC_STARTUP, TpmRc::Retry);
+        let result = startup(&mut tpm, &RetryPolicy::new_no_retry());
+        assert_eq!(response_code(result), TpmRc::Retry as u32);
+        assert_eq!(tpm.calls().len(), 1);
+    }
+
+    #[test]
+    fn no_retry_on_errors() {
+        for &rc in [TpmRc::Failure, TpmRc::AuthFail, TpmRc::Lockout].iter() {
+            let mut tpm = TpmMockDevice::new();
+            tpm.expect_error(tcg::TPM_CC_STARTUP, rc)
+                .expect_response(tcg::TPM_CC_STARTUP, vec![]);
+
+            let result = startup(&mut tpm, &policy(DEFAULT_MAX_ATTEMPTS));
+            assert_eq!(response_code(result), rc as u32);
+            assert_eq!(tpm.calls().len(), 1, "{:?}", rc);
         }
+
+        // Only the response codes of the policy are retried
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_error(tcg::TPM_CC_STARTUP, TpmRc::Testing);
+        let policy = RetryPolicy {
+            retry_on: vec![TpmRc::Retry],
+            ..policy(DEFAULT_MAX_ATTEMPTS)
+        };
+        let result = startup(&mut tpm, &policy);
+        assert_eq!(response_code(result), TpmRc::Testing as u32);
+        assert_eq!(tpm.calls().len(), 1);
+    }
+
+    #[test]
+    fn on_retry() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let policy = RetryPolicy {
+            on_retry: Some(Box::new(move |event: &RetryEvent| {
+                recorded.lock().unwrap().push(*event)
+            })),
+            ..policy(DEFAULT_MAX_ATTEMPTS)
+        };
+
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_error(tcg::TPM_CC_STARTUP, TpmRc::Yielded)
+            .expect_error(tcg::TPM_CC_STARTUP, TpmRc::NvRate)
+            .expect_response(tcg::TPM_CC_STARTUP, vec![]);
+        startup(&mut tpm, &policy).unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].command_code, tcg::TPM_CC_STARTUP);
+        assert_eq!(events[0].response_code, TpmRc::Yielded);
+        assert_eq!(events[0].attempt, 1);
+        assert_eq!(events[1].response_code, TpmRc::NvRate);
+        assert_eq!(events[1].attempt, 2);
+    }
+
+    #[test]
+    fn retry_delay() {
+        let policy = RetryPolicy {
+            initial_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(100),
+            ..RetryPolicy::new()
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(20));
+        assert_eq!(policy.delay(2), Duration::from_millis(40));
+        assert_eq!(policy.delay(3), Duration::from_millis(80));
+        assert_eq!(policy.delay(4), Duration::from_millis(100));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(100));
     }
-    return Ok(());
 }
@@ -20,7 +20,7 @@ pub fn tpm2_startauth_session(
     nonce[5] = 0x06;
     nonce[6] = 0x07;
 
-    let mut resp_buff = inout::StaticByteBuffer::new();
+    let mut resp_buff = inout::DynamicByteBuffer::new();
 
     // TPM_SE_POLICY indicates a policy session, which therefore does not
     // make use of HMAC authorization. From TPM specs:
@@ -44,10 +44,7 @@ pub fn tpm2_startauth_session(
     // symmetric
     // authentication hash
     let params: [&dyn Tpm2StructOut; 5] = [
-        &tcg::Tpm2bDigest {
-            size: 16,
-            buffer: nonce,
-        },
+        &tcg::Tpm2bDigest::from_slice(&nonce[..16]),
         &tcg::Tpm2bEncryptedSecret::new(),
         &tcg::TPM_SE_POLICY,
         &tcg::TpmtSymDef::new_null(),
@@ -109,7 +106,7 @@ pub fn tpm2_policy_secret(
         &expiration,
     ];
 
-    let mut resp_buff = inout::StaticByteBuffer::new();
+    let mut resp_buff = inout::DynamicByteBuffer::new();
 
     run::run_command(
         tpm,
@@ -122,3 +119,70 @@ pub fn tpm2_policy_secret(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::tpm2::types::constants::TpmRc;
+
+    #[test]
+    fn startauth_session() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(
+            tcg::TPM_START_AUTH_SESSION,
+            vec![
+                Box::new(0x03000000u32),
+                Box::new(tcg::Tpm2bNonce::from_slice(&[0xa5; 16])),
+            ],
+        );
+
+        let auth = tpm2_startauth_session(&mut tpm).unwrap();
+        assert_eq!(auth.session_handle, 0x03000000);
+        assert_eq!(tpm.calls()[0].tag, tcg::TPM_ST_NO_SESSION);
+    }
+
+    #[test]
+    fn startauth_session_error() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_error(tcg::TPM_START_AUTH_SESSION, TpmRc::Mode);
+
+        match tpm2_startauth_session(&mut tpm).err() {
+            Some(errors::CommandError::ResponseError(err)) => {
+                assert_eq!(err.error_code, TpmRc::Mode as u32)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn startauth_session_truncated() {
+        // The session handle without the nonceTPM
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(tcg::TPM_START_AUTH_SESSION, vec![Box::new(0x03000000u32)]);
+
+        match tpm2_startauth_session(&mut tpm).err() {
+            Some(errors::CommandError::DeserializationError(_)) => (),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn policy_secret_error() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_error(tcg::TPM_CC_POLICY_SECRET, TpmRc::AuthFail);
+
+        let auth = tcg::TpmsAuthCommand {
+            session_handle: 0x03000000,
+            nonce: tcg::Tpm2bNonce::new(),
+            session_attributes: tcg::TPMA_SESSION_CONTINUE_SESSION,
+            hmac: tcg::Tpm2bDigest::new(),
+        };
+        match tpm2_policy_secret(&mut tpm, tcg::TPM_RH_ENDORSEMENT, auth).err() {
+            Some(errors::CommandError::ResponseError(err)) => {
+                assert_eq!(err.error_code, TpmRc::AuthFail as u32)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
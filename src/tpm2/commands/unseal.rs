@@ -1,15 +1,16 @@
 use crate::device;
-use crate::tcg;
 use crate::tpm2::commands::{commands, run};
 use crate::tpm2::errors;
 use crate::tpm2::serialization::inout;
 use crate::tpm2::serialization::inout::Tpm2StructIn;
+use crate::tpm2::types::tcg;
 use std::result;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct UnsealResponse {
     header: commands::ResponseHeader,
-    data: tcg::Tpm2bData,
+    parameter_size: u32,
+    data: tcg::Tpm2bSensitiveData,
 }
 
 impl inout::Tpm2StructIn for UnsealResponse {
@@ -18,6 +19,7 @@ impl inout::Tpm2StructIn for UnsealResponse {
         buff: &mut dyn inout::RwBytes,
     ) -> result::Result<(), errors::DeserializationError> {
         self.header.unpack(buff)?;
+        self.parameter_size.unpack(buff)?;
         self.data.unpack(buff)?;
         Ok(())
     }
@@ -26,7 +28,7 @@ impl inout::Tpm2StructIn for UnsealResponse {
 pub fn tpm2_unseal(
     tpm: &mut dyn device::raw::TpmDeviceOps,
     handle: tcg::Handle,
-) -> result::Result<tcg::Tpm2bData, errors::CommandError> {
+) -> result::Result<tcg::Tpm2bSensitiveData, errors::CommandError> {
     let handles: [tcg::Handle; 1] = [handle];
 
     let auths: [tcg::TpmsAuthCommand; 1] = [tcg::TpmsAuthCommand {
@@ -38,7 +40,7 @@ pub fn tpm2_unseal(
 
     let params: [&dyn inout::Tpm2StructOut; 0] = [];
 
-    let mut resp_buff = inout::StaticByteBuffer::new();
+    let mut resp_buff = inout::DynamicByteBuffer::new();
 
     run::run_command(
         tpm,
@@ -49,11 +51,79 @@ pub fn tpm2_unseal(
         &mut resp_buff,
     )?;
 
-    let mut data: tcg::Tpm2bData = tcg::Tpm2bData {
-        size: 0,
-        buffer: [0; 1024],
-    };
+    // The response to a command with sessions carries parameterSize ahead
+    // of the parameters, the authorization area follows them
+    let mut param_size: u32 = 0;
+    param_size.unpack(&mut resp_buff)?;
 
-    data.unpack(&mut resp_buff)?;
-    return Ok(data);
+    let mut data = tcg::Tpm2bSensitiveData::new();
+
+    inout::unpack_exact(&mut data, param_size as usize, &mut resp_buff)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::mock::TpmMockDevice;
+    use crate::tpm2::types::constants::TpmRc;
+
+    #[test]
+    fn unseal() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(
+            tcg::TPM_CC_UNSEAL,
+            vec![Box::new(tcg::Tpm2bSensitiveData::from_slice(b"secret"))],
+        );
+
+        let data = tpm2_unseal(&mut tpm, 0x80000000).unwrap();
+        assert_eq!(data.get_buffer(), b"secret");
+    }
+
+    #[test]
+    fn unseal_response() {
+        // A TPM2_Unseal response as sent by a TPM, with parameterSize and
+        // the response to the password session
+        let response = vec![
+            0x80, 0x02, 0x00, 0x00, 0x00, 0x1b, 0x00, 0x00, 0x00, 0x00, // header
+            0x00, 0x00, 0x00, 0x08, // parameterSize
+            0x00, 0x06, b's', b'e', b'c', b'r', b'e', b't', // outData
+            0x00, 0x00, 0x01, 0x00, 0x00, // TPMS_AUTH_RESPONSE
+        ];
+        let mut unseal = UnsealResponse {
+            header: commands::ResponseHeader::new(),
+            parameter_size: 0,
+            data: tcg::Tpm2bSensitiveData::new(),
+        };
+        unseal
+            .unpack(&mut inout::DynamicByteBuffer::from_vec(response))
+            .unwrap();
+        assert_eq!(unseal.parameter_size, 8);
+        assert_eq!(unseal.data.get_buffer(), b"secret");
+    }
+
+    #[test]
+    fn unseal_error() {
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_error(tcg::TPM_CC_UNSEAL, TpmRc::AuthFail);
+
+        match tpm2_unseal(&mut tpm, 0x80000000).err() {
+            Some(errors::CommandError::ResponseError(err)) => {
+                assert_eq!(err.error_code, TpmRc::AuthFail as u32)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unseal_truncated() {
+        // A 16 bytes outData carrying only 2 bytes
+        let mut tpm = TpmMockDevice::new();
+        tpm.expect_response(tcg::TPM_CC_UNSEAL, vec![Box::new(16u16), Box::new(0u16)]);
+
+        match tpm2_unseal(&mut tpm, 0x80000000).err() {
+            Some(errors::CommandError::DeserializationError(_)) => (),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
 }
@@ -1,5 +1,12 @@
+#[cfg(feature = "std")]
+use crate::device::errors::{PolicyError, ResponseSizeError, TimeoutError, TpmDeviceError};
+
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::string::ToString;
+use core::fmt;
+#[cfg(feature = "std")]
 use std::error::Error;
-use std::fmt;
 
 // IoError is an error encountered while talking to the TPM
 #[derive(Debug)]
@@ -7,6 +14,7 @@ pub struct IoError {
     pub msg: String,
 }
 
+#[cfg(feature = "std")]
 impl Error for IoError {}
 
 impl fmt::Display for IoError {
@@ -21,6 +29,7 @@ pub struct ResponseError {
     pub error_code: u32,
 }
 
+#[cfg(feature = "std")]
 impl Error for ResponseError {}
 
 impl fmt::Display for ResponseError {
@@ -35,6 +44,7 @@ pub struct SerializationError {
     pub msg: String,
 }
 
+#[cfg(feature = "std")]
 impl Error for SerializationError {}
 
 impl fmt::Display for SerializationError {
@@ -49,11 +59,12 @@ pub struct DeserializationError {
     pub msg: String,
 }
 
+#[cfg(feature = "std")]
 impl Error for DeserializationError {}
 
 impl fmt::Display for DeserializationError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "SerializationError: {}", self.msg)
+        write!(f, "DeserializationError: {}", self.msg)
     }
 }
 
@@ -63,6 +74,7 @@ pub struct InputParameterError {
     pub msg: String,
 }
 
+#[cfg(feature = "std")]
 impl Error for InputParameterError {}
 
 impl fmt::Display for InputParameterError {
@@ -77,6 +89,7 @@ pub struct TpmStructFormatError {
     pub msg: String,
 }
 
+#[cfg(feature = "std")]
 impl Error for TpmStructFormatError {}
 
 impl fmt::Display for TpmStructFormatError {
@@ -86,44 +99,127 @@ impl fmt::Display for TpmStructFormatError {
 }
 
 // CommandError is an error raised while running a command towards the TPM
+#[cfg(feature = "std")]
 #[derive(Debug)]
 pub enum CommandError {
     IoError(IoError),
     ResponseError(ResponseError),
+    SerializationError(SerializationError),
     DeserializationError(DeserializationError),
     InputParameterError(InputParameterError),
     TpmStructFormatError(TpmStructFormatError),
+    ResponseSizeError(ResponseSizeError),
+    TimeoutError(TimeoutError),
+    // PolicyError is a command rejected by a device::policy::TpmPolicyFilter
+    PolicyError(PolicyError),
+    // TpmError is a failure preparing the command, e.g. while deriving keys
+    TpmError(TpmError),
 }
 
+#[cfg(feature = "std")]
 impl Error for CommandError {}
 
+#[cfg(feature = "std")]
 impl fmt::Display for CommandError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "CommandError: {}", self)
+        let err: &dyn fmt::Display = match self {
+            CommandError::IoError(err) => err,
+            CommandError::ResponseError(err) => err,
+            CommandError::SerializationError(err) => err,
+            CommandError::DeserializationError(err) => err,
+            CommandError::InputParameterError(err) => err,
+            CommandError::TpmStructFormatError(err) => err,
+            CommandError::ResponseSizeError(err) => err,
+            CommandError::TimeoutError(err) => err,
+            CommandError::PolicyError(err) => err,
+            CommandError::TpmError(err) => err,
+        };
+        write!(f, "CommandError: {}", err)
     }
 }
+
+#[cfg(feature = "std")]
+impl From<SerializationError> for CommandError {
+    fn from(err: SerializationError) -> Self {
+        CommandError::SerializationError(err)
+    }
+}
+
+#[cfg(feature = "std")]
 impl From<DeserializationError> for CommandError {
     fn from(err: DeserializationError) -> Self {
         CommandError::DeserializationError(err)
     }
 }
 
+#[cfg(feature = "std")]
 impl From<TpmStructFormatError> for CommandError {
     fn from(err: TpmStructFormatError) -> Self {
         CommandError::TpmStructFormatError(err)
     }
 }
 
+#[cfg(feature = "std")]
+impl From<TpmError> for CommandError {
+    fn from(err: TpmError) -> Self {
+        CommandError::TpmError(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<TpmDeviceError> for CommandError {
+    fn from(err: TpmDeviceError) -> Self {
+        match err {
+            TpmDeviceError::IoError(err) => CommandError::IoError(IoError { msg: err.msg }),
+            TpmDeviceError::ResponseSizeError(err) => CommandError::ResponseSizeError(err),
+            TpmDeviceError::ReplayError(err) => CommandError::IoError(IoError {
+                msg: err.to_string(),
+            }),
+            TpmDeviceError::TimeoutError(err) => CommandError::TimeoutError(err),
+            TpmDeviceError::PolicyError(err) => CommandError::PolicyError(err),
+            TpmDeviceError::SerializationError(err) => CommandError::SerializationError(err),
+        }
+    }
+}
+
 // TpmError indicates a generic TPM error
 #[derive(Debug)]
 pub struct TpmError {
     pub msg: String,
 }
 
+#[cfg(feature = "std")]
 impl Error for TpmError {}
 
 impl fmt::Display for TpmError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "TpmError: {}", self)
+        write!(f, "TpmError: {}", self.msg)
+    }
+}
+
+impl From<SerializationError> for TpmError {
+    fn from(err: SerializationError) -> Self {
+        TpmError { msg: err.msg }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let err = TpmError {
+            msg: String::from("no RNG"),
+        };
+        assert_eq!(err.to_string(), "TpmError: no RNG");
+        assert_eq!(
+            CommandError::from(err).to_string(),
+            "CommandError: TpmError: no RNG"
+        );
+        assert_eq!(
+            CommandError::ResponseError(ResponseError { error_code: 0x101 }).to_string(),
+            "CommandError: ResponseError: 101"
+        );
     }
 }
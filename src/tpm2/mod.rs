@@ -1,3 +1,4 @@
+#[cfg(feature = "std")]
 pub mod commands;
 pub mod errors;
 pub mod serialization;
@@ -1,8 +1,10 @@
 use crate::tpm2::errors;
-use std::convert::TryFrom;
-use std::result;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::result;
 
-const MAX_TPM2_IO_BUF_SIZE: usize = 4096;
+pub const MAX_TPM2_IO_BUF_SIZE: usize = 4096;
 
 // RwBytes is a generic interface for reading and writing bytes.
 // It might be backed by a statically or dynamicall allocated
@@ -10,12 +12,14 @@ const MAX_TPM2_IO_BUF_SIZE: usize = 4096;
 pub trait RwBytes {
     // write_bytes writes a certain amount of bytes
     // to the underlying buffer, changing the
-    // underlying write pointer
-    fn write_bytes(&mut self, bytes: &[u8]);
+    // underlying write pointer. It fails if the
+    // buffer cannot hold them.
+    fn write_bytes(&mut self, bytes: &[u8]) -> result::Result<(), errors::SerializationError>;
     // read_bytes reads a certain amount of bytes
     // from the underlying buffer, changing the
-    // underlying read pointer
-    fn read_bytes(&mut self, size: usize) -> &[u8];
+    // underlying read pointer. It fails if fewer
+    // bytes are left to read.
+    fn read_bytes(&mut self, size: usize) -> result::Result<&[u8], errors::DeserializationError>;
     // to_bytes returs a slice representation of the whole
     // buffer
     fn to_bytes(&self) -> &[u8];
@@ -29,32 +33,28 @@ pub struct StaticByteBuffer {
 }
 
 impl RwBytes for StaticByteBuffer {
-    fn write_bytes(&mut self, bytes: &[u8]) {
-        if self.wrptr + bytes.len() > self.buf.len() {
-            panic!(
-                "buffer lenght not sufficient for write_bytes: {} > {}",
-                self.wrptr + bytes.len(),
-                self.buf.len(),
-            );
+    fn write_bytes(&mut self, bytes: &[u8]) -> result::Result<(), errors::SerializationError> {
+        if bytes.len() > self.buf.len() - self.wrptr {
+            return Err(errors::SerializationError {
+                msg: format!(
+                    "buffer length not sufficient for write_bytes: {} > {}",
+                    self.wrptr + bytes.len(),
+                    self.buf.len(),
+                ),
+            });
         }
         self.buf[self.wrptr..self.wrptr + bytes.len()].clone_from_slice(bytes);
         self.wrptr += bytes.len();
+        Ok(())
     }
 
-    fn read_bytes(&mut self, size: usize) -> &[u8] {
-        if self.rdptr + size > self.wrptr {
-            panic!(
-                "buffer lenght not sufficient for read_bytes: {} > {}",
-                self.rdptr + size,
-                self.wrptr,
-            );
-        }
-        self.rdptr += size;
-        return &self.buf[self.rdptr - size..self.rdptr];
+    fn read_bytes(&mut self, size: usize) -> result::Result<&[u8], errors::DeserializationError> {
+        self.rdptr = check_read(self.rdptr, size, self.wrptr)?;
+        Ok(&self.buf[self.rdptr - size..self.rdptr])
     }
 
     fn to_bytes(&self) -> &[u8] {
-        return &self.buf[0..self.wrptr];
+        &self.buf[0..self.wrptr]
     }
 }
 
@@ -68,10 +68,85 @@ impl StaticByteBuffer {
     }
 }
 
+// DynamicByteBuffer implements a bytes buffer with dynamic allocation,
+// growing as bytes are written. Unlike StaticByteBuffer it is not bound to
+// MAX_TPM2_IO_BUF_SIZE.
+#[derive(Debug, Clone, Default)]
+pub struct DynamicByteBuffer {
+    rdptr: usize,
+    buf: Vec<u8>,
+}
+
+impl RwBytes for DynamicByteBuffer {
+    fn write_bytes(&mut self, bytes: &[u8]) -> result::Result<(), errors::SerializationError> {
+        self.buf.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, size: usize) -> result::Result<&[u8], errors::DeserializationError> {
+        self.rdptr = check_read(self.rdptr, size, self.buf.len())?;
+        Ok(&self.buf[self.rdptr - size..self.rdptr])
+    }
+
+    fn to_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DynamicByteBuffer {
+    pub fn new() -> Self {
+        DynamicByteBuffer::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        DynamicByteBuffer {
+            rdptr: 0,
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    // from_vec wraps bytes without copying them, reading starts at the
+    // beginning
+    pub fn from_vec(bytes: Vec<u8>) -> Self {
+        DynamicByteBuffer {
+            rdptr: 0,
+            buf: bytes,
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+
+    // remaining returns the number of bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.rdptr
+    }
+}
+
+// check_read returns the read pointer after reading size bytes at rdptr
+// from a buffer holding len bytes, failing if the buffer is too short
+fn check_read(
+    rdptr: usize,
+    size: usize,
+    len: usize,
+) -> result::Result<usize, errors::DeserializationError> {
+    match rdptr.checked_add(size) {
+        Some(end) if end <= len => Ok(end),
+        _ => Err(errors::DeserializationError {
+            msg: format!(
+                "buffer length not sufficient for read_bytes: {} bytes requested, {} left",
+                size,
+                len - rdptr,
+            ),
+        }),
+    }
+}
+
 // Tpm2StructOut is a trait for TPM objects which can be serialized in
 // big endian byte stream for TPM operations
 pub trait Tpm2StructOut {
-    fn pack(&self, buff: &mut dyn RwBytes);
+    fn pack(&self, buff: &mut dyn RwBytes) -> result::Result<(), errors::SerializationError>;
 }
 
 // Tpm2StructIn is a trait for TPM objects which can be deserialized from
@@ -83,13 +158,154 @@ pub trait Tpm2StructIn {
     ) -> result::Result<(), errors::DeserializationError>;
 }
 
+// Tpm2UnionIn is a trait for TPMU unions, whose member is chosen by a
+// selector field of the enclosing structure, e.g. the type of TPMT_PUBLIC.
+// Unions are packed with Tpm2StructOut, which needs no selector.
+pub trait Tpm2UnionIn {
+    fn unpack_selected(
+        &mut self,
+        selector: u32,
+        buff: &mut dyn RwBytes,
+    ) -> result::Result<(), errors::DeserializationError>;
+}
+
+// Tpm2StructIn and Tpm2StructOut (see tpm2_derive) can be derived. Structure
+// fields are marshalled in declaration order, and the following field
+// attributes are supported:
+//
+//   #[tpm2b]                   the field is preceded by its size as a u16
+//   #[count = "count"]         the first count elements of an array field
+//   #[selector = "type_alg"]   a union field whose member is chosen by the
+//                              type_alg field
+//
+// Unions are enums deriving both traits, Tpm2StructIn implementing
+// Tpm2UnionIn for them. Each variant names the selector value it is
+// unpacked for, e.g.
+//
+//   #[derive(Tpm2StructIn, Tpm2StructOut)]
+//   enum TpmuSymKeyBits {
+//       #[selector = "TpmAlgId::AES"]
+//       Aes(TpmKeyBits),
+//       #[selector = "TpmAlgId::Null"]
+//       Null,
+//   }
+//
+// The functions below implement the attributes for the generated code.
+
+// pack_sized packs field preceded by its size as a u16
+pub fn pack_sized(
+    field: &dyn Tpm2StructOut,
+    buff: &mut dyn RwBytes,
+) -> result::Result<(), errors::SerializationError> {
+    let mut sized = DynamicByteBuffer::new();
+    field.pack(&mut sized)?;
+    let size = sized.to_bytes().len();
+    if size > u16::MAX as usize {
+        return Err(errors::SerializationError {
+            msg: format!(
+                "sized structure of {} bytes exceeds maximum of {}",
+                size,
+                u16::MAX
+            ),
+        });
+    }
+    (size as u16).pack(buff)?;
+    buff.write_bytes(sized.to_bytes())
+}
+
+// unpack_sized unpacks a field preceded by its size as a u16, which must be
+// consumed exactly. A size of 0 denotes an absent structure, leaving field
+// untouched.
+pub fn unpack_sized(
+    field: &mut dyn Tpm2StructIn,
+    buff: &mut dyn RwBytes,
+) -> result::Result<(), errors::DeserializationError> {
+    let mut size: u16 = 0;
+    size.unpack(buff)?;
+    if size == 0 {
+        return Ok(());
+    }
+    unpack_exact(field, size as usize, buff)
+}
+
+// unpack_exact unpacks a field from exactly the next size bytes, for TPM2B
+// structures which keep their size in a field of their own
+pub fn unpack_exact(
+    field: &mut dyn Tpm2StructIn,
+    size: usize,
+    buff: &mut dyn RwBytes,
+) -> result::Result<(), errors::DeserializationError> {
+    let mut sized = DynamicByteBuffer::from_vec(buff.read_bytes(size)?.to_vec());
+    field.unpack(&mut sized)?;
+    if sized.remaining() > 0 {
+        return Err(errors::DeserializationError {
+            msg: format!(
+                "sized structure of {} bytes has {} trailing bytes",
+                size,
+                sized.remaining()
+            ),
+        });
+    }
+    Ok(())
+}
+
+// pack_counted packs the first count items
+pub fn pack_counted<T: Tpm2StructOut>(
+    what: &str,
+    items: &[T],
+    count: usize,
+    buff: &mut dyn RwBytes,
+) -> result::Result<(), errors::SerializationError> {
+    if count > items.len() {
+        return Err(errors::SerializationError {
+            msg: format!(
+                "{} count {} exceeds maximum of {}",
+                what,
+                count,
+                items.len()
+            ),
+        });
+    }
+    for item in items[..count].iter() {
+        item.pack(buff)?;
+    }
+    Ok(())
+}
+
+// unpack_counted unpacks count items, resetting them first
+pub fn unpack_counted<T: Tpm2StructIn + Default>(
+    what: &str,
+    items: &mut [T],
+    count: usize,
+    buff: &mut dyn RwBytes,
+) -> result::Result<(), errors::DeserializationError> {
+    if count > items.len() {
+        return Err(errors::DeserializationError {
+            msg: format!(
+                "{} count {} exceeds maximum of {}",
+                what,
+                count,
+                items.len()
+            ),
+        });
+    }
+    for item in items[..count].iter_mut() {
+        *item = T::default();
+        item.unpack(buff)?;
+    }
+    Ok(())
+}
+
 // impl_tpm2_io is a macro which implments Tpm2StructIn and Tpm2StructOut for
 // primitive types.
 macro_rules! impl_tpm2_io {
     ($T: ident) => {
         impl Tpm2StructOut for $T {
-            fn pack(&self, buff: &mut dyn RwBytes) {
-                buff.write_bytes(&self.to_be_bytes()[..]);
+            fn pack(
+                &self,
+                buff: &mut dyn RwBytes,
+            ) -> result::Result<(), errors::SerializationError> {
+                buff.write_bytes(&self.to_be_bytes()[..])
             }
         }
 
@@ -98,7 +314,8 @@ macro_rules! impl_tpm2_io {
                 &mut self,
                 buff: &mut dyn RwBytes,
             ) -> result::Result<(), errors::DeserializationError> {
-                let byte_array = <[u8; size_of!($T)]>::try_from(&buff.read_bytes(size_of!($T))[..]);
+                let byte_array =
+                    <[u8; size_of!($T)]>::try_from(&buff.read_bytes(size_of!($T))?[..]);
                 match byte_array {
                     Ok(byte_array) => {
                         *self = $T::from_be_bytes(byte_array);
@@ -118,9 +335,35 @@ impl_tpm2_io! { u16 }
 impl_tpm2_io! { u32 }
 impl_tpm2_io! { u64 }
 
+// Fixed size arrays are marshalled element by element
+impl<T: Tpm2StructOut, const N: usize> Tpm2StructOut for [T; N] {
+    fn pack(&self, buff: &mut dyn RwBytes) -> result::Result<(), errors::SerializationError> {
+        for item in self.iter() {
+            item.pack(buff)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Tpm2StructIn, const N: usize> Tpm2StructIn for [T; N] {
+    fn unpack(
+        &mut self,
+        buff: &mut dyn RwBytes,
+    ) -> result::Result<(), errors::DeserializationError> {
+        for item in self.iter_mut() {
+            item.unpack(buff)?;
+        }
+        Ok(())
+    }
+}
+
 // normally belong to Command/Response structures
-pub fn pack(fields: &[impl Tpm2StructOut], buff: &mut dyn RwBytes) {
+pub fn pack(
+    fields: &[impl Tpm2StructOut],
+    buff: &mut dyn RwBytes,
+) -> result::Result<(), errors::SerializationError> {
     for field in fields.iter() {
-        field.pack(buff)
+        field.pack(buff)?;
     }
+    Ok(())
 }
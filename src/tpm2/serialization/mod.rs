@@ -1 +1,3 @@
 pub mod inout;
+#[cfg(feature = "serde")]
+pub mod tools;
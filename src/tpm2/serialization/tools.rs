@@ -0,0 +1,23 @@
+// Helpers for the serde representation of the TPM structures, which follows
+// the YAML printed by tpm2-tools. Algorithms are named, and byte buffers are
+// hex strings: PCR digests are printed as 0x followed by uppercase digits
+// (tpm2_pcrread), other buffers as bare lowercase digits (tpm2_readpublic).
+// Both forms are accepted when deserializing.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::result;
+
+// encode_digest formats a digest the way tpm2_pcrread prints it
+pub fn encode_digest(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode_upper(bytes))
+}
+
+// decode_hex parses a hex string, with or without a 0x prefix
+pub fn decode_hex(value: &str) -> result::Result<Vec<u8>, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    hex::decode(digits).map_err(|err| format!("invalid hex string {}: {}", value, err))
+}
@@ -5,11 +5,12 @@
 use crate::tpm2::errors;
 use crate::tpm2::serialization::inout::{RwBytes, Tpm2StructIn, Tpm2StructOut};
 
-use std::convert::TryFrom;
-use std::{fmt,result};
+use alloc::string::String;
+use core::convert::TryFrom;
+use core::{fmt, result, str};
 
 /// TPM_ALG_ID
-#[derive(Clone, Copy, Default, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Default, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 #[repr(u16)]
 pub enum TpmAlgId {
     #[default]
@@ -32,6 +33,7 @@ pub enum TpmAlgId {
 	RSAES = 0x0015,
 	RSAPSS = 0x0016,
 	OAEP = 0x0017,
+	ECDSA = 0x0018,
 	ECDH = 0x0019,
 	ECDAA = 0x001A,
 	SM2 = 0x001B,
@@ -54,8 +56,59 @@ pub enum TpmAlgId {
 }
 
 impl Tpm2StructOut for TpmAlgId {
-    fn pack(&self, buff: &mut dyn RwBytes) {
-        buff.write_bytes(&(*self as u16).to_be_bytes()[..]);
+    fn pack(&self, buff: &mut dyn RwBytes) -> result::Result<(), errors::SerializationError> {
+        buff.write_bytes(&(*self as u16).to_be_bytes()[..])
+    }
+}
+
+impl TryFrom<u16> for TpmAlgId {
+    type Error = errors::DeserializationError;
+
+    fn try_from(value: u16) -> result::Result<Self, errors::DeserializationError> {
+        Ok(match value {
+            0x0000 => Self::Error,
+            0x0001 => Self::RSA,
+            0x0003 => Self::TDES,
+            0x0004 => Self::SHA1,
+            0x0005 => Self::HMAC,
+            0x0006 => Self::AES,
+            0x0007 => Self::MGF1,
+            0x0008 => Self::KeyedHash,
+            0x000A => Self::XOR,
+            0x000B => Self::SHA256,
+            0x000C => Self::SHA384,
+            0x000D => Self::SHA512,
+            0x0010 => Self::Null,
+            0x0012 => Self::SM3_256,
+            0x0013 => Self::SM4,
+            0x0014 => Self::RSASSA,
+            0x0015 => Self::RSAES,
+            0x0016 => Self::RSAPSS,
+            0x0017 => Self::OAEP,
+            0x0018 => Self::ECDSA,
+            0x0019 => Self::ECDH,
+            0x001A => Self::ECDAA,
+            0x001B => Self::SM2,
+            0x001C => Self::ECSCHNORR,
+            0x001D => Self::ECMQV,
+            0x0020 => Self::KDF1_SP800_56A,
+            0x0021 => Self::KDF2,
+            0x0022 => Self::KDF1_SP800_108,
+            0x0023 => Self::ECC,
+            0x0025 => Self::SymCipher,
+            0x0026 => Self::Camellia,
+            0x0027 => Self::SHA3_256,
+            0x0028 => Self::SHA3_384,
+            0x0029 => Self::SHA3_512,
+            0x0040 => Self::CTR,
+            0x0041 => Self::OFB,
+            0x0042 => Self::CBC,
+            0x0043 => Self::CFB,
+            0x0044 => Self::ECB,
+            _ => return Err(errors::DeserializationError {
+                msg: format!("unknown algorithm {:#x}", value),
+            }),
+        })
     }
 }
 
@@ -64,52 +117,10 @@ impl Tpm2StructIn for TpmAlgId {
         &mut self,
         buff: &mut dyn RwBytes,
     ) -> result::Result<(), errors::DeserializationError> {
-        let byte_array = <[u8; size_of!(TpmAlgId)]>::try_from(&buff.read_bytes(size_of!(TpmAlgId))[..]);
+        let byte_array = <[u8; size_of!(TpmAlgId)]>::try_from(&buff.read_bytes(size_of!(TpmAlgId))?[..]);
         match byte_array {
             Ok(byte_array) => {
-                *self = match u16::from_be_bytes(byte_array) {
-                    0x0000 => Self::Error,
-                    0x0001 => Self::RSA,
-                    0x0003 => Self::TDES,
-                    0x0004 => Self::SHA1,
-                    0x0005 => Self::HMAC,
-                    0x0006 => Self::AES,
-                    0x0007 => Self::MGF1,
-                    0x0008 => Self::KeyedHash,
-                    0x000A => Self::XOR,
-                    0x000B => Self::SHA256,
-                    0x000C => Self::SHA384,
-                    0x000D => Self::SHA512,
-                    0x0010 => Self::Null,
-                    0x0012 => Self::SM3_256,
-                    0x0013 => Self::SM4,
-                    0x0014 => Self::RSASSA,
-                    0x0015 => Self::RSAES,
-                    0x0016 => Self::RSAPSS,
-                    0x0017 => Self::OAEP,
-                    0x0019 => Self::ECDH,
-                    0x001A => Self::ECDAA,
-                    0x001B => Self::SM2,
-                    0x001C => Self::ECSCHNORR,
-                    0x001D => Self::ECMQV,
-                    0x0020 => Self::KDF1_SP800_56A,
-                    0x0021 => Self::KDF2,
-                    0x0022 => Self::KDF1_SP800_108,
-                    0x0023 => Self::ECC,
-                    0x0025 => Self::SymCipher,
-                    0x0026 => Self::Camellia,
-                    0x0027 => Self::SHA3_256,
-                    0x0028 => Self::SHA3_384,
-                    0x0029 => Self::SHA3_512,
-                    0x0040 => Self::CTR,
-                    0x0041 => Self::OFB,
-                    0x0042 => Self::CBC,
-                    0x0043 => Self::CFB,
-                    0x0044 => Self::ECB,
-                    _ => return Err(errors::DeserializationError {
-                        msg: String::from("could not prepare byteArray"),
-                    }),
-                };
+                *self = TpmAlgId::try_from(u16::from_be_bytes(byte_array))?;
                 Ok(())
             }
             Err(_) => Err(errors::DeserializationError {
@@ -124,3 +135,155 @@ impl fmt::Display for TpmAlgId {
         write!(f, "{}", &(*self as u16))
     }
 }
+
+impl TpmAlgId {
+    const ALL: [TpmAlgId; 39] = [
+        Self::Error,
+        Self::RSA,
+        Self::TDES,
+        Self::SHA1,
+        Self::HMAC,
+        Self::AES,
+        Self::MGF1,
+        Self::KeyedHash,
+        Self::XOR,
+        Self::SHA256,
+        Self::SHA384,
+        Self::SHA512,
+        Self::Null,
+        Self::SM3_256,
+        Self::SM4,
+        Self::RSASSA,
+        Self::RSAES,
+        Self::RSAPSS,
+        Self::OAEP,
+        Self::ECDSA,
+        Self::ECDH,
+        Self::ECDAA,
+        Self::SM2,
+        Self::ECSCHNORR,
+        Self::ECMQV,
+        Self::KDF1_SP800_56A,
+        Self::KDF2,
+        Self::KDF1_SP800_108,
+        Self::ECC,
+        Self::SymCipher,
+        Self::Camellia,
+        Self::SHA3_256,
+        Self::SHA3_384,
+        Self::SHA3_512,
+        Self::CTR,
+        Self::OFB,
+        Self::CBC,
+        Self::CFB,
+        Self::ECB,
+    ];
+
+    /// Name of the algorithm as printed by tpm2-tools
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::RSA => "rsa",
+            Self::TDES => "tdes",
+            Self::SHA1 => "sha1",
+            Self::HMAC => "hmac",
+            Self::AES => "aes",
+            Self::MGF1 => "mgf1",
+            Self::KeyedHash => "keyedhash",
+            Self::XOR => "xor",
+            Self::SHA256 => "sha256",
+            Self::SHA384 => "sha384",
+            Self::SHA512 => "sha512",
+            Self::Null => "null",
+            Self::SM3_256 => "sm3_256",
+            Self::SM4 => "sm4",
+            Self::RSASSA => "rsassa",
+            Self::RSAES => "rsaes",
+            Self::RSAPSS => "rsapss",
+            Self::OAEP => "oaep",
+            Self::ECDSA => "ecdsa",
+            Self::ECDH => "ecdh",
+            Self::ECDAA => "ecdaa",
+            Self::SM2 => "sm2",
+            Self::ECSCHNORR => "ecschnorr",
+            Self::ECMQV => "ecmqv",
+            Self::KDF1_SP800_56A => "kdf1_sp800_56a",
+            Self::KDF2 => "kdf2",
+            Self::KDF1_SP800_108 => "kdf1_sp800_108",
+            Self::ECC => "ecc",
+            Self::SymCipher => "symcipher",
+            Self::Camellia => "camellia",
+            Self::SHA3_256 => "sha3_256",
+            Self::SHA3_384 => "sha3_384",
+            Self::SHA3_512 => "sha3_512",
+            Self::CTR => "ctr",
+            Self::OFB => "ofb",
+            Self::CBC => "cbc",
+            Self::CFB => "cfb",
+            Self::ECB => "ecb",
+        }
+    }
+}
+
+impl str::FromStr for TpmAlgId {
+    type Err = errors::DeserializationError;
+
+    /// Parses an algorithm name as printed by tpm2-tools, or its numeric
+    /// value
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        if let Some(hex) = name.strip_prefix("0x") {
+            return match u16::from_str_radix(hex, 16) {
+                Ok(value) => TpmAlgId::try_from(value),
+                Err(_) => Err(errors::DeserializationError {
+                    msg: format!("invalid algorithm {}", s),
+                }),
+            };
+        }
+        if let Ok(value) = name.parse::<u16>() {
+            return TpmAlgId::try_from(value);
+        }
+        TpmAlgId::ALL
+            .iter()
+            .copied()
+            .find(|alg| alg.name() == name)
+            .ok_or(errors::DeserializationError {
+                msg: format!("unknown algorithm {}", s),
+            })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for TpmAlgId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TpmAlgId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        struct AlgVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for AlgVisitor {
+            type Value = TpmAlgId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "an algorithm name or value")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> result::Result<TpmAlgId, E> {
+                v.parse().map_err(|err: errors::DeserializationError| E::custom(err.msg))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> result::Result<TpmAlgId, E> {
+                u16::try_from(v)
+                    .ok()
+                    .and_then(|value| TpmAlgId::try_from(value).ok())
+                    .ok_or_else(|| E::custom(format!("unknown algorithm {:#x}", v)))
+            }
+        }
+
+        deserializer.deserialize_any(AlgVisitor)
+    }
+}
@@ -0,0 +1,181 @@
+//! TPM Library Part 2 Chapter 6.5 - TPM_CC
+//!
+//! Names and handle area sizes of the TPM 2.0 commands, used to decode
+//! command streams for diagnostics.
+
+use crate::tpm2::types::tcg::TpmCc;
+
+/// Static description of a TPM command
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CommandInfo {
+    pub command_code: TpmCc,
+    /// Name as given in TPM Library Part 3, without the TPM2_ prefix
+    pub name: &'static str,
+    /// Number of handles in the command handle area (Part 3, "Handles"
+    /// rows of each command table). Handles passed as parameters, such as
+    /// the one of TPM2_FlushContext, are not counted.
+    pub handles: usize,
+}
+
+const fn cmd(command_code: TpmCc, name: &'static str, handles: usize) -> CommandInfo {
+    CommandInfo {
+        command_code,
+        name,
+        handles,
+    }
+}
+
+/// All commands defined by TPM Library Part 2 rev 1.59, sorted by command code
+pub const COMMANDS: &[CommandInfo] = &[
+    cmd(0x0000011F, "NV_UndefineSpaceSpecial", 2),
+    cmd(0x00000120, "EvictControl", 2),
+    cmd(0x00000121, "HierarchyControl", 1),
+    cmd(0x00000122, "NV_UndefineSpace", 2),
+    cmd(0x00000124, "ChangeEPS", 1),
+    cmd(0x00000125, "ChangePPS", 1),
+    cmd(0x00000126, "Clear", 1),
+    cmd(0x00000127, "ClearControl", 1),
+    cmd(0x00000128, "ClockSet", 1),
+    cmd(0x00000129, "HierarchyChangeAuth", 1),
+    cmd(0x0000012A, "NV_DefineSpace", 1),
+    cmd(0x0000012B, "PCR_Allocate", 1),
+    cmd(0x0000012C, "PCR_SetAuthPolicy", 1),
+    cmd(0x0000012D, "PP_Commands", 1),
+    cmd(0x0000012E, "SetPrimaryPolicy", 1),
+    cmd(0x0000012F, "FieldUpgradeStart", 2),
+    cmd(0x00000130, "ClockRateAdjust", 1),
+    cmd(0x00000131, "CreatePrimary", 1),
+    cmd(0x00000132, "NV_GlobalWriteLock", 1),
+    cmd(0x00000133, "GetCommandAuditDigest", 2),
+    cmd(0x00000134, "NV_Increment", 2),
+    cmd(0x00000135, "NV_SetBits", 2),
+    cmd(0x00000136, "NV_Extend", 2),
+    cmd(0x00000137, "NV_Write", 2),
+    cmd(0x00000138, "NV_WriteLock", 2),
+    cmd(0x00000139, "DictionaryAttackLockReset", 1),
+    cmd(0x0000013A, "DictionaryAttackParameters", 1),
+    cmd(0x0000013B, "NV_ChangeAuth", 1),
+    cmd(0x0000013C, "PCR_Event", 1),
+    cmd(0x0000013D, "PCR_Reset", 1),
+    cmd(0x0000013E, "SequenceComplete", 1),
+    cmd(0x0000013F, "SetAlgorithmSet", 1),
+    cmd(0x00000140, "SetCommandCodeAuditStatus", 1),
+    cmd(0x00000141, "FieldUpgradeData", 0),
+    cmd(0x00000142, "IncrementalSelfTest", 0),
+    cmd(0x00000143, "SelfTest", 0),
+    cmd(0x00000144, "Startup", 0),
+    cmd(0x00000145, "Shutdown", 0),
+    cmd(0x00000146, "StirRandom", 0),
+    cmd(0x00000147, "ActivateCredential", 2),
+    cmd(0x00000148, "Certify", 2),
+    cmd(0x00000149, "PolicyNV", 3),
+    cmd(0x0000014A, "CertifyCreation", 2),
+    cmd(0x0000014B, "Duplicate", 2),
+    cmd(0x0000014C, "GetTime", 2),
+    cmd(0x0000014D, "GetSessionAuditDigest", 3),
+    cmd(0x0000014E, "NV_Read", 2),
+    cmd(0x0000014F, "NV_ReadLock", 2),
+    cmd(0x00000150, "ObjectChangeAuth", 2),
+    cmd(0x00000151, "PolicySecret", 2),
+    cmd(0x00000152, "Rewrap", 2),
+    cmd(0x00000153, "Create", 1),
+    cmd(0x00000154, "ECDH_ZGen", 1),
+    cmd(0x00000155, "HMAC", 1),
+    cmd(0x00000156, "Import", 1),
+    cmd(0x00000157, "Load", 1),
+    cmd(0x00000158, "Quote", 1),
+    cmd(0x00000159, "RSA_Decrypt", 1),
+    cmd(0x0000015B, "HMAC_Start", 1),
+    cmd(0x0000015C, "SequenceUpdate", 1),
+    cmd(0x0000015D, "Sign", 1),
+    cmd(0x0000015E, "Unseal", 1),
+    cmd(0x00000160, "PolicySigned", 2),
+    cmd(0x00000161, "ContextLoad", 0),
+    cmd(0x00000162, "ContextSave", 1),
+    cmd(0x00000163, "ECDH_KeyGen", 1),
+    cmd(0x00000164, "EncryptDecrypt", 1),
+    cmd(0x00000165, "FlushContext", 0),
+    cmd(0x00000167, "LoadExternal", 0),
+    cmd(0x00000168, "MakeCredential", 1),
+    cmd(0x00000169, "NV_ReadPublic", 1),
+    cmd(0x0000016A, "PolicyAuthorize", 1),
+    cmd(0x0000016B, "PolicyAuthValue", 1),
+    cmd(0x0000016C, "PolicyCommandCode", 1),
+    cmd(0x0000016D, "PolicyCounterTimer", 1),
+    cmd(0x0000016E, "PolicyCpHash", 1),
+    cmd(0x0000016F, "PolicyLocality", 1),
+    cmd(0x00000170, "PolicyNameHash", 1),
+    cmd(0x00000171, "PolicyOR", 1),
+    cmd(0x00000172, "PolicyTicket", 1),
+    cmd(0x00000173, "ReadPublic", 1),
+    cmd(0x00000174, "RSA_Encrypt", 1),
+    cmd(0x00000176, "StartAuthSession", 2),
+    cmd(0x00000177, "VerifySignature", 1),
+    cmd(0x00000178, "ECC_Parameters", 0),
+    cmd(0x00000179, "FirmwareRead", 0),
+    cmd(0x0000017A, "GetCapability", 0),
+    cmd(0x0000017B, "GetRandom", 0),
+    cmd(0x0000017C, "GetTestResult", 0),
+    cmd(0x0000017D, "Hash", 0),
+    cmd(0x0000017E, "PCR_Read", 0),
+    cmd(0x0000017F, "PolicyPCR", 1),
+    cmd(0x00000180, "PolicyRestart", 1),
+    cmd(0x00000181, "ReadClock", 0),
+    cmd(0x00000182, "PCR_Extend", 1),
+    cmd(0x00000183, "PCR_SetAuthValue", 1),
+    cmd(0x00000184, "NV_Certify", 3),
+    cmd(0x00000185, "EventSequenceComplete", 2),
+    cmd(0x00000186, "HashSequenceStart", 0),
+    cmd(0x00000187, "PolicyPhysicalPresence", 1),
+    cmd(0x00000188, "PolicyDuplicationSelect", 1),
+    cmd(0x00000189, "PolicyGetDigest", 1),
+    cmd(0x0000018A, "TestParms", 0),
+    cmd(0x0000018B, "Commit", 1),
+    cmd(0x0000018C, "PolicyPassword", 1),
+    cmd(0x0000018D, "ZGen_2Phase", 1),
+    cmd(0x0000018E, "EC_Ephemeral", 0),
+    cmd(0x0000018F, "PolicyNvWritten", 1),
+    cmd(0x00000190, "PolicyTemplate", 1),
+    cmd(0x00000191, "CreateLoaded", 1),
+    cmd(0x00000192, "PolicyAuthorizeNV", 3),
+    cmd(0x00000193, "EncryptDecrypt2", 1),
+    cmd(0x00000194, "AC_GetCapability", 1),
+    cmd(0x00000195, "AC_Send", 3),
+    cmd(0x00000196, "Policy_AC_SendSelect", 1),
+    cmd(0x00000197, "CertifyX509", 2),
+    cmd(0x00000198, "ACT_SetTimeout", 1),
+    cmd(0x00000199, "ECC_Encrypt", 1),
+    cmd(0x0000019A, "ECC_Decrypt", 1),
+];
+
+/// command_info returns the description of a command, or None for vendor
+/// specific or unknown command codes
+pub fn command_info(command_code: TpmCc) -> Option<&'static CommandInfo> {
+    COMMANDS
+        .binary_search_by_key(&command_code, |c| c.command_code)
+        .ok()
+        .map(|index| &COMMANDS[index])
+}
+
+/// command_name returns the name of a command, or "Unknown" if the command
+/// code is not in COMMANDS
+pub fn command_name(command_code: TpmCc) -> &'static str {
+    match command_info(command_code) {
+        Some(info) => info.name,
+        None => "Unknown",
+    }
+}
+
+/// response_handles returns the number of handles in the response handle
+/// area of a command. Only commands creating a transient object, a sequence
+/// or a session return a handle.
+pub fn response_handles(command_code: TpmCc) -> usize {
+    match command_code {
+        // TPM2_CreatePrimary, TPM2_HMAC_Start, TPM2_Load, TPM2_ContextLoad,
+        // TPM2_LoadExternal, TPM2_StartAuthSession, TPM2_HashSequenceStart,
+        // TPM2_CreateLoaded
+        0x00000131 | 0x0000015B | 0x00000157 | 0x00000161 | 0x00000167 | 0x00000176
+        | 0x00000186 | 0x00000191 => 1,
+        _ => 0,
+    }
+}
@@ -3,3 +3,5 @@ pub use crate::tpm2::types::constants::algid::TpmAlgId;
 
 pub mod rc;
 pub use crate::tpm2::types::constants::rc::TpmRc;
+
+pub mod cc;
@@ -5,8 +5,9 @@
 use crate::tpm2::errors;
 use crate::tpm2::serialization::inout::{RwBytes, Tpm2StructIn};
 
-use std::convert::TryFrom;
-use std::{fmt,result};
+use alloc::string::String;
+use core::convert::TryFrom;
+use core::{fmt,result};
 
 #[cfg(build_rc_strings)]
 use phf::{phf_map};
@@ -131,123 +132,131 @@ impl TpmRc {
 
 // TpmRc does not need to be marshalled out, ie. no Tpm2StructOut trait
 
+impl TryFrom<u32> for TpmRc {
+    type Error = errors::DeserializationError;
+
+    fn try_from(value: u32) -> result::Result<Self, Self::Error> {
+        Ok(match value {
+            a if a == TpmRc::Success as u32 => Self::Success,
+            a if a == TpmRc::BadTag as u32 => Self::BadTag,
+            // TPM_RC_VER1 Section
+            a if a == TpmRc::Ver1 as u32 => Self::INITIALIZE,
+            a if a == TpmRc::Failure as u32 => Self::Failure,
+            a if a == TpmRc::Sequence as u32 => Self::Sequence,
+            a if a == TpmRc::Private as u32 => Self::Private,
+            a if a == TpmRc::HMAC as u32 => Self::HMAC,
+            a if a == TpmRc::Disabled as u32 => Self::Disabled,
+            a if a == TpmRc::Exclusive as u32 => Self::Exclusive,
+            a if a == TpmRc::AuthType as u32 => Self::AuthType,
+            a if a == TpmRc::AuthMissing as u32 => Self::AuthMissing,
+            a if a == TpmRc::Policy as u32 => Self::Policy,
+            a if a == TpmRc::Pcr as u32 => Self::Pcr,
+            a if a == TpmRc::PcrChanged as u32 => Self::PcrChanged,
+            a if a == TpmRc::Upgrade as u32 => Self::Upgrade,
+            a if a == TpmRc::TooManyContexts as u32 => Self::TooManyContexts,
+            a if a == TpmRc::AuthUnavailable as u32 => Self::AuthUnavailable,
+            a if a == TpmRc::Reboot as u32 => Self::Reboot,
+            a if a == TpmRc::Unbalanced as u32 => Self::Unbalanced,
+            a if a == TpmRc::CommandSize as u32 => Self::CommandSize,
+            a if a == TpmRc::CommandCode as u32 => Self::CommandCode,
+            a if a == TpmRc::AuthSize as u32 => Self::AuthSize,
+            a if a == TpmRc::AuthContext as u32 => Self::AuthContext,
+            a if a == TpmRc::NvRange as u32 => Self::NvRange,
+            a if a == TpmRc::NvSize as u32 => Self::NvSize,
+            a if a == TpmRc::NvLocked as u32 => Self::NvLocked,
+            a if a == TpmRc::NvAuthorization as u32 => Self::NvAuthorization,
+            a if a == TpmRc::NvUninitialized as u32 => Self::NvUninitialized,
+            a if a == TpmRc::NvSpace as u32 => Self::NvSpace,
+            a if a == TpmRc::NvDefined as u32 => Self::NvDefined,
+            a if a == TpmRc::BadContext as u32 => Self::BadContext,
+            a if a == TpmRc::CpHash as u32 => Self::CpHash,
+            a if a == TpmRc::Parent as u32 => Self::Parent,
+            a if a == TpmRc::NeedsTest as u32 => Self::NeedsTest,
+            a if a == TpmRc::NoResult as u32 => Self::NoResult,
+            a if a == TpmRc::Sensitive as u32 => Self::Sensitive,
+            a if a == TpmRc::MaxFM0 as u32 => Self::MaxFM0,
+            //TPM_RC_FMT1 Ssection
+            a if a == TpmRc::Fmt1 as u32 => Self::Fmt1,
+            a if a == TpmRc::Asymmetric as u32 => Self::Asymmetric,
+            a if a == TpmRc::Attributes as u32 => Self::Attributes,
+            a if a == TpmRc::Hash as u32 => Self::Hash,
+            a if a == TpmRc::Value as u32 => Self::Value,
+            a if a == TpmRc::Hierarchy as u32 => Self::Hierarchy,
+            a if a == TpmRc::KeySize as u32 => Self::KeySize,
+            a if a == TpmRc::Mgf as u32 => Self::Mgf,
+            a if a == TpmRc::Mode as u32 => Self::Mode,
+            a if a == TpmRc::Type as u32 => Self::Type,
+            a if a == TpmRc::Handle as u32 => Self::Handle,
+            a if a == TpmRc::Kdf as u32 => Self::Kdf,
+            a if a == TpmRc::Range as u32 => Self::Range,
+            a if a == TpmRc::AuthFail as u32 => Self::AuthFail,
+            a if a == TpmRc::Nonce as u32 => Self::Nonce,
+            a if a == TpmRc::Pp as u32 => Self::Pp,
+            a if a == TpmRc::Scheme as u32 => Self::Scheme,
+            a if a == TpmRc::Size as u32 => Self::Size,
+            a if a == TpmRc::Symmetric as u32 => Self::Symmetric,
+            a if a == TpmRc::Tag as u32 => Self::Tag,
+            a if a == TpmRc::Selector as u32 => Self::Selector,
+            a if a == TpmRc::Insufficient as u32 => Self::Insufficient,
+            a if a == TpmRc::Signature as u32 => Self::Signature,
+            a if a == TpmRc::Key as u32 => Self::Key,
+            a if a == TpmRc::PolicyFail as u32 => Self::PolicyFail,
+            a if a == TpmRc::Integrity as u32 => Self::Integrity,
+            a if a == TpmRc::Ticket as u32 => Self::Ticket,
+            a if a == TpmRc::ReservedBits as u32 => Self::ReservedBits,
+            a if a == TpmRc::BadAuth as u32 => Self::BadAuth,
+            a if a == TpmRc::Expired as u32 => Self::Expired,
+            a if a == TpmRc::PolicyCc as u32 => Self::PolicyCc,
+            a if a == TpmRc::Binding as u32 => Self::Binding,
+            a if a == TpmRc::Curve as u32 => Self::Curve,
+            a if a == TpmRc::EccPoint as u32 => Self::EccPoint,
+            //TPM_RC_WARN Ssection
+            a if a == TpmRc::Warn as u32 => Self::Warn,
+            a if a == TpmRc::ContextGap as u32 => Self::ContextGap,
+            a if a == TpmRc::ObjectMemory as u32 => Self::ObjectMemory,
+            a if a == TpmRc::SessionMemory as u32 => Self::SessionMemory,
+            a if a == TpmRc::Memory as u32 => Self::Memory,
+            a if a == TpmRc::SessionHandles as u32 => Self::SessionHandles,
+            a if a == TpmRc::ObjectHandles as u32 => Self::ObjectHandles,
+            a if a == TpmRc::Locality as u32 => Self::Locality,
+            a if a == TpmRc::Yielded as u32 => Self::Yielded,
+            a if a == TpmRc::Canceled as u32 => Self::Canceled,
+            a if a == TpmRc::Testing as u32 => Self::Testing,
+            a if a == TpmRc::ReferenceH0 as u32 => Self::ReferenceH0,
+            a if a == TpmRc::ReferenceH1 as u32 => Self::ReferenceH1,
+            a if a == TpmRc::ReferenceH2 as u32 => Self::ReferenceH2,
+            a if a == TpmRc::ReferenceH3 as u32 => Self::ReferenceH3,
+            a if a == TpmRc::ReferenceH4 as u32 => Self::ReferenceH4,
+            a if a == TpmRc::ReferenceH5 as u32 => Self::ReferenceH5,
+            a if a == TpmRc::ReferenceH6 as u32 => Self::ReferenceH6,
+            a if a == TpmRc::ReferenceS0 as u32 => Self::ReferenceS0,
+            a if a == TpmRc::ReferenceS1 as u32 => Self::ReferenceS1,
+            a if a == TpmRc::ReferenceS2 as u32 => Self::ReferenceS2,
+            a if a == TpmRc::ReferenceS3 as u32 => Self::ReferenceS3,
+            a if a == TpmRc::ReferenceS4 as u32 => Self::ReferenceS4,
+            a if a == TpmRc::ReferenceS5 as u32 => Self::ReferenceS5,
+            a if a == TpmRc::ReferenceS6 as u32 => Self::ReferenceS6,
+            a if a == TpmRc::NvRate as u32 => Self::NvRate,
+            a if a == TpmRc::Lockout as u32 => Self::Lockout,
+            a if a == TpmRc::Retry as u32 => Self::Retry,
+            a if a == TpmRc::NvUnavailable as u32 => Self::NvUnavailable,
+            a if a == TpmRc::NotUsed as u32 => Self::NotUsed,
+            _ => return Err(errors::DeserializationError {
+                msg: format!("unknown response code {:#x}", value),
+            }),
+        })
+    }
+}
+
 impl Tpm2StructIn for TpmRc {
     fn unpack(
         &mut self,
         buff: &mut dyn RwBytes,
     ) -> result::Result<(), errors::DeserializationError> {
-        let byte_array = <[u8; size_of!(TpmRc)]>::try_from(&buff.read_bytes(size_of!(TpmRc))[..]);
+        let byte_array = <[u8; size_of!(TpmRc)]>::try_from(&buff.read_bytes(size_of!(TpmRc))?[..]);
         match byte_array {
             Ok(byte_array) => {
-                *self = match u32::from_be_bytes(byte_array) {
-                    a if a == TpmRc::Success as u32 => Self::Success,
-                    a if a == TpmRc::BadTag as u32 => Self::BadTag,
-                    // TPM_RC_VER1 Section
-                    a if a == TpmRc::Ver1 as u32 => Self::INITIALIZE,
-                    a if a == TpmRc::Failure as u32 => Self::Failure,
-                    a if a == TpmRc::Sequence as u32 => Self::Sequence,
-                    a if a == TpmRc::Private as u32 => Self::Private,
-                    a if a == TpmRc::HMAC as u32 => Self::HMAC,
-                    a if a == TpmRc::Disabled as u32 => Self::Disabled,
-                    a if a == TpmRc::Exclusive as u32 => Self::Exclusive,
-                    a if a == TpmRc::AuthType as u32 => Self::AuthType,
-                    a if a == TpmRc::AuthMissing as u32 => Self::AuthMissing,
-                    a if a == TpmRc::Policy as u32 => Self::Policy,
-                    a if a == TpmRc::Pcr as u32 => Self::Pcr,
-                    a if a == TpmRc::PcrChanged as u32 => Self::PcrChanged,
-                    a if a == TpmRc::Upgrade as u32 => Self::Upgrade,
-                    a if a == TpmRc::TooManyContexts as u32 => Self::TooManyContexts,
-                    a if a == TpmRc::AuthUnavailable as u32 => Self::AuthUnavailable,
-                    a if a == TpmRc::Reboot as u32 => Self::Reboot,
-                    a if a == TpmRc::Unbalanced as u32 => Self::Unbalanced,
-                    a if a == TpmRc::CommandSize as u32 => Self::CommandSize,
-                    a if a == TpmRc::CommandCode as u32 => Self::CommandCode,
-                    a if a == TpmRc::AuthSize as u32 => Self::AuthSize,
-                    a if a == TpmRc::AuthContext as u32 => Self::AuthContext,
-                    a if a == TpmRc::NvRange as u32 => Self::NvRange,
-                    a if a == TpmRc::NvSize as u32 => Self::NvSize,
-                    a if a == TpmRc::NvLocked as u32 => Self::NvLocked,
-                    a if a == TpmRc::NvAuthorization as u32 => Self::NvAuthorization,
-                    a if a == TpmRc::NvUninitialized as u32 => Self::NvUninitialized,
-                    a if a == TpmRc::NvSpace as u32 => Self::NvSpace,
-                    a if a == TpmRc::NvDefined as u32 => Self::NvDefined,
-                    a if a == TpmRc::BadContext as u32 => Self::BadContext,
-                    a if a == TpmRc::CpHash as u32 => Self::CpHash,
-                    a if a == TpmRc::Parent as u32 => Self::Parent,
-                    a if a == TpmRc::NeedsTest as u32 => Self::NeedsTest,
-                    a if a == TpmRc::NoResult as u32 => Self::NoResult,
-                    a if a == TpmRc::Sensitive as u32 => Self::Sensitive,
-                    a if a == TpmRc::MaxFM0 as u32 => Self::MaxFM0,
-                    //TPM_RC_FMT1 Ssection
-                    a if a == TpmRc::Fmt1 as u32 => Self::Fmt1,
-                    a if a == TpmRc::Asymmetric as u32 => Self::Asymmetric,
-                    a if a == TpmRc::Attributes as u32 => Self::Attributes,
-                    a if a == TpmRc::Hash as u32 => Self::Hash,
-                    a if a == TpmRc::Value as u32 => Self::Value,
-                    a if a == TpmRc::Hierarchy as u32 => Self::Hierarchy,
-                    a if a == TpmRc::KeySize as u32 => Self::KeySize,
-                    a if a == TpmRc::Mgf as u32 => Self::Mgf,
-                    a if a == TpmRc::Mode as u32 => Self::Mode,
-                    a if a == TpmRc::Type as u32 => Self::Type,
-                    a if a == TpmRc::Handle as u32 => Self::Handle,
-                    a if a == TpmRc::Kdf as u32 => Self::Kdf,
-                    a if a == TpmRc::Range as u32 => Self::Range,
-                    a if a == TpmRc::AuthFail as u32 => Self::AuthFail,
-                    a if a == TpmRc::Nonce as u32 => Self::Nonce,
-                    a if a == TpmRc::Pp as u32 => Self::Pp,
-                    a if a == TpmRc::Scheme as u32 => Self::Scheme,
-                    a if a == TpmRc::Size as u32 => Self::Size,
-                    a if a == TpmRc::Symmetric as u32 => Self::Symmetric,
-                    a if a == TpmRc::Tag as u32 => Self::Tag,
-                    a if a == TpmRc::Selector as u32 => Self::Selector,
-                    a if a == TpmRc::Insufficient as u32 => Self::Insufficient,
-                    a if a == TpmRc::Signature as u32 => Self::Signature,
-                    a if a == TpmRc::Key as u32 => Self::Key,
-                    a if a == TpmRc::PolicyFail as u32 => Self::PolicyFail,
-                    a if a == TpmRc::Integrity as u32 => Self::Integrity,
-                    a if a == TpmRc::Ticket as u32 => Self::Ticket,
-                    a if a == TpmRc::ReservedBits as u32 => Self::ReservedBits,
-                    a if a == TpmRc::BadAuth as u32 => Self::BadAuth,
-                    a if a == TpmRc::Expired as u32 => Self::Expired,
-                    a if a == TpmRc::PolicyCc as u32 => Self::PolicyCc,
-                    a if a == TpmRc::Binding as u32 => Self::Binding,
-                    a if a == TpmRc::Curve as u32 => Self::Curve,
-                    a if a == TpmRc::EccPoint as u32 => Self::EccPoint,
-                    //TPM_RC_WARN Ssection
-                    a if a == TpmRc::Warn as u32 => Self::Warn,
-                    a if a == TpmRc::ContextGap as u32 => Self::ContextGap,
-                    a if a == TpmRc::ObjectMemory as u32 => Self::ObjectMemory,
-                    a if a == TpmRc::SessionMemory as u32 => Self::SessionMemory,
-                    a if a == TpmRc::Memory as u32 => Self::Memory,
-                    a if a == TpmRc::SessionHandles as u32 => Self::SessionHandles,
-                    a if a == TpmRc::ObjectHandles as u32 => Self::ObjectHandles,
-                    a if a == TpmRc::Locality as u32 => Self::Locality,
-                    a if a == TpmRc::Yielded as u32 => Self::Yielded,
-                    a if a == TpmRc::Canceled as u32 => Self::Canceled,
-                    a if a == TpmRc::Testing as u32 => Self::Testing,
-                    a if a == TpmRc::ReferenceH0 as u32 => Self::ReferenceH0,
-                    a if a == TpmRc::ReferenceH1 as u32 => Self::ReferenceH1,
-                    a if a == TpmRc::ReferenceH2 as u32 => Self::ReferenceH2,
-                    a if a == TpmRc::ReferenceH3 as u32 => Self::ReferenceH3,
-                    a if a == TpmRc::ReferenceH4 as u32 => Self::ReferenceH4,
-                    a if a == TpmRc::ReferenceH5 as u32 => Self::ReferenceH5,
-                    a if a == TpmRc::ReferenceH6 as u32 => Self::ReferenceH6,
-                    a if a == TpmRc::ReferenceS0 as u32 => Self::ReferenceS0,
-                    a if a == TpmRc::ReferenceS1 as u32 => Self::ReferenceS1,
-                    a if a == TpmRc::ReferenceS2 as u32 => Self::ReferenceS2,
-                    a if a == TpmRc::ReferenceS3 as u32 => Self::ReferenceS3,
-                    a if a == TpmRc::ReferenceS4 as u32 => Self::ReferenceS4,
-                    a if a == TpmRc::ReferenceS5 as u32 => Self::ReferenceS5,
-                    a if a == TpmRc::ReferenceS6 as u32 => Self::ReferenceS6,
-                    a if a == TpmRc::NvRate as u32 => Self::NvRate,
-                    a if a == TpmRc::Lockout as u32 => Self::Lockout,
-                    a if a == TpmRc::Retry as u32 => Self::Retry,
-                    a if a == TpmRc::NvUnavailable as u32 => Self::NvUnavailable,
-                    a if a == TpmRc::NotUsed as u32 => Self::NotUsed,
-                    _ => return Err(errors::DeserializationError {
-                        msg: String::from("could not prepare byteArray"),
-                    }),
-                };
+                *self = TpmRc::try_from(u32::from_be_bytes(byte_array))?;
                 Ok(())
             }
             Err(_) => Err(errors::DeserializationError {
@@ -371,3 +380,47 @@ impl fmt::Display for TpmRc {
         write!(f, "{:#x}", &(*self as u32))
     }
 }
+
+// TpmRc is serialized as its numeric value in hexadecimal, the way tpm2-tools
+// prints response codes
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for TpmRc {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:#x}", *self as u32))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TpmRc {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        struct RcVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for RcVisitor {
+            type Value = TpmRc;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a response code")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> result::Result<TpmRc, E> {
+                let value = match v.strip_prefix("0x").or_else(|| v.strip_prefix("0X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => v.parse::<u32>(),
+                };
+                match value {
+                    Ok(value) => self.visit_u64(value as u64),
+                    Err(_) => Err(E::custom(format!("invalid response code {}", v))),
+                }
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> result::Result<TpmRc, E> {
+                u32::try_from(v)
+                    .map_err(|_| E::custom(format!("invalid response code {:#x}", v)))
+                    .and_then(|value| TpmRc::try_from(value).map_err(|err| E::custom(err.msg)))
+            }
+        }
+
+        deserializer.deserialize_any(RcVisitor)
+    }
+}
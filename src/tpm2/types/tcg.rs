@@ -1,9 +1,18 @@
 use crate::tpm2::errors;
 use crate::tpm2::serialization::inout;
 use crate::tpm2::serialization::inout::{RwBytes, Tpm2StructOut};
+#[cfg(feature = "serde")]
+use crate::tpm2::serialization::tools;
 use crate::tpm2::types::constants::TpmAlgId;
 
-use std::{mem, result, fmt, str};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::{mem, result, str};
+
+#[cfg(feature = "serde")]
+use alloc::collections::BTreeMap;
+#[cfg(feature = "serde")]
+use core::convert::TryFrom;
 
 use aes;
 use aes::cipher::{AsyncStreamCipher, KeyIvInit};
@@ -21,6 +30,8 @@ use rand;
 use rsa;
 use rsa::{Oaep, PublicKey, PublicKeyParts};
 
+use tpm2_derive::{Tpm2StructIn, Tpm2StructOut};
+
 // Types
 pub type TpmiStCommandTag = u16;
 pub type TpmCc = u32;
@@ -28,14 +39,18 @@ pub type TpmSu = u16;
 pub type TpmaObject = u32;
 pub type TpmaSession = u8;
 pub type TpmKeyBits = u16;
+pub type TpmEccCurve = u16;
 
 pub type Handle = u32;
 
 pub type TpmiShAuthSession = Handle;
 
+pub const TPM_RH_OWNER: Handle = 0x40000001;
 pub const TPM_RH_NULL: Handle = 0x40000007;
 pub const TPM_RS_PW: Handle = 0x40000009;
+pub const TPM_RH_LOCKOUT: Handle = 0x4000000A;
 pub const TPM_RH_ENDORSEMENT: Handle = 0x4000000B;
+pub const TPM_RH_PLATFORM: Handle = 0x4000000C;
 
 pub const TPMA_SESSION_CONTINUE_SESSION: TpmaSession = 0x1;
 
@@ -44,6 +59,7 @@ pub type TpmiAlgPublic = TpmAlgId;
 pub type TpmiAlgHash = TpmAlgId;
 pub type TpmiAlgKdf = TpmAlgId;
 pub type TpmiAlgRsaScheme = TpmAlgId;
+pub type TpmiAlgEccScheme = TpmAlgId;
 pub type TpmiAlgSym = TpmAlgId;
 pub type TpmiAlgSymObject = TpmAlgId;
 pub type TpmiAlgSymMode = TpmAlgId;
@@ -51,13 +67,29 @@ pub type TpmiAlgSymMode = TpmAlgId;
 pub type TpmiAlgKeyedHashScheme = TpmAlgId;
 
 pub type TpmiRsaKeyBits = TpmKeyBits;
+pub type TpmiEccCurve = TpmEccCurve;
 
 pub type TpmSe = u8;
 
+pub type TpmiYesNo = u8;
+pub type TpmiStAttest = u16;
+pub type TpmGenerated = u32;
+
 pub const TPM_SE_HMAC: TpmSe = 0x00;
 pub const TPM_SE_POLICY: TpmSe = 0x01;
 pub const TPM_SE_TRIAL: TpmSe = 0x03;
 
+// ECC curves
+pub const TPM_ECC_NONE: TpmEccCurve = 0x0000;
+pub const TPM_ECC_NIST_P192: TpmEccCurve = 0x0001;
+pub const TPM_ECC_NIST_P224: TpmEccCurve = 0x0002;
+pub const TPM_ECC_NIST_P256: TpmEccCurve = 0x0003;
+pub const TPM_ECC_NIST_P384: TpmEccCurve = 0x0004;
+pub const TPM_ECC_NIST_P521: TpmEccCurve = 0x0005;
+pub const TPM_ECC_BN_P256: TpmEccCurve = 0x0010;
+pub const TPM_ECC_BN_P638: TpmEccCurve = 0x0011;
+pub const TPM_ECC_SM2_P256: TpmEccCurve = 0x0020;
+
 // TPM2 command codes
 pub const TPM_CC_PCR_READ: TpmCc = 0x0000017E;
 pub const TPM_CC_STARTUP: TpmCc = 0x00000144;
@@ -66,15 +98,78 @@ pub const TPM_CC_UNSEAL: TpmCc = 0x0000015E;
 pub const TPM_CC_POLICY_SECRET: TpmCc = 0x00000151;
 pub const TPM_START_AUTH_SESSION: TpmCc = 0x00000176;
 pub const TPM_CC_LOAD: TpmCc = 0x00000157;
+pub const TPM_CC_CONTEXT_LOAD: TpmCc = 0x00000161;
+pub const TPM_CC_CONTEXT_SAVE: TpmCc = 0x00000162;
+pub const TPM_CC_FLUSH_CONTEXT: TpmCc = 0x00000165;
+pub const TPM_CC_EVICT_CONTROL: TpmCc = 0x00000120;
+pub const TPM_CC_CLEAR: TpmCc = 0x00000126;
+pub const TPM_CC_HIERARCHY_CHANGE_AUTH: TpmCc = 0x00000129;
+pub const TPM_CC_QUOTE: TpmCc = 0x00000158;
+pub const TPM_CC_HIERARCHY_CONTROL: TpmCc = 0x00000121;
+pub const TPM_CC_CREATE_PRIMARY: TpmCc = 0x00000131;
+pub const TPM_CC_SEQUENCE_COMPLETE: TpmCc = 0x0000013E;
+pub const TPM_CC_SELF_TEST: TpmCc = 0x00000143;
+pub const TPM_CC_NV_READ: TpmCc = 0x0000014E;
+pub const TPM_CC_CREATE: TpmCc = 0x00000153;
+pub const TPM_CC_SEQUENCE_UPDATE: TpmCc = 0x0000015C;
+pub const TPM_CC_VERIFY_SIGNATURE: TpmCc = 0x00000177;
+pub const TPM_CC_GET_CAPABILITY: TpmCc = 0x0000017A;
+pub const TPM_CC_GET_RANDOM: TpmCc = 0x0000017B;
+pub const TPM_CC_PCR_EXTEND: TpmCc = 0x00000182;
+pub const TPM_CC_EVENT_SEQUENCE_COMPLETE: TpmCc = 0x00000185;
+pub const TPM_CC_HASH_SEQUENCE_START: TpmCc = 0x00000186;
+pub const TPM_CC_CREATE_LOADED: TpmCc = 0x00000191;
 
 pub const TPM2_NUM_PCR_BANKS: usize = 16;
 pub const TPM2_MAX_PCRS: usize = 24;
 pub const HASH_SIZE: usize = 512;
-pub const RSA_KEY_NAX_NUM_BYTES: usize = 256;
 pub const TPM2_PCR_SELECT_MAX: usize = (TPM2_MAX_PCRS + 7) / 8;
 pub const MAX_SYM_DATA: usize = 128;
 pub const RSA_KEY_NUM_BYTES: usize = 2048;
 pub const MAX_SEED_LEN: usize = 32;
+// MAX_ECC_KEY_BYTES is the size of the largest ECC parameter, for NIST P-521
+pub const MAX_ECC_KEY_BYTES: usize = 66;
+pub const LABEL_MAX_BUFFER: usize = 32;
+// MAX_DATA_SIZE is the size of TPMT_HA, which TPM2B_DATA is defined upon
+pub const MAX_DATA_SIZE: usize = 2 + MAX_HASH_SIZE;
+// MAX_RSA_KEY_BYTES is the size of the modulus of the largest RSA key
+// supported, RSA 4096
+pub const MAX_RSA_KEY_BYTES: usize = 512;
+// MAX_SYM_KEY_BYTES is the size of the largest symmetric key, AES-256
+pub const MAX_SYM_KEY_BYTES: usize = 32;
+// RSA_PRIVATE_SIZE is the size of a TPM2B_PRIVATE_KEY_RSA, a prime of the
+// largest RSA key
+pub const RSA_PRIVATE_SIZE: usize = MAX_RSA_KEY_BYTES / 2;
+// PRIVATE_VENDOR_SPECIFIC_BYTES is the size of a
+// TPM2B_PRIVATE_VENDOR_SPECIFIC, five halves of the largest RSA key
+pub const PRIVATE_VENDOR_SPECIFIC_BYTES: usize = MAX_RSA_KEY_BYTES / 2 * 5;
+// MAX_SENSITIVE_COMPOSITE_SIZE is sizeof(TPMU_SENSITIVE_COMPOSITE), the size
+// of its largest member
+pub const MAX_SENSITIVE_COMPOSITE_SIZE: usize = 2 + max_size(&[
+    RSA_PRIVATE_SIZE,
+    PRIVATE_VENDOR_SPECIFIC_BYTES,
+    MAX_ECC_KEY_BYTES,
+    MAX_SYM_DATA,
+    MAX_SYM_KEY_BYTES,
+]);
+// MAX_SENSITIVE_SIZE bounds a marshalled TPMT_SENSITIVE: its type, auth and
+// seed values, and its sensitive area
+pub const MAX_SENSITIVE_SIZE: usize = 2 + 2 * (2 + MAX_HASH_SIZE) + MAX_SENSITIVE_COMPOSITE_SIZE;
+// MAX_PRIVATE_SIZE is the size of _PRIVATE, the outer and inner integrity
+// digests followed by a TPM2B_SENSITIVE
+pub const MAX_PRIVATE_SIZE: usize = 2 * (2 + MAX_HASH_SIZE) + 2 + MAX_SENSITIVE_SIZE;
+
+const fn max_size(sizes: &[usize]) -> usize {
+    let mut max = 0;
+    let mut i = 0;
+    while i < sizes.len() {
+        if sizes[i] > max {
+            max = sizes[i];
+        }
+        i += 1;
+    }
+    max
+}
 
 // TPM2 startup types
 pub const TPM_SU_CLEAR: TpmSu = 0x0000;
@@ -84,64 +179,142 @@ pub const TPM_SU_STATE: TpmSu = 0x0001;
 pub const TPM_ST_NO_SESSION: TpmiStCommandTag = 0x8001;
 pub const TPM_ST_SESSIONS: TpmiStCommandTag = 0x8002;
 
+// Attestation structure tags
+pub const TPM_ST_ATTEST_NV: TpmiStAttest = 0x8014;
+pub const TPM_ST_ATTEST_COMMAND_AUDIT: TpmiStAttest = 0x8015;
+pub const TPM_ST_ATTEST_SESSION_AUDIT: TpmiStAttest = 0x8016;
+pub const TPM_ST_ATTEST_CERTIFY: TpmiStAttest = 0x8017;
+pub const TPM_ST_ATTEST_QUOTE: TpmiStAttest = 0x8018;
+pub const TPM_ST_ATTEST_TIME: TpmiStAttest = 0x8019;
+pub const TPM_ST_ATTEST_CREATION: TpmiStAttest = 0x801A;
+pub const TPM_ST_ATTEST_NV_DIGEST: TpmiStAttest = 0x801C;
+
+// TPM_GENERATED_VALUE is the magic of every attestation structure produced
+// by a TPM
+pub const TPM_GENERATED_VALUE: TpmGenerated = 0xff544347;
+
 // MAX_HASH_SIZE represents the size of the longest hash digest supported (sha512)
 pub const MAX_HASH_SIZE: usize = 64;
 
-// TPM2B_DIGEST
-#[derive(Copy, Clone, Debug)]
-pub struct Tpm2bDigest {
-    pub size: u16,
-    pub buffer: [u8; MAX_HASH_SIZE],
+// Tpm2bBuffer is a TPM2B structure holding at most MAX bytes. The size
+// prefix is computed when packing, and checked against MAX both when packing
+// and when unpacking.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tpm2bBuffer<const MAX: usize> {
+    buffer: Vec<u8>,
+}
+
+impl<const MAX: usize> Tpm2bBuffer<MAX> {
+    pub fn new() -> Self {
+        Tpm2bBuffer { buffer: Vec::new() }
+    }
+
+    pub fn from_vec(buffer: Vec<u8>) -> Self {
+        Tpm2bBuffer { buffer }
+    }
+
+    pub fn from_slice(buffer: &[u8]) -> Self {
+        Tpm2bBuffer::from_vec(buffer.to_vec())
+    }
+
+    pub fn get_buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buffer
+    }
 }
 
-impl inout::Tpm2StructOut for Tpm2bDigest {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.size.pack(buff);
-        buff.write_bytes(&self.buffer[0..self.size as usize]);
+impl<const MAX: usize> inout::Tpm2StructOut for Tpm2bBuffer<MAX> {
+    fn pack(
+        &self,
+        buff: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), errors::SerializationError> {
+        if self.buffer.len() > MAX {
+            return Err(errors::SerializationError {
+                msg: format!(
+                    "sized buffer of size {} exceeds maximum of {}",
+                    self.buffer.len(),
+                    MAX
+                ),
+            });
+        }
+        (self.buffer.len() as u16).pack(buff)?;
+        buff.write_bytes(&self.buffer)
     }
 }
 
-impl inout::Tpm2StructIn for Tpm2bDigest {
+impl<const MAX: usize> inout::Tpm2StructIn for Tpm2bBuffer<MAX> {
     fn unpack(
         &mut self,
         buff: &mut dyn inout::RwBytes,
     ) -> result::Result<(), errors::DeserializationError> {
-        self.size.unpack(buff)?;
-        self.buffer[0..self.size as usize].clone_from_slice(buff.read_bytes(self.size as usize));
+        let mut size: u16 = 0;
+        size.unpack(buff)?;
+        check_size("sized buffer", size as usize, MAX)?;
+        self.buffer = buff.read_bytes(size as usize)?.to_vec();
         Ok(())
     }
 }
 
-// Structures defined as TPM2B_DIGEST
-pub type Tpm2bAuth = Tpm2bDigest;
-pub type Tpm2bNonce = Tpm2bDigest;
-
-impl Tpm2bDigest {
-    pub fn new() -> Self {
-        Tpm2bDigest {
-            size: 0,
-            buffer: [0; MAX_HASH_SIZE],
-        }
+// Tpm2bBuffer serializes as a hex string, the way tpm2_readpublic prints
+// buffers
+#[cfg(feature = "serde")]
+impl<const MAX: usize> serde::Serialize for Tpm2bBuffer<MAX> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(&self.buffer))
     }
-    pub fn get_buffer(&self) -> &[u8] {
-        &self.buffer[..]
+}
+
+#[cfg(feature = "serde")]
+impl<'de, const MAX: usize> serde::Deserialize<'de> for Tpm2bBuffer<MAX> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let buffer = tools::decode_hex(&value).map_err(serde::de::Error::custom)?;
+        check_size("sized buffer", buffer.len(), MAX)
+            .map_err(|err| serde::de::Error::custom(err.msg))?;
+        Ok(Tpm2bBuffer { buffer })
     }
+}
 
-    pub fn from_vec(size: u16, buffer: &[u8]) -> Self {
-        let mut digest_buffer = [0; MAX_HASH_SIZE];
-        digest_buffer[0..size as usize].clone_from_slice(buffer);
-        Tpm2bDigest {
-            size: size,
-            buffer: digest_buffer,
-        }
+// TPM2B_DIGEST
+pub type Tpm2bDigest = Tpm2bBuffer<MAX_HASH_SIZE>;
+
+// check_size fails if a size or count field read from the TPM exceeds the
+// capacity of the structure it is unpacked into
+fn check_size(
+    what: &str,
+    size: usize,
+    max: usize,
+) -> result::Result<(), errors::DeserializationError> {
+    if size > max {
+        return Err(errors::DeserializationError {
+            msg: format!("{} of size {} exceeds maximum of {}", what, size, max),
+        });
     }
+    Ok(())
 }
 
+// Structures defined as TPM2B_DIGEST
+pub type Tpm2bAuth = Tpm2bDigest;
+pub type Tpm2bNonce = Tpm2bDigest;
+
 // TPML_DIGEST
+#[derive(Tpm2StructIn)]
 pub struct TpmlDigest {
     count: u32,
     // digests can contain at most 8 entries. From TPM 2.0 Spec, Structures,
     // TPML_DIGEST is defined as digests[count]{:8}
+    #[count = "count"]
     digests: [Tpm2bDigest; 8],
 }
 
@@ -149,7 +322,7 @@ impl TpmlDigest {
     pub fn new() -> Self {
         TpmlDigest {
             count: 0,
-            digests: [Tpm2bDigest::new(); 8],
+            digests: Default::default(),
         }
     }
     pub fn get_digest(
@@ -168,12 +341,12 @@ impl TpmlDigest {
     }
 
     pub fn num_digests(&self) -> u32 {
-        return self.count;
+        self.count
     }
 }
 
 // TPMS_PCR_SELECTION
-#[derive(Copy, Clone, Default, Debug)]
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
 pub struct TpmsPcrSelection {
     pub hash: TpmAlgId,
     pub sizeof_select: u8,
@@ -191,10 +364,22 @@ impl TpmsPcrSelection {
 }
 
 impl inout::Tpm2StructOut for TpmsPcrSelection {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.hash.pack(buff);
-        self.sizeof_select.pack(buff);
-        buff.write_bytes(&self.pcr_select);
+    fn pack(
+        &self,
+        buff: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), errors::SerializationError> {
+        if self.sizeof_select as usize > TPM2_PCR_SELECT_MAX {
+            return Err(errors::SerializationError {
+                msg: format!(
+                    "pcr selection of size {} exceeds maximum of {}",
+                    self.sizeof_select, TPM2_PCR_SELECT_MAX
+                ),
+            });
+        }
+        self.hash.pack(buff)?;
+        self.sizeof_select.pack(buff)?;
+        buff.write_bytes(&self.pcr_select[..self.sizeof_select as usize])?;
+        Ok(())
     }
 }
 
@@ -205,32 +390,23 @@ impl inout::Tpm2StructIn for TpmsPcrSelection {
     ) -> result::Result<(), errors::DeserializationError> {
         self.hash.unpack(buff)?;
         self.sizeof_select.unpack(buff)?;
-        self.pcr_select
-            .clone_from_slice(buff.read_bytes(self.sizeof_select as usize));
-        Ok(())
-    }
-}
-
-impl inout::Tpm2StructIn for TpmlDigest {
-    fn unpack(
-        &mut self,
-        buff: &mut dyn inout::RwBytes,
-    ) -> result::Result<(), errors::DeserializationError> {
-        self.count.unpack(buff)?;
-        for _pcr_count in 0..self.count {
-            let mut size: u16 = 0;
-            size.unpack(buff)?;
-            let buffer = buff.read_bytes(size as usize);
-            self.digests[_pcr_count as usize] = Tpm2bDigest::from_vec(size, buffer);
-        }
+        check_size(
+            "pcr selection",
+            self.sizeof_select as usize,
+            TPM2_PCR_SELECT_MAX,
+        )?;
+        self.pcr_select = [0; TPM2_PCR_SELECT_MAX];
+        self.pcr_select[0..self.sizeof_select as usize]
+            .clone_from_slice(buff.read_bytes(self.sizeof_select as usize)?);
         Ok(())
     }
 }
 
 // TPML_PCR_SELECTION
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmlPcrSelection {
     pub count: u32,
+    #[count = "count"]
     pub pcr_selections: [TpmsPcrSelection; TPM2_NUM_PCR_BANKS],
 }
 
@@ -243,132 +419,109 @@ impl TpmlPcrSelection {
     }
 }
 
-impl inout::Tpm2StructOut for TpmlPcrSelection {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.count.pack(buff);
-        let mut count = 0;
-        for pcr_selection in self.pcr_selections.iter() {
-            if count >= self.count {
-                break;
-            }
-            pcr_selection.pack(buff);
-            count += 1;
-        }
+// TpmlPcrSelection serializes as the PCR selections printed by tpm2-tools,
+// i.e. a list of banks mapping the hash algorithm to the selected PCR
+// indexes, e.g. [{sha256: [0, 1, 2]}]
+#[cfg(feature = "serde")]
+impl serde::Serialize for TpmlPcrSelection {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        let count = (self.count as usize).min(TPM2_NUM_PCR_BANKS);
+        let banks: Vec<BTreeMap<TpmAlgId, Vec<u32>>> = self.pcr_selections[..count]
+            .iter()
+            .map(|selection| {
+                let size = (selection.sizeof_select as usize).min(TPM2_PCR_SELECT_MAX);
+                let pcrs = (0..size as u32 * 8)
+                    .filter(|pcr| selection.pcr_select[*pcr as usize / 8] & (1 << (pcr % 8)) != 0)
+                    .collect();
+                let mut bank = BTreeMap::new();
+                bank.insert(selection.hash, pcrs);
+                bank
+            })
+            .collect();
+        serde::Serialize::serialize(&banks, serializer)
     }
 }
 
-impl inout::Tpm2StructIn for TpmlPcrSelection {
-    fn unpack(
-        &mut self,
-        buff: &mut dyn inout::RwBytes,
-    ) -> result::Result<(), errors::DeserializationError> {
-        self.count.unpack(buff)?;
-        for _pcr_count in 0..self.count {
-            let mut pcr_selection: TpmsPcrSelection = Default::default();
-            pcr_selection.unpack(buff)?;
-            self.pcr_selections[_pcr_count as usize] = pcr_selection;
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TpmlPcrSelection {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        let banks: Vec<BTreeMap<TpmAlgId, Vec<u32>>> =
+            serde::Deserialize::deserialize(deserializer)?;
+        let mut selections = TpmlPcrSelection::new();
+        for (hash, pcrs) in banks.into_iter().flatten() {
+            if selections.count as usize == TPM2_NUM_PCR_BANKS {
+                return Err(serde::de::Error::custom(format!(
+                    "more than {} PCR banks selected",
+                    TPM2_NUM_PCR_BANKS
+                )));
+            }
+            let selection = &mut selections.pcr_selections[selections.count as usize];
+            selection.hash = hash;
+            selection.sizeof_select = TPM2_PCR_SELECT_MAX as u8;
+            for pcr in pcrs {
+                if pcr as usize >= TPM2_MAX_PCRS {
+                    return Err(serde::de::Error::custom(format!(
+                        "invalid PCR index {}",
+                        pcr
+                    )));
+                }
+                selection.pcr_select[pcr as usize / 8] |= 1 << (pcr % 8);
+            }
+            selections.count += 1;
         }
-        Ok(())
+        Ok(selections)
     }
 }
 
 // TPMU_ENCRYPTED_SECRET
 #[derive(Copy, Clone)]
 pub union TpmuEncryptedSecret {
-    ecc: [u8; mem::size_of::<TpmsEccPoint>()],
-    rsa: [u8; RSA_KEY_NAX_NUM_BYTES],
-    symmetric: [u8; mem::size_of::<Tpm2bDigest>()],
-    keyed_hash: [u8; mem::size_of::<Tpm2bDigest>()],
+    ecc: [u8; 2 * (2 + MAX_ECC_KEY_BYTES)],
+    rsa: [u8; MAX_RSA_KEY_BYTES],
+    symmetric: [u8; 2 + MAX_HASH_SIZE],
+    keyed_hash: [u8; 2 + MAX_HASH_SIZE],
 }
 
 // TPM2B_ENCRYPTED_SECRET
-#[derive(Copy, Clone, Debug)]
-pub struct Tpm2bEncryptedSecret {
-    pub size: u16,
-    // Secret size is defined as the mexium size held by a TpmuEncryptedSecret structure
-    pub secret: [u8; mem::size_of::<TpmuEncryptedSecret>()],
-}
-
-impl Tpm2bEncryptedSecret {
-    pub fn new() -> Self {
-        Tpm2bEncryptedSecret {
-            size: 0,
-            secret: [0; mem::size_of::<TpmuEncryptedSecret>()],
-        }
-    }
-}
-
-impl inout::Tpm2StructOut for Tpm2bEncryptedSecret {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        // If size is 0, this should be an empty buffer
-        self.size.pack(buff);
-        buff.write_bytes(&self.secret[0..self.size as usize]);
-    }
-}
+// Secret size is defined as the maximum size held by a TpmuEncryptedSecret
+// structure
+pub type Tpm2bEncryptedSecret = Tpm2bBuffer<{ mem::size_of::<TpmuEncryptedSecret>() }>;
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct _Private {
     integrity_outer: Tpm2bDigest,
     //integrity_inner: Tpm2bDigest,
-    size_sensitive: u16,
-    enc_sensitive: [u8; mem::size_of::<Tpm2bSensitive>()],
+    // enc_sensitive is an encrypted TPM2B_SENSITIVE, which includes its size
+    enc_sensitive: Vec<u8>,
 }
 
 impl inout::Tpm2StructOut for _Private {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.integrity_outer.pack(buff);
+    fn pack(
+        &self,
+        buff: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), errors::SerializationError> {
+        self.integrity_outer.pack(buff)?;
         //if self.integrity_inner.size > 0 {
-        //    self.integrity_inner.pack(buff);
+        //    self.integrity_inner.pack(buff)?;
         //}
-        //self.size_sensitive.pack(buff);
-        buff.write_bytes(&self.enc_sensitive[0..self.size_sensitive as usize]);
-    }
-}
-
-// TPM2B_PRIVATE
-#[derive(Copy, Clone, Debug)]
-pub struct Tpm2bPrivate {
-    size: u16,
-    // buffer is sized based on _PRIVATE data structure, which is defined
-    // as follows:
-    // * integrityOuter: TPM2B_DIGEST
-    // * integrityInner: TPM2B_DIGEST
-    // * se:nsitive: TPM2B_SENSITIVE
-    buffer: [u8; mem::size_of::<Tpm2bDigest>() * 2 + mem::size_of::<Tpm2bSensitive>()],
-}
-
-impl inout::Tpm2StructOut for Tpm2bPrivate {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.size.pack(buff);
-        buff.write_bytes(&self.buffer[0..self.size as usize]);
-    }
-}
-
-impl Tpm2bPrivate {
-    pub fn new() -> Self {
-        Tpm2bPrivate {
-            size: 0,
-            buffer: [0; mem::size_of::<Tpm2bDigest>() * 2 + mem::size_of::<Tpm2bSensitive>()],
-        }
-    }
-}
-
-impl inout::Tpm2StructIn for Tpm2bPrivate {
-    fn unpack(
-        &mut self,
-        buff: &mut dyn inout::RwBytes,
-    ) -> result::Result<(), errors::DeserializationError> {
-        self.size.unpack(buff)?;
-        self.buffer[0..self.size as usize].clone_from_slice(buff.read_bytes(self.size as usize));
+        buff.write_bytes(&self.enc_sensitive)?;
         Ok(())
     }
 }
 
-pub fn get_name(public: TpmtPublic) -> [u8; 34] {
+// TPM2B_PRIVATE
+// buffer is sized based on _PRIVATE data structure, which is defined
+// as follows:
+// * integrityOuter: TPM2B_DIGEST
+// * integrityInner: TPM2B_DIGEST
+// * sensitive: TPM2B_SENSITIVE
+pub type Tpm2bPrivate = Tpm2bBuffer<MAX_PRIVATE_SIZE>;
+
+pub fn get_name(public: &TpmtPublic) -> result::Result<[u8; 34], errors::SerializationError> {
     // The name of a TPMT_PUBLIC data structure requires
     // that the algorithm type os pre-pended
-    let mut buff = inout::StaticByteBuffer::new();
-    public.pack(&mut buff);
+    let mut buff = inout::DynamicByteBuffer::new();
+    public.pack(&mut buff)?;
 
     let mut hasher = Sha256::new();
     hasher.update(buff.to_bytes());
@@ -378,7 +531,7 @@ pub fn get_name(public: TpmtPublic) -> [u8; 34] {
     name[0] = 0x00;
     name[1] = 0x0b;
     name[2..].clone_from_slice(&hasher.finalize()[..]);
-    return name;
+    Ok(name)
 }
 
 pub fn kdfa(
@@ -387,7 +540,7 @@ pub fn kdfa(
     context_u: &[u8],
     context_v: &[u8],
     bits: u32,
-) -> result::Result<inout::StaticByteBuffer, errors::TpmError> {
+) -> result::Result<inout::DynamicByteBuffer, errors::TpmError> {
     let bytes = (bits + 7) / 8;
 
     let mut counter: u32 = 1;
@@ -397,10 +550,12 @@ pub fn kdfa(
     // TODO: this should not be hardcoded
     type HmacSha256 = Hmac<Sha256>;
 
-    let mut buff = inout::StaticByteBuffer::new();
+    let mut buff = Vec::new();
 
-    while buff.to_bytes().len() < bytes as usize {
-        let mut mac = HmacSha256::new_from_slice(key).expect("could not create HMAC");
+    while buff.len() < bytes as usize {
+        let mut mac = HmacSha256::new_from_slice(key).map_err(|err| errors::TpmError {
+            msg: format!("could not create HMAC: {}", err),
+        })?;
 
         BigEndian::write_u32(&mut buff_4b, counter);
         mac.update(&buff_4b);
@@ -415,31 +570,29 @@ pub fn kdfa(
         mac.update(&buff_4b);
 
         let result = mac.finalize();
-        buff.write_bytes(&result.into_bytes());
+        buff.extend_from_slice(&result.into_bytes());
     }
 
-    let out: &[u8] = &buff.to_bytes()[0..bytes as usize];
-
-    let mut key = inout::StaticByteBuffer::new();
+    buff.truncate(bytes as usize);
     let mask_bits = bits % 8;
     if mask_bits > 0 {
-        key.write_bytes(&[out[0] & (1 << mask_bits) - 1]);
-        key.write_bytes(&out[1..]);
-    } else {
-        key.write_bytes(out);
+        buff[0] &= (1 << mask_bits) - 1;
     }
 
-    Ok(key)
+    Ok(inout::DynamicByteBuffer::from_vec(buff))
 }
 
 impl Tpm2bPrivate {
     // Creates a `duplicate` object of type TPM2B_PRIVATE
-    pub fn new_duplicate(
+    //
+    // rng is used for the OAEP padding of the encrypted seed
+    pub fn new_duplicate<R: rand::CryptoRng + rand::RngCore>(
         parent: &rsa::RsaPublicKey,
-        sensitive: TpmtSensitive,
-        public: TpmtPublic,
+        sensitive: &TpmtSensitive,
+        public: &TpmtPublic,
         enc_seed_out: &mut Tpm2bEncryptedSecret,
-    ) -> Self {
+        rng: &mut R,
+    ) -> result::Result<Self, errors::TpmError> {
         // Algorithm for creating a `duplicate` TPM2B_PRIVATE structure is the following:
         // * Create seed for symmetric encryption of sensitive
         // * Encrypt seed with parent object
@@ -458,30 +611,25 @@ impl Tpm2bPrivate {
         //
 
         // Create serialized TPM2B_SENSITIVE from TpmtSensitive.
-        let mut temp = inout::StaticByteBuffer::new();
-        sensitive.pack(&mut temp);
-        let size = temp.to_bytes().len() as u16;
-
-        let mut sensitive_buff = inout::StaticByteBuffer::new();
+        let mut sensitive_buff = inout::DynamicByteBuffer::new();
         Tpm2bSensitive {
-            size: size,
-            sensitive_area: sensitive,
+            sensitive_area: sensitive.clone(),
         }
-        .pack(&mut sensitive_buff);
+        .pack(&mut sensitive_buff)?;
 
         // Encrypt the serialized TPM2B_SENSITIVE structure
         // Equivalent to the call:
         // encryptSecret(packedSecret, seed, nameEncoded, ek)
         // Where
         // packedSecret is sensitive_buff
-        let name = get_name(public);
-        println!("name is {:02x?}", name);
+        let name = get_name(public)?;
+        log::trace!("name is {:02x?}", name);
 
-        let mut public_buff = inout::StaticByteBuffer::new();
+        let mut public_buff = inout::DynamicByteBuffer::new();
 
-        public.pack(&mut public_buff);
+        public.pack(&mut public_buff)?;
 
-        println!("public buff is {:02x?}", public_buff.to_bytes());
+        log::trace!("public area is {:02x?}", public_buff.to_bytes());
 
         // TPM2B_SENSITIVE is given by the concatenation of
         // `size_buff` and `sensitive_buff`.
@@ -497,77 +645,52 @@ impl Tpm2bPrivate {
 
         // Encrypt the seed with parent key. This cannot match the encrypted seed of another
         // implementation because we are reading from rnd.
-        let mut rng = rand::thread_rng();
 
         //let label: &[u8] = &[0x44, 0x55, 0x50, 0x4c, 0x49, 0x43, 0x41, 0x54, 0x45];
 
         //let label_str = str::from_utf8(label).expect("label is wrong");
 
         //printl0n!("Label is {:02x?}", label_str);
-        println!("Encrypting with parent {:02x?}", parent);
 
         let padding = Oaep::new_with_label::<sha2::Sha256, &str>("DUPLICATE\0");
         let enc_seed = parent
-            .encrypt(&mut rng, padding, &seed[..])
-            .expect("failed to encrypt");
+            .encrypt(rng, padding, &seed[..])
+            .map_err(|err| errors::TpmError {
+                msg: format!("failed to encrypt the seed: {}", err),
+            })?;
 
-        enc_seed_out.size = enc_seed.len() as u16;
-        enc_seed_out.secret[0..enc_seed_out.size as usize].clone_from_slice(&enc_seed);
-
-        println!("encrypted seed is {:02x?}", enc_seed);
-        println!("");
-        println!("");
-
-        let result = kdfa(&seed[..], "STORAGE".as_bytes(), &name[..], &[], 128);
+        log::trace!("encrypted seed is {:02x?}", enc_seed);
+        *enc_seed_out = Tpm2bEncryptedSecret::from_vec(enc_seed);
 
         let mut key: [u8; 16] = [0; 16];
-
-        match result {
-            Ok(res) => {
-                key.clone_from_slice(res.to_bytes());
-                println!("key is {:x?}", key);
-            }
-            Err(_) => {
-                panic!("error while calculating key");
-            }
-        }
+        key.clone_from_slice(
+            kdfa(&seed[..], "STORAGE".as_bytes(), &name[..], &[], 128)?.to_bytes(),
+        );
 
         type Aes128CfbEnc = cfb_mode::Encryptor<aes::Aes128>;
 
         let iv = [0x00; 16];
 
-        let mut encrypted_buff: [u8; 256] = [0; 256];
+        let mut encrypted_buff = vec![0; sensitive_buff.to_bytes().len()];
 
         Aes128CfbEnc::new(&key.into(), &iv.into())
-            .encrypt_b2b(
-                &mut sensitive_buff.to_bytes(),
-                &mut encrypted_buff[0..sensitive_buff.to_bytes().len()],
-            )
-            .unwrap();
-
-        println!("Encrypted buffer is {:x?}", encrypted_buff);
+            .encrypt_b2b(sensitive_buff.to_bytes(), &mut encrypted_buff)
+            .map_err(|err| errors::TpmError {
+                msg: format!("failed to encrypt the sensitive area: {}", err),
+            })?;
 
         // Creation of HMAC
-        let result = kdfa(&seed[..], "INTEGRITY".as_bytes(), &[], &[], 256);
-        let mut mac_key: [u8; 32] = [0; 32];
-
-        match result {
-            Ok(res) => {
-                mac_key.clone_from_slice(res.to_bytes());
-                println!("key is {:x?}", key);
-            }
-            Err(_) => {
-                panic!("error while calculating key");
-            }
-        }
+        let mac_key = kdfa(&seed[..], "INTEGRITY".as_bytes(), &[], &[], 256)?;
 
-        println!("MAC key {:x?}", mac_key);
         // TODO: this should not be hardcoded
         type HmacSha256 = Hmac<Sha256>;
 
-        let mut mac = HmacSha256::new_from_slice(&mac_key[..]).expect("could not create HMAC");
+        let mut mac =
+            HmacSha256::new_from_slice(mac_key.to_bytes()).map_err(|err| errors::TpmError {
+                msg: format!("could not create HMAC: {}", err),
+            })?;
 
-        mac.update(&encrypted_buff[0..sensitive_buff.to_bytes().len()]);
+        mac.update(&encrypted_buff);
         mac.update(&name[..]);
 
         // Create the _PRIVATE data structure consisting of the following
@@ -582,34 +705,14 @@ impl Tpm2bPrivate {
         let hmac_result = mac.finalize();
         let hmac_bytes = hmac_result.into_bytes();
 
-        println!("mac bytes are {:x?}", hmac_bytes);
-
-        let mut buffer: [u8; MAX_HASH_SIZE] = [0; MAX_HASH_SIZE];
-        buffer[0..32].clone_from_slice(&hmac_bytes[..]);
-
-        let mut enc_sensitive: [u8; mem::size_of::<Tpm2bSensitive>()] =
-            [0; mem::size_of::<Tpm2bSensitive>()];
-        enc_sensitive[0..256].clone_from_slice(&encrypted_buff);
-
         let private = _Private {
-            integrity_outer: Tpm2bDigest {
-                size: hmac_bytes.len() as u16,
-                buffer: buffer,
-            },
+            integrity_outer: Tpm2bDigest::from_slice(&hmac_bytes[..]),
             // Since for creation of duplicate IV was all zero, it doesn't need to
             // be added to _Private, so integrity_inner shall be ignored
-            //integrity_inner: Tpm2bDigest {
-            //    size: 0,
-            //    buffer: [0; 64],
-            //},
-
-            // enc_sensitive already includes the size, so size_sensitive is not needed
-            // neither is Tpm2bDigest needed
-            //size_sensitive: sensitive_buff.to_bytes().len() as u16,
-            // I am mi-using size_sensitive here to be able to serialize with specific
-            // boundary the enc_sentistive, but its value shoudl be known
-            size_sensitive: sensitive_buff.to_bytes().len() as u16,
-            enc_sensitive: enc_sensitive,
+            //integrity_inner: Tpm2bDigest::new(),
+
+            // enc_sensitive already includes the size
+            enc_sensitive: encrypted_buff,
         };
 
         // The import blob then consists in:
@@ -618,77 +721,58 @@ impl Tpm2bPrivate {
         // * Public Area
         // * PCRA
 
-        let mut private_buff = inout::StaticByteBuffer::new();
-        private.pack(&mut private_buff);
+        let mut private_buff = inout::DynamicByteBuffer::new();
+        private.pack(&mut private_buff)?;
 
-        let mut duplicate = Tpm2bPrivate {
-            size: private_buff.to_bytes().len() as u16,
-            buffer: [0; mem::size_of::<Tpm2bDigest>() * 2 + mem::size_of::<Tpm2bSensitive>()],
-        };
-        duplicate.buffer[0..private_buff.to_bytes().len()]
-            .clone_from_slice(private_buff.to_bytes());
+        let duplicate = Tpm2bPrivate::from_vec(private_buff.into_vec());
 
-        let mut duplicate_buff = inout::StaticByteBuffer::new();
+        let mut duplicate_buff = inout::DynamicByteBuffer::new();
 
-        duplicate.pack(&mut duplicate_buff);
-        println!("duplicate is {:02x?}", duplicate_buff.to_bytes());
+        duplicate.pack(&mut duplicate_buff)?;
+        log::trace!("duplicate is {:02x?}", duplicate_buff.to_bytes());
 
-        return duplicate;
+        Ok(duplicate)
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 // TPM2B_SENSITIVE
 pub struct Tpm2bSensitive {
-    size: u16,
+    // An empty TPM2B_SENSITIVE denotes an object without sensitive area
+    #[tpm2b]
     sensitive_area: TpmtSensitive,
 }
 
-impl inout::Tpm2StructOut for Tpm2bSensitive {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.size.pack(buff);
-        self.sensitive_area.pack(buff);
-    }
-}
-
-#[derive(Copy, Clone)]
 // TPM2B_SENSITIVE_DATA
-pub struct Tpm2bSensitiveData {
-    size: u16,
-    buffer: [u8; MAX_SYM_DATA],
-}
+pub type Tpm2bSensitiveData = Tpm2bBuffer<MAX_SYM_DATA>;
 
-impl inout::Tpm2StructOut for Tpm2bSensitiveData {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.size.pack(buff);
-        buff.write_bytes(&self.buffer[0..self.size as usize]);
-    }
-}
-
-#[derive(Copy, Clone)]
+// TPMU_SENSITIVE_COMPOSITE
+#[derive(Clone, Debug, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 enum TpmuSensitiveComposite {
-    Rsa(Tpm2bPublicKeyRsa),
+    #[selector = "TpmAlgId::RSA"]
+    Rsa(Tpm2bPrivateKeyRsa),
+    #[selector = "TpmAlgId::ECC"]
     Ecc(Tpm2bEccParameter),
+    // TPM2B_SYM_KEY of symmetric keys has the same layout as
+    // TPM2B_SENSITIVE_DATA
+    #[selector = "TpmAlgId::KeyedHash"]
+    #[selector = "TpmAlgId::SymCipher"]
     Bits(Tpm2bSensitiveData),
 }
 
-impl inout::Tpm2StructOut for TpmuSensitiveComposite {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        match *self {
-            TpmuSensitiveComposite::Bits(value) => value.pack(buff),
-            _ => {
-                panic!("cannot serialize TpmuSensitiveComposite")
-            }
-        }
+impl Default for TpmuSensitiveComposite {
+    fn default() -> Self {
+        TpmuSensitiveComposite::Bits(Tpm2bSensitiveData::default())
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 // TPMT_SENSITIVE
 pub struct TpmtSensitive {
     sensitive_type: TpmiAlgPublic,
     auth_value: Tpm2bAuth,
     seed_value: Tpm2bDigest,
+    #[selector = "sensitive_type"]
     sensitive: TpmuSensitiveComposite,
 }
 
@@ -698,26 +782,21 @@ impl TpmtSensitive {
         if data.len() > MAX_SYM_DATA || data.len() > 65536 {
             panic!("data is too large");
         }
-        let mut sensitive_buffer = [0; MAX_SYM_DATA];
-        sensitive_buffer[0..data.len()].clone_from_slice(data);
-
         // The seed_value of _SENSITIVE object containing symmetric
         // data object is used to calculate `unique` in TPMT_PUBLIC as
         //
         // unique := Hash(seed_value || sensitive)
-        let mut seed_buffer: [u8; MAX_HASH_SIZE] = [
+        let seed_buffer: [u8; 32] = [
             0xb9, 0xfa, 0x57, 0xb8, 0x5c, 0x55, 0xde, 0x9c, 0xf3, 0xb2, 0x06, 0x47, 0x46, 0xf5,
             0x48, 0x55, 0x1b, 0x7e, 0x35, 0xdf, 0xc5, 0xf2, 0x33, 0x1e, 0x51, 0xe3, 0x06, 0x65,
-            0x74, 0xa4, 0x71, 0x13, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x74, 0xa4, 0x71, 0x13,
         ];
         // TODO: This doesn't need to be hash or random, just random
         //let rnd = rand::thread_rng().gen::<[u8; 32]>();
         //let mut hasher = Sha256::new();
         //hasher.update(rnd);
         //let seed_result = hasher.finalize();
-        //seed_buffer[0..seed_result.len()].clone_from_slice(&seed_result);
+        //let seed_buffer = seed_result;
 
         TpmtSensitive {
             // TPM_ALG_KEYEDHASH indicates a symmetric data representing
@@ -725,93 +804,76 @@ impl TpmtSensitive {
             sensitive_type: TpmAlgId::KeyedHash,
             // Empty Auth value indicates that there is no auth associated
             // with this sensitive object
-            auth_value: Tpm2bAuth {
-                size: 0,
-                buffer: [0; MAX_HASH_SIZE],
-            },
+            auth_value: Tpm2bAuth::new(),
             // For a symmetric object, seedValue field is used as an
             // obfuscation value
-            seed_value: Tpm2bDigest {
-                size: 32,
-                buffer: seed_buffer,
-            },
-            sensitive: TpmuSensitiveComposite::Bits(Tpm2bSensitiveData {
-                size: data.len() as u16,
-                buffer: sensitive_buffer,
-            }),
+            seed_value: Tpm2bDigest::from_slice(&seed_buffer),
+            sensitive: TpmuSensitiveComposite::Bits(Tpm2bSensitiveData::from_slice(data)),
         }
     }
 }
 
-impl inout::Tpm2StructOut for TpmtSensitive {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.sensitive_type.pack(buff);
-        self.auth_value.pack(buff);
-        self.seed_value.pack(buff);
-        self.sensitive.pack(buff);
-    }
-}
-
 // TPMU_PUBLIC_ID
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Tpm2StructIn)]
 enum TpmuPublicId {
+    #[selector = "TpmAlgId::KeyedHash"]
     KeyedHash(Tpm2bDigest),
+    #[selector = "TpmAlgId::SymCipher"]
     Sym(Tpm2bDigest),
+    #[selector = "TpmAlgId::RSA"]
     Rsa(Tpm2bPublicKeyRsa),
+    #[selector = "TpmAlgId::ECC"]
     Ecc(TpmsEccPoint),
-    Derive(TpmsDerive),
 }
 
 impl inout::Tpm2StructOut for TpmuPublicId {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        match *self {
-            TpmuPublicId::KeyedHash(value) => {
-                value.pack(buff);
+    fn pack(
+        &self,
+        buff: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), errors::SerializationError> {
+        match self {
+            TpmuPublicId::KeyedHash(value) | TpmuPublicId::Sym(value) => {
+                value.pack(buff)?;
             }
             TpmuPublicId::Rsa(value) => {
-                value.pack(buff);
+                value.pack(buff)?;
             }
-            _ => {
-                panic!("cannot serialize TpmuPublicId");
+            TpmuPublicId::Ecc(value) => {
+                value.pack(buff)?;
             }
         }
+        Ok(())
+    }
+}
+
+impl Default for TpmuPublicId {
+    fn default() -> Self {
+        TpmuPublicId::KeyedHash(Tpm2bDigest::new())
     }
 }
 
 impl TpmuPublicId {
     // new_rsa creates a new TpmuPublicId for RSA keys
     pub fn new_rsa(key: &rsa::RsaPublicKey) -> Self {
-        let mut modulus = [0; RSA_KEY_NAX_NUM_BYTES];
-        modulus[0..key.size()].clone_from_slice(key.n().to_bytes_le().as_slice());
-        let id = TpmuPublicId::Rsa(Tpm2bPublicKeyRsa {
-            // size of the buffer containing the modulus
-            size: key.size() as u16,
-            // The buffer
-            buffer: modulus,
-        });
-        id
+        TpmuPublicId::Rsa(Tpm2bPublicKeyRsa::from_vec(key.n().to_bytes_le()))
     }
 
     pub fn new_keyed_hash(data: &[u8]) -> Self {
-        let mut buffer = [0; MAX_HASH_SIZE];
-        buffer[0..data.len()].clone_from_slice(data);
-        TpmuPublicId::KeyedHash(Tpm2bDigest {
-            size: data.len() as u16,
-            buffer: buffer,
-        })
+        TpmuPublicId::KeyedHash(Tpm2bDigest::from_slice(data))
     }
 }
 
 // TPMS_SCHEME_HASH
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmsSchemeHash {
     hash_alg: TpmiAlgHash,
 }
 
-impl inout::Tpm2StructOut for TpmsSchemeHash {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.hash_alg.pack(buff);
-    }
+// TPMS_SCHEME_ECDAA
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsSchemeEcdaa {
+    hash_alg: TpmiAlgHash,
+    count: u16,
 }
 
 pub type TpmsSchemeHmac = TpmsSchemeHash;
@@ -821,93 +883,75 @@ pub type TpmsSigSchemeRsassa = TpmsSchemeHash;
 pub type TpmsSigSchemeEcdh = TpmsSchemeHash;
 pub type TpmsSigSchemeEcmqv = TpmsSchemeHash;
 pub type TpmsSigSchemeRsapss = TpmsSchemeHash;
-pub type TpmsSigSchemeEcdaa = TpmsSchemeHash;
+pub type TpmsSigSchemeEcdaa = TpmsSchemeEcdaa;
 pub type TpmsSigSchemeSm2 = TpmsSchemeHash;
 pub type TpmsSigSchemeEcschnorr = TpmsSchemeHash;
-pub type TpmsSigSchemeRsaes = TpmsSchemeHash;
 pub type TpmsSigSchemeOaep = TpmsSchemeHash;
 
 // Types of TPMU_ASYM_SCHEME
-#[derive(Copy, Clone)]
-pub union TpmuAsymScheme {
-    pub ecdsa: TpmsSigSchemeEcdsa,
-    pub rsassa: TpmsSigSchemeRsassa,
-    pub ecdh: TpmsSigSchemeEcdh,
-    pub ecmqv: TpmsSigSchemeEcmqv,
-    pub rsapss: TpmsSigSchemeRsapss,
-    pub ecdaa: TpmsSigSchemeEcdaa,
-    pub smc2: TpmsSigSchemeSm2,
-    pub ecschnorr: TpmsSigSchemeEcschnorr,
-    pub rsaes: TpmsSigSchemeRsaes,
-    pub oaep: TpmsSigSchemeOaep,
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub enum TpmuAsymScheme {
+    #[selector = "TpmAlgId::ECDSA"]
+    Ecdsa(TpmsSigSchemeEcdsa),
+    #[selector = "TpmAlgId::RSASSA"]
+    Rsassa(TpmsSigSchemeRsassa),
+    #[selector = "TpmAlgId::ECDH"]
+    Ecdh(TpmsSigSchemeEcdh),
+    #[selector = "TpmAlgId::ECMQV"]
+    Ecmqv(TpmsSigSchemeEcmqv),
+    #[selector = "TpmAlgId::RSAPSS"]
+    Rsapss(TpmsSigSchemeRsapss),
+    #[selector = "TpmAlgId::ECDAA"]
+    Ecdaa(TpmsSigSchemeEcdaa),
+    #[selector = "TpmAlgId::SM2"]
+    Sm2(TpmsSigSchemeSm2),
+    #[selector = "TpmAlgId::ECSCHNORR"]
+    Ecschnorr(TpmsSigSchemeEcschnorr),
+    // RSAES takes no parameters
+    #[selector = "TpmAlgId::RSAES"]
+    Rsaes,
+    #[selector = "TpmAlgId::OAEP"]
+    Oaep(TpmsSigSchemeOaep),
+    #[default]
+    #[selector = "TpmAlgId::Null"]
+    Null,
 }
 
 impl TpmuAsymScheme {
     pub fn new_rsassa_tpmu_asym_scheme() -> Self {
-        return TpmuAsymScheme {
-            rsassa: TpmsSigSchemeRsassa {
-                hash_alg: TpmAlgId::RSASSA,
-            },
-        };
-    }
-}
-
-impl std::fmt::Debug for TpmuAsymScheme {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Ok(())
+        TpmuAsymScheme::Rsassa(TpmsSigSchemeRsassa {
+            hash_alg: TpmAlgId::SHA256,
+        })
     }
 }
 
 // TPMS_SCHEME_XOR
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmsSchemeXor {
     hash_alg: TpmiAlgHash,
     kdf: TpmiAlgKdf,
 }
 
-impl inout::Tpm2StructOut for TpmsSchemeXor {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.hash_alg.pack(buff);
-        self.kdf.pack(buff);
-    }
-}
-
 // TPMU_SCHEME_KEYEDHASH
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 enum TpmuSchemeKeyedHash {
+    #[selector = "TpmAlgId::HMAC"]
     Hmac(TpmsSchemeHmac),
+    #[selector = "TpmAlgId::XOR"]
     Xor(TpmsSchemeXor),
+    #[default]
+    #[selector = "TpmAlgId::Null"]
     Null,
 }
 
-impl inout::Tpm2StructOut for TpmuSchemeKeyedHash {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        match *self {
-            TpmuSchemeKeyedHash::Hmac(value) => {
-                value.pack(buff);
-            }
-            TpmuSchemeKeyedHash::Xor(value) => {
-                value.pack(buff);
-            }
-            _ => {}
-        }
-    }
-}
-
 // TPMT_KEYEDHASH_SCHEME
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmtKeyedHashScheme {
     scheme: TpmiAlgKeyedHashScheme,
+    #[selector = "scheme"]
     details: TpmuSchemeKeyedHash,
 }
 
-impl inout::Tpm2StructOut for TpmtKeyedHashScheme {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.scheme.pack(buff);
-        self.details.pack(buff);
-    }
-}
-
 impl TpmtKeyedHashScheme {
     pub fn new_keyed_hash_scheme() -> Self {
         TpmtKeyedHashScheme {
@@ -919,17 +963,11 @@ impl TpmtKeyedHashScheme {
 }
 
 // TPMS_KEYEDHASH_PARMS
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmsKeyedHashParms {
     scheme: TpmtKeyedHashScheme,
 }
 
-impl inout::Tpm2StructOut for TpmsKeyedHashParms {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.scheme.pack(buff);
-    }
-}
-
 impl TpmsKeyedHashParms {
     pub fn new_keyed_hash_parms() -> Self {
         TpmsKeyedHashParms {
@@ -939,77 +977,63 @@ impl TpmsKeyedHashParms {
 }
 
 // TPMS_SYMCIPHER_PARMS
-#[derive(Copy, Clone, Debug)]
-pub struct TpmsSymcipherParms {}
-
-// TPMS_ECC_PARMS
-#[derive(Copy, Clone, Debug)]
-pub struct TpmsEccParms {}
-
-// TPMS_ASYM_PARMS
-#[derive(Copy, Clone, Debug)]
-pub struct TpmsAsymParms {}
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsSymcipherParms {
+    sym: TpmtSymDefObject,
+}
 
 // TPMU_SYM_KEY_BITS
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 enum TpmuSymKeyBits {
+    #[selector = "TpmAlgId::AES"]
+    #[selector = "TpmAlgId::SM4"]
+    #[selector = "TpmAlgId::Camellia"]
     Sym(TpmKeyBits),
+    #[selector = "TpmAlgId::XOR"]
     Xor(TpmiAlgHash),
+    #[default]
+    #[selector = "TpmAlgId::Null"]
     Null,
 }
 
-impl inout::Tpm2StructOut for TpmuSymKeyBits {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        match self {
-            TpmuSymKeyBits::Sym(value) => {
-                value.pack(buff);
-            }
-            TpmuSymKeyBits::Xor(value) => {
-                value.pack(buff);
-            }
-            TpmuSymKeyBits::Null => {}
-        }
-    }
-}
-
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 enum TpmuSymMode {
+    #[selector = "TpmAlgId::AES"]
+    #[selector = "TpmAlgId::SM4"]
+    #[selector = "TpmAlgId::Camellia"]
     Sym(TpmiAlgSymMode),
     // TPM_ALG_XOR and TPM_ALG_NULL do not require a mode
+    #[selector = "TpmAlgId::XOR"]
     Xor,
+    #[default]
+    #[selector = "TpmAlgId::Null"]
     Null,
 }
 
-impl inout::Tpm2StructOut for TpmuSymMode {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        match self {
-            TpmuSymMode::Sym(value) => {
-                value.pack(buff);
-            }
-            TpmuSymMode::Xor | TpmuSymMode::Null => {}
-        }
-    }
-}
-
 // TPMU_SYM_DETAILS. The spec currently does not make any use of this
-// structure
-#[derive(Copy, Clone, Debug)]
+// structure, it is empty for every algorithm
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 enum TpmuSymDetails {
+    #[selector = "TpmAlgId::AES"]
+    #[selector = "TpmAlgId::SM4"]
+    #[selector = "TpmAlgId::Camellia"]
     Sym,
+    #[selector = "TpmAlgId::XOR"]
     Xor,
+    #[default]
+    #[selector = "TpmAlgId::Null"]
     Null,
 }
 
-impl inout::Tpm2StructOut for TpmuSymDetails {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {}
-}
-
 // TPMT_SYM_DEF_OBJECT
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmtSymDefObject {
     algorithm: TpmiAlgSymObject,
+    #[selector = "algorithm"]
     key_bits: TpmuSymKeyBits,
+    #[selector = "algorithm"]
     mode: TpmuSymMode,
+    #[selector = "algorithm"]
     details: TpmuSymDetails,
 }
 
@@ -1033,21 +1057,14 @@ impl TpmtSymDefObject {
     }
 }
 
-impl inout::Tpm2StructOut for TpmtSymDefObject {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        if self.algorithm == TpmAlgId::Null {
-            self.algorithm.pack(buff);
-        } else {
-            panic!("not supported");
-        }
-    }
-}
-
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmtSymDef {
     algorithm: TpmiAlgSym,
+    #[selector = "algorithm"]
     key_bits: TpmuSymKeyBits,
+    #[selector = "algorithm"]
     mode: TpmuSymMode,
+    #[selector = "algorithm"]
     details: TpmuSymDetails,
 }
 
@@ -1062,33 +1079,25 @@ impl TpmtSymDef {
     }
 }
 
-impl inout::Tpm2StructOut for TpmtSymDef {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.algorithm.pack(buff);
-        self.key_bits.pack(buff);
-        self.mode.pack(buff);
-        self.details.pack(buff);
-    }
-}
-
 // TPMT_RSA_SCHEME
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmtRsaScheme {
     scheme: TpmiAlgRsaScheme,
+    #[selector = "scheme"]
     details: TpmuAsymScheme,
 }
 
 impl TpmtRsaScheme {
     pub fn new_tpmt_rsa_scheme() -> Self {
         TpmtRsaScheme {
-            scheme: TpmAlgId::RSA,
+            scheme: TpmAlgId::RSASSA,
             details: TpmuAsymScheme::new_rsassa_tpmu_asym_scheme(),
         }
     }
 }
 
 // TPMS_RSA_PARMS
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmsRsaParams {
     symmetric: TpmtSymDefObject,
     scheme: TpmtRsaScheme,
@@ -1116,94 +1125,143 @@ impl TpmsRsaParams {
     }
 }
 
+pub type TpmsSchemeMgf1 = TpmsSchemeHash;
+pub type TpmsSchemeKdf1Sp800_56a = TpmsSchemeHash;
+pub type TpmsSchemeKdf2 = TpmsSchemeHash;
+pub type TpmsSchemeKdf1Sp800_108 = TpmsSchemeHash;
+
+// TPMU_KDF_SCHEME
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+enum TpmuKdfScheme {
+    #[selector = "TpmAlgId::MGF1"]
+    Mgf1(TpmsSchemeMgf1),
+    #[selector = "TpmAlgId::KDF1_SP800_56A"]
+    Kdf1Sp80056a(TpmsSchemeKdf1Sp800_56a),
+    #[selector = "TpmAlgId::KDF2"]
+    Kdf2(TpmsSchemeKdf2),
+    #[selector = "TpmAlgId::KDF1_SP800_108"]
+    Kdf1Sp800108(TpmsSchemeKdf1Sp800_108),
+    #[default]
+    #[selector = "TpmAlgId::Null"]
+    Null,
+}
+
+// TPMT_KDF_SCHEME
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmtKdfScheme {
+    scheme: TpmiAlgKdf,
+    #[selector = "scheme"]
+    details: TpmuKdfScheme,
+}
+
+// TPMT_ECC_SCHEME
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmtEccScheme {
+    scheme: TpmiAlgEccScheme,
+    #[selector = "scheme"]
+    details: TpmuAsymScheme,
+}
+
 // TPMS_ECC_PARMS
-#[derive(Default, Debug)]
-pub struct TpmsEccParams {}
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsEccParms {
+    symmetric: TpmtSymDefObject,
+    scheme: TpmtEccScheme,
+    curve_id: TpmiEccCurve,
+    kdf: TpmtKdfScheme,
+}
 
 // TPM2B_LABEL
-#[derive(Copy, Clone, Debug)]
-pub struct Tpm2bLabel {
-    size: u16,
-    buffer: [u8; RSA_KEY_NAX_NUM_BYTES],
-}
+pub type Tpm2bLabel = Tpm2bBuffer<LABEL_MAX_BUFFER>;
 
 // TPM2B_ECC_PARAMETER
-#[derive(Copy, Clone, Debug)]
-pub struct Tpm2bEccParameter {
-    size: u16,
-    buffer: [u8; 256usize],
-}
+pub type Tpm2bEccParameter = Tpm2bBuffer<MAX_ECC_KEY_BYTES>;
 
 // TPM2B_PUBLIC_KEY_RSA
 // This sized buffer holds the largest RSA public key supported by the TPM.
 // Buffer will contain the modulus of the RSA key.
-#[derive(Copy, Clone, Debug)]
-pub struct Tpm2bPublicKeyRsa {
-    size: u16,
-    buffer: [u8; RSA_KEY_NAX_NUM_BYTES],
-}
+pub type Tpm2bPublicKeyRsa = Tpm2bBuffer<MAX_RSA_KEY_BYTES>;
 
-impl inout::Tpm2StructOut for Tpm2bPublicKeyRsa {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.size.pack(buff);
-        buff.write_bytes(&self.buffer[0..self.size as usize]);
-    }
-}
+// TPM2B_PRIVATE_KEY_RSA
+// This sized buffer holds a prime of the largest RSA key supported.
+pub type Tpm2bPrivateKeyRsa = Tpm2bBuffer<RSA_PRIVATE_SIZE>;
 
 // TPMS_ECC_POINT
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmsEccPoint {
     x: Tpm2bEccParameter,
     y: Tpm2bEccParameter,
 }
 
 // TPMS_DERIVE
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct TpmsDerive {
     label: Tpm2bLabel,
     //context: Tpm2bContext,
 }
 
-#[derive(Copy, Clone, Debug)]
+// TPMU_PUBLIC_PARMS
+#[derive(Copy, Clone, Debug, PartialEq, Tpm2StructIn)]
 enum TpmuPublicParms {
+    #[selector = "TpmAlgId::KeyedHash"]
     KeyedHashDetail(TpmsKeyedHashParms),
+    #[selector = "TpmAlgId::SymCipher"]
     SymDetail(TpmsSymcipherParms),
+    #[selector = "TpmAlgId::RSA"]
     RsaDetail(TpmsRsaParams),
+    #[selector = "TpmAlgId::ECC"]
     EccDetail(TpmsEccParms),
-    AsymDetail(TpmsAsymParms),
+}
+
+impl Default for TpmuPublicParms {
+    fn default() -> Self {
+        TpmuPublicParms::KeyedHashDetail(TpmsKeyedHashParms::default())
+    }
 }
 
 impl TpmuPublicParms {
     pub fn new_rsa_public_params(key: &rsa::RsaPublicKey) -> Self {
-        return TpmuPublicParms::RsaDetail(TpmsRsaParams::new_tpms_rsa_params(key));
+        TpmuPublicParms::RsaDetail(TpmsRsaParams::new_tpms_rsa_params(key))
     }
 
     pub fn new_keyed_hash_parms() -> Self {
-        return TpmuPublicParms::KeyedHashDetail(TpmsKeyedHashParms::new_keyed_hash_parms());
+        TpmuPublicParms::KeyedHashDetail(TpmsKeyedHashParms::new_keyed_hash_parms())
     }
 }
 
 impl inout::Tpm2StructOut for TpmuPublicParms {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
+    fn pack(
+        &self,
+        buff: &mut dyn inout::RwBytes,
+    ) -> result::Result<(), errors::SerializationError> {
         match *self {
             TpmuPublicParms::KeyedHashDetail(params) => {
-                params.pack(buff);
+                params.pack(buff)?;
             }
-            _ => {
-                panic!("cannot serialize KeyedHashDetail");
+            TpmuPublicParms::SymDetail(params) => {
+                params.pack(buff)?;
+            }
+            TpmuPublicParms::RsaDetail(params) => {
+                params.pack(buff)?;
+            }
+            TpmuPublicParms::EccDetail(params) => {
+                params.pack(buff)?;
             }
         }
+        Ok(())
     }
 }
 
 // TPMT_PUBLIC
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmtPublic {
     type_alg: TpmiAlgPublic,
     name_alg: TpmiAlgHash,
     object_attributes: TpmaObject,
     auth_policy: Tpm2bDigest,
+    #[selector = "type_alg"]
     parameters: TpmuPublicParms,
+    #[selector = "type_alg"]
     unique: TpmuPublicId,
 }
 
@@ -1216,17 +1274,6 @@ pub fn new_default_ek_auth_policy() -> Tpm2bDigest {
     Tpm2bDigest::new()
 }
 
-impl inout::Tpm2StructOut for TpmtPublic {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.type_alg.pack(buff);
-        self.name_alg.pack(buff);
-        self.object_attributes.pack(buff);
-        self.auth_policy.pack(buff);
-        self.parameters.pack(buff);
-        self.unique.pack(buff);
-    }
-}
-
 impl TpmtPublic {
     // Creates a TPMT_PUBLIC data structure for RSA key (type == TPM_ALG_RSA)
     pub fn new_rsa(key: &rsa::RsaPublicKey) -> Self {
@@ -1252,11 +1299,11 @@ impl TpmtPublic {
     pub fn new_data_object(sensitive: &TpmtSensitive) -> Self {
         let mut hasher = Sha256::new();
 
-        hasher.update(&sensitive.seed_value.buffer[0..sensitive.seed_value.size as usize]);
+        hasher.update(sensitive.seed_value.get_buffer());
 
-        match sensitive.sensitive {
+        match &sensitive.sensitive {
             TpmuSensitiveComposite::Bits(value) => {
-                hasher.update(&value.buffer[0..value.size as usize]);
+                hasher.update(value.get_buffer());
             }
             _ => {
                 panic!("cannot create new data object with this sensitive type");
@@ -1280,67 +1327,530 @@ impl TpmtPublic {
     }
 }
 
-// TPM2B_PUBLIC
-// An object description requires a TPM2B_PUBLIC structure and may require a TPMT_SENSITIVE
-// structure. When the structure is stored off the TPM, the TPMT_SENSITIVE structure is
-// encrypted within a TPM2B_PRIVATE structure
-#[derive(Clone, Copy, Debug)]
-pub struct Tpm2bPublic {
-    pub size: u16,
-    pub public: TpmtPublic,
+// Object attributes, as named by tpm2-tools
+#[cfg(feature = "serde")]
+const OBJECT_ATTRIBUTES: [(TpmaObject, &str); 12] = [
+    (0x00000002, "fixedtpm"),
+    (0x00000004, "stclear"),
+    (0x00000010, "fixedparent"),
+    (0x00000020, "sensitivedataorigin"),
+    (0x00000040, "userwithauth"),
+    (0x00000080, "adminwithpolicy"),
+    (0x00000400, "noda"),
+    (0x00000800, "encryptedduplication"),
+    (0x00010000, "restricted"),
+    (0x00020000, "decrypt"),
+    (0x00040000, "sign"),
+    (0x00080000, "x509sign"),
+];
+
+// ECC curves, as named by tpm2-tools
+#[cfg(feature = "serde")]
+const ECC_CURVES: [(TpmEccCurve, &str); 8] = [
+    (TPM_ECC_NIST_P192, "NIST p192"),
+    (TPM_ECC_NIST_P224, "NIST p224"),
+    (TPM_ECC_NIST_P256, "NIST p256"),
+    (TPM_ECC_NIST_P384, "NIST p384"),
+    (TPM_ECC_NIST_P521, "NIST p521"),
+    (TPM_ECC_BN_P256, "BN P256"),
+    (TPM_ECC_BN_P638, "BN P638"),
+    (TPM_ECC_SM2_P256, "SM2 p256"),
+];
+
+// AlgInfo is an algorithm as printed by tpm2-tools, by name and value. Only
+// the name is used when deserializing.
+#[cfg(feature = "serde")]
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct AlgInfo {
+    value: TpmAlgId,
+    #[serde(default)]
+    raw: u16,
+}
+
+#[cfg(feature = "serde")]
+impl From<TpmAlgId> for AlgInfo {
+    fn from(alg: TpmAlgId) -> Self {
+        AlgInfo {
+            value: alg,
+            raw: alg as u16,
+        }
+    }
 }
 
-impl Tpm2bPublic {
-    pub fn new_rsa(key: &rsa::RsaPublicKey) -> Self {
-        let public: TpmtPublic = TpmtPublic::new_rsa(key);
-        Tpm2bPublic {
-            size: 0,
-            public: public,
+// AttributesInfo are object attributes as printed by tpm2-tools, by name and
+// value. Only the value is used when deserializing.
+#[cfg(feature = "serde")]
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct AttributesInfo {
+    #[serde(default)]
+    value: String,
+    raw: TpmaObject,
+}
+
+#[cfg(feature = "serde")]
+impl From<TpmaObject> for AttributesInfo {
+    fn from(attributes: TpmaObject) -> Self {
+        let names: Vec<&str> = OBJECT_ATTRIBUTES
+            .iter()
+            .filter(|(flag, _)| attributes & flag != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        AttributesInfo {
+            value: names.join("|"),
+            raw: attributes,
         }
     }
+}
 
-    pub fn new_public_data_object(parent: &rsa::RsaPublicKey, sensitive: &TpmtSensitive) -> Self {
-        Tpm2bPublic {
-            size: 0,
-            public: TpmtPublic::new_data_object(sensitive),
+// CurveInfo is an ECC curve as printed by tpm2-tools, by name and value.
+// Only the value is used when deserializing.
+#[cfg(feature = "serde")]
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct CurveInfo {
+    #[serde(default)]
+    value: String,
+    raw: TpmiEccCurve,
+}
+
+#[cfg(feature = "serde")]
+impl From<TpmiEccCurve> for CurveInfo {
+    fn from(curve: TpmiEccCurve) -> Self {
+        let name = ECC_CURVES
+            .iter()
+            .find(|(value, _)| *value == curve)
+            .map_or("unknown", |(_, name)| *name);
+        CurveInfo {
+            value: String::from(name),
+            raw: curve,
         }
     }
 }
 
-impl inout::Tpm2StructOut for Tpm2bPublic {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.size.pack(buff);
-        self.public.pack(buff);
+// PublicInfo is the layout of a TPMT_PUBLIC printed by tpm2_readpublic. The
+// fields that follow the object type only exist for some types.
+#[cfg(feature = "serde")]
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct PublicInfo {
+    name_alg: AlgInfo,
+    attributes: AttributesInfo,
+    #[serde(rename = "type")]
+    type_alg: AlgInfo,
+    // TPM_ALG_KEYEDHASH
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    algorithm: Option<AlgInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash_alg: Option<AlgInfo>,
+    // TPM_ALG_ECC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    curve_id: Option<CurveInfo>,
+    // TPM_ALG_KEYEDHASH and TPM_ALG_ECC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kdfa_alg: Option<AlgInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kdfa_halg: Option<AlgInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyedhash: Option<Tpm2bDigest>,
+    // TPM_ALG_RSA
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exponent: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bits: Option<TpmiRsaKeyBits>,
+    // TPM_ALG_RSA and TPM_ALG_ECC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scheme: Option<AlgInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scheme_halg: Option<AlgInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scheme_count: Option<u16>,
+    // TPM_ALG_SYMCIPHER, TPM_ALG_RSA and TPM_ALG_ECC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sym_alg: Option<AlgInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sym_mode: Option<AlgInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sym_keybits: Option<TpmKeyBits>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symcipher: Option<Tpm2bDigest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rsa: Option<Tpm2bPublicKeyRsa>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x: Option<Tpm2bEccParameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    y: Option<Tpm2bEccParameter>,
+    #[serde(rename = "authorization policy")]
+    authorization_policy: Tpm2bDigest,
+}
+
+// required returns the value of a field that the object type requires
+#[cfg(feature = "serde")]
+fn required<T>(field: Option<T>, name: &str) -> result::Result<T, String> {
+    field.ok_or(format!("missing field `{}`", name))
+}
+
+#[cfg(feature = "serde")]
+impl PublicInfo {
+    fn set_sym(&mut self, sym: &TpmtSymDefObject) {
+        self.sym_alg = Some(sym.algorithm.into());
+        if let TpmuSymMode::Sym(mode) = sym.mode {
+            self.sym_mode = Some(mode.into());
+        }
+        if let TpmuSymKeyBits::Sym(bits) = sym.key_bits {
+            self.sym_keybits = Some(bits);
+        }
+    }
+
+    fn get_sym(&self) -> result::Result<TpmtSymDefObject, String> {
+        let algorithm = required(self.sym_alg.as_ref(), "sym-alg")?.value;
+        match algorithm {
+            TpmAlgId::AES | TpmAlgId::SM4 | TpmAlgId::Camellia => Ok(TpmtSymDefObject {
+                algorithm,
+                key_bits: TpmuSymKeyBits::Sym(*required(self.sym_keybits.as_ref(), "sym-keybits")?),
+                mode: TpmuSymMode::Sym(required(self.sym_mode.as_ref(), "sym-mode")?.value),
+                details: TpmuSymDetails::Sym,
+            }),
+            TpmAlgId::Null => Ok(TpmtSymDefObject::new_null()),
+            _ => Err(format!("unsupported sym-alg {}", algorithm.name())),
+        }
+    }
+
+    fn set_scheme(&mut self, scheme: TpmAlgId, details: &TpmuAsymScheme) {
+        self.scheme = Some(scheme.into());
+        match *details {
+            TpmuAsymScheme::Ecdsa(details)
+            | TpmuAsymScheme::Rsassa(details)
+            | TpmuAsymScheme::Ecdh(details)
+            | TpmuAsymScheme::Ecmqv(details)
+            | TpmuAsymScheme::Rsapss(details)
+            | TpmuAsymScheme::Sm2(details)
+            | TpmuAsymScheme::Ecschnorr(details)
+            | TpmuAsymScheme::Oaep(details) => {
+                self.scheme_halg = Some(details.hash_alg.into());
+            }
+            TpmuAsymScheme::Ecdaa(details) => {
+                self.scheme_halg = Some(details.hash_alg.into());
+                self.scheme_count = Some(details.count);
+            }
+            TpmuAsymScheme::Rsaes | TpmuAsymScheme::Null => {}
+        }
+    }
+
+    // get_scheme returns the asymmetric scheme, which must be TPM_ALG_NULL or
+    // one of the schemes of the object type
+    fn get_scheme(
+        &self,
+        object_type: &str,
+        schemes: &[TpmAlgId],
+    ) -> result::Result<(TpmAlgId, TpmuAsymScheme), String> {
+        let scheme = required(self.scheme.as_ref(), "scheme")?.value;
+        if scheme == TpmAlgId::Null {
+            return Ok((scheme, TpmuAsymScheme::Null));
+        }
+        if !schemes.contains(&scheme) {
+            return Err(format!(
+                "unsupported {} scheme {}",
+                object_type,
+                scheme.name()
+            ));
+        }
+        if scheme == TpmAlgId::RSAES {
+            return Ok((scheme, TpmuAsymScheme::Rsaes));
+        }
+        let hash_alg = required(self.scheme_halg.as_ref(), "scheme-halg")?.value;
+        let hash = TpmsSchemeHash { hash_alg };
+        let details = match scheme {
+            TpmAlgId::ECDSA => TpmuAsymScheme::Ecdsa(hash),
+            TpmAlgId::RSASSA => TpmuAsymScheme::Rsassa(hash),
+            TpmAlgId::ECDH => TpmuAsymScheme::Ecdh(hash),
+            TpmAlgId::ECMQV => TpmuAsymScheme::Ecmqv(hash),
+            TpmAlgId::RSAPSS => TpmuAsymScheme::Rsapss(hash),
+            TpmAlgId::SM2 => TpmuAsymScheme::Sm2(hash),
+            TpmAlgId::ECSCHNORR => TpmuAsymScheme::Ecschnorr(hash),
+            TpmAlgId::OAEP => TpmuAsymScheme::Oaep(hash),
+            TpmAlgId::ECDAA => TpmuAsymScheme::Ecdaa(TpmsSchemeEcdaa {
+                hash_alg,
+                count: *required(self.scheme_count.as_ref(), "scheme-count")?,
+            }),
+            _ => {
+                return Err(format!(
+                    "unsupported {} scheme {}",
+                    object_type,
+                    scheme.name()
+                ))
+            }
+        };
+        Ok((scheme, details))
+    }
+
+    fn get_rsa_scheme(&self) -> result::Result<TpmtRsaScheme, String> {
+        let (scheme, details) = self.get_scheme(
+            "RSA",
+            &[
+                TpmAlgId::RSASSA,
+                TpmAlgId::RSAPSS,
+                TpmAlgId::RSAES,
+                TpmAlgId::OAEP,
+            ],
+        )?;
+        Ok(TpmtRsaScheme { scheme, details })
+    }
+
+    fn get_ecc_scheme(&self) -> result::Result<TpmtEccScheme, String> {
+        let (scheme, details) = self.get_scheme(
+            "ECC",
+            &[
+                TpmAlgId::ECDSA,
+                TpmAlgId::ECDH,
+                TpmAlgId::ECMQV,
+                TpmAlgId::ECDAA,
+                TpmAlgId::SM2,
+                TpmAlgId::ECSCHNORR,
+            ],
+        )?;
+        Ok(TpmtEccScheme { scheme, details })
+    }
+
+    fn set_kdf(&mut self, kdf: &TpmtKdfScheme) {
+        self.kdfa_alg = Some(kdf.scheme.into());
+        match kdf.details {
+            TpmuKdfScheme::Mgf1(details)
+            | TpmuKdfScheme::Kdf1Sp80056a(details)
+            | TpmuKdfScheme::Kdf2(details)
+            | TpmuKdfScheme::Kdf1Sp800108(details) => {
+                self.kdfa_halg = Some(details.hash_alg.into());
+            }
+            TpmuKdfScheme::Null => {}
+        }
+    }
+
+    fn get_kdf(&self) -> result::Result<TpmtKdfScheme, String> {
+        let scheme = required(self.kdfa_alg.as_ref(), "kdfa-alg")?.value;
+        if scheme == TpmAlgId::Null {
+            return Ok(TpmtKdfScheme {
+                scheme,
+                details: TpmuKdfScheme::Null,
+            });
+        }
+        let hash = TpmsSchemeHash {
+            hash_alg: required(self.kdfa_halg.as_ref(), "kdfa-halg")?.value,
+        };
+        let details = match scheme {
+            TpmAlgId::MGF1 => TpmuKdfScheme::Mgf1(hash),
+            TpmAlgId::KDF1_SP800_56A => TpmuKdfScheme::Kdf1Sp80056a(hash),
+            TpmAlgId::KDF2 => TpmuKdfScheme::Kdf2(hash),
+            TpmAlgId::KDF1_SP800_108 => TpmuKdfScheme::Kdf1Sp800108(hash),
+            _ => return Err(format!("unsupported kdf {}", scheme.name())),
+        };
+        Ok(TpmtKdfScheme { scheme, details })
+    }
+
+    fn set_keyed_hash_scheme(&mut self, scheme: &TpmtKeyedHashScheme) {
+        self.algorithm = Some(scheme.scheme.into());
+        match scheme.details {
+            TpmuSchemeKeyedHash::Hmac(details) => {
+                self.hash_alg = Some(details.hash_alg.into());
+            }
+            TpmuSchemeKeyedHash::Xor(details) => {
+                self.hash_alg = Some(details.hash_alg.into());
+                self.kdfa_alg = Some(details.kdf.into());
+            }
+            TpmuSchemeKeyedHash::Null => {}
+        }
+    }
+
+    fn get_keyed_hash_scheme(&self) -> result::Result<TpmtKeyedHashScheme, String> {
+        let scheme = required(self.algorithm.as_ref(), "algorithm")?.value;
+        let details = match scheme {
+            TpmAlgId::HMAC => TpmuSchemeKeyedHash::Hmac(TpmsSchemeHash {
+                hash_alg: required(self.hash_alg.as_ref(), "hash-alg")?.value,
+            }),
+            TpmAlgId::XOR => TpmuSchemeKeyedHash::Xor(TpmsSchemeXor {
+                hash_alg: required(self.hash_alg.as_ref(), "hash-alg")?.value,
+                kdf: required(self.kdfa_alg.as_ref(), "kdfa-alg")?.value,
+            }),
+            TpmAlgId::Null => TpmuSchemeKeyedHash::Null,
+            _ => return Err(format!("unsupported keyedhash scheme {}", scheme.name())),
+        };
+        Ok(TpmtKeyedHashScheme { scheme, details })
     }
 }
 
-// TPM2B_DATA
-#[derive(Debug, Clone, Copy)]
-pub struct Tpm2bData {
-    pub size: u16,
-    pub buffer: [u8; 1024],
+#[cfg(feature = "serde")]
+impl TryFrom<&TpmtPublic> for PublicInfo {
+    type Error = String;
+
+    fn try_from(public: &TpmtPublic) -> result::Result<Self, Self::Error> {
+        let mut info = PublicInfo {
+            name_alg: public.name_alg.into(),
+            attributes: public.object_attributes.into(),
+            type_alg: public.type_alg.into(),
+            authorization_policy: public.auth_policy.clone(),
+            ..Default::default()
+        };
+        match (&public.parameters, &public.unique) {
+            (TpmuPublicParms::KeyedHashDetail(params), TpmuPublicId::KeyedHash(unique)) => {
+                info.set_keyed_hash_scheme(&params.scheme);
+                info.keyedhash = Some(unique.clone());
+            }
+            (TpmuPublicParms::SymDetail(params), TpmuPublicId::Sym(unique)) => {
+                info.set_sym(&params.sym);
+                info.symcipher = Some(unique.clone());
+            }
+            (TpmuPublicParms::RsaDetail(params), TpmuPublicId::Rsa(unique)) => {
+                info.exponent = Some(params.exponent);
+                info.bits = Some(params.key_bits);
+                info.set_scheme(params.scheme.scheme, &params.scheme.details);
+                info.set_sym(&params.symmetric);
+                info.rsa = Some(unique.clone());
+            }
+            (TpmuPublicParms::EccDetail(params), TpmuPublicId::Ecc(unique)) => {
+                info.curve_id = Some(params.curve_id.into());
+                info.set_kdf(&params.kdf);
+                info.set_scheme(params.scheme.scheme, &params.scheme.details);
+                info.set_sym(&params.symmetric);
+                info.x = Some(unique.x.clone());
+                info.y = Some(unique.y.clone());
+            }
+            _ => {
+                return Err(format!(
+                    "cannot serialize TpmtPublic of type {}",
+                    public.type_alg.name()
+                ))
+            }
+        }
+        Ok(info)
+    }
 }
 
-impl inout::Tpm2StructOut for Tpm2bData {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.size.pack(buff);
-        buff.write_bytes(&self.buffer[0..self.size as usize]);
+#[cfg(feature = "serde")]
+impl TryFrom<PublicInfo> for TpmtPublic {
+    type Error = String;
+
+    fn try_from(info: PublicInfo) -> result::Result<Self, Self::Error> {
+        let (parameters, unique) = match info.type_alg.value {
+            TpmAlgId::KeyedHash => (
+                TpmuPublicParms::KeyedHashDetail(TpmsKeyedHashParms {
+                    scheme: info.get_keyed_hash_scheme()?,
+                }),
+                TpmuPublicId::KeyedHash(required(info.keyedhash, "keyedhash")?),
+            ),
+            TpmAlgId::SymCipher => (
+                TpmuPublicParms::SymDetail(TpmsSymcipherParms {
+                    sym: info.get_sym()?,
+                }),
+                TpmuPublicId::Sym(required(info.symcipher, "symcipher")?),
+            ),
+            TpmAlgId::RSA => (
+                TpmuPublicParms::RsaDetail(TpmsRsaParams {
+                    symmetric: info.get_sym()?,
+                    scheme: info.get_rsa_scheme()?,
+                    key_bits: required(info.bits, "bits")?,
+                    exponent: required(info.exponent, "exponent")?,
+                }),
+                TpmuPublicId::Rsa(required(info.rsa, "rsa")?),
+            ),
+            TpmAlgId::ECC => (
+                TpmuPublicParms::EccDetail(TpmsEccParms {
+                    symmetric: info.get_sym()?,
+                    scheme: info.get_ecc_scheme()?,
+                    curve_id: required(info.curve_id.as_ref(), "curve-id")?.raw,
+                    kdf: info.get_kdf()?,
+                }),
+                TpmuPublicId::Ecc(TpmsEccPoint {
+                    x: required(info.x, "x")?,
+                    y: required(info.y, "y")?,
+                }),
+            ),
+            alg => return Err(format!("unsupported object type {}", alg.name())),
+        };
+        Ok(TpmtPublic {
+            type_alg: info.type_alg.value,
+            name_alg: info.name_alg.value,
+            object_attributes: info.attributes.raw,
+            auth_policy: info.authorization_policy,
+            parameters,
+            unique,
+        })
     }
 }
 
-impl inout::Tpm2StructIn for Tpm2bData {
-    fn unpack(
-        &mut self,
-        buff: &mut dyn inout::RwBytes,
-    ) -> result::Result<(), errors::DeserializationError> {
-        self.size.unpack(buff)?;
-        self.buffer[0..self.size as usize].clone_from_slice(buff.read_bytes(self.size as usize));
-        Ok(())
+// TpmtPublic serializes as tpm2_readpublic prints it. Only the object types
+// that can be marshalled, i.e. keyedhash, symcipher, rsa and ecc, are
+// supported.
+#[cfg(feature = "serde")]
+impl serde::Serialize for TpmtPublic {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        PublicInfo::try_from(self)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TpmtPublic {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        TpmtPublic::try_from(PublicInfo::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// TPM2B_PUBLIC
+// An object description requires a TPM2B_PUBLIC structure and may require a TPMT_SENSITIVE
+// structure. When the structure is stored off the TPM, the TPMT_SENSITIVE structure is
+// encrypted within a TPM2B_PRIVATE structure
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct Tpm2bPublic {
+    #[tpm2b]
+    pub public: TpmtPublic,
+}
+
+impl Tpm2bPublic {
+    pub fn new_rsa(key: &rsa::RsaPublicKey) -> Self {
+        Tpm2bPublic {
+            public: TpmtPublic::new_rsa(key),
+        }
+    }
+
+    pub fn new_public_data_object(parent: &rsa::RsaPublicKey, sensitive: &TpmtSensitive) -> Self {
+        Tpm2bPublic {
+            public: TpmtPublic::new_data_object(sensitive),
+        }
+    }
+}
+
+// TPM2B_DATA
+pub type Tpm2bData = Tpm2bBuffer<MAX_DATA_SIZE>;
+
+// MAX_CONTEXT_SIZE bounds the size of a saved context. TPMs report their own
+// limit as TPM_PT_MAX_CONTEXT_SIZE, which is below this value for common
+// implementations.
+pub const MAX_CONTEXT_SIZE: usize = 3072;
+
+// TPM2B_CONTEXT_DATA
+pub type Tpm2bContextData = Tpm2bBuffer<MAX_CONTEXT_SIZE>;
+
+// TPMS_CONTEXT
+#[derive(Debug, Clone, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsContext {
+    pub sequence: u64,
+    pub saved_handle: Handle,
+    pub hierarchy: Handle,
+    pub context_blob: Tpm2bContextData,
+}
+
+impl TpmsContext {
+    pub fn new() -> Self {
+        TpmsContext {
+            sequence: 0,
+            saved_handle: 0,
+            hierarchy: 0,
+            context_blob: Tpm2bContextData::new(),
+        }
     }
 }
 
 // TPMS_AUTH_COMMAND structure
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Tpm2StructOut)]
 pub struct TpmsAuthCommand {
     pub session_handle: TpmiShAuthSession,
     pub nonce: Tpm2bNonce,
@@ -1348,18 +1858,1441 @@ pub struct TpmsAuthCommand {
     pub hmac: Tpm2bAuth,
 }
 
-impl inout::Tpm2StructOut for TpmsAuthCommand {
-    fn pack(&self, buff: &mut dyn inout::RwBytes) {
-        self.session_handle.pack(buff);
-        self.nonce.pack(buff);
-        self.session_attributes.pack(buff);
-        self.hmac.pack(buff);
-    }
-}
-
 // TPMS_AUTH_RESPONSE
+#[derive(Debug, Clone, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
 pub struct TpmsAuthResponse {
     pub nonce: Tpm2bNonce,
     pub session_attributes: TpmaSession,
     pub hmac: Tpm2bAuth,
 }
+
+// MAX_NAME_SIZE is the size of TPMU_NAME, whose largest member is a TPMT_HA
+pub const MAX_NAME_SIZE: usize = 2 + MAX_HASH_SIZE;
+// MAX_NV_BUFFER_SIZE is the MAX_NV_BUFFER_SIZE of the reference
+// implementation, TPMs report their own limit as TPM_PT_NV_BUFFER_MAX
+pub const MAX_NV_BUFFER_SIZE: usize = 1024;
+
+// TPM2B_NAME
+pub type Tpm2bName = Tpm2bBuffer<MAX_NAME_SIZE>;
+
+// TPM2B_MAX_NV_BUFFER
+pub type Tpm2bMaxNvBuffer = Tpm2bBuffer<MAX_NV_BUFFER_SIZE>;
+
+// TPMS_CLOCK_INFO
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsClockInfo {
+    pub clock: u64,
+    pub reset_count: u32,
+    pub restart_count: u32,
+    pub safe: TpmiYesNo,
+}
+
+// TPMS_TIME_INFO
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsTimeInfo {
+    pub time: u64,
+    pub clock_info: TpmsClockInfo,
+}
+
+// TPMS_TIME_ATTEST_INFO
+#[derive(Copy, Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsTimeAttestInfo {
+    pub time: TpmsTimeInfo,
+    pub firmware_version: u64,
+}
+
+// TPMS_CERTIFY_INFO
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsCertifyInfo {
+    pub name: Tpm2bName,
+    pub qualified_name: Tpm2bName,
+}
+
+// TPMS_QUOTE_INFO
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsQuoteInfo {
+    pub pcr_select: TpmlPcrSelection,
+    pub pcr_digest: Tpm2bDigest,
+}
+
+// TPMS_COMMAND_AUDIT_INFO
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsCommandAuditInfo {
+    pub audit_counter: u64,
+    pub digest_alg: TpmAlgId,
+    pub audit_digest: Tpm2bDigest,
+    pub command_digest: Tpm2bDigest,
+}
+
+// TPMS_SESSION_AUDIT_INFO
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsSessionAuditInfo {
+    pub exclusive_session: TpmiYesNo,
+    pub session_digest: Tpm2bDigest,
+}
+
+// TPMS_CREATION_INFO
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsCreationInfo {
+    pub object_name: Tpm2bName,
+    pub creation_hash: Tpm2bDigest,
+}
+
+// TPMS_NV_CERTIFY_INFO
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsNvCertifyInfo {
+    pub index_name: Tpm2bName,
+    pub offset: u16,
+    pub nv_contents: Tpm2bMaxNvBuffer,
+}
+
+// TPMS_NV_DIGEST_CERTIFY_INFO
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsNvDigestCertifyInfo {
+    pub index_name: Tpm2bName,
+    pub nv_digest: Tpm2bDigest,
+}
+
+// TPMU_ATTEST
+#[derive(Clone, Debug, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub enum TpmuAttest {
+    #[selector = "TPM_ST_ATTEST_CERTIFY"]
+    Certify(TpmsCertifyInfo),
+    #[selector = "TPM_ST_ATTEST_CREATION"]
+    Creation(TpmsCreationInfo),
+    #[selector = "TPM_ST_ATTEST_QUOTE"]
+    Quote(TpmsQuoteInfo),
+    #[selector = "TPM_ST_ATTEST_COMMAND_AUDIT"]
+    CommandAudit(TpmsCommandAuditInfo),
+    #[selector = "TPM_ST_ATTEST_SESSION_AUDIT"]
+    SessionAudit(TpmsSessionAuditInfo),
+    #[selector = "TPM_ST_ATTEST_TIME"]
+    Time(TpmsTimeAttestInfo),
+    #[selector = "TPM_ST_ATTEST_NV"]
+    Nv(TpmsNvCertifyInfo),
+    #[selector = "TPM_ST_ATTEST_NV_DIGEST"]
+    NvDigest(TpmsNvDigestCertifyInfo),
+}
+
+impl Default for TpmuAttest {
+    fn default() -> Self {
+        TpmuAttest::Quote(TpmsQuoteInfo::default())
+    }
+}
+
+// TPMS_ATTEST
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct TpmsAttest {
+    pub magic: TpmGenerated,
+    pub attest_type: TpmiStAttest,
+    pub qualified_signer: Tpm2bName,
+    pub extra_data: Tpm2bData,
+    pub clock_info: TpmsClockInfo,
+    pub firmware_version: u64,
+    #[selector = "attest_type"]
+    pub attested: TpmuAttest,
+}
+
+impl TpmsAttest {
+    // is_tpm_generated tells whether the structure carries the magic of
+    // the TPM. It does not tell whether the signature over it is valid.
+    pub fn is_tpm_generated(&self) -> bool {
+        self.magic == TPM_GENERATED_VALUE
+    }
+}
+
+// TPM2B_ATTEST
+#[derive(Clone, Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+pub struct Tpm2bAttest {
+    #[tpm2b]
+    pub attestation_data: TpmsAttest,
+}
+
+// ClockInfo is a TPMS_CLOCK_INFO as printed by tpm2_print
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClockInfo {
+    clock: u64,
+    reset_count: u32,
+    restart_count: u32,
+    safe: TpmiYesNo,
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for TpmsClockInfo {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        ClockInfo {
+            clock: self.clock,
+            reset_count: self.reset_count,
+            restart_count: self.restart_count,
+            safe: self.safe,
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TpmsClockInfo {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        let info = ClockInfo::deserialize(deserializer)?;
+        Ok(TpmsClockInfo {
+            clock: info.clock,
+            reset_count: info.reset_count,
+            restart_count: info.restart_count,
+            safe: info.safe,
+        })
+    }
+}
+
+// PcrSelectionInfo is a TPMS_PCR_SELECTION as printed by tpm2_print, with
+// the hash algorithm by value and name, e.g. "11 (sha256)". Only the value
+// is used when deserializing.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PcrSelectionInfo {
+    hash: String,
+    sizeof_select: u8,
+    pcr_select: String,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PcrSelectInfo {
+    count: u32,
+    pcr_selections: BTreeMap<u32, PcrSelectionInfo>,
+}
+
+// QuoteInfo is a TPMS_QUOTE_INFO as printed by tpm2_print. Unlike
+// TpmlPcrSelection on its own, the selection is printed field by field.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuoteInfo {
+    pcr_select: PcrSelectInfo,
+    pcr_digest: Tpm2bDigest,
+}
+
+#[cfg(feature = "serde")]
+impl From<&TpmsQuoteInfo> for QuoteInfo {
+    fn from(quote: &TpmsQuoteInfo) -> Self {
+        let count = (quote.pcr_select.count as usize).min(TPM2_NUM_PCR_BANKS);
+        let pcr_selections = quote.pcr_select.pcr_selections[..count]
+            .iter()
+            .enumerate()
+            .map(|(index, selection)| {
+                let size = (selection.sizeof_select as usize).min(TPM2_PCR_SELECT_MAX);
+                let info = PcrSelectionInfo {
+                    hash: format!("{} ({})", selection.hash as u16, selection.hash.name()),
+                    sizeof_select: selection.sizeof_select,
+                    pcr_select: hex::encode(&selection.pcr_select[..size]),
+                };
+                (index as u32, info)
+            })
+            .collect();
+        QuoteInfo {
+            pcr_select: PcrSelectInfo {
+                count: count as u32,
+                pcr_selections,
+            },
+            pcr_digest: quote.pcr_digest.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<QuoteInfo> for TpmsQuoteInfo {
+    type Error = String;
+
+    fn try_from(info: QuoteInfo) -> result::Result<Self, Self::Error> {
+        let selections = info.pcr_select.pcr_selections;
+        if selections.len() > TPM2_NUM_PCR_BANKS {
+            return Err(format!(
+                "more than {} PCR banks selected",
+                TPM2_NUM_PCR_BANKS
+            ));
+        }
+        if selections.len() != info.pcr_select.count as usize {
+            return Err(format!(
+                "count {} does not match the {} PCR selections",
+                info.pcr_select.count,
+                selections.len()
+            ));
+        }
+        let mut pcr_select = TpmlPcrSelection::new();
+        for (index, selection) in selections.into_values().enumerate() {
+            let hash = selection
+                .hash
+                .split_whitespace()
+                .next()
+                .and_then(|value| value.parse::<u16>().ok())
+                .and_then(|value| TpmAlgId::try_from(value).ok())
+                .ok_or(format!("invalid hash {}", selection.hash))?;
+            let bitmap = tools::decode_hex(&selection.pcr_select)?;
+            if bitmap.len() != selection.sizeof_select as usize
+                || bitmap.len() > TPM2_PCR_SELECT_MAX
+            {
+                return Err(format!("invalid pcrSelect {}", selection.pcr_select));
+            }
+            let pcrs = &mut pcr_select.pcr_selections[index];
+            pcrs.hash = hash;
+            pcrs.sizeof_select = selection.sizeof_select;
+            pcrs.pcr_select[..bitmap.len()].copy_from_slice(&bitmap);
+        }
+        pcr_select.count = info.pcr_select.count;
+        Ok(TpmsQuoteInfo {
+            pcr_select,
+            pcr_digest: info.pcr_digest,
+        })
+    }
+}
+
+// TpmsQuoteInfo serializes as tpm2_print -t TPMS_QUOTE_INFO prints it
+#[cfg(feature = "serde")]
+impl serde::Serialize for TpmsQuoteInfo {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        QuoteInfo::from(self).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TpmsQuoteInfo {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        TpmsQuoteInfo::try_from(QuoteInfo::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct AttestedInfo {
+    quote: TpmsQuoteInfo,
+}
+
+// AttestInfo is a TPMS_ATTEST as printed by tpm2_print. magic, type and
+// firmwareVersion are printed as big-endian hex strings.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AttestInfo {
+    magic: String,
+    #[serde(rename = "type")]
+    attest_type: String,
+    qualified_signer: Tpm2bName,
+    extra_data: Tpm2bData,
+    clock_info: TpmsClockInfo,
+    firmware_version: String,
+    attested: AttestedInfo,
+}
+
+// decode_be decodes a big-endian hex string of exactly N bytes
+#[cfg(feature = "serde")]
+fn decode_be<const N: usize>(value: &str, name: &str) -> result::Result<[u8; N], String> {
+    let bytes = tools::decode_hex(value)?;
+    <[u8; N]>::try_from(bytes.as_slice())
+        .map_err(|_| format!("{} must be {} bytes, got {}", name, N, bytes.len()))
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<&TpmsAttest> for AttestInfo {
+    type Error = String;
+
+    fn try_from(attest: &TpmsAttest) -> result::Result<Self, Self::Error> {
+        let quote = match &attest.attested {
+            TpmuAttest::Quote(quote) if attest.attest_type == TPM_ST_ATTEST_QUOTE => quote,
+            _ => {
+                return Err(format!(
+                    "cannot serialize TpmsAttest of type {:#x}",
+                    attest.attest_type
+                ))
+            }
+        };
+        Ok(AttestInfo {
+            magic: hex::encode(attest.magic.to_be_bytes()),
+            attest_type: hex::encode(attest.attest_type.to_be_bytes()),
+            qualified_signer: attest.qualified_signer.clone(),
+            extra_data: attest.extra_data.clone(),
+            clock_info: attest.clock_info,
+            firmware_version: hex::encode(attest.firmware_version.to_be_bytes()),
+            attested: AttestedInfo {
+                quote: quote.clone(),
+            },
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<AttestInfo> for TpmsAttest {
+    type Error = String;
+
+    fn try_from(info: AttestInfo) -> result::Result<Self, Self::Error> {
+        let attest_type = u16::from_be_bytes(decode_be(&info.attest_type, "type")?);
+        if attest_type != TPM_ST_ATTEST_QUOTE {
+            return Err(format!("unsupported attestation type {:#x}", attest_type));
+        }
+        Ok(TpmsAttest {
+            magic: u32::from_be_bytes(decode_be(&info.magic, "magic")?),
+            attest_type,
+            qualified_signer: info.qualified_signer,
+            extra_data: info.extra_data,
+            clock_info: info.clock_info,
+            firmware_version: u64::from_be_bytes(decode_be(
+                &info.firmware_version,
+                "firmwareVersion",
+            )?),
+            attested: TpmuAttest::Quote(info.attested.quote),
+        })
+    }
+}
+
+// TpmsAttest serializes as tpm2_print -t TPMS_ATTEST prints a quote. Only
+// TPM_ST_ATTEST_QUOTE attestations are supported: serializing a certify,
+// creation, audit, time or NV attestation fails, as does deserializing one.
+// They still marshal with Tpm2StructIn and Tpm2StructOut.
+#[cfg(feature = "serde")]
+impl serde::Serialize for TpmsAttest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        AttestInfo::try_from(self)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TpmsAttest {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        TpmsAttest::try_from(AttestInfo::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm2::serialization::inout::{DynamicByteBuffer, Tpm2StructIn};
+    use rsa::pkcs8::DecodePublicKey;
+
+    // roundtrip packs value, unpacks it into a default object and checks
+    // that the latter packs to the same bytes
+    fn roundtrip<T: Tpm2StructIn + Tpm2StructOut + Default>(value: &T) -> (T, Vec<u8>) {
+        let mut buff = DynamicByteBuffer::new();
+        value.pack(&mut buff).unwrap();
+        let mut unpacked = T::default();
+        unpacked.unpack(&mut buff).unwrap();
+        assert_eq!(buff.remaining(), 0);
+
+        let mut repacked = DynamicByteBuffer::new();
+        unpacked.pack(&mut repacked).unwrap();
+        assert_eq!(repacked.to_bytes(), buff.to_bytes());
+        (unpacked, buff.into_vec())
+    }
+
+    fn unpack<T: Tpm2StructIn + Default>(
+        bytes: &[u8],
+    ) -> result::Result<T, errors::DeserializationError> {
+        let mut value = T::default();
+        value.unpack(&mut DynamicByteBuffer::from_vec(bytes.to_vec()))?;
+        Ok(value)
+    }
+
+    fn ek_public_key() -> rsa::RsaPublicKey {
+        rsa::RsaPublicKey::from_public_key_pem(include_str!("../../../data/ek.pub")).unwrap()
+    }
+
+    // sized wraps a TPMT structure into its TPM2B counterpart
+    fn sized(value: &dyn Tpm2StructOut) -> Vec<u8> {
+        let mut buff = DynamicByteBuffer::new();
+        inout::pack_sized(value, &mut buff).unwrap();
+        buff.into_vec()
+    }
+
+    // srk_public is the outPublic of TPM2_CreatePrimary for the RSA 2048
+    // storage key template
+    fn srk_public() -> Vec<u8> {
+        srk_rsa_public(2048)
+    }
+
+    // srk_rsa_public is the outPublic of TPM2_CreatePrimary for the RSA
+    // storage key template with a key_bits modulus
+    fn srk_rsa_public(key_bits: u16) -> Vec<u8> {
+        let modulus = key_bits / 8;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(26 + modulus).to_be_bytes()); // size
+        bytes.extend_from_slice(&[
+            0x00, 0x01, // type: TPM_ALG_RSA
+            0x00, 0x0b, // nameAlg: TPM_ALG_SHA256
+            0x00, 0x03, 0x00, 0x72, // objectAttributes
+            0x00, 0x00, // authPolicy
+            0x00, 0x06, 0x00, 0x80, 0x00, 0x43, // symmetric: AES 128 CFB
+            0x00, 0x10, // scheme: TPM_ALG_NULL
+        ]);
+        bytes.extend_from_slice(&key_bits.to_be_bytes()); // keyBits
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // exponent
+        bytes.extend_from_slice(&modulus.to_be_bytes()); // unique
+        bytes.extend((0..modulus).map(|b| b as u8));
+        bytes
+    }
+
+    // srk_ecc_public is the outPublic of TPM2_CreatePrimary for the ECC
+    // NIST P-256 storage key template
+    fn srk_ecc_public() -> Vec<u8> {
+        let mut bytes = vec![
+            0x00, 0x5a, // size
+            0x00, 0x23, // type: TPM_ALG_ECC
+            0x00, 0x0b, // nameAlg: TPM_ALG_SHA256
+            0x00, 0x03, 0x00, 0x72, // objectAttributes
+            0x00, 0x00, // authPolicy
+            0x00, 0x06, 0x00, 0x80, 0x00, 0x43, // symmetric: AES 128 CFB
+            0x00, 0x10, // scheme: TPM_ALG_NULL
+            0x00, 0x03, // curveID: TPM_ECC_NIST_P256
+            0x00, 0x10, // kdf: TPM_ALG_NULL
+            0x00, 0x20, // x
+        ];
+        bytes.extend(0..32);
+        bytes.extend_from_slice(&[0x00, 0x20]); // y
+        bytes.extend(32..64);
+        bytes
+    }
+
+    #[test]
+    fn sized_buffer() {
+        let (digest, bytes) = roundtrip(&Tpm2bDigest::from_slice(&[0xaa; 32]));
+        assert_eq!(bytes[..2], [0x00, 0x20]);
+        assert_eq!(digest.get_buffer(), &[0xaa; 32][..]);
+
+        let (_, bytes) = roundtrip(&Tpm2bDigest::new());
+        assert_eq!(bytes, [0x00, 0x00]);
+
+        // larger than a TPM2B_DIGEST can be
+        let oversized = Tpm2bDigest::from_slice(&[0; MAX_HASH_SIZE + 1]);
+        assert!(oversized.pack(&mut DynamicByteBuffer::new()).is_err());
+        let mut bytes = vec![0x00, MAX_HASH_SIZE as u8 + 1];
+        bytes.extend(std::iter::repeat(0).take(MAX_HASH_SIZE + 1));
+        assert!(unpack::<Tpm2bDigest>(&bytes).is_err());
+    }
+
+    #[test]
+    fn private_size() {
+        // sizeof(TPM2B_PRIVATE) of a TPM supporting RSA 4096 and SHA-512,
+        // the vendor specific sensitive area being the largest
+        assert_eq!(MAX_SENSITIVE_COMPOSITE_SIZE, 2 + 1280);
+        assert_eq!(MAX_SENSITIVE_SIZE, 2 + 66 + 66 + 1282);
+        assert_eq!(MAX_PRIVATE_SIZE, 66 + 66 + 2 + 1416);
+
+        let (private, _) = roundtrip(&Tpm2bPrivate::from_vec(vec![0x5a; MAX_PRIVATE_SIZE]));
+        assert_eq!(private.get_buffer().len(), MAX_PRIVATE_SIZE);
+        let oversized = Tpm2bPrivate::from_vec(vec![0x5a; MAX_PRIVATE_SIZE + 1]);
+        assert!(oversized.pack(&mut DynamicByteBuffer::new()).is_err());
+    }
+
+    #[test]
+    fn sym_def_object() {
+        let (aes, bytes) = roundtrip(&TpmtSymDefObject::new_aes_128());
+        assert_eq!(bytes, [0x00, 0x06, 0x00, 0x80, 0x00, 0x43]);
+        assert!(matches!(aes.key_bits, TpmuSymKeyBits::Sym(128)));
+        assert!(matches!(aes.mode, TpmuSymMode::Sym(TpmAlgId::CFB)));
+
+        let (null, bytes) = roundtrip(&TpmtSymDefObject::new_null());
+        assert_eq!(bytes, [0x00, 0x10]);
+        assert!(matches!(null.key_bits, TpmuSymKeyBits::Null));
+    }
+
+    #[test]
+    fn sym_def() {
+        let (_, bytes) = roundtrip(&TpmtSymDef::new_null());
+        assert_eq!(bytes, [0x00, 0x10]);
+
+        let xor: TpmtSymDef = unpack(&[0x00, 0x0a, 0x00, 0x0b]).unwrap();
+        assert!(matches!(
+            xor.key_bits,
+            TpmuSymKeyBits::Xor(TpmAlgId::SHA256)
+        ));
+        assert!(matches!(xor.mode, TpmuSymMode::Xor));
+        roundtrip(&xor);
+    }
+
+    #[test]
+    fn rsa_scheme() {
+        let (_, bytes) = roundtrip(&TpmtRsaScheme::new_tpmt_rsa_scheme());
+        assert_eq!(bytes, [0x00, 0x14, 0x00, 0x0b]);
+
+        let ecdaa: TpmtRsaScheme = unpack(&[0x00, 0x1a, 0x00, 0x0b, 0x00, 0x05]).unwrap();
+        assert!(matches!(ecdaa.details, TpmuAsymScheme::Ecdaa(scheme) if scheme.count == 5));
+
+        let rsaes: TpmtRsaScheme = unpack(&[0x00, 0x15]).unwrap();
+        assert!(matches!(rsaes.details, TpmuAsymScheme::Rsaes));
+    }
+
+    #[test]
+    fn rsa_params() {
+        let (params, _) = roundtrip(&TpmsRsaParams::new_tpms_rsa_params(&ek_public_key()));
+        assert_eq!(params.key_bits, 2048);
+        assert_eq!(params.exponent, 65537);
+    }
+
+    #[test]
+    fn public_rsa() {
+        let public = TpmtPublic::new_rsa(&ek_public_key());
+        let (unpacked, bytes) = roundtrip(&public);
+        assert_eq!(unpacked.type_alg, TpmAlgId::RSA);
+        assert!(matches!(&unpacked.unique, TpmuPublicId::Rsa(key) if key.len() == 256));
+
+        let (_, sized_bytes) = roundtrip(&Tpm2bPublic { public: public });
+        assert_eq!(sized_bytes[..2], (bytes.len() as u16).to_be_bytes());
+        assert_eq!(sized_bytes[2..], bytes[..]);
+    }
+
+    #[test]
+    fn public_data_object() {
+        let sensitive = TpmtSensitive::new("secret data".as_bytes());
+        let public = TpmtPublic::new_data_object(&sensitive);
+        let (unpacked, _) = roundtrip(&public);
+        assert_eq!(unpacked.type_alg, TpmAlgId::KeyedHash);
+        assert!(matches!(
+            unpacked.parameters,
+            TpmuPublicParms::KeyedHashDetail(params) if matches!(params.scheme.details, TpmuSchemeKeyedHash::Null)
+        ));
+        assert_eq!(get_name(&unpacked).unwrap(), get_name(&public).unwrap());
+    }
+
+    #[test]
+    fn public_from_tpm() {
+        let bytes = srk_public();
+        let public: Tpm2bPublic = unpack(&bytes).unwrap();
+        assert_eq!(public.public.type_alg, TpmAlgId::RSA);
+        assert_eq!(public.public.name_alg, TpmAlgId::SHA256);
+        assert_eq!(public.public.object_attributes, 0x00030072);
+        match public.public.parameters {
+            TpmuPublicParms::RsaDetail(params) => {
+                assert_eq!(params.symmetric.algorithm, TpmAlgId::AES);
+                assert_eq!(params.scheme.scheme, TpmAlgId::Null);
+                assert_eq!(params.key_bits, 2048);
+            }
+            parameters => panic!("unexpected parameters {:?}", parameters),
+        }
+
+        let mut repacked = DynamicByteBuffer::new();
+        public.pack(&mut repacked).unwrap();
+        assert_eq!(repacked.to_bytes(), &bytes[..]);
+    }
+
+    #[test]
+    fn public_rsa_4096() {
+        let bytes = srk_rsa_public(4096);
+        let public: Tpm2bPublic = unpack(&bytes).unwrap();
+        match &public.public.parameters {
+            TpmuPublicParms::RsaDetail(params) => assert_eq!(params.key_bits, 4096),
+            parameters => panic!("unexpected parameters {:?}", parameters),
+        }
+        match &public.public.unique {
+            TpmuPublicId::Rsa(modulus) => assert_eq!(modulus.get_buffer().len(), 512),
+            unique => panic!("unexpected unique {:?}", unique),
+        }
+
+        let (_, repacked) = roundtrip(&public);
+        assert_eq!(repacked, bytes);
+    }
+
+    #[test]
+    fn public_malformed() {
+        let bytes = srk_public();
+        // truncated
+        for len in 0..bytes.len() {
+            assert!(unpack::<Tpm2bPublic>(&bytes[..len]).is_err());
+        }
+        // size not matching the structure
+        let mut trailing = bytes.clone();
+        trailing[1] += 1;
+        trailing.push(0);
+        assert!(unpack::<Tpm2bPublic>(&trailing).is_err());
+        // not an object type
+        let mut null = bytes.clone();
+        null[3] = 0x10;
+        assert!(unpack::<Tpm2bPublic>(&null).is_err());
+    }
+
+    #[test]
+    fn public_ecc() {
+        let bytes = srk_ecc_public();
+        let (public, repacked) = roundtrip(&unpack::<Tpm2bPublic>(&bytes).unwrap());
+        assert_eq!(repacked, bytes);
+        assert_eq!(public.public.type_alg, TpmAlgId::ECC);
+        match public.public.parameters {
+            TpmuPublicParms::EccDetail(params) => {
+                assert_eq!(params.symmetric, TpmtSymDefObject::new_aes_128());
+                assert_eq!(params.scheme.scheme, TpmAlgId::Null);
+                assert_eq!(params.curve_id, TPM_ECC_NIST_P256);
+                assert_eq!(params.kdf.scheme, TpmAlgId::Null);
+            }
+            parameters => panic!("unexpected parameters {:?}", parameters),
+        }
+        match &public.public.unique {
+            TpmuPublicId::Ecc(point) => {
+                assert_eq!(point.x.get_buffer(), &bytes[26..58]);
+                assert_eq!(point.y.get_buffer(), &bytes[60..]);
+            }
+            unique => panic!("unexpected unique {:?}", unique),
+        }
+
+        // ECDSA signing key with a KDF
+        let mut signing = bytes.clone();
+        signing.splice(18..20, [0x00, 0x18, 0x00, 0x0b]);
+        signing.splice(24..26, [0x00, 0x20, 0x00, 0x0b]);
+        signing[1] += 4;
+        let (public, repacked) = roundtrip(&unpack::<Tpm2bPublic>(&signing).unwrap());
+        assert_eq!(repacked, signing);
+        match public.public.parameters {
+            TpmuPublicParms::EccDetail(params) => {
+                let sha256 = TpmsSchemeHash {
+                    hash_alg: TpmAlgId::SHA256,
+                };
+                assert_eq!(params.scheme.details, TpmuAsymScheme::Ecdsa(sha256));
+                assert_eq!(params.kdf.details, TpmuKdfScheme::Kdf1Sp80056a(sha256));
+            }
+            parameters => panic!("unexpected parameters {:?}", parameters),
+        }
+    }
+
+    // quote is the TPMS_ATTEST signed by TPM2_Quote over PCRs 0 to 2 of the
+    // SHA256 bank
+    fn quote() -> Vec<u8> {
+        let mut bytes = vec![
+            0xff, 0x54, 0x43, 0x47, // magic: TPM_GENERATED_VALUE
+            0x80, 0x18, // type: TPM_ST_ATTEST_QUOTE
+            0x00, 0x22, 0x00, 0x0b, // qualifiedSigner
+        ];
+        bytes.extend(0..32);
+        bytes.extend_from_slice(&[0x00, 0x04, 0xca, 0xfe, 0xba, 0xbe]); // extraData
+        bytes.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xe2, 0x40, // clock
+            0x00, 0x00, 0x00, 0x02, // resetCount
+            0x00, 0x00, 0x00, 0x03, // restartCount
+            0x01, // safe
+            0x20, 0x19, 0x10, 0x23, 0x00, 0x16, 0x36, 0x36, // firmwareVersion
+            0x00, 0x00, 0x00, 0x01, // pcrSelect.count
+            0x00, 0x0b, 0x03, 0x07, 0x00, 0x00, // sha256: 0, 1, 2
+            0x00, 0x20, // pcrDigest
+        ]);
+        bytes.extend(32..64);
+        bytes
+    }
+
+    #[test]
+    fn attest_quote() {
+        let bytes = quote();
+        let (attest, repacked) = roundtrip(&unpack::<TpmsAttest>(&bytes).unwrap());
+        assert_eq!(repacked, bytes);
+        assert!(attest.is_tpm_generated());
+        assert_eq!(attest.attest_type, TPM_ST_ATTEST_QUOTE);
+        assert_eq!(attest.qualified_signer.len(), 34);
+        assert_eq!(attest.extra_data.get_buffer(), [0xca, 0xfe, 0xba, 0xbe]);
+        assert_eq!(
+            attest.clock_info,
+            TpmsClockInfo {
+                clock: 123456,
+                reset_count: 2,
+                restart_count: 3,
+                safe: 1,
+            }
+        );
+        assert_eq!(attest.firmware_version, 0x2019102300163636);
+        match &attest.attested {
+            TpmuAttest::Quote(quote) => {
+                assert_eq!(quote.pcr_select.count, 1);
+                assert_eq!(quote.pcr_select.pcr_selections[0].hash, TpmAlgId::SHA256);
+                assert_eq!(quote.pcr_digest.get_buffer(), &bytes[bytes.len() - 32..]);
+            }
+            attested => panic!("unexpected attested {:?}", attested),
+        }
+
+        let (_, sized_bytes) = roundtrip(&Tpm2bAttest {
+            attestation_data: attest,
+        });
+        assert_eq!(sized_bytes, sized(&unpack::<TpmsAttest>(&bytes).unwrap()));
+
+        for len in 0..bytes.len() {
+            assert!(unpack::<TpmsAttest>(&bytes[..len]).is_err());
+        }
+        // not an attestation type
+        let mut command = bytes.clone();
+        command[5] = 0x01;
+        assert!(unpack::<TpmsAttest>(&command).is_err());
+    }
+
+    #[test]
+    fn sensitive() {
+        let sensitive = TpmtSensitive::new("secret data".as_bytes());
+        let (unpacked, bytes) = roundtrip(&sensitive);
+        assert_eq!(unpacked.sensitive_type, TpmAlgId::KeyedHash);
+        match &unpacked.sensitive {
+            TpmuSensitiveComposite::Bits(data) => {
+                assert_eq!(data.get_buffer(), "secret data".as_bytes())
+            }
+            _ => panic!("unexpected sensitive composite"),
+        }
+
+        let (_, sized_bytes) = roundtrip(&Tpm2bSensitive {
+            sensitive_area: sensitive.clone(),
+        });
+        assert_eq!(sized_bytes, sized(&sensitive));
+        assert_eq!(sized_bytes[2..], bytes[..]);
+    }
+
+    #[test]
+    fn sensitive_rsa() {
+        let mut bytes = vec![
+            0x00, 0x01, // sensitiveType: TPM_ALG_RSA
+            0x00, 0x00, // authValue
+            0x00, 0x00, // seedValue
+            0x00, 0x80, // sensitive
+        ];
+        bytes.extend(std::iter::repeat(0xa5).take(128));
+        let (_, repacked) = roundtrip(&unpack::<TpmtSensitive>(&bytes).unwrap());
+        assert_eq!(repacked, bytes);
+
+        // A prime of an RSA 4096 key fits, a modulus does not
+        let prime = Tpm2bPrivateKeyRsa::from_vec(vec![0xa5; RSA_PRIVATE_SIZE]);
+        let (_, repacked) = roundtrip(&TpmtSensitive {
+            sensitive_type: TpmAlgId::RSA,
+            auth_value: Tpm2bAuth::new(),
+            seed_value: Tpm2bDigest::new(),
+            sensitive: TpmuSensitiveComposite::Rsa(prime),
+        });
+        assert_eq!(repacked.len(), 8 + RSA_PRIVATE_SIZE);
+        let modulus = Tpm2bPrivateKeyRsa::from_vec(vec![0xa5; MAX_RSA_KEY_BYTES]);
+        assert!(modulus.pack(&mut DynamicByteBuffer::new()).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_public() {
+        let bytes = srk_public();
+        let public: TpmtPublic = unpack(&bytes[2..]).unwrap();
+        let json = serde_json::to_value(&public).unwrap();
+        assert_eq!(json["type"]["value"], "rsa");
+        assert_eq!(json["name-alg"]["raw"], 0x0b);
+        assert_eq!(
+            json["attributes"]["value"],
+            "fixedtpm|fixedparent|sensitivedataorigin|userwithauth|restricted|decrypt"
+        );
+        assert_eq!(json["sym-alg"]["value"], "aes");
+        assert_eq!(json["sym-mode"]["value"], "cfb");
+        assert_eq!(json["sym-keybits"], 128);
+        assert_eq!(json["scheme"]["value"], "null");
+        assert_eq!(json["bits"], 2048);
+        assert_eq!(json["rsa"], hex::encode(&bytes[bytes.len() - 256..]));
+        assert_eq!(json["authorization policy"], "");
+
+        let (_, repacked) = roundtrip(&serde_json::from_value::<TpmtPublic>(json).unwrap());
+        assert_eq!(repacked, bytes[2..]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_public_ecc() {
+        let bytes = srk_ecc_public();
+        let public: TpmtPublic = unpack(&bytes[2..]).unwrap();
+        let json = serde_json::to_value(&public).unwrap();
+        assert_eq!(json["type"]["value"], "ecc");
+        assert_eq!(json["curve-id"]["value"], "NIST p256");
+        assert_eq!(json["curve-id"]["raw"], 0x03);
+        assert_eq!(json["kdfa-alg"]["value"], "null");
+        assert_eq!(json["scheme"]["value"], "null");
+        assert_eq!(json["sym-alg"]["value"], "aes");
+        assert_eq!(json["x"], hex::encode(&bytes[26..58]));
+        assert_eq!(json["y"], hex::encode(&bytes[60..]));
+
+        let (_, repacked) = roundtrip(&serde_json::from_value::<TpmtPublic>(json).unwrap());
+        assert_eq!(repacked, bytes[2..]);
+
+        let mut json = serde_json::to_value(&public).unwrap();
+        json["scheme"]["value"] = serde_json::json!("rsassa");
+        json["scheme-halg"] = serde_json::json!({"value": "sha256"});
+        assert!(serde_json::from_value::<TpmtPublic>(json).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_attest() {
+        let bytes = quote();
+        let attest: TpmsAttest = unpack(&bytes).unwrap();
+        let json = serde_json::to_value(&attest).unwrap();
+        assert_eq!(json["magic"], "ff544347");
+        assert_eq!(json["type"], "8018");
+        assert_eq!(json["qualifiedSigner"], hex::encode(&bytes[8..42]));
+        assert_eq!(json["extraData"], "cafebabe");
+        assert_eq!(
+            json["clockInfo"],
+            serde_json::json!({"clock": 123456, "resetCount": 2, "restartCount": 3, "safe": 1})
+        );
+        assert_eq!(json["firmwareVersion"], "2019102300163636");
+        let quote = &json["attested"]["quote"];
+        assert_eq!(quote["pcrSelect"]["count"], 1);
+        assert_eq!(
+            quote["pcrSelect"]["pcrSelections"]["0"],
+            serde_json::json!({"hash": "11 (sha256)", "sizeofSelect": 3, "pcrSelect": "070000"})
+        );
+        assert_eq!(quote["pcrDigest"], hex::encode(&bytes[bytes.len() - 32..]));
+
+        let (_, repacked) = roundtrip(&serde_json::from_value::<TpmsAttest>(json.clone()).unwrap());
+        assert_eq!(repacked, bytes);
+
+        let mut count = json.clone();
+        count["attested"]["quote"]["pcrSelect"]["count"] = serde_json::json!(2);
+        assert!(serde_json::from_value::<TpmsAttest>(count).is_err());
+        let mut magic = json.clone();
+        magic["magic"] = serde_json::json!("ff5443");
+        assert!(serde_json::from_value::<TpmsAttest>(magic).is_err());
+
+        // Attestations other than quotes are not supported
+        let time = TpmsAttest {
+            attest_type: TPM_ST_ATTEST_TIME,
+            attested: TpmuAttest::Time(TpmsTimeAttestInfo::default()),
+            ..attest
+        };
+        assert!(serde_json::to_value(&time).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_pcr_selection() {
+        let json = r#"[{"sha1":[0,1]},{"sha256":[7,16,23]}]"#;
+        let selection: TpmlPcrSelection = serde_json::from_str(json).unwrap();
+        let (_, bytes) = roundtrip(&selection);
+        assert_eq!(
+            bytes,
+            [
+                0x00, 0x00, 0x00, 0x02, // count
+                0x00, 0x04, 0x03, 0x03, 0x00, 0x00, // sha1: 0, 1
+                0x00, 0x0b, 0x03, 0x80, 0x00, 0x81, // sha256: 7, 16, 23
+            ]
+        );
+        assert_eq!(serde_json::to_string(&selection).unwrap(), json);
+
+        assert!(serde_json::from_str::<TpmlPcrSelection>(r#"[{"sha256":[24]}]"#).is_err());
+        assert!(serde_json::from_str::<TpmlPcrSelection>(r#"[{"sha257":[0]}]"#).is_err());
+    }
+}
+
+// Property based round trip tests: every type marshalled in both directions
+// must unpack to the value it was packed from
+#[cfg(test)]
+mod proptests {
+    use super::*;
+    use crate::tpm2::serialization::inout::{DynamicByteBuffer, Tpm2StructIn};
+    use proptest::prelude::*;
+    use proptest::sample::select;
+    use std::convert::TryFrom;
+
+    // roundtrip checks that value unpacks from the bytes it packs to, and
+    // that unpacking consumes them all
+    fn roundtrip<T: Tpm2StructIn + Tpm2StructOut + Default + PartialEq>(
+        value: &T,
+    ) -> result::Result<(), TestCaseError> {
+        let mut buff = DynamicByteBuffer::new();
+        value
+            .pack(&mut buff)
+            .map_err(|err| TestCaseError::fail(err.msg))?;
+        let mut unpacked = T::default();
+        unpacked
+            .unpack(&mut buff)
+            .map_err(|err| TestCaseError::fail(err.msg))?;
+        prop_assert_eq!(buff.remaining(), 0);
+        prop_assert!(unpacked == *value);
+        Ok(())
+    }
+
+    fn alg() -> impl Strategy<Value = TpmAlgId> {
+        (0u16..=0x44).prop_filter_map("not an algorithm", |value| TpmAlgId::try_from(value).ok())
+    }
+
+    fn hash_alg() -> impl Strategy<Value = TpmiAlgHash> {
+        select(vec![
+            TpmAlgId::SHA1,
+            TpmAlgId::SHA256,
+            TpmAlgId::SHA384,
+            TpmAlgId::SHA512,
+        ])
+    }
+
+    fn buffer<const MAX: usize>() -> impl Strategy<Value = Tpm2bBuffer<MAX>> {
+        prop::collection::vec(any::<u8>(), 0..=MAX).prop_map(Tpm2bBuffer::from_vec)
+    }
+
+    fn pcr_selection() -> impl Strategy<Value = TpmsPcrSelection> {
+        (
+            hash_alg(),
+            0..=TPM2_PCR_SELECT_MAX,
+            any::<[u8; TPM2_PCR_SELECT_MAX]>(),
+        )
+            .prop_map(|(hash, size, mut pcr_select)| {
+                pcr_select[size..].iter_mut().for_each(|byte| *byte = 0);
+                TpmsPcrSelection {
+                    hash,
+                    sizeof_select: size as u8,
+                    pcr_select,
+                }
+            })
+    }
+
+    fn pcr_selections() -> impl Strategy<Value = TpmlPcrSelection> {
+        prop::collection::vec(pcr_selection(), 0..=TPM2_NUM_PCR_BANKS).prop_map(|selections| {
+            let mut list = TpmlPcrSelection::new();
+            list.count = selections.len() as u32;
+            list.pcr_selections[..selections.len()].copy_from_slice(&selections);
+            list
+        })
+    }
+
+    fn scheme_hash() -> impl Strategy<Value = TpmsSchemeHash> {
+        hash_alg().prop_map(|hash_alg| TpmsSchemeHash { hash_alg })
+    }
+
+    fn scheme_ecdaa() -> impl Strategy<Value = TpmsSchemeEcdaa> {
+        (hash_alg(), any::<u16>()).prop_map(|(hash_alg, count)| TpmsSchemeEcdaa { hash_alg, count })
+    }
+
+    fn scheme_xor() -> impl Strategy<Value = TpmsSchemeXor> {
+        let kdf = select(vec![
+            TpmAlgId::KDF1_SP800_56A,
+            TpmAlgId::KDF2,
+            TpmAlgId::KDF1_SP800_108,
+        ]);
+        (hash_alg(), kdf).prop_map(|(hash_alg, kdf)| TpmsSchemeXor { hash_alg, kdf })
+    }
+
+    fn keyed_hash_scheme() -> impl Strategy<Value = TpmtKeyedHashScheme> {
+        prop_oneof![
+            scheme_hash().prop_map(|hmac| TpmtKeyedHashScheme {
+                scheme: TpmAlgId::HMAC,
+                details: TpmuSchemeKeyedHash::Hmac(hmac),
+            }),
+            scheme_xor().prop_map(|xor| TpmtKeyedHashScheme {
+                scheme: TpmAlgId::XOR,
+                details: TpmuSchemeKeyedHash::Xor(xor),
+            }),
+            Just(TpmtKeyedHashScheme::new_keyed_hash_scheme()),
+        ]
+    }
+
+    // sym_def is the content of both TPMT_SYM_DEF and TPMT_SYM_DEF_OBJECT
+    fn sym_def() -> impl Strategy<Value = (TpmAlgId, TpmuSymKeyBits, TpmuSymMode, TpmuSymDetails)> {
+        let block_cipher = select(vec![TpmAlgId::AES, TpmAlgId::SM4, TpmAlgId::Camellia]);
+        let mode = select(vec![
+            TpmAlgId::CTR,
+            TpmAlgId::OFB,
+            TpmAlgId::CBC,
+            TpmAlgId::CFB,
+            TpmAlgId::ECB,
+        ]);
+        prop_oneof![
+            (block_cipher, any::<TpmKeyBits>(), mode).prop_map(|(algorithm, bits, mode)| (
+                algorithm,
+                TpmuSymKeyBits::Sym(bits),
+                TpmuSymMode::Sym(mode),
+                TpmuSymDetails::Sym
+            )),
+            hash_alg().prop_map(|hash| (
+                TpmAlgId::XOR,
+                TpmuSymKeyBits::Xor(hash),
+                TpmuSymMode::Xor,
+                TpmuSymDetails::Xor
+            )),
+            Just((
+                TpmAlgId::Null,
+                TpmuSymKeyBits::Null,
+                TpmuSymMode::Null,
+                TpmuSymDetails::Null
+            )),
+        ]
+    }
+
+    fn sym_def_object() -> impl Strategy<Value = TpmtSymDefObject> {
+        sym_def().prop_map(|(algorithm, key_bits, mode, details)| TpmtSymDefObject {
+            algorithm,
+            key_bits,
+            mode,
+            details,
+        })
+    }
+
+    fn rsa_scheme() -> impl Strategy<Value = TpmtRsaScheme> {
+        let hash_schemes = select(vec![
+            (
+                TpmAlgId::ECDSA,
+                TpmuAsymScheme::Ecdsa as fn(TpmsSchemeHash) -> TpmuAsymScheme,
+            ),
+            (TpmAlgId::RSASSA, TpmuAsymScheme::Rsassa),
+            (TpmAlgId::ECDH, TpmuAsymScheme::Ecdh),
+            (TpmAlgId::ECMQV, TpmuAsymScheme::Ecmqv),
+            (TpmAlgId::RSAPSS, TpmuAsymScheme::Rsapss),
+            (TpmAlgId::SM2, TpmuAsymScheme::Sm2),
+            (TpmAlgId::ECSCHNORR, TpmuAsymScheme::Ecschnorr),
+            (TpmAlgId::OAEP, TpmuAsymScheme::Oaep),
+        ]);
+        prop_oneof![
+            (hash_schemes, scheme_hash()).prop_map(|((scheme, variant), hash)| TpmtRsaScheme {
+                scheme,
+                details: variant(hash),
+            }),
+            scheme_ecdaa().prop_map(|ecdaa| TpmtRsaScheme {
+                scheme: TpmAlgId::ECDAA,
+                details: TpmuAsymScheme::Ecdaa(ecdaa),
+            }),
+            Just(TpmtRsaScheme {
+                scheme: TpmAlgId::RSAES,
+                details: TpmuAsymScheme::Rsaes,
+            }),
+            Just(TpmtRsaScheme {
+                scheme: TpmAlgId::Null,
+                details: TpmuAsymScheme::Null,
+            }),
+        ]
+    }
+
+    fn rsa_params() -> impl Strategy<Value = TpmsRsaParams> {
+        (sym_def_object(), rsa_scheme(), any::<u16>(), any::<u32>()).prop_map(
+            |(symmetric, scheme, key_bits, exponent)| TpmsRsaParams {
+                symmetric,
+                scheme,
+                key_bits,
+                exponent,
+            },
+        )
+    }
+
+    fn ecc_scheme() -> impl Strategy<Value = TpmtEccScheme> {
+        let hash_schemes = select(vec![
+            (
+                TpmAlgId::ECDSA,
+                TpmuAsymScheme::Ecdsa as fn(TpmsSchemeHash) -> TpmuAsymScheme,
+            ),
+            (TpmAlgId::ECDH, TpmuAsymScheme::Ecdh),
+            (TpmAlgId::ECMQV, TpmuAsymScheme::Ecmqv),
+            (TpmAlgId::SM2, TpmuAsymScheme::Sm2),
+            (TpmAlgId::ECSCHNORR, TpmuAsymScheme::Ecschnorr),
+        ]);
+        prop_oneof![
+            (hash_schemes, scheme_hash()).prop_map(|((scheme, variant), hash)| TpmtEccScheme {
+                scheme,
+                details: variant(hash),
+            }),
+            scheme_ecdaa().prop_map(|ecdaa| TpmtEccScheme {
+                scheme: TpmAlgId::ECDAA,
+                details: TpmuAsymScheme::Ecdaa(ecdaa),
+            }),
+            Just(TpmtEccScheme {
+                scheme: TpmAlgId::Null,
+                details: TpmuAsymScheme::Null,
+            }),
+        ]
+    }
+
+    fn kdf_scheme() -> impl Strategy<Value = TpmtKdfScheme> {
+        let kdfs = select(vec![
+            (
+                TpmAlgId::MGF1,
+                TpmuKdfScheme::Mgf1 as fn(TpmsSchemeHash) -> TpmuKdfScheme,
+            ),
+            (TpmAlgId::KDF1_SP800_56A, TpmuKdfScheme::Kdf1Sp80056a),
+            (TpmAlgId::KDF2, TpmuKdfScheme::Kdf2),
+            (TpmAlgId::KDF1_SP800_108, TpmuKdfScheme::Kdf1Sp800108),
+        ]);
+        prop_oneof![
+            (kdfs, scheme_hash()).prop_map(|((scheme, variant), hash)| TpmtKdfScheme {
+                scheme,
+                details: variant(hash),
+            }),
+            Just(TpmtKdfScheme {
+                scheme: TpmAlgId::Null,
+                details: TpmuKdfScheme::Null,
+            }),
+        ]
+    }
+
+    fn ecc_params() -> impl Strategy<Value = TpmsEccParms> {
+        (
+            sym_def_object(),
+            ecc_scheme(),
+            any::<TpmiEccCurve>(),
+            kdf_scheme(),
+        )
+            .prop_map(|(symmetric, scheme, curve_id, kdf)| TpmsEccParms {
+                symmetric,
+                scheme,
+                curve_id,
+                kdf,
+            })
+    }
+
+    fn ecc_point() -> impl Strategy<Value = TpmsEccPoint> {
+        (buffer(), buffer()).prop_map(|(x, y)| TpmsEccPoint { x, y })
+    }
+
+    fn public() -> impl Strategy<Value = TpmtPublic> {
+        let details = prop_oneof![
+            (keyed_hash_scheme(), buffer()).prop_map(|(scheme, unique)| (
+                TpmAlgId::KeyedHash,
+                TpmuPublicParms::KeyedHashDetail(TpmsKeyedHashParms { scheme }),
+                TpmuPublicId::KeyedHash(unique)
+            )),
+            (sym_def_object(), buffer()).prop_map(|(sym, unique)| (
+                TpmAlgId::SymCipher,
+                TpmuPublicParms::SymDetail(TpmsSymcipherParms { sym }),
+                TpmuPublicId::Sym(unique)
+            )),
+            (rsa_params(), buffer()).prop_map(|(params, unique)| (
+                TpmAlgId::RSA,
+                TpmuPublicParms::RsaDetail(params),
+                TpmuPublicId::Rsa(unique)
+            )),
+            (ecc_params(), ecc_point()).prop_map(|(params, unique)| (
+                TpmAlgId::ECC,
+                TpmuPublicParms::EccDetail(params),
+                TpmuPublicId::Ecc(unique)
+            )),
+        ];
+        (hash_alg(), any::<TpmaObject>(), buffer(), details).prop_map(
+            |(name_alg, object_attributes, auth_policy, (type_alg, parameters, unique))| {
+                TpmtPublic {
+                    type_alg,
+                    name_alg,
+                    object_attributes,
+                    auth_policy,
+                    parameters,
+                    unique,
+                }
+            },
+        )
+    }
+
+    fn sensitive() -> impl Strategy<Value = TpmtSensitive> {
+        let composite = prop_oneof![
+            buffer().prop_map(|prime| (TpmAlgId::RSA, TpmuSensitiveComposite::Rsa(prime))),
+            buffer().prop_map(|key| (TpmAlgId::ECC, TpmuSensitiveComposite::Ecc(key))),
+            (
+                select(vec![TpmAlgId::KeyedHash, TpmAlgId::SymCipher]),
+                buffer()
+            )
+                .prop_map(|(sensitive_type, bits)| (
+                    sensitive_type,
+                    TpmuSensitiveComposite::Bits(bits)
+                )),
+        ];
+        (buffer(), buffer(), composite).prop_map(
+            |(auth_value, seed_value, (sensitive_type, sensitive))| TpmtSensitive {
+                sensitive_type,
+                auth_value,
+                seed_value,
+                sensitive,
+            },
+        )
+    }
+
+    fn clock_info() -> impl Strategy<Value = TpmsClockInfo> {
+        (any::<u64>(), any::<u32>(), any::<u32>(), any::<TpmiYesNo>()).prop_map(
+            |(clock, reset_count, restart_count, safe)| TpmsClockInfo {
+                clock,
+                reset_count,
+                restart_count,
+                safe,
+            },
+        )
+    }
+
+    fn attested() -> impl Strategy<Value = (TpmiStAttest, TpmuAttest)> {
+        prop_oneof![
+            (buffer(), buffer()).prop_map(|(name, qualified_name)| (
+                TPM_ST_ATTEST_CERTIFY,
+                TpmuAttest::Certify(TpmsCertifyInfo {
+                    name,
+                    qualified_name
+                })
+            )),
+            (buffer(), buffer()).prop_map(|(object_name, creation_hash)| (
+                TPM_ST_ATTEST_CREATION,
+                TpmuAttest::Creation(TpmsCreationInfo {
+                    object_name,
+                    creation_hash
+                })
+            )),
+            (pcr_selections(), buffer()).prop_map(|(pcr_select, pcr_digest)| (
+                TPM_ST_ATTEST_QUOTE,
+                TpmuAttest::Quote(TpmsQuoteInfo {
+                    pcr_select,
+                    pcr_digest
+                })
+            )),
+            (any::<u64>(), hash_alg(), buffer(), buffer()).prop_map(
+                |(audit_counter, digest_alg, audit_digest, command_digest)| (
+                    TPM_ST_ATTEST_COMMAND_AUDIT,
+                    TpmuAttest::CommandAudit(TpmsCommandAuditInfo {
+                        audit_counter,
+                        digest_alg,
+                        audit_digest,
+                        command_digest,
+                    })
+                )
+            ),
+            (any::<TpmiYesNo>(), buffer()).prop_map(|(exclusive_session, session_digest)| (
+                TPM_ST_ATTEST_SESSION_AUDIT,
+                TpmuAttest::SessionAudit(TpmsSessionAuditInfo {
+                    exclusive_session,
+                    session_digest
+                })
+            )),
+            (any::<u64>(), clock_info(), any::<u64>()).prop_map(
+                |(time, clock_info, firmware_version)| (
+                    TPM_ST_ATTEST_TIME,
+                    TpmuAttest::Time(TpmsTimeAttestInfo {
+                        time: TpmsTimeInfo { time, clock_info },
+                        firmware_version,
+                    })
+                )
+            ),
+            (buffer(), any::<u16>(), buffer()).prop_map(|(index_name, offset, nv_contents)| (
+                TPM_ST_ATTEST_NV,
+                TpmuAttest::Nv(TpmsNvCertifyInfo {
+                    index_name,
+                    offset,
+                    nv_contents
+                })
+            )),
+            (buffer(), buffer()).prop_map(|(index_name, nv_digest)| (
+                TPM_ST_ATTEST_NV_DIGEST,
+                TpmuAttest::NvDigest(TpmsNvDigestCertifyInfo {
+                    index_name,
+                    nv_digest
+                })
+            )),
+        ]
+    }
+
+    fn attest() -> impl Strategy<Value = TpmsAttest> {
+        (
+            any::<TpmGenerated>(),
+            buffer(),
+            buffer(),
+            clock_info(),
+            any::<u64>(),
+            attested(),
+        )
+            .prop_map(
+                |(magic, qualified_signer, extra_data, clock_info, firmware_version, attested)| {
+                    TpmsAttest {
+                        magic,
+                        attest_type: attested.0,
+                        qualified_signer,
+                        extra_data,
+                        clock_info,
+                        firmware_version,
+                        attested: attested.1,
+                    }
+                },
+            )
+    }
+
+    fn context() -> impl Strategy<Value = TpmsContext> {
+        (any::<u64>(), any::<Handle>(), any::<Handle>(), buffer()).prop_map(
+            |(sequence, saved_handle, hierarchy, context_blob)| TpmsContext {
+                sequence,
+                saved_handle,
+                hierarchy,
+                context_blob,
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn primitives(byte: u8, short: u16, word: u32, long: u64, array: [u16; 4]) {
+            roundtrip(&byte)?;
+            roundtrip(&short)?;
+            roundtrip(&word)?;
+            roundtrip(&long)?;
+            roundtrip(&array)?;
+        }
+
+        #[test]
+        fn algorithm(value in alg()) {
+            roundtrip(&value)?;
+        }
+
+        #[test]
+        fn sized_buffers(
+            digest in buffer::<MAX_HASH_SIZE>(),
+            private in buffer::<MAX_PRIVATE_SIZE>(),
+            secret in buffer::<{ mem::size_of::<TpmuEncryptedSecret>() }>(),
+        ) {
+            roundtrip(&digest)?;
+            roundtrip(&private)?;
+            roundtrip(&secret)?;
+        }
+
+        #[test]
+        fn pcr_selection_list(selection in pcr_selection(), list in pcr_selections()) {
+            roundtrip(&selection)?;
+            roundtrip(&list)?;
+        }
+
+        #[test]
+        fn schemes(
+            hash in scheme_hash(),
+            ecdaa in scheme_ecdaa(),
+            xor in scheme_xor(),
+            keyed_hash in keyed_hash_scheme(),
+            rsa in rsa_scheme(),
+            ecc in ecc_scheme(),
+            kdf in kdf_scheme(),
+        ) {
+            roundtrip(&hash)?;
+            roundtrip(&ecdaa)?;
+            roundtrip(&xor)?;
+            roundtrip(&keyed_hash)?;
+            roundtrip(&TpmsKeyedHashParms { scheme: keyed_hash })?;
+            roundtrip(&rsa)?;
+            roundtrip(&ecc)?;
+            roundtrip(&kdf)?;
+        }
+
+        #[test]
+        fn sym_defs(object in sym_def_object(), (algorithm, key_bits, mode, details) in sym_def()) {
+            roundtrip(&object)?;
+            roundtrip(&TpmsSymcipherParms { sym: object })?;
+            roundtrip(&TpmtSymDef { algorithm, key_bits, mode, details })?;
+        }
+
+        #[test]
+        fn public_area(
+            params in rsa_params(),
+            ecc in ecc_params(),
+            point in ecc_point(),
+            public in public(),
+        ) {
+            roundtrip(&params)?;
+            roundtrip(&ecc)?;
+            roundtrip(&point)?;
+            roundtrip(&public)?;
+            roundtrip(&Tpm2bPublic { public })?;
+        }
+
+        #[test]
+        fn sensitive_area(sensitive in sensitive()) {
+            roundtrip(&sensitive)?;
+            roundtrip(&Tpm2bSensitive { sensitive_area: sensitive })?;
+        }
+
+        #[test]
+        fn context_blob(context in context()) {
+            roundtrip(&context)?;
+        }
+
+        #[test]
+        fn attestation(attest in attest()) {
+            roundtrip(&attest)?;
+            roundtrip(&Tpm2bAttest { attestation_data: attest })?;
+        }
+    }
+}
@@ -0,0 +1,570 @@
+// Derive macros for the Tpm2StructIn and Tpm2StructOut traits of the tpm2
+// crate (see tpm2::serialization::inout). The generated code refers to the
+// traits through crate::tpm2, so the macros can only be used from within
+// the tpm2 crate.
+//
+// Structures are marshalled field by field in declaration order. Fields may
+// carry one of the following attributes:
+//
+//   #[tpm2b]                   the field is preceded by its size as a u16
+//   #[count = "count"]         only the first count elements of the array
+//                              field are marshalled, count being a field
+//                              declared before it
+//   #[selector = "type_alg"]   the field is a union whose member is chosen
+//                              by the type_alg field, declared before it
+//
+// Unions are enums. Tpm2StructOut packs the fields of the current variant,
+// Tpm2StructIn implements Tpm2UnionIn and unpacks the variant marked with
+// the selector value, e.g. #[selector = "TpmAlgId::RSA"]. A variant may be
+// marked with several selector values, and fields of variants unpacked this
+// way must implement Default.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Attribute, Data, DataEnum, DeriveInput, Expr, ExprLit, Fields, Ident, Lit,
+    Member, Meta, Result,
+};
+
+#[proc_macro_derive(Tpm2StructOut, attributes(tpm2b, count, selector))]
+pub fn derive_tpm2_struct_out(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_out(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+#[proc_macro_derive(Tpm2StructIn, attributes(tpm2b, count, selector))]
+pub fn derive_tpm2_struct_in(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_in(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+// FieldKind is the marshalling of a structure field, as selected by its
+// attributes
+enum FieldKind {
+    Plain,
+    Sized,
+    Counted(Member),
+    Union(Member),
+}
+
+struct Field {
+    member: Member,
+    kind: FieldKind,
+}
+
+fn expand_out(input: &DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let packs = struct_fields(&data.fields)?
+                .into_iter()
+                .map(|field| pack_field(name, &field));
+            quote! {
+                #(#packs)*
+                Ok(())
+            }
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let bindings = bindings(&variant.fields);
+                let pattern = pattern(name, ident, &variant.fields, &bindings);
+                quote! {
+                    #pattern => {
+                        #(crate::tpm2::serialization::inout::Tpm2StructOut::pack(#bindings, buff)?;)*
+                    }
+                }
+            });
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+                Ok(())
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "Tpm2StructOut cannot be derived for unions, use an enum",
+            ))
+        }
+    };
+    Ok(quote! {
+        impl #impl_generics crate::tpm2::serialization::inout::Tpm2StructOut for #name #ty_generics #where_clause {
+            fn pack(
+                &self,
+                buff: &mut dyn crate::tpm2::serialization::inout::RwBytes,
+            ) -> ::core::result::Result<(), crate::tpm2::errors::SerializationError> {
+                #body
+            }
+        }
+    })
+}
+
+fn expand_in(input: &DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    match &input.data {
+        Data::Struct(data) => {
+            let unpacks = struct_fields(&data.fields)?
+                .into_iter()
+                .map(|field| unpack_field(name, &field));
+            Ok(quote! {
+                impl #impl_generics crate::tpm2::serialization::inout::Tpm2StructIn for #name #ty_generics #where_clause {
+                    fn unpack(
+                        &mut self,
+                        buff: &mut dyn crate::tpm2::serialization::inout::RwBytes,
+                    ) -> ::core::result::Result<(), crate::tpm2::errors::DeserializationError> {
+                        #(#unpacks)*
+                        Ok(())
+                    }
+                }
+            })
+        }
+        Data::Enum(data) => {
+            let body = unpack_union(name, data)?;
+            Ok(quote! {
+                impl #impl_generics crate::tpm2::serialization::inout::Tpm2UnionIn for #name #ty_generics #where_clause {
+                    fn unpack_selected(
+                        &mut self,
+                        selector: u32,
+                        buff: &mut dyn crate::tpm2::serialization::inout::RwBytes,
+                    ) -> ::core::result::Result<(), crate::tpm2::errors::DeserializationError> {
+                        #body
+                    }
+                }
+            })
+        }
+        Data::Union(_) => Err(syn::Error::new(
+            Span::call_site(),
+            "Tpm2StructIn cannot be derived for unions, use an enum",
+        )),
+    }
+}
+
+fn pack_field(name: &Ident, field: &Field) -> TokenStream2 {
+    let member = &field.member;
+    match &field.kind {
+        FieldKind::Plain | FieldKind::Union(_) => quote! {
+            crate::tpm2::serialization::inout::Tpm2StructOut::pack(&self.#member, buff)?;
+        },
+        FieldKind::Sized => quote! {
+            crate::tpm2::serialization::inout::pack_sized(&self.#member, buff)?;
+        },
+        FieldKind::Counted(count) => {
+            let what = describe(name, member);
+            quote! {
+                crate::tpm2::serialization::inout::pack_counted(
+                    #what,
+                    &self.#member[..],
+                    self.#count as usize,
+                    buff,
+                )?;
+            }
+        }
+    }
+}
+
+fn unpack_field(name: &Ident, field: &Field) -> TokenStream2 {
+    let member = &field.member;
+    match &field.kind {
+        FieldKind::Plain => quote! {
+            crate::tpm2::serialization::inout::Tpm2StructIn::unpack(&mut self.#member, buff)?;
+        },
+        FieldKind::Sized => quote! {
+            crate::tpm2::serialization::inout::unpack_sized(&mut self.#member, buff)?;
+        },
+        FieldKind::Counted(count) => {
+            let what = describe(name, member);
+            quote! {
+                crate::tpm2::serialization::inout::unpack_counted(
+                    #what,
+                    &mut self.#member[..],
+                    self.#count as usize,
+                    buff,
+                )?;
+            }
+        }
+        FieldKind::Union(selector) => quote! {
+            crate::tpm2::serialization::inout::Tpm2UnionIn::unpack_selected(
+                &mut self.#member,
+                self.#selector as u32,
+                buff,
+            )?;
+        },
+    }
+}
+
+// unpack_union builds the body of Tpm2UnionIn::unpack_selected, matching
+// the selector against the values of every variant marked with some
+fn unpack_union(name: &Ident, data: &DataEnum) -> Result<TokenStream2> {
+    let mut arms = Vec::new();
+    for variant in data.variants.iter() {
+        let values = selector_values(&variant.attrs)?;
+        if values.is_empty() {
+            continue;
+        }
+        for field in variant.fields.iter() {
+            if let Some(attr) = field.attrs.iter().find(|attr| is_field_attr(attr)) {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    "attributes are not supported on union members",
+                ));
+            }
+        }
+        let ident = &variant.ident;
+        let bindings = bindings(&variant.fields);
+        let construct = pattern(name, ident, &variant.fields, &bindings);
+        arms.push(quote! {
+            s if #(s == (#values) as u32)||* => {
+                #(let mut #bindings = ::core::default::Default::default();)*
+                #(crate::tpm2::serialization::inout::Tpm2StructIn::unpack(&mut #bindings, buff)?;)*
+                *self = #construct;
+            }
+        });
+    }
+    let unsupported = format!("{} has no member for selector {{:#x}}", name);
+    Ok(quote! {
+        match selector {
+            #(#arms)*
+            _ => {
+                return Err(crate::tpm2::errors::DeserializationError {
+                    msg: format!(#unsupported, selector),
+                })
+            }
+        }
+        Ok(())
+    })
+}
+
+fn struct_fields(fields: &Fields) -> Result<Vec<Field>> {
+    let members: Vec<Member> = fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(index.into()),
+        })
+        .collect();
+    let mut result = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        let member = members[index].clone();
+        let mut kind = FieldKind::Plain;
+        for attr in field.attrs.iter().filter(|attr| is_field_attr(attr)) {
+            if !matches!(kind, FieldKind::Plain) {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    "tpm2b, count and selector are mutually exclusive",
+                ));
+            }
+            kind = if attr.path().is_ident("tpm2b") {
+                attr.meta.require_path_only()?;
+                FieldKind::Sized
+            } else if attr.path().is_ident("count") {
+                FieldKind::Counted(preceding_field(attr, &members[..index])?)
+            } else {
+                FieldKind::Union(preceding_field(attr, &members[..index])?)
+            };
+        }
+        result.push(Field { member, kind });
+    }
+    Ok(result)
+}
+
+fn is_field_attr(attr: &Attribute) -> bool {
+    attr.path().is_ident("tpm2b")
+        || attr.path().is_ident("count")
+        || attr.path().is_ident("selector")
+}
+
+// string_value returns the string of a name = "value" attribute
+fn string_value(attr: &Attribute) -> Result<syn::LitStr> {
+    match &attr.meta {
+        Meta::NameValue(meta) => match &meta.value {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(value),
+                ..
+            }) => Ok(value.clone()),
+            value => Err(syn::Error::new(value.span(), "expected a string literal")),
+        },
+        meta => Err(syn::Error::new(meta.span(), "expected name = \"value\"")),
+    }
+}
+
+// field_name returns the structure field named by a count or selector
+// attribute
+fn field_name(attr: &Attribute) -> Result<Member> {
+    let value = string_value(attr)?;
+    match value.value().parse::<usize>() {
+        Ok(index) => Ok(Member::Unnamed(index.into())),
+        Err(_) => Ok(Member::Named(value.parse::<Ident>()?)),
+    }
+}
+
+// preceding_field returns the field named by a count or selector attribute,
+// which must be one of the fields declared before the attribute's field:
+// those are unpacked first
+fn preceding_field(attr: &Attribute, preceding: &[Member]) -> Result<Member> {
+    let member = field_name(attr)?;
+    if !preceding.contains(&member) {
+        let value = string_value(attr)?;
+        return Err(syn::Error::new(
+            value.span(),
+            format!(
+                "`{}` is not a field declared before this one",
+                value.value()
+            ),
+        ));
+    }
+    Ok(member)
+}
+
+// selector_values returns the selector values a union variant is unpacked
+// for
+fn selector_values(attrs: &[Attribute]) -> Result<Vec<Expr>> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("selector"))
+        .map(|attr| string_value(attr)?.parse::<Expr>())
+        .collect()
+}
+
+// bindings names the fields of a variant
+fn bindings(fields: &Fields) -> Vec<Ident> {
+    (0..fields.len())
+        .map(|index| format_ident!("field{}", index))
+        .collect()
+}
+
+// pattern matches, or constructs, a variant with its fields bound to bindings
+fn pattern(name: &Ident, variant: &Ident, fields: &Fields, bindings: &[Ident]) -> TokenStream2 {
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote! { #name::#variant { #(#names: #bindings),* } }
+        }
+        Fields::Unnamed(_) => quote! { #name::#variant(#(#bindings),*) },
+        Fields::Unit => quote! { #name::#variant },
+    }
+}
+
+fn describe(name: &Ident, member: &Member) -> String {
+    match member {
+        Member::Named(ident) => format!("{}.{}", name, ident),
+        Member::Unnamed(index) => format!("{}.{}", name, index.index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    // assert_expands checks that the expansion contains the statement
+    fn assert_expands(expansion: Result<TokenStream2>, statement: TokenStream2) {
+        let expansion = expansion.unwrap().to_string();
+        let statement = statement.to_string();
+        assert!(
+            expansion.contains(&statement),
+            "{} not found in {}",
+            statement,
+            expansion
+        );
+    }
+
+    fn assert_error(expansion: Result<TokenStream2>, msg: &str) {
+        match expansion {
+            Err(err) => assert_eq!(err.to_string(), msg),
+            Ok(tokens) => panic!("unexpected expansion {}", tokens),
+        }
+    }
+
+    #[test]
+    fn sized_field() {
+        let input: DeriveInput = parse_quote! {
+            struct Sensitive {
+                #[tpm2b]
+                sensitive: TpmtSensitive,
+            }
+        };
+        assert_expands(
+            expand_out(&input),
+            quote! {
+                crate::tpm2::serialization::inout::pack_sized(&self.sensitive, buff)?;
+            },
+        );
+        assert_expands(
+            expand_in(&input),
+            quote! {
+                crate::tpm2::serialization::inout::unpack_sized(&mut self.sensitive, buff)?;
+            },
+        );
+    }
+
+    #[test]
+    fn counted_field() {
+        let input: DeriveInput = parse_quote! {
+            struct Digests {
+                count: u32,
+                #[count = "count"]
+                digests: [Tpm2bDigest; 8],
+            }
+        };
+        assert_expands(
+            expand_out(&input),
+            quote! {
+                crate::tpm2::serialization::inout::pack_counted(
+                    "Digests.digests",
+                    &self.digests[..],
+                    self.count as usize,
+                    buff,
+                )?;
+            },
+        );
+        assert_expands(
+            expand_in(&input),
+            quote! {
+                crate::tpm2::serialization::inout::unpack_counted(
+                    "Digests.digests",
+                    &mut self.digests[..],
+                    self.count as usize,
+                    buff,
+                )?;
+            },
+        );
+    }
+
+    #[test]
+    fn selected_field() {
+        let input: DeriveInput = parse_quote! {
+            struct Public(TpmiAlgPublic, #[selector = "0"] TpmuPublicParms);
+        };
+        assert_expands(
+            expand_out(&input),
+            quote! {
+                crate::tpm2::serialization::inout::Tpm2StructOut::pack(&self.1, buff)?;
+            },
+        );
+        assert_expands(
+            expand_in(&input),
+            quote! {
+                crate::tpm2::serialization::inout::Tpm2UnionIn::unpack_selected(
+                    &mut self.1,
+                    self.0 as u32,
+                    buff,
+                )?;
+            },
+        );
+    }
+
+    #[test]
+    fn union() {
+        let input: DeriveInput = parse_quote! {
+            enum Parms {
+                #[selector = "TpmAlgId::RSA"]
+                Rsa(TpmsRsaParms),
+                #[selector = "TpmAlgId::KEYEDHASH"]
+                #[selector = "TpmAlgId::SYMCIPHER"]
+                Detail { detail: TpmsKeyedHashParms },
+                Null,
+            }
+        };
+        assert_expands(
+            expand_out(&input),
+            quote! {
+                Parms::Detail { detail: field0 } => {
+                    crate::tpm2::serialization::inout::Tpm2StructOut::pack(field0, buff)?;
+                }
+            },
+        );
+        assert_expands(
+            expand_in(&input),
+            quote! {
+                s if s == (TpmAlgId::RSA) as u32 => {
+                    let mut field0 = ::core::default::Default::default();
+                    crate::tpm2::serialization::inout::Tpm2StructIn::unpack(&mut field0, buff)?;
+                    *self = Parms::Rsa(field0);
+                }
+            },
+        );
+        assert_expands(
+            expand_in(&input),
+            quote! {
+                s if s == (TpmAlgId::KEYEDHASH) as u32 || s == (TpmAlgId::SYMCIPHER) as u32 => {
+                    let mut field0 = ::core::default::Default::default();
+                    crate::tpm2::serialization::inout::Tpm2StructIn::unpack(&mut field0, buff)?;
+                    *self = Parms::Detail { detail: field0 };
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn bad_attributes() {
+        let inputs: [(DeriveInput, &str); 6] = [
+            (
+                parse_quote! { struct S { #[tpm2b = "size"] data: Tpm2bData } },
+                "unexpected token in attribute",
+            ),
+            (
+                parse_quote! { struct S { count: u32, #[count(count)] data: [u8; 4] } },
+                "expected name = \"value\"",
+            ),
+            (
+                parse_quote! { struct S { count: u32, #[count = 4] data: [u8; 4] } },
+                "expected a string literal",
+            ),
+            (
+                parse_quote! { struct S { count: u32, #[tpm2b] #[count = "count"] data: [u8; 4] } },
+                "tpm2b, count and selector are mutually exclusive",
+            ),
+            (
+                parse_quote! { struct S { #[selector = "type_alg"] parms: TpmuPublicParms } },
+                "`type_alg` is not a field declared before this one",
+            ),
+            (
+                parse_quote! { struct S { #[count = "count"] data: [u8; 4], count: u32 } },
+                "`count` is not a field declared before this one",
+            ),
+        ];
+        for (input, msg) in inputs.iter() {
+            assert_error(expand_out(input), msg);
+            assert_error(expand_in(input), msg);
+        }
+    }
+
+    #[test]
+    fn bad_unions() {
+        let input: DeriveInput = parse_quote! {
+            enum Parms {
+                #[selector = "TpmAlgId::RSA"]
+                Rsa(#[tpm2b] TpmsRsaParms),
+            }
+        };
+        assert_error(
+            expand_in(&input),
+            "attributes are not supported on union members",
+        );
+
+        let input: DeriveInput = parse_quote! {
+            union Parms {
+                rsa: TpmsRsaParms,
+            }
+        };
+        assert_error(
+            expand_out(&input),
+            "Tpm2StructOut cannot be derived for unions, use an enum",
+        );
+        assert_error(
+            expand_in(&input),
+            "Tpm2StructIn cannot be derived for unions, use an enum",
+        );
+    }
+}
@@ -0,0 +1,9 @@
+// The macros expand to paths under crate::tpm2, so test cases which compile
+// import the tpm2 module of the tpm2 crate at their root, as if they were the
+// tpm2 crate itself.
+#[test]
+fn ui() {
+    let cases = trybuild::TestCases::new();
+    cases.pass("tests/ui/pass/*.rs");
+    cases.compile_fail("tests/ui/fail/*.rs");
+}
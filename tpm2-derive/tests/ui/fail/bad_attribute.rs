@@ -0,0 +1,10 @@
+use tpm2_derive::Tpm2StructOut;
+
+#[derive(Tpm2StructOut)]
+struct Digests {
+    count: u32,
+    #[count(count)]
+    digests: [u16; 8],
+}
+
+fn main() {}
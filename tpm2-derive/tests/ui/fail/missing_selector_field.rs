@@ -0,0 +1,9 @@
+use tpm2_derive::Tpm2StructIn;
+
+#[derive(Tpm2StructIn)]
+struct Public {
+    #[selector = "type_alg"]
+    parameters: u16,
+}
+
+fn main() {}
@@ -0,0 +1,59 @@
+use ::tpm2::tpm2::serialization::inout::{DynamicByteBuffer, RwBytes, Tpm2StructIn, Tpm2StructOut};
+use tpm2::tpm2;
+use tpm2_derive::{Tpm2StructIn, Tpm2StructOut};
+
+#[derive(Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+struct Inner {
+    value: u32,
+}
+
+#[derive(Debug, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+enum Choice {
+    #[selector = "1"]
+    Word(u16),
+    #[selector = "2"]
+    Inner { inner: Inner },
+}
+
+impl Default for Choice {
+    fn default() -> Self {
+        Choice::Word(0)
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Tpm2StructIn, Tpm2StructOut)]
+struct Message {
+    kind: u32,
+    #[selector = "kind"]
+    choice: Choice,
+    count: u16,
+    #[count = "count"]
+    values: [u16; 4],
+    #[tpm2b]
+    inner: Inner,
+}
+
+fn main() {
+    let message = Message {
+        kind: 2,
+        choice: Choice::Inner {
+            inner: Inner { value: 0x01020304 },
+        },
+        count: 2,
+        values: [0x0a0b, 0x0c0d, 0, 0],
+        inner: Inner { value: 0x05060708 },
+    };
+    let mut buff = DynamicByteBuffer::new();
+    message.pack(&mut buff).unwrap();
+    assert_eq!(
+        buff.to_bytes(),
+        &[
+            0x00, 0x00, 0x00, 0x02, 0x01, 0x02, 0x03, 0x04, 0x00, 0x02, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x00, 0x04, 0x05, 0x06, 0x07, 0x08,
+        ][..]
+    );
+
+    let mut unpacked = Message::default();
+    unpacked.unpack(&mut buff).unwrap();
+    assert_eq!(unpacked, message);
+}